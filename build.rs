@@ -1,20 +1,145 @@
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+/// Target triple to build the guest for, e.g. `x86_64-unknown-linux-gnu`;
+/// the toolchain's default target when unset
+const GUEST_TARGET_ENV: &str = "GUEST_TARGET";
+/// Further prebuilt guests to embed as agent profiles, as comma-separated
+/// `name=path` pairs such as `minimal=guests/minimal.so`
+const GUEST_PROFILES_ENV: &str = "GUEST_PROFILES";
+/// Profile of the guest built from `guest/`; see `guest_binary::DEFAULT_PROFILE`
+const DEFAULT_PROFILE: &str = "default";
+
 fn main() {
     println!("cargo:rerun-if-changed=guest/src");
     println!("cargo:rerun-if-changed=guest/Cargo.toml");
+    println!("cargo:rerun-if-changed=src/guest_error.rs");
+    println!("cargo:rerun-if-changed=proto");
+    println!("cargo:rerun-if-env-changed={}", GUEST_TARGET_ENV);
+    println!("cargo:rerun-if-env-changed={}", GUEST_PROFILES_ENV);
+
+    // Generate the gRPC service from the published .proto
+    tonic_build::configure()
+        .btree_map(["."])
+        .compile_protos(&["proto/jobs.proto"], &["proto"])
+        .expect("Failed to compile protos");
 
-    // Build the guest binary
-    let status = Command::new("cargo")
-        .args(&["build", "--release"])
-        .current_dir("guest")
-        .status()
-        .expect("Failed to build guest");
+    // Build the guest binary, for an explicit target if one is configured so
+    // CI runners produce the same artifact as dev machines
+    let target = std::env::var(GUEST_TARGET_ENV)
+        .ok()
+        .filter(|target| !target.trim().is_empty());
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut build = Command::new(&cargo);
+    build.args(["build", "--release"]).current_dir("guest");
+    if let Some(target) = &target {
+        build.args(["--target", target]);
+    }
 
+    let status = build.status().unwrap_or_else(|e| {
+        fail(
+            &format!("Failed to run `{} build` for the guest: {}", cargo, e),
+            "Make sure cargo is installed and on PATH",
+        )
+    });
     if !status.success() {
-        panic!("Guest build failed");
+        let hint = match &target {
+            Some(target) => format!(
+                "Check that the target is installed (`rustup target add {}`) and that \
+                 {} names a valid target triple",
+                target, GUEST_TARGET_ENV
+            ),
+            None => format!(
+                "See the errors above; set {} to build for a target other than the \
+                 toolchain's default",
+                GUEST_TARGET_ENV
+            ),
+        };
+        fail("Guest build failed", &hint);
     }
 
     // Tell cargo where to find the guest binary for include_bytes!
-    println!("cargo:rustc-env=GUEST_BINARY_PATH=guest/target/release/libagent_guest.so");
+    let mut artifact = PathBuf::from("guest/target");
+    if let Some(target) = &target {
+        artifact.push(target);
+    }
+    artifact.push("release/libagent_guest.so");
+    if !artifact.is_file() {
+        fail(
+            &format!("Guest build succeeded but {} does not exist", artifact.display()),
+            "The guest must build as a cdylib named agent_guest; check [lib] in \
+             guest/Cargo.toml and that CARGO_TARGET_DIR isn't set for the guest build",
+        );
+    }
+    println!("cargo:rustc-env=GUEST_BINARY_PATH={}", artifact.display());
+
+    embed_guest_profiles();
+}
+
+/// Write `$OUT_DIR/guest_profiles.rs`, embedding the guests listed in
+/// `GUEST_PROFILES` alongside the one built from `guest/`
+fn embed_guest_profiles() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let profiles = std::env::var(GUEST_PROFILES_ENV).unwrap_or_default();
+
+    let mut entries = String::new();
+    let mut names = vec![DEFAULT_PROFILE.to_string()];
+    for entry in profiles.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let Some((name, path)) = entry.split_once('=') else {
+            fail(
+                &format!("Invalid {} entry '{}'", GUEST_PROFILES_ENV, entry),
+                "Entries are name=path pairs, e.g. minimal=guests/minimal.so",
+            );
+        };
+        let name = name.trim();
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            fail(
+                &format!("Invalid agent profile name '{}'", name),
+                "Profile names may only contain letters, digits, '-' and '_'",
+            );
+        }
+        if names.iter().any(|existing| existing == name) {
+            fail(
+                &format!("Agent profile '{}' is listed more than once", name),
+                &format!(
+                    "'{}' is the guest built from guest/; give every other profile \
+                     its own name",
+                    DEFAULT_PROFILE
+                ),
+            );
+        }
+
+        let path = manifest_dir.join(path.trim());
+        if !path.is_file() {
+            fail(
+                &format!(
+                    "Guest binary of agent profile '{}' not found at {}",
+                    name,
+                    path.display()
+                ),
+                "Build that guest first; relative paths are resolved from the crate root",
+            );
+        }
+        println!("cargo:rerun-if-changed={}", path.display());
+        entries.push_str(&format!("    ({:?}, include_bytes!({:?})),\n", name, path));
+        names.push(name.to_string());
+    }
+
+    let contents = format!(
+        "/// Guests embedded from `{}`, by agent profile\n\
+         pub const EXTRA_PROFILES: &[(&str, &[u8])] = &[\n{}];\n",
+        GUEST_PROFILES_ENV, entries
+    );
+    let out = Path::new(&std::env::var("OUT_DIR").unwrap()).join("guest_profiles.rs");
+    std::fs::write(&out, contents)
+        .unwrap_or_else(|e| panic!("Failed to write {}: {}", out.display(), e));
+}
+
+/// Abort the build with an error and how to fix it
+fn fail(message: &str, hint: &str) -> ! {
+    panic!("{}\n  hint: {}", message, hint)
 }
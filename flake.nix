@@ -35,6 +35,7 @@
 
         nativeBuildInputs = with pkgs; [
           pkg-config
+          protobuf
           rustToolchain
         ];
 
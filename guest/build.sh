@@ -3,12 +3,18 @@ set -e
 
 echo "Building Hyperlight guest binary..."
 
-# Build the guest library
+# Build the guest library, for $GUEST_TARGET if set
 cd "$(dirname "$0")"
-cargo build --release
+if [ -n "$GUEST_TARGET" ]; then
+    cargo build --release --target "$GUEST_TARGET"
+    ARTIFACT="$(pwd)/target/$GUEST_TARGET/release/libagent_guest.so"
+else
+    cargo build --release
+    ARTIFACT="$(pwd)/target/release/libagent_guest.so"
+fi
 
 echo "Guest binary built successfully!"
-echo "Location: $(pwd)/target/release/libagent_guest.so"
+echo "Location: $ARTIFACT"
 echo ""
 echo "To use this guest with the redis-agent-worker, set:"
-echo "  export GUEST_BINARY_PATH=$(pwd)/target/release/libagent_guest.so"
+echo "  export GUEST_BINARY_PATH=$ARTIFACT"
@@ -6,7 +6,7 @@ extern crate alloc;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::format;
-use hyperlight_common::flatbuffer_wrappers::function_call::FunctionCall;
+use hyperlight_common::flatbuffer_wrappers::function_call::{FunctionCall, FunctionCallType};
 use hyperlight_common::flatbuffer_wrappers::function_types::{
     ParameterType, ParameterValue, ReturnType,
 };
@@ -16,8 +16,14 @@ use hyperlight_guest::error::{HyperlightGuestError, Result};
 use hyperlight_guest_bin::guest_function::definition::GuestFunctionDefinition;
 use hyperlight_guest_bin::guest_function::register::register_function;
 use hyperlight_guest_bin::host_comm::call_host_function;
+use serde::{Deserialize, Serialize};
 use tracing::{Span, instrument};
 
+#[path = "../../src/guest_error.rs"]
+mod guest_error;
+
+use guest_error::{GuestError, GuestErrorCode};
+
 /// Main entry point for the hyperlight guest
 /// Registers all available guest functions
 #[no_mangle]
@@ -46,6 +52,26 @@ pub extern "C" fn hyperlight_main() {
         call_mcp_tool as usize,
     );
     register_function(call_mcp_tool_def);
+
+    // Register LLM completion function
+    let call_llm_def = GuestFunctionDefinition::new(
+        "CallLLM".to_string(),
+        Vec::from(&[
+            ParameterType::String,  // request (JSON)
+        ]),
+        ReturnType::String,
+        call_llm as usize,
+    );
+    register_function(call_llm_def);
+
+    // Register the self-test the host runs to check the functions above
+    let self_test_def = GuestFunctionDefinition::new(
+        "RunGuestSelfTest".to_string(),
+        Vec::new(),
+        ReturnType::String,
+        run_guest_self_test as usize,
+    );
+    register_function(self_test_def);
 }
 
 /// Main agent execution function
@@ -74,29 +100,109 @@ fn execute_agent(function_call: &FunctionCall) -> Result<Vec<u8>> {
         )),
     };
 
+    if prompt.trim().is_empty() {
+        return Err(guest_error(GuestErrorCode::BadPrompt, "The prompt is empty"));
+    }
+
     // Agent logic implementation
+    // The time budget gates the request, and is checked before the steps
+    // leading up to it, which may already use it up
+    check_limits()?;
+
     // 1. Initialize connection to MCP server (through host)
+    report_progress("connect", 10, "Connecting to the MCP server")?;
     call_host_function::<()>(
         "InitializeMCPConnection",
         Some(Vec::from(&[ParameterValue::String(mcp_server_url.clone())])),
         ReturnType::Void,
-    )?;
+    )
+    .map_err(|e| guest_error(GuestErrorCode::ToolUnavailable, &e.message))?;
 
     // 2. Get available tools from MCP server
+    report_progress("discover", 30, "Listing the available tools")?;
     let tools_json = call_host_function::<String>(
         "GetMCPTools",
         None,
         ReturnType::String,
+    )
+    .map_err(|e| guest_error(GuestErrorCode::ToolUnavailable, &e.message))?;
+
+    // 3. Get a summary of the repository to orient in
+    report_progress("orient", 45, "Mapping the repository")?;
+    let repo_map_json = call_host_function::<String>(
+        "GetRepoMap",
+        None,
+        ReturnType::String,
     )?;
 
-    // 3. Process the prompt and determine which tools to use
-    let response = process_agent_request(prompt, &tools_json)?;
+    // 4. Process the prompt and determine which tools to use
+    check_limits()?;
+    report_progress("work", 60, "Working on the prompt")?;
+    let response = process_agent_request(prompt, &tools_json, &repo_map_json)?;
 
+    report_progress("done", 100, "Finished")?;
     Ok(get_flatbuffer_result(&*response))
 }
 
-/// Process an agent request with the given prompt and available tools
-fn process_agent_request(prompt: &str, tools_json: &str) -> Result<String> {
+/// An error the host maps to a retry or dead-letter decision by its code
+fn guest_error(code: GuestErrorCode, message: &str) -> HyperlightGuestError {
+    HyperlightGuestError::new(ErrorCode::GuestError, GuestError { code, message }.to_string())
+}
+
+/// Tell the host how far along the agent is, so the job's status shows it
+/// while the guest is still running
+fn report_progress(step: &str, percent: u32, message: &str) -> Result<()> {
+    call_host_function::<()>(
+        "ReportProgress",
+        Some(Vec::from(&[
+            ParameterValue::String(step.to_string()),
+            ParameterValue::UInt(percent),
+            ParameterValue::String(message.to_string()),
+        ])),
+        ReturnType::Void,
+    )
+}
+
+/// Budget of the agent's requests, as `GetLimits` reports it
+#[derive(Deserialize)]
+struct Limits {
+    /// Milliseconds left of the time budget
+    remaining_ms: Option<u64>,
+}
+
+impl Limits {
+    /// Why the agent must stop before its next step, if it must
+    fn reached(&self) -> Option<String> {
+        if self.remaining_ms == Some(0) {
+            return Some("Stopped when time ran out".to_string());
+        }
+        None
+    }
+}
+
+/// Stop with a `BudgetExceeded` error if the budget doesn't allow the next
+/// step
+fn check_limits() -> Result<()> {
+    match get_limits()?.reached() {
+        Some(reason) => Err(guest_error(GuestErrorCode::BudgetExceeded, &reason)),
+        None => Ok(()),
+    }
+}
+
+/// Ask the host how much of the agent's budget is left
+fn get_limits() -> Result<Limits> {
+    let limits_json = call_host_function::<String>("GetLimits", None, ReturnType::String)?;
+    serde_json::from_str(&limits_json).map_err(|e| {
+        HyperlightGuestError::new(
+            ErrorCode::GuestError,
+            format!("Host returned malformed limits: {}", e),
+        )
+    })
+}
+
+/// Process an agent request with the given prompt, available tools and
+/// repository map
+fn process_agent_request(prompt: &str, tools_json: &str, repo_map_json: &str) -> Result<String> {
     // Simple agent logic:
     // 1. Analyze the prompt
     // 2. Determine which tools to call
@@ -105,9 +211,10 @@ fn process_agent_request(prompt: &str, tools_json: &str) -> Result<String> {
 
     // For now, return a simple response that demonstrates the agent is working
     let response = format!(
-        "Agent processed prompt: '{}'\nAvailable tools: {}\n\nAgent is running securely in Hyperlight guest!",
+        "Agent processed prompt: '{}'\nAvailable tools: {}\nRepository: {}\n\nAgent is running securely in Hyperlight guest!",
         prompt,
-        tools_json
+        tools_json,
+        repo_map_json
     );
 
     Ok(response)
@@ -151,3 +258,163 @@ fn call_mcp_tool(function_call: &FunctionCall) -> Result<Vec<u8>> {
 
     Ok(get_flatbuffer_result(&*result))
 }
+
+/// Send a completion request to an LLM through the host
+/// The host picks the provider and fails over to the next when one errors
+fn call_llm(function_call: &FunctionCall) -> Result<Vec<u8>> {
+    let params = function_call.parameters.as_ref()
+        .ok_or_else(|| HyperlightGuestError::new(
+            ErrorCode::GuestFunctionParameterTypeMismatch,
+            "Missing parameters".to_string(),
+        ))?;
+
+    let request_json = match &params[0] {
+        ParameterValue::String(s) => s,
+        _ => return Err(HyperlightGuestError::new(
+            ErrorCode::GuestFunctionParameterTypeMismatch,
+            "First parameter must be string (request)".to_string(),
+        )),
+    };
+
+    let result = call_host_function::<String>(
+        "LlmComplete",
+        Some(Vec::from(&[ParameterValue::String(request_json.clone())])),
+        ReturnType::String,
+    )
+    .map_err(|e| guest_error(GuestErrorCode::ToolUnavailable, &e.message))?;
+
+    Ok(get_flatbuffer_result(&*result))
+}
+
+/// One check of `RunGuestSelfTest`, as reported to the host
+#[derive(Serialize)]
+struct SelfTestCheck {
+    name: String,
+    passed: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    message: String,
+}
+
+#[derive(Serialize)]
+struct SelfTestReport {
+    checks: Vec<SelfTestCheck>,
+}
+
+/// Exercise each guest function with known inputs and report the outcome
+/// as JSON. Only cases that are decided before calling the host are run,
+/// so the host needs no MCP server
+fn run_guest_self_test(_function_call: &FunctionCall) -> Result<Vec<u8>> {
+    let call = |name: &str, parameters: Option<Vec<ParameterValue>>| {
+        FunctionCall::new(
+            name.to_string(),
+            parameters,
+            FunctionCallType::Guest,
+            ReturnType::String,
+        )
+    };
+    let string = |s: &str| ParameterValue::String(s.to_string());
+
+    let mut checks = Vec::new();
+    let mut check = |name: &str, outcome: core::result::Result<(), String>| {
+        checks.push(SelfTestCheck {
+            name: name.to_string(),
+            passed: outcome.is_ok(),
+            message: outcome.err().unwrap_or_default(),
+        });
+    };
+
+    check(
+        "ExecuteAgent/missing_parameters",
+        expect_mismatch(execute_agent(&call("ExecuteAgent", None))),
+    );
+    check(
+        "ExecuteAgent/prompt_not_string",
+        expect_mismatch(execute_agent(&call(
+            "ExecuteAgent",
+            Some(Vec::from([ParameterValue::Int(1), string("")])),
+        ))),
+    );
+    check(
+        "ExecuteAgent/mcp_url_not_string",
+        expect_mismatch(execute_agent(&call(
+            "ExecuteAgent",
+            Some(Vec::from([string("Fix the build"), ParameterValue::Int(1)])),
+        ))),
+    );
+    check(
+        "ExecuteAgent/empty_prompt",
+        match execute_agent(&call("ExecuteAgent", Some(Vec::from([string(" "), string("")])))) {
+            Err(e) => match GuestErrorCode::find(&e.message) {
+                Some((GuestErrorCode::BadPrompt, _)) => Ok(()),
+                _ => Err(format!("Expected a bad_prompt error, got: {}", e.message)),
+            },
+            Ok(_) => Err("Expected a bad_prompt error, but the call succeeded".to_string()),
+        },
+    );
+    check(
+        "CallMCPTool/missing_parameters",
+        expect_mismatch(call_mcp_tool(&call("CallMCPTool", None))),
+    );
+    check(
+        "CallMCPTool/tool_name_not_string",
+        expect_mismatch(call_mcp_tool(&call(
+            "CallMCPTool",
+            Some(Vec::from([ParameterValue::Int(1), string("{}")])),
+        ))),
+    );
+    check(
+        "CallMCPTool/arguments_not_string",
+        expect_mismatch(call_mcp_tool(&call(
+            "CallMCPTool",
+            Some(Vec::from([string("read_file"), ParameterValue::Int(1)])),
+        ))),
+    );
+    check(
+        "CallLLM/missing_parameters",
+        expect_mismatch(call_llm(&call("CallLLM", None))),
+    );
+    check(
+        "CallLLM/request_not_string",
+        expect_mismatch(call_llm(&call("CallLLM", Some(Vec::from([ParameterValue::Int(1)]))))),
+    );
+    check(
+        "ExecuteAgent/process_agent_request",
+        match process_agent_request("Fix the build", "[\"read_file\"]", "{\"files\":1}") {
+            Ok(response) if response.contains("Fix the build") && response.contains("read_file") => {
+                Ok(())
+            }
+            Ok(response) => Err(format!("Response lacks the prompt or tools: {}", response)),
+            Err(e) => Err(format!("{:?}", e)),
+        },
+    );
+
+    check(
+        "ExecuteAgent/limits",
+        {
+            let limits = Limits { remaining_ms: Some(1_000) };
+            let unlimited = Limits { remaining_ms: None };
+            let out_of_time = Limits { remaining_ms: Some(0) };
+            match (limits.reached(), unlimited.reached(), out_of_time.reached()) {
+                (None, None, Some(_)) => Ok(()),
+                outcome => Err(format!("Limits were applied wrongly: {:?}", outcome)),
+            }
+        },
+    );
+
+    let report = serde_json::to_string(&SelfTestReport { checks }).map_err(|e| {
+        HyperlightGuestError::new(
+            ErrorCode::GuestError,
+            format!("Failed to serialize self-test report: {}", e),
+        )
+    })?;
+    Ok(get_flatbuffer_result(&*report))
+}
+
+/// Pass if a guest function rejected its parameters
+fn expect_mismatch(result: Result<Vec<u8>>) -> core::result::Result<(), String> {
+    match result {
+        Err(e) if e.kind == ErrorCode::GuestFunctionParameterTypeMismatch => Ok(()),
+        Err(e) => Err(format!("Expected a parameter type mismatch, got {:?}: {}", e.kind, e.message)),
+        Ok(_) => Err("Expected a parameter type mismatch, but the call succeeded".to_string()),
+    }
+}
@@ -3,13 +3,89 @@ use hyperlight_host::sandbox::SandboxConfiguration;
 use hyperlight_host::{new_error, GuestBinary, MultiUseSandbox, UninitializedSandbox};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock};
 use tracing::{debug, error, info, warn};
 use url::Url;
 
-use crate::guest_binary::GUEST_BINARY;
+use crate::guest_binary;
+use crate::guest_error::GuestErrorCode;
+use crate::llm::{self, LlmRouter};
+use crate::memo::{CallMemo, MemoConfig};
+use crate::metrics::SandboxMetrics;
+use crate::repo_map::{self, RepoMap};
+use crate::result::ToolCall;
+use crate::scratch::ScratchDir;
+use crate::tool_schema::ToolSchemas;
+use crate::trace::{self, TRACEPARENT};
+
+/// MCP tools a read-only job may call
+///
+/// MCP doesn't say which tools write, so a read-only job gets an allow list
+/// of the well-known ones that only read rather than a list of writers that
+/// would miss any tool it doesn't know.
+pub const READ_ONLY_TOOLS: [&str; 10] = [
+    "read_file",
+    "read_text_file",
+    "read_media_file",
+    "read_multiple_files",
+    "list_directory",
+    "list_directory_with_sizes",
+    "directory_tree",
+    "search_files",
+    "get_file_info",
+    "list_allowed_directories",
+];
+
+/// Why the guest may not call `tool`, if it may not: it isn't in the job's
+/// `allowed_tools`, or the job is read-only and the tool may write
+fn tool_refusal(tool: &str, allowed_tools: Option<&[String]>, read_only: bool) -> Option<String> {
+    if allowed_tools.is_some_and(|tools| !tools.iter().any(|allowed| allowed == tool)) {
+        return Some(format!("Tool not allowed: {}", tool));
+    }
+    if read_only && !READ_ONLY_TOOLS.contains(&tool) {
+        return Some(format!("Tool not allowed for read-only jobs: {}", tool));
+    }
+    None
+}
+
+/// A phase of the agent's work, reported by the guest through `ReportProgress`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentProgress {
+    pub step: String,
+    /// 0 to 100
+    pub percent: u8,
+    pub message: String,
+}
+
+/// Budget of the guest's agent, which it checks through `GetLimits` before
+/// connecting to the MCP server and before its request, so it stops with a
+/// `BudgetExceeded` error instead of being killed by the job timeout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AgentLimits {
+    pub max_elapsed: Option<Duration>,
+}
+
+/// What `GetLimits` returns to the guest, as JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestLimits {
+    /// Milliseconds left of the execution's time budget
+    pub remaining_ms: Option<u64>,
+}
+
+impl AgentLimits {
+    /// The limits left to an execution that must finish by `deadline`
+    fn remaining(&self, deadline: Option<Instant>) -> GuestLimits {
+        GuestLimits {
+            remaining_ms: deadline.map(|deadline| {
+                deadline.saturating_duration_since(Instant::now()).as_millis() as u64
+            }),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentConfig {
@@ -22,6 +98,33 @@ pub struct AgentExecutor {
     http_client: Client,
     // Track the allowed MCP server URL for this executor instance
     allowed_mcp_url: Arc<RwLock<Option<Url>>>,
+    // MCP tool calls made during the current execution
+    transcript: Arc<Mutex<Vec<ToolCall>>>,
+    // Tools the current execution may call, or all tools when unset
+    allowed_tools: Arc<Mutex<Option<Vec<String>>>>,
+    // Trace context of the current execution's job, sent on MCP requests
+    traceparent: Arc<Mutex<Option<String>>>,
+    // Receives the current execution's progress reports
+    progress: Arc<Mutex<Option<mpsc::UnboundedSender<AgentProgress>>>>,
+    // Schemas MCP tool responses must match before the guest sees them
+    tool_schemas: Arc<ToolSchemas>,
+    // Checkout of the current execution, summarized by `GetRepoMap`
+    repo_path: Arc<Mutex<Option<PathBuf>>>,
+    // Host functions the current execution's guest called
+    host_calls: Arc<AtomicU64>,
+    // LLM tokens the current execution's completion requests used
+    tokens_used: Arc<AtomicU64>,
+    // LLM providers the guest's completion requests are routed to
+    llm_router: Arc<LlmRouter>,
+    // Responses of the current execution's calls, reused for identical ones
+    memo: Arc<Mutex<CallMemo>>,
+    // Scratch directory of the current execution's job
+    scratch: Arc<Mutex<Option<ScratchDir>>>,
+    // Whether the current execution's guest is refused file writes
+    read_only: Arc<AtomicBool>,
+    limits: AgentLimits,
+    // When the current execution's time budget runs out
+    deadline: Arc<Mutex<Option<Instant>>>,
 }
 
 impl AgentExecutor {
@@ -30,16 +133,77 @@ impl AgentExecutor {
             config,
             http_client: Client::new(),
             allowed_mcp_url: Arc::new(RwLock::new(None)),
+            transcript: Arc::new(Mutex::new(Vec::new())),
+            allowed_tools: Arc::new(Mutex::new(None)),
+            traceparent: Arc::new(Mutex::new(None)),
+            progress: Arc::new(Mutex::new(None)),
+            tool_schemas: Arc::new(ToolSchemas::default()),
+            repo_path: Arc::new(Mutex::new(None)),
+            host_calls: Arc::new(AtomicU64::new(0)),
+            tokens_used: Arc::new(AtomicU64::new(0)),
+            llm_router: Arc::new(
+                LlmRouter::new(Vec::new()).expect("HTTP client without options always builds"),
+            ),
+            memo: Arc::new(Mutex::new(CallMemo::default())),
+            scratch: Arc::new(Mutex::new(None)),
+            read_only: Arc::new(AtomicBool::new(false)),
+            limits: AgentLimits::default(),
+            deadline: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Have the guest's agent stop once it reaches `limits`
+    pub fn with_limits(mut self, limits: AgentLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Answer the guest's repeated calls within an execution from their
+    /// first response, for the tools and LLM requests `config` names
+    pub fn with_memo(mut self, config: MemoConfig) -> Self {
+        self.memo = Arc::new(Mutex::new(CallMemo::new(config)));
+        self
+    }
+
+    /// Route the guest's LLM requests through `router`, which fails over
+    /// between its providers
+    pub fn with_llm_router(mut self, router: LlmRouter) -> Self {
+        self.llm_router = Arc::new(router);
+        self
+    }
+
+    /// Send the guest's MCP requests with `client`, sharing its connection
+    /// pool
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.http_client = client;
+        self
+    }
+
+    /// Check MCP tool responses against `schemas`, handing the guest a
+    /// structured error instead of a response that doesn't match
+    pub fn with_tool_schemas(mut self, schemas: ToolSchemas) -> Self {
+        self.tool_schemas = Arc::new(schemas);
+        self
+    }
+
     /// Execute the agent with the given prompt in the repository
-    /// The agent runs in Hyperlight with restricted permissions
+    /// The agent runs in Hyperlight with restricted permissions, and may only
+    /// call the MCP tools in `allowed_tools` if given. It runs the guest of
+    /// `agent_profile`, or the default guest when unset. Progress the guest
+    /// reports is sent to `progress`, which is closed once the agent returns.
+    /// The guest's scratch files go to `scratch`; without one it has none.
+    /// A `read_only` execution may only read files
+    #[allow(clippy::too_many_arguments)]
     pub async fn execute(
         &self,
         repo_path: &Path,
         prompt: &str,
         mcp_connection_url: Option<&str>,
+        allowed_tools: Option<&[String]>,
+        agent_profile: Option<&str>,
+        progress: Option<mpsc::UnboundedSender<AgentProgress>>,
+        scratch: Option<&ScratchDir>,
+        read_only: bool,
     ) -> Result<AgentResult> {
         info!("Executing agent in repository: {:?}", repo_path);
         debug!("Prompt: {}", prompt);
@@ -54,10 +218,98 @@ impl AgentExecutor {
             warn!("No MCP URL provided - agent will have no network access");
         }
 
+        self.transcript.lock().unwrap().clear();
+        *self.allowed_tools.lock().unwrap() = allowed_tools.map(<[String]>::to_vec);
+        // Host functions run outside the job's task, so capture its trace now
+        *self.traceparent.lock().unwrap() = trace::current().map(|trace| trace.traceparent());
+        *self.repo_path.lock().unwrap() = Some(repo_path.to_path_buf());
+        *self.scratch.lock().unwrap() = scratch.cloned();
+        self.read_only.store(read_only, Ordering::Relaxed);
+        self.host_calls.store(0, Ordering::Relaxed);
+        self.tokens_used.store(0, Ordering::Relaxed);
+        self.memo.lock().unwrap().clear();
+
+        let resident_before = resident_bytes();
+        let mut sandbox = self.sandbox(agent_profile).await?;
+
+        // Call the guest's ExecuteAgent function
+        let mcp_url_param = mcp_connection_url.unwrap_or("");
+
+        info!("Calling guest ExecuteAgent function");
+        *self.progress.lock().unwrap() = progress;
+        // The call blocks this thread until the guest returns
+        let faults_before = page_faults();
+        let started = Instant::now();
+        *self.deadline.lock().unwrap() = self.limits.max_elapsed.map(|max| started + max);
+        let output = sandbox.call::<String>(
+            "ExecuteAgent",
+            (prompt.to_string(), mcp_url_param.to_string()),
+        );
+        let execution_ms = started.elapsed().as_millis() as u64;
+        self.progress.lock().unwrap().take();
+        let output = output
+            .map_err(guest_failure)
+            .context("Failed to call guest function")?;
+
+        // Measured while the sandbox is still mapped
+        let metrics = SandboxMetrics {
+            guest_memory_bytes: resident_bytes()
+                .zip(resident_before)
+                .map_or(0, |(after, before)| after.saturating_sub(before)),
+            execution_ms,
+            host_calls: self.host_calls.load(Ordering::Relaxed),
+            page_faults: page_faults()
+                .zip(faults_before)
+                .map(|(after, before)| after.saturating_sub(before)),
+        };
+        info!(
+            "Agent execution completed successfully in {}ms with {} host calls, using {} KiB",
+            metrics.execution_ms,
+            metrics.host_calls,
+            metrics.guest_memory_bytes / 1024
+        );
+        let hits = self.memo.lock().unwrap().hits();
+        if hits > 0 {
+            info!("Answered {} repeated calls from earlier responses", hits);
+        }
+
+        Ok(AgentResult {
+            success: true,
+            exit_code: 0,
+            stdout: output,
+            stderr: String::new(),
+            tool_calls: std::mem::take(&mut *self.transcript.lock().unwrap()),
+            sandbox: metrics,
+            tokens_used: self.tokens_used.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Boot the guest of `agent_profile` and run its `RunGuestSelfTest`
+    /// function, which checks each guest function against known inputs
+    /// without calling out to the host
+    pub async fn self_test(&self, agent_profile: Option<&str>) -> Result<GuestSelfTest> {
+        let mut sandbox = self.sandbox(agent_profile).await?;
+        let report = sandbox
+            .call::<String>("RunGuestSelfTest", ())
+            .context("Failed to call guest self-test")?;
+        serde_json::from_str(&report).context("Guest returned a malformed self-test report")
+    }
+
+    /// Boot a sandbox running the guest of `agent_profile`, or the default
+    /// guest when unset, with the host functions registered
+    async fn sandbox(&self, agent_profile: Option<&str>) -> Result<MultiUseSandbox> {
+        let profile = agent_profile.unwrap_or(guest_binary::DEFAULT_PROFILE);
+        let guest = guest_binary::guest_binary(Some(profile))
+            .with_context(|| format!("No guest binary embedded for agent profile {}", profile))?;
+
         // Load the guest binary from embedded bytes
-        let guest_binary = GuestBinary::Buffer(GUEST_BINARY);
+        let guest_binary = GuestBinary::Buffer(guest);
 
-        info!("Loading embedded guest binary ({} bytes)", GUEST_BINARY.len());
+        info!(
+            "Loading embedded guest binary of profile {} ({} bytes)",
+            profile,
+            guest.len()
+        );
 
         // Create sandbox configuration
         let config = SandboxConfiguration::default();
@@ -74,28 +326,12 @@ impl AgentExecutor {
         self.register_host_functions(&mut uninitialized).await?;
 
         // Evolve into a multi-use sandbox
-        let mut sandbox: MultiUseSandbox = uninitialized
+        let sandbox: MultiUseSandbox = uninitialized
             .evolve()
             .context("Failed to evolve sandbox")?;
 
         info!("Hyperlight sandbox initialized successfully");
-
-        // Call the guest's ExecuteAgent function
-        let mcp_url_param = mcp_connection_url.unwrap_or("");
-
-        info!("Calling guest ExecuteAgent function");
-        let output: String = sandbox
-            .call("ExecuteAgent", (prompt.to_string(), mcp_url_param.to_string()))
-            .context("Failed to call guest function")?;
-
-        info!("Agent execution completed successfully");
-
-        Ok(AgentResult {
-            success: true,
-            exit_code: 0,
-            stdout: output,
-            stderr: String::new(),
-        })
+        Ok(sandbox)
     }
 
     /// Register host functions that the guest can call
@@ -110,8 +346,10 @@ impl AgentExecutor {
         // Host function: Initialize MCP connection
         // Validates that the URL matches the allowed MCP server
         let allowed_for_init = allowed_url.clone();
+        let calls_for_init = self.host_calls.clone();
         sandbox
             .register("InitializeMCPConnection", move |url_str: String| -> hyperlight_host::Result<()> {
+                calls_for_init.fetch_add(1, Ordering::Relaxed);
                 // Validate URL matches allowed MCP server
                 let url = Url::parse(&url_str)
                     .map_err(|e| new_error!("Invalid URL: {}", e))?;
@@ -141,8 +379,11 @@ impl AgentExecutor {
         // Host function: Get available MCP tools
         let http_for_tools = http_client.clone();
         let allowed_for_tools = allowed_url.clone();
+        let trace_for_tools = self.traceparent.clone();
+        let calls_for_tools = self.host_calls.clone();
         sandbox
             .register("GetMCPTools", move || -> hyperlight_host::Result<String> {
+                calls_for_tools.fetch_add(1, Ordering::Relaxed);
                 let allowed = allowed_for_tools.blocking_read();
                 let mcp_url = allowed
                     .as_ref()
@@ -157,9 +398,12 @@ impl AgentExecutor {
                 let rt = tokio::runtime::Runtime::new()
                     .map_err(|e| new_error!("Failed to create runtime: {}", e))?;
 
+                let mut request = http_for_tools.get(tools_url.as_str());
+                if let Some(traceparent) = trace_for_tools.lock().unwrap().as_deref() {
+                    request = request.header(TRACEPARENT, traceparent);
+                }
                 let response = rt.block_on(async {
-                    http_for_tools
-                        .get(tools_url.as_str())
+                    request
                         .send()
                         .await
                         .map_err(|e| new_error!("HTTP request failed: {}", e))?
@@ -175,13 +419,46 @@ impl AgentExecutor {
         // Host function: Execute MCP tool
         let http_for_exec = http_client.clone();
         let allowed_for_exec = allowed_url.clone();
+        let transcript_for_exec = self.transcript.clone();
+        let tools_for_exec = self.allowed_tools.clone();
+        let trace_for_exec = self.traceparent.clone();
+        let schemas_for_exec = self.tool_schemas.clone();
+        let memo_for_exec = self.memo.clone();
+        let calls_for_exec = self.host_calls.clone();
+        let read_only_for_exec = self.read_only.clone();
         sandbox
             .register("ExecuteMCPTool", move |tool_name: String, arguments_json: String| -> hyperlight_host::Result<String> {
+                calls_for_exec.fetch_add(1, Ordering::Relaxed);
                 let allowed = allowed_for_exec.blocking_read();
                 let mcp_url = allowed
                     .as_ref()
                     .ok_or_else(|| new_error!("MCP server not configured"))?;
 
+                let refusal = tool_refusal(
+                    &tool_name,
+                    tools_for_exec.lock().unwrap().as_deref(),
+                    read_only_for_exec.load(Ordering::Relaxed),
+                );
+                if let Some(refusal) = refusal {
+                    error!("Blocked call to tool '{}': {}", tool_name, refusal);
+                    return Err(new_error!("{}", refusal));
+                }
+
+                let memoized = memo_for_exec
+                    .lock()
+                    .unwrap()
+                    .tool_response(&tool_name, &arguments_json);
+                if let Some(response) = memoized {
+                    debug!("Answered tool '{}' with the response of an identical call", tool_name);
+                    transcript_for_exec.lock().unwrap().push(ToolCall {
+                        tool: tool_name,
+                        arguments: arguments_json,
+                        response: response.clone(),
+                        cached: true,
+                    });
+                    return Ok(response);
+                }
+
                 // Make request to MCP server to execute tool
                 let tool_url = mcp_url.join(&format!("/tools/{}", tool_name))
                     .map_err(|e| new_error!("URL join error: {}", e))?;
@@ -191,11 +468,15 @@ impl AgentExecutor {
                 let rt = tokio::runtime::Runtime::new()
                     .map_err(|e| new_error!("Failed to create runtime: {}", e))?;
 
+                let mut request = http_for_exec
+                    .post(tool_url.as_str())
+                    .header("Content-Type", "application/json")
+                    .body(arguments_json.clone());
+                if let Some(traceparent) = trace_for_exec.lock().unwrap().as_deref() {
+                    request = request.header(TRACEPARENT, traceparent);
+                }
                 let response = rt.block_on(async {
-                    http_for_exec
-                        .post(tool_url.as_str())
-                        .header("Content-Type", "application/json")
-                        .body(arguments_json)
+                    request
                         .send()
                         .await
                         .map_err(|e| new_error!("HTTP request failed: {}", e))?
@@ -204,21 +485,279 @@ impl AgentExecutor {
                         .map_err(|e| new_error!("Failed to read response: {}", e))
                 })?;
 
+                // A malformed response would only confuse the agent
+                let response = match schemas_for_exec.validate(&tool_name, &response) {
+                    Ok(()) => {
+                        memo_for_exec
+                            .lock()
+                            .unwrap()
+                            .record_tool(&tool_name, &arguments_json, &response);
+                        response
+                    }
+                    Err(invalid) => {
+                        warn!("{}: {}", invalid.message, response);
+                        serde_json::to_string(&invalid)
+                            .map_err(|e| new_error!("Failed to serialize error: {}", e))?
+                    }
+                };
+
+                transcript_for_exec.lock().unwrap().push(ToolCall {
+                    tool: tool_name,
+                    arguments: arguments_json,
+                    response: response.clone(),
+                    cached: false,
+                });
+
                 Ok(response)
             })
             .context("Failed to register ExecuteMCPTool host function")?;
 
+        // Host function: Report progress
+        // Relays the guest's progress through its loop to the job's status
+        let progress_for_report = self.progress.clone();
+        let calls_for_report = self.host_calls.clone();
+        sandbox
+            .register("ReportProgress", move |step: String, percent: u32, message: String| -> hyperlight_host::Result<()> {
+                calls_for_report.fetch_add(1, Ordering::Relaxed);
+                let progress = AgentProgress {
+                    step,
+                    percent: percent.min(100) as u8,
+                    message,
+                };
+                debug!(
+                    "Agent progress: {} ({}%): {}",
+                    progress.step, progress.percent, progress.message
+                );
+                if let Some(sender) = progress_for_report.lock().unwrap().as_ref() {
+                    // The receiver only goes away with the job
+                    let _ = sender.send(progress);
+                }
+                Ok(())
+            })
+            .context("Failed to register ReportProgress host function")?;
+
+        // Host function: Get repository map
+        // Summarizes the checkout so the guest needn't read it file by file
+        let repo_for_map = self.repo_path.clone();
+        let calls_for_map = self.host_calls.clone();
+        sandbox
+            .register("GetRepoMap", move || -> hyperlight_host::Result<String> {
+                calls_for_map.fetch_add(1, Ordering::Relaxed);
+                let repo_path = repo_for_map
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .ok_or_else(|| new_error!("No repository checked out"))?;
+                let map = RepoMap::build(&repo_path, repo_map::DEFAULT_MAX_BYTES)
+                    .map_err(|e| new_error!("Failed to map repository: {:#}", e))?;
+                debug!(
+                    "Mapped {} files of {:?} for the guest",
+                    map.files, repo_path
+                );
+                serde_json::to_string(&map)
+                    .map_err(|e| new_error!("Failed to serialize repository map: {}", e))
+            })
+            .context("Failed to register GetRepoMap host function")?;
+
+        // Host functions: Scratch files
+        // Kept outside the checkout for the rest of the job, never committed
+        let scratch_for_write = self.scratch.clone();
+        let read_only_for_write = self.read_only.clone();
+        let calls_for_write = self.host_calls.clone();
+        sandbox
+            .register("WriteScratchFile", move |path: String, contents: String| -> hyperlight_host::Result<()> {
+                calls_for_write.fetch_add(1, Ordering::Relaxed);
+                if read_only_for_write.load(Ordering::Relaxed) {
+                    error!("Blocked write of scratch file {}: the job is read-only", path);
+                    return Err(new_error!("File writes are disabled for read-only jobs"));
+                }
+                let scratch = scratch_for_write.lock().unwrap();
+                let scratch = scratch
+                    .as_ref()
+                    .ok_or_else(|| new_error!("No scratch directory for this job"))?;
+                scratch
+                    .write(&path, contents.as_bytes())
+                    .map_err(|e| new_error!("Failed to write scratch file: {:#}", e))?;
+                debug!("Guest wrote scratch file {} ({} bytes)", path, contents.len());
+                Ok(())
+            })
+            .context("Failed to register WriteScratchFile host function")?;
+
+        let scratch_for_read = self.scratch.clone();
+        let calls_for_read = self.host_calls.clone();
+        sandbox
+            .register("ReadScratchFile", move |path: String| -> hyperlight_host::Result<String> {
+                calls_for_read.fetch_add(1, Ordering::Relaxed);
+                let scratch = scratch_for_read.lock().unwrap();
+                let scratch = scratch
+                    .as_ref()
+                    .ok_or_else(|| new_error!("No scratch directory for this job"))?;
+                let contents = scratch
+                    .read(&path)
+                    .map_err(|e| new_error!("Failed to read scratch file: {:#}", e))?;
+                String::from_utf8(contents)
+                    .map_err(|_| new_error!("Scratch file {} isn't UTF-8", path))
+            })
+            .context("Failed to register ReadScratchFile host function")?;
+
+        let scratch_for_list = self.scratch.clone();
+        let calls_for_list = self.host_calls.clone();
+        sandbox
+            .register("ListScratchFiles", move || -> hyperlight_host::Result<String> {
+                calls_for_list.fetch_add(1, Ordering::Relaxed);
+                let scratch = scratch_for_list.lock().unwrap();
+                let scratch = scratch
+                    .as_ref()
+                    .ok_or_else(|| new_error!("No scratch directory for this job"))?;
+                let files = scratch
+                    .list()
+                    .map_err(|e| new_error!("Failed to list scratch files: {:#}", e))?;
+                serde_json::to_string(&files)
+                    .map_err(|e| new_error!("Failed to serialize scratch files: {}", e))
+            })
+            .context("Failed to register ListScratchFiles host function")?;
+
+        // Host function: LLM completion
+        // Sent to the first healthy provider, failing over to the next
+        let router_for_llm = self.llm_router.clone();
+        let trace_for_llm = self.traceparent.clone();
+        let calls_for_llm = self.host_calls.clone();
+        let tokens_for_llm = self.tokens_used.clone();
+        let memo_for_llm = self.memo.clone();
+        sandbox
+            .register("LlmComplete", move |request_json: String| -> hyperlight_host::Result<String> {
+                calls_for_llm.fetch_add(1, Ordering::Relaxed);
+                if router_for_llm.is_empty() {
+                    error!("Blocked LLM request: no LLM provider configured");
+                    return Err(new_error!("No LLM provider configured"));
+                }
+                if let Some(response) = memo_for_llm.lock().unwrap().llm_response(&request_json) {
+                    debug!("Answered LLM request with the response of an identical one");
+                    return Ok(response);
+                }
+
+                // Create a new runtime for this blocking call
+                let rt = tokio::runtime::Runtime::new()
+                    .map_err(|e| new_error!("Failed to create runtime: {}", e))?;
+
+                let traceparent = trace_for_llm.lock().unwrap().clone();
+                let (provider, response) = rt
+                    .block_on(router_for_llm.complete(&request_json, traceparent.as_deref()))
+                    .map_err(|e| new_error!("LLM request failed: {:#}", e))?;
+                debug!("LLM request answered by provider {}", provider);
+                if let Some(tokens) = llm::tokens_used(&response) {
+                    tokens_for_llm.fetch_add(tokens, Ordering::Relaxed);
+                }
+                memo_for_llm.lock().unwrap().record_llm(&request_json, &response);
+
+                Ok(response)
+            })
+            .context("Failed to register LlmComplete host function")?;
+
+        // Host function: Get limits
+        // The guest's budget, with the time that is left of it
+        let limits = self.limits;
+        let deadline_for_limits = self.deadline.clone();
+        let calls_for_limits = self.host_calls.clone();
+        sandbox
+            .register("GetLimits", move || -> hyperlight_host::Result<String> {
+                calls_for_limits.fetch_add(1, Ordering::Relaxed);
+                let remaining = limits.remaining(*deadline_for_limits.lock().unwrap());
+                serde_json::to_string(&remaining)
+                    .map_err(|e| new_error!("Failed to serialize limits: {}", e))
+            })
+            .context("Failed to register GetLimits host function")?;
+
         info!("All host functions registered successfully");
         Ok(())
     }
 }
 
+/// Resident memory of the worker process, read from `/proc/self/statm`
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+/// Page faults of the calling thread so far
+#[cfg(target_os = "linux")]
+fn page_faults() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) } != 0 {
+        return None;
+    }
+    Some((usage.ru_minflt + usage.ru_majflt) as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn page_faults() -> Option<u64> {
+    None
+}
+
+/// Report of the guest's `RunGuestSelfTest` function
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestSelfTest {
+    pub checks: Vec<GuestCheck>,
+}
+
+/// One check of a guest self-test
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestCheck {
+    /// Guest function and case checked, e.g. `ExecuteAgent/missing_parameters`
+    pub name: String,
+    pub passed: bool,
+    /// What went wrong, for a failed check
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+impl GuestSelfTest {
+    pub fn failures(&self) -> Vec<&GuestCheck> {
+        self.checks.iter().filter(|check| !check.passed).collect()
+    }
+}
+
+/// A failure the guest reported with one of its error codes, which the
+/// worker retries or dead-letters the job by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestFailure {
+    pub code: GuestErrorCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for GuestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Guest failed with {}: {}", self.code.as_str(), self.message)
+    }
+}
+
+impl std::error::Error for GuestFailure {}
+
+/// A `GuestFailure` if the guest's error carries a code, otherwise the error
+/// as it is
+fn guest_failure(error: hyperlight_host::HyperlightError) -> anyhow::Error {
+    match GuestErrorCode::find(&error.to_string()) {
+        Some((code, message)) => GuestFailure {
+            code,
+            message: message.to_string(),
+        }
+        .into(),
+        None => error.into(),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AgentResult {
     pub success: bool,
     pub exit_code: i32,
     pub stdout: String,
     pub stderr: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub sandbox: SandboxMetrics,
+    /// LLM tokens the providers reported the agent's requests used
+    pub tokens_used: u64,
 }
 
 impl AgentResult {
@@ -240,12 +779,46 @@ mod tests {
         assert!(executor.http_client.get("http://example.com").build().is_ok());
     }
 
+    #[test]
+    fn test_remaining_limits() {
+        let limits = AgentLimits {
+            max_elapsed: Some(Duration::from_secs(60)),
+        };
+        let remaining = limits.remaining(Some(Instant::now() + Duration::from_secs(30)));
+        assert!(remaining.remaining_ms.is_some_and(|ms| ms > 29_000 && ms <= 30_000));
+        assert_eq!(limits.remaining(Some(Instant::now())).remaining_ms, Some(0));
+        assert_eq!(
+            serde_json::to_string(&AgentLimits::default().remaining(None)).unwrap(),
+            r#"{"remaining_ms":null}"#
+        );
+    }
+
+    #[test]
+    fn test_tool_refusal() {
+        let allowed = ["read_file".to_string(), "write_file".to_string()];
+        assert_eq!(tool_refusal("write_file", None, false), None);
+        assert_eq!(tool_refusal("read_file", Some(&allowed), false), None);
+        assert_eq!(
+            tool_refusal("delete_file", Some(&allowed), false).as_deref(),
+            Some("Tool not allowed: delete_file")
+        );
+
+        // Read-only jobs can't write, even with tools they allow themselves
+        assert_eq!(tool_refusal("read_file", None, true), None);
+        assert_eq!(
+            tool_refusal("write_file", Some(&allowed), true).as_deref(),
+            Some("Tool not allowed for read-only jobs: write_file")
+        );
+        assert!(tool_refusal("run_command", None, true).is_some());
+    }
+
     #[tokio::test]
     async fn test_guest_binary_embedded() {
         // Verify the guest binary is embedded and non-empty
-        assert!(!GUEST_BINARY.is_empty(), "Guest binary should be embedded");
+        let guest = guest_binary::GUEST_BINARY;
+        assert!(!guest.is_empty(), "Guest binary should be embedded");
         assert!(
-            GUEST_BINARY.len() > 1000,
+            guest.len() > 1000,
             "Guest binary should be a reasonable size"
         );
     }
@@ -263,7 +836,7 @@ mod tests {
 
         // Execute without MCP URL (should fail gracefully)
         let result = executor
-            .execute(&temp_dir, "test prompt", None)
+            .execute(&temp_dir, "test prompt", None, None, None, None, None, false)
             .await;
 
         // Clean up
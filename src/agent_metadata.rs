@@ -0,0 +1,190 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::guest_binary;
+use crate::queue::{now_secs, Job};
+use crate::result::ToolCall;
+use crate::trace;
+
+/// File in the repository root the metadata is committed as
+pub const METADATA_FILE: &str = ".agent-metadata";
+/// Notes ref the provenance records are attached to commits under
+pub const NOTES_REF: &str = "refs/notes/agent";
+
+/// Where the metadata of the agent's commits is recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMode {
+    /// Write `.agent-metadata` into each commit
+    File,
+    /// Attach the full provenance record to each commit as a git note under
+    /// `refs/notes/agent`, leaving the tree and commit message as they are
+    Note,
+}
+
+impl FromStr for MetadataMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "file" => Ok(MetadataMode::File),
+            "note" => Ok(MetadataMode::Note),
+            _ => bail!("Unknown agent metadata mode {}; expected file or note", s),
+        }
+    }
+}
+
+/// Record of how a commit was generated, so repositories can audit which
+/// changes came from the agent and under which configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentMetadata {
+    pub job_id: String,
+    /// SHA-256 of the prompt the commit answers; the prompt itself may hold
+    /// details the repository shouldn't
+    pub prompt_sha256: String,
+    /// Version of the worker that ran the agent
+    pub worker_version: String,
+    pub worker_id: String,
+    pub agent_profile: String,
+    /// SHA-256 of the guest binary of `agent_profile`
+    #[serde(default)]
+    pub guest_sha256: String,
+    /// MCP tools the agent called, sorted
+    pub tools: Vec<String>,
+    /// Tools the job allowed the agent; unset when it allowed all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_tools: Option<Vec<String>>,
+    pub timestamp: u64,
+    /// The rest of the record, only kept in notes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// Details of a commit's origin too large or too sensitive for the tree:
+/// the prompt itself and where the run started from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub prompt: String,
+    pub repo_url: String,
+    pub base_branch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_commit: Option<String>,
+    /// Trace the job ran under, otherwise a `Trace-Id` commit trailer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Calls the agent made to each MCP tool
+    pub tool_calls: BTreeMap<String, usize>,
+}
+
+impl Provenance {
+    pub fn new(
+        job: &Job,
+        prompt: &str,
+        tool_calls: &[ToolCall],
+        base_commit: Option<&str>,
+    ) -> Self {
+        let mut calls = BTreeMap::new();
+        for call in tool_calls {
+            *calls.entry(call.tool.clone()).or_default() += 1;
+        }
+        Self {
+            prompt: prompt.to_string(),
+            repo_url: job.repo_url.clone(),
+            base_branch: job.base_branch.clone(),
+            base_commit: base_commit.map(str::to_string),
+            trace_id: trace::current().map(|trace| trace.trace_id().to_string()),
+            tool_calls: calls,
+        }
+    }
+}
+
+impl AgentMetadata {
+    pub fn new(job: &Job, prompt: &str, tool_calls: &[ToolCall], worker_id: &str) -> Self {
+        let mut tools: Vec<String> = tool_calls.iter().map(|call| call.tool.clone()).collect();
+        tools.sort();
+        tools.dedup();
+        let agent_profile = job.options.agent_profile.as_deref();
+        Self {
+            job_id: job.id.clone(),
+            prompt_sha256: Sha256::digest(prompt.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            worker_version: env!("CARGO_PKG_VERSION").to_string(),
+            worker_id: worker_id.to_string(),
+            agent_profile: agent_profile.unwrap_or(guest_binary::DEFAULT_PROFILE).to_string(),
+            guest_sha256: guest_binary::sha256(agent_profile)
+                .unwrap_or_default()
+                .to_string(),
+            tools,
+            allowed_tools: job.options.allowed_tools.clone(),
+            timestamp: now_secs(),
+            provenance: None,
+        }
+    }
+
+    pub fn with_provenance(mut self, provenance: Provenance) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// Pretty JSON, as written to `.agent-metadata` or a note
+    pub fn to_json(&self) -> String {
+        let mut json =
+            serde_json::to_string_pretty(self).expect("agent metadata is always serializable");
+        json.push('\n');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_metadata() {
+        let mut job = Job {
+            id: "job-1".to_string(),
+            repo_url: "git@github.com:org/app.git".to_string(),
+            base_branch: "main".to_string(),
+            prompt: "Fix the build".to_string(),
+            ..Default::default()
+        };
+        job.options.allowed_tools = Some(vec!["read_file".to_string()]);
+        let call = |tool: &str| ToolCall {
+            tool: tool.to_string(),
+            arguments: "{}".to_string(),
+            response: "{}".to_string(),
+            cached: false,
+        };
+        let metadata = AgentMetadata::new(
+            &job,
+            "Fix the build",
+            &[call("write_file"), call("read_file"), call("write_file")],
+            "worker-1",
+        );
+        assert_eq!(metadata.tools, ["read_file", "write_file"]);
+        assert_eq!(metadata.agent_profile, guest_binary::DEFAULT_PROFILE);
+        assert_eq!(Some(metadata.guest_sha256.as_str()), guest_binary::sha256(None));
+        assert_eq!(
+            metadata.prompt_sha256,
+            "0201e6edf2ecdee074810e40fd418a41cfde45419d258943be9c33884684d95f"
+        );
+
+        assert!(!metadata.to_json().contains("Fix the build"));
+
+        let calls = [call("write_file"), call("read_file"), call("write_file")];
+        let metadata =
+            metadata.with_provenance(Provenance::new(&job, "Fix the build", &calls, None));
+        let provenance = metadata.provenance.as_ref().unwrap();
+        assert_eq!(provenance.tool_calls["write_file"], 2);
+        assert_eq!(provenance.base_branch, "main");
+        let parsed: AgentMetadata = serde_json::from_str(&metadata.to_json()).unwrap();
+        assert_eq!(parsed, metadata);
+
+        assert_eq!("note".parse::<MetadataMode>().unwrap(), MetadataMode::Note);
+        assert!("commit".parse::<MetadataMode>().is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use tracing::info;
+
+use crate::queue::{Job, ReliableQueue};
+
+/// Which part of the queue a backed-up job came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Section {
+    Pending,
+    Processing,
+}
+
+/// One line of a backup file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupRecord {
+    pub section: Section,
+    pub job: Job,
+    /// The job's status hash, verbatim
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub status: BTreeMap<String, String>,
+}
+
+/// Number of jobs exported or imported per section
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackupReport {
+    pub pending: usize,
+    pub processing: usize,
+}
+
+impl BackupReport {
+    fn count(&mut self, section: Section) {
+        match section {
+            Section::Pending => self.pending += 1,
+            Section::Processing => self.processing += 1,
+        }
+    }
+}
+
+/// Write every job in the queue to `out` as JSON lines, pending jobs first in
+/// the order they will be dequeued
+pub async fn export(queue: &mut ReliableQueue, mut out: impl Write) -> Result<BackupReport> {
+    let sections = [
+        (Section::Pending, queue.list_pending().await?),
+        (Section::Processing, queue.list_processing().await?),
+    ];
+
+    let mut report = BackupReport::default();
+    for (section, jobs) in sections {
+        for job in jobs {
+            let status = queue.raw_status(&job.id).await?.into_iter().collect();
+            let record = BackupRecord {
+                section,
+                job,
+                status,
+            };
+            serde_json::to_writer(&mut out, &record).context("Failed to write backup")?;
+            writeln!(out).context("Failed to write backup")?;
+            report.count(section);
+        }
+    }
+    out.flush().context("Failed to write backup")?;
+
+    info!(
+        "Exported {} pending and {} in-flight jobs",
+        report.pending, report.processing
+    );
+    Ok(report)
+}
+
+/// Read a backup written by `export`
+///
+/// Every line is parsed and validated before anything is returned, so a
+/// damaged backup is rejected as a whole.
+pub fn read_backup(input: impl BufRead) -> Result<Vec<BackupRecord>> {
+    let mut records = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = line.context("Failed to read backup")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: BackupRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid backup record on line {}", index + 1))?;
+        record
+            .job
+            .validate()
+            .with_context(|| format!("Invalid job on line {}", index + 1))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Restore a backup into an empty queue
+pub async fn import(queue: &mut ReliableQueue, records: &[BackupRecord]) -> Result<BackupReport> {
+    if queue.depths().await? != (0, 0) {
+        bail!("Queue is not empty; import only restores into an empty queue");
+    }
+
+    let mut report = BackupReport::default();
+    for record in records {
+        let status: HashMap<String, String> = record
+            .status
+            .iter()
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        match record.section {
+            Section::Pending => queue.import_job(&record.job, &status).await?,
+            Section::Processing => queue.restore_processing(&record.job, &status).await?,
+        }
+        report.count(record.section);
+    }
+
+    info!(
+        "Imported {} pending and {} in-flight jobs",
+        report.pending, report.processing
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_backup_rejects_invalid_jobs() {
+        let valid = r#"{"section":"pending","job":{"id":"a","repo_url":"r","branch":"main","prompt":"p"}}"#;
+        let records = read_backup(format!("{}\n\n", valid).as_bytes()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].section, Section::Pending);
+
+        let missing_prompt = r#"{"section":"processing","job":{"id":"b","repo_url":"r","branch":"main","prompt":""}}"#;
+        let err = read_backup(format!("{}\n{}\n", valid, missing_prompt).as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::queue::{Job, ReliableQueue};
+
+/// Parameters of a benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub redis_url: String,
+    /// Throwaway queue the benchmark fills and clears
+    pub queue_name: String,
+    /// Number of synthetic jobs to enqueue
+    pub jobs: usize,
+    /// Number of in-process no-op workers draining the queue, 0 to only enqueue
+    pub workers: usize,
+}
+
+/// Latency distribution and throughput of one phase
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseReport {
+    pub operations: usize,
+    pub total_ms: f64,
+    pub per_second: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl PhaseReport {
+    fn new(mut latencies: Vec<Duration>, total: Duration) -> Self {
+        latencies.sort();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+        Self {
+            operations: latencies.len(),
+            total_ms: ms(total),
+            per_second: latencies.len() as f64 / total.as_secs_f64().max(f64::EPSILON),
+            p50_ms: ms(percentile(&latencies, 50.0)),
+            p90_ms: ms(percentile(&latencies, 90.0)),
+            p99_ms: ms(percentile(&latencies, 99.0)),
+            max_ms: ms(latencies.last().copied().unwrap_or_default()),
+        }
+    }
+}
+
+/// Results of a benchmark run
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub queue_name: String,
+    pub enqueue: PhaseReport,
+    /// Dequeue + ACK by the no-op workers, if any ran
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dequeue: Option<PhaseReport>,
+}
+
+/// Nearest-rank percentile of an ascending list of samples
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn synthetic_job(index: usize) -> Job {
+    Job {
+        id: format!("bench-{}", index),
+        repo_url: "git@example.com:bench/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: format!("Synthetic benchmark job {}", index),
+        ..Default::default()
+    }
+}
+
+/// Enqueue synthetic jobs, optionally drain them with no-op workers, and
+/// clear the benchmark queue afterwards
+pub async fn run(config: &BenchConfig) -> Result<BenchReport> {
+    let mut queue = ReliableQueue::new(&config.redis_url, &config.queue_name, 1).await?;
+    if queue.depths().await? != (0, 0) {
+        bail!(
+            "Queue {} is not empty; benchmarks need a throwaway queue",
+            config.queue_name
+        );
+    }
+
+    let result = measure(config, &mut queue).await;
+    queue.clear().await?;
+    result
+}
+
+async fn measure(config: &BenchConfig, queue: &mut ReliableQueue) -> Result<BenchReport> {
+    info!("Enqueueing {} synthetic jobs", config.jobs);
+    let mut latencies = Vec::with_capacity(config.jobs);
+    let started = Instant::now();
+    for index in 0..config.jobs {
+        let job = synthetic_job(index);
+        let op = Instant::now();
+        queue.enqueue(&job).await?;
+        latencies.push(op.elapsed());
+    }
+    let enqueue = PhaseReport::new(latencies, started.elapsed());
+
+    let dequeue = if config.workers > 0 {
+        Some(drain(config).await?)
+    } else {
+        None
+    };
+
+    Ok(BenchReport {
+        queue_name: config.queue_name.clone(),
+        enqueue,
+        dequeue,
+    })
+}
+
+/// Dequeue and ACK every job with `config.workers` concurrent no-op workers
+async fn drain(config: &BenchConfig) -> Result<PhaseReport> {
+    info!("Draining with {} no-op workers", config.workers);
+    let remaining = Arc::new(AtomicUsize::new(config.jobs));
+
+    let started = Instant::now();
+    let mut handles = Vec::with_capacity(config.workers);
+    for _ in 0..config.workers {
+        // Each worker needs its own connection, since BLMOVE blocks it
+        let mut queue = ReliableQueue::new(&config.redis_url, &config.queue_name, 1).await?;
+        let remaining = remaining.clone();
+
+        handles.push(tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            let mut last_ack = None;
+            while remaining.load(Ordering::SeqCst) > 0 {
+                let op = Instant::now();
+                if let Some(job) = queue.dequeue().await? {
+                    queue.ack(&job).await?;
+                    latencies.push(op.elapsed());
+                    last_ack = Some(Instant::now());
+                    remaining.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+            Ok::<_, anyhow::Error>((latencies, last_ack))
+        }));
+    }
+
+    let mut latencies = Vec::with_capacity(config.jobs);
+    let mut finished = started;
+    for handle in handles {
+        let (worker_latencies, last_ack) = handle.await??;
+        latencies.extend(worker_latencies);
+        finished = finished.max(last_ack.unwrap_or(started));
+    }
+
+    // Measured to the last ACK, not including workers idling on an empty queue
+    Ok(PhaseReport::new(latencies, finished - started))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile_nearest_rank() {
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&samples, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&samples[..1], 90.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+}
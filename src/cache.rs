@@ -0,0 +1,74 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::queue::{ContextFile, Job, JobOptions};
+
+/// Everything that decides what a job's agent runs produce
+#[derive(Serialize)]
+struct CacheInput<'a> {
+    repo_url: &'a str,
+    base_commit: &'a str,
+    target_branch: &'a str,
+    prompts: Vec<&'a str>,
+    context: &'a [ContextFile],
+    options: JobOptions,
+}
+
+/// Content address of a job's result: the hex SHA-256 of its prompts,
+/// context and options and of the commit it starts from
+///
+/// Jobs with the same key would run the same agent on the same code, so one
+/// can reuse the other's result. The job ID is deliberately left out.
+pub fn cache_key(job: &Job, base_commit: &str, target_branch: &str) -> String {
+    let input = CacheInput {
+        repo_url: &job.repo_url,
+        base_commit,
+        target_branch,
+        prompts: job.prompts(),
+        context: &job.context,
+        // These limit how a job runs, not what it produces
+        options: JobOptions {
+            timeout_secs: None,
+            max_retries: None,
+            no_cache: false,
+            require_approval: false,
+            approved: false,
+            ..job.options.clone()
+        },
+    };
+    let json = serde_json::to_vec(&input).expect("cache input is always serializable");
+    Sha256::digest(json)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let job = Job {
+            id: "job-1".to_string(),
+            repo_url: "git@github.com:org/app.git".to_string(),
+            base_branch: "main".to_string(),
+            prompt: "Fix the build".to_string(),
+            ..Default::default()
+        };
+        let key = cache_key(&job, "4f2a", "main");
+        assert_eq!(key.len(), 64);
+
+        let mut rerun = job.clone();
+        rerun.id = "job-2".to_string();
+        rerun.options.max_retries = Some(3);
+        rerun.options.no_cache = true;
+        assert_eq!(cache_key(&rerun, "4f2a", "main"), key);
+
+        assert_ne!(cache_key(&job, "9c1e", "main"), key);
+        assert_ne!(cache_key(&job, "4f2a", "agent/fix"), key);
+        let mut other_prompt = job.clone();
+        other_prompt.tasks.push("Then add a test".to_string());
+        assert_ne!(cache_key(&other_prompt, "4f2a", "main"), key);
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Defaults for the global CLI options, selected with `--profile`
+///
+/// Anything given on the command line or in the environment still wins.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub redis_url: Option<String>,
+    pub queue_name: Option<String>,
+    pub key_prefix: Option<String>,
+    pub allocator_api_url: Option<String>,
+    pub work_dir: Option<String>,
+    pub log_level: Option<String>,
+    pub log_file: Option<PathBuf>,
+    pub tenant: Option<String>,
+}
+
+/// Contents of the CLI defaults file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Profile used when `--profile` isn't given
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl ConfigFile {
+    /// Load the file at `path`, or `None` if it doesn't exist
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+
+        let config = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok(Some(config))
+    }
+
+    /// The named profile, or the default profile if no name is given
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<&Profile>> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(None);
+        };
+
+        match self.profiles.get(name) {
+            Some(profile) => Ok(Some(profile)),
+            None => bail!(
+                "Unknown profile {} (available: {})",
+                name,
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/redis-agent-worker/config.toml`, falling back to
+/// `~/.config/redis-agent-worker/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_home.join("redis-agent-worker").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+default_profile = "dev"
+
+[profiles.dev]
+redis_url = "redis://localhost:6379"
+
+[profiles.prod]
+redis_url = "redis://prod.internal:6379"
+queue_name = "prod_jobs"
+key_prefix = "agentworker:prod:"
+"#;
+
+    #[test]
+    fn test_profile_selection() {
+        let config: ConfigFile = toml::from_str(CONFIG).unwrap();
+
+        let dev = config.profile(None).unwrap().unwrap();
+        assert_eq!(dev.redis_url.as_deref(), Some("redis://localhost:6379"));
+        assert_eq!(dev.queue_name, None);
+
+        let prod = config.profile(Some("prod")).unwrap().unwrap();
+        assert_eq!(prod.queue_name.as_deref(), Some("prod_jobs"));
+        assert_eq!(prod.key_prefix.as_deref(), Some("agentworker:prod:"));
+
+        let err = config.profile(Some("staging")).unwrap_err();
+        assert!(err.to_string().contains("available: dev, prod"));
+
+        let no_default = ConfigFile::default();
+        assert!(no_default.profile(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let typo = "[profiles.dev]\nredis-url = \"redis://localhost\"\n";
+        assert!(toml::from_str::<ConfigFile>(typo).is_err());
+    }
+}
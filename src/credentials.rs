@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::github::GitHubApp;
+use crate::policy::RepoPattern;
+
+/// How to authenticate to a git remote
+#[derive(Clone, Default)]
+pub enum GitCredential {
+    /// Keys loaded in the running ssh-agent
+    #[default]
+    SshAgent,
+    /// A private key file, optionally protected by a passphrase
+    SshKey {
+        private_key: PathBuf,
+        public_key: Option<PathBuf>,
+        passphrase: Option<String>,
+    },
+    /// A username and access token for HTTPS remotes
+    Token { username: String, token: String },
+    /// A GitHub App installation, exchanged by `GitCredentials::resolve` for
+    /// a token scoped to the one repository a job works on
+    GitHubApp(Arc<GitHubApp>),
+}
+
+impl std::fmt::Debug for GitCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print passphrases or tokens
+        match self {
+            GitCredential::SshAgent => f.write_str("SshAgent"),
+            GitCredential::SshKey { private_key, .. } => {
+                write!(f, "SshKey({})", private_key.display())
+            }
+            GitCredential::Token { username, .. } => write!(f, "Token({})", username),
+            GitCredential::GitHubApp(app) => write!(f, "{:?}", app),
+        }
+    }
+}
+
+/// Credentials to use per repository, from the first matching pattern
+/// Repositories no pattern matches use the ssh-agent
+#[derive(Debug, Clone, Default)]
+pub struct GitCredentials {
+    entries: Vec<(RepoPattern, GitCredential)>,
+}
+
+/// Contents of the credentials file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    #[serde(default, rename = "credential")]
+    credentials: Vec<CredentialEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CredentialEntry {
+    /// Repository pattern, e.g. `github.com/org1/*`
+    pattern: String,
+    #[serde(flatten)]
+    spec: CredentialSpec,
+}
+
+/// Secrets are named by environment variable rather than stored in the file
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialSpec {
+    SshAgent,
+    SshKey {
+        private_key: PathBuf,
+        public_key: Option<PathBuf>,
+        passphrase_env: Option<String>,
+    },
+    Token {
+        #[serde(default = "default_token_username")]
+        username: String,
+        token_env: String,
+    },
+    GithubApp {
+        app_id: u64,
+        installation_id: u64,
+        private_key: PathBuf,
+    },
+}
+
+fn default_token_username() -> String {
+    "x-access-token".to_string()
+}
+
+fn read_env(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("Environment variable {} is not set", name))
+}
+
+impl GitCredentials {
+    /// Load the credentials file at `path`, reading every referenced secret
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let file: CredentialsFile = toml::from_str(contents)?;
+
+        let entries = file
+            .credentials
+            .into_iter()
+            .map(|entry| {
+                let credential = match entry.spec {
+                    CredentialSpec::SshAgent => GitCredential::SshAgent,
+                    CredentialSpec::SshKey {
+                        private_key,
+                        public_key,
+                        passphrase_env,
+                    } => GitCredential::SshKey {
+                        private_key,
+                        public_key,
+                        passphrase: passphrase_env.as_deref().map(read_env).transpose()?,
+                    },
+                    CredentialSpec::Token {
+                        username,
+                        token_env,
+                    } => GitCredential::Token {
+                        username,
+                        token: read_env(&token_env)?,
+                    },
+                    CredentialSpec::GithubApp {
+                        app_id,
+                        installation_id,
+                        private_key,
+                    } => {
+                        let pem = std::fs::read_to_string(&private_key).with_context(|| {
+                            format!("Failed to read {}", private_key.display())
+                        })?;
+                        let app = GitHubApp::new(app_id, installation_id, &pem)?;
+                        GitCredential::GitHubApp(Arc::new(app))
+                    }
+                };
+                Ok((RepoPattern::parse(&entry.pattern)?, credential))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { entries })
+    }
+
+    /// Credential for the repository at `repo_url`
+    pub fn for_repo(&self, repo_url: &str) -> &GitCredential {
+        static SSH_AGENT: GitCredential = GitCredential::SshAgent;
+        self.entries
+            .iter()
+            .find(|(pattern, _)| pattern.matches(repo_url))
+            .map_or(&SSH_AGENT, |(_, credential)| credential)
+    }
+
+    /// Credential to clone and push `repo_url` with, exchanging a GitHub App
+    /// for a short-lived token that can't touch any other repository
+    pub async fn resolve(&self, repo_url: &str) -> Result<GitCredential> {
+        match self.for_repo(repo_url) {
+            GitCredential::GitHubApp(app) => Ok(GitCredential::Token {
+                username: default_token_username(),
+                token: app.repo_token(repo_url).await?,
+            }),
+            credential => Ok(credential.clone()),
+        }
+    }
+
+    /// Why configured credentials couldn't authenticate, found without
+    /// contacting any remote: key files that can't be read and ssh-agent
+    /// entries with no agent to ask
+    pub fn problems(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter_map(|(_, credential)| {
+                let problem = match credential {
+                    GitCredential::SshAgent if !ssh_agent_available() => {
+                        "SSH_AUTH_SOCK doesn't point at a running ssh-agent".to_string()
+                    }
+                    GitCredential::SshKey {
+                        private_key,
+                        public_key,
+                        ..
+                    } => [Some(private_key), public_key.as_ref()]
+                        .into_iter()
+                        .flatten()
+                        .find_map(|path| {
+                            std::fs::File::open(path)
+                                .err()
+                                .map(|e| format!("Failed to read {}: {}", path.display(), e))
+                        })?,
+                    _ => return None,
+                };
+                Some(format!("{:?}: {}", credential, problem))
+            })
+            .collect()
+    }
+}
+
+/// Whether `SSH_AUTH_SOCK` names a socket, as a running ssh-agent leaves
+pub fn ssh_agent_available() -> bool {
+    std::env::var_os("SSH_AUTH_SOCK").is_some_and(|socket| Path::new(&socket).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_matching_credential_wins() {
+        std::env::set_var("TEST_CREDENTIALS_ORG1_TOKEN", "secret");
+        let credentials = GitCredentials::parse(
+            r#"
+            [[credential]]
+            pattern = "github.com/org1/*"
+            type = "token"
+            token_env = "TEST_CREDENTIALS_ORG1_TOKEN"
+
+            [[credential]]
+            pattern = "gitlab.internal/**"
+            type = "ssh_key"
+            private_key = "/etc/agent-worker/gitlab_ed25519"
+
+            [[credential]]
+            pattern = "github.com/*/*"
+            type = "ssh_agent"
+            "#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            credentials.for_repo("https://github.com/org1/app.git"),
+            GitCredential::Token { username, token }
+                if username == "x-access-token" && token == "secret"
+        ));
+        assert!(matches!(
+            credentials.for_repo("git@gitlab.internal:team/app.git"),
+            GitCredential::SshKey { passphrase: None, .. }
+        ));
+        assert!(matches!(
+            credentials.for_repo("git@github.com:org2/app.git"),
+            GitCredential::SshAgent
+        ));
+        assert!(matches!(
+            credentials.for_repo("/srv/git/app.git"),
+            GitCredential::SshAgent
+        ));
+        assert!(credentials
+            .problems()
+            .iter()
+            .any(|problem| problem.starts_with("SshKey(/etc/agent-worker/gitlab_ed25519)")));
+
+        let missing_secret = GitCredentials::parse(
+            r#"
+            [[credential]]
+            pattern = "github.com/org1/*"
+            type = "token"
+            token_env = "TEST_CREDENTIALS_UNSET_TOKEN"
+            "#,
+        );
+        assert!(missing_secret.is_err());
+    }
+}
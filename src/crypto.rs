@@ -0,0 +1,155 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Marks a payload as encrypted, and with which scheme
+const PREFIX: &str = "enc:v1:";
+
+const NONCE_LEN: usize = 24;
+
+/// Authenticated encryption of job payloads stored in Redis
+///
+/// Payloads are sealed with XChaCha20-Poly1305. The nonce is derived from an
+/// HMAC of the plaintext, so the same job always encrypts to the same
+/// payload: the queue finds in-flight jobs by their exact value (LREM).
+/// The only thing this reveals is whether two payloads are identical.
+///
+/// The nonce MAC and the cipher each use their own subkey, derived from the
+/// configured key with a distinct label.
+#[derive(Clone)]
+pub struct PayloadCipher {
+    nonce_key: [u8; 32],
+    cipher_key: [u8; 32],
+}
+
+impl PayloadCipher {
+    /// Create a cipher from a base64-encoded 32-byte key
+    pub fn from_base64(key: &str) -> Result<Self> {
+        let bytes = BASE64
+            .decode(key.trim())
+            .context("Encryption key is not valid base64")?;
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| {
+                anyhow!("Encryption key must be 32 bytes, got {}", bytes.len())
+            })?;
+        Ok(Self {
+            nonce_key: subkey(&key, b"agent-worker payload nonce key")?,
+            cipher_key: subkey(&key, b"agent-worker payload cipher key")?,
+        })
+    }
+
+    /// Encrypt a serialized payload
+    pub fn seal(&self, plaintext: &str) -> Result<String> {
+        self.seal_bytes(plaintext.as_bytes())
+    }
+
+    /// Encrypt a payload that may not be text, such as a context file
+    pub fn seal_bytes(&self, plaintext: &[u8]) -> Result<String> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce_key)
+            .context("Invalid encryption key")?;
+        mac.update(b"agent-worker payload nonce");
+        mac.update(plaintext);
+        let digest = mac.finalize().into_bytes();
+        let nonce = XNonce::from_slice(&digest[..NONCE_LEN]);
+
+        let ciphertext = XChaCha20Poly1305::new((&self.cipher_key).into())
+            .encrypt(nonce, plaintext)
+            .map_err(|_| anyhow!("Failed to encrypt payload"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", PREFIX, BASE64.encode(sealed)))
+    }
+
+    /// Decrypt a payload produced by `seal`
+    pub fn open(&self, payload: &str) -> Result<String> {
+        String::from_utf8(self.open_bytes(payload)?).context("Decrypted payload is not UTF-8")
+    }
+
+    /// Decrypt a payload produced by `seal_bytes`
+    pub fn open_bytes(&self, payload: &str) -> Result<Vec<u8>> {
+        let Some(encoded) = payload.strip_prefix(PREFIX) else {
+            bail!("Payload is not encrypted");
+        };
+        let sealed = BASE64
+            .decode(encoded)
+            .context("Encrypted payload is not valid base64")?;
+        if sealed.len() < NONCE_LEN {
+            bail!("Encrypted payload is truncated");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        XChaCha20Poly1305::new((&self.cipher_key).into())
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt payload; wrong key or tampered data"))
+    }
+}
+
+/// HMAC of `label` under `key`, so subkeys for different uses are independent
+fn subkey(key: &[u8; 32], label: &[u8]) -> Result<[u8; 32]> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).context("Invalid encryption key")?;
+    mac.update(label);
+    Ok(mac.finalize().into_bytes().into())
+}
+
+impl std::fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the key
+        f.write_str("PayloadCipher")
+    }
+}
+
+/// Whether a stored payload was written by `PayloadCipher::seal`
+pub fn is_encrypted(payload: &str) -> bool {
+    payload.starts_with(PREFIX)
+}
+
+/// Whether two secrets, such as tokens or signatures, are equal
+///
+/// Every byte is compared, so the time taken doesn't reveal how much of a
+/// guess was right; only the length can be told apart.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_is_deterministic_and_authenticated() {
+        let cipher = PayloadCipher::from_base64(&BASE64.encode([7u8; 32])).unwrap();
+        let other = PayloadCipher::from_base64(&BASE64.encode([8u8; 32])).unwrap();
+
+        assert_ne!(cipher.nonce_key, cipher.cipher_key);
+        assert_ne!(cipher.nonce_key, [7u8; 32]);
+        assert_ne!(cipher.cipher_key, [7u8; 32]);
+
+        let sealed = cipher.seal(r#"{"id":"job-1"}"#).unwrap();
+        assert!(is_encrypted(&sealed));
+        assert_eq!(sealed, cipher.seal(r#"{"id":"job-1"}"#).unwrap());
+        assert_ne!(sealed, cipher.seal(r#"{"id":"job-2"}"#).unwrap());
+        assert_eq!(cipher.open(&sealed).unwrap(), r#"{"id":"job-1"}"#);
+
+        assert!(other.open(&sealed).is_err());
+        assert!(cipher.open(r#"{"id":"job-1"}"#).is_err());
+        assert!(PayloadCipher::from_base64(&BASE64.encode([7u8; 16])).is_err());
+
+        assert!(constant_time_eq("s3cret", "s3cret"));
+        assert!(!constant_time_eq("s3cret", "s3creT"));
+        assert!(!constant_time_eq("s3cret", "s3cre"));
+
+        let sealed = cipher.seal_bytes(&[0xff, 0x00, 0x7f]).unwrap();
+        assert_eq!(cipher.open_bytes(&sealed).unwrap(), vec![0xff, 0x00, 0x7f]);
+        assert!(cipher.open(&sealed).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::result::ToolCall;
+
+/// Template commit messages and pull request descriptions are rendered from
+pub const DEFAULT_TEMPLATE: &str = "\
+Agent changes for job: {job_id}{task}
+
+{summary}
+
+{rationale}
+
+Tools used:
+{tools}";
+
+const PLACEHOLDERS: [&str; 6] = ["job_id", "task", "prompt", "summary", "rationale", "tools"];
+
+/// The rationale is cut short beyond this many characters
+const MAX_RATIONALE_LEN: usize = 4000;
+/// Tools listed under `{tools}`, most called first
+const MAX_TOOLS: usize = 5;
+
+/// What a description is rendered from
+pub struct DescriptionInput<'a> {
+    pub job_id: &'a str,
+    /// 1-based task number and task count, for jobs with several prompts
+    pub task: Option<(usize, usize)>,
+    pub prompt: &'a str,
+    /// The agent's report: its first paragraph is the summary, the rest the
+    /// rationale
+    pub report: &'a str,
+    pub tool_calls: &'a [ToolCall],
+}
+
+/// A commit message or pull request description template
+///
+/// `{job_id}`, `{task}` (` (task 1/2)` for multi-task jobs), `{prompt}`,
+/// `{summary}`, `{rationale}` and `{tools}` are substituted. Sections that
+/// come out empty leave no blank lines behind.
+#[derive(Debug, Clone)]
+pub struct DescriptionTemplate {
+    template: String,
+}
+
+impl Default for DescriptionTemplate {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+impl DescriptionTemplate {
+    pub fn new(template: &str) -> Result<Self> {
+        if template.lines().next().is_none_or(|subject| subject.trim().is_empty()) {
+            bail!("The template's first line is the commit subject and must not be empty");
+        }
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&name) {
+                bail!(
+                    "Unknown placeholder {{{}}}; expected one of {}",
+                    name,
+                    PLACEHOLDERS.join(", ")
+                );
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let template = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read template {}", path.display()))?;
+        Self::new(template.trim_end())
+            .with_context(|| format!("Invalid template {}", path.display()))
+    }
+
+    pub fn render(&self, input: &DescriptionInput) -> String {
+        let report = input.report.trim();
+        let (summary, rationale) = match report.split_once("\n\n") {
+            Some((summary, rationale)) => (summary.trim(), truncate(rationale.trim())),
+            None => (report, String::new()),
+        };
+        let task = match input.task {
+            Some((index, total)) => format!(" (task {}/{})", index, total),
+            None => String::new(),
+        };
+
+        let rendered = self
+            .template
+            .replace("{job_id}", input.job_id)
+            .replace("{task}", &task)
+            .replace("{prompt}", input.prompt.trim())
+            .replace("{summary}", summary)
+            .replace("{rationale}", &rationale)
+            .replace("{tools}", &tool_highlights(input.tool_calls));
+        collapse_empty_sections(&rendered)
+    }
+}
+
+fn truncate(text: &str) -> String {
+    if text.chars().count() > MAX_RATIONALE_LEN {
+        let cut: String = text.chars().take(MAX_RATIONALE_LEN).collect();
+        format!("{}...", cut)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `- tool (N calls)` lines for the most called tools
+fn tool_highlights(tool_calls: &[ToolCall]) -> String {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for call in tool_calls {
+        *counts.entry(&call.tool).or_default() += 1;
+    }
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    let mut lines: Vec<String> = counts
+        .iter()
+        .take(MAX_TOOLS)
+        .map(|(tool, count)| match count {
+            1 => format!("- {}", tool),
+            _ => format!("- {} ({} calls)", tool, count),
+        })
+        .collect();
+    if counts.len() > MAX_TOOLS {
+        lines.push(format!("- and {} more", counts.len() - MAX_TOOLS));
+    }
+    lines.join("\n")
+}
+
+/// Drop the blank lines left by empty sections, and sections whose only
+/// content was an empty list under a heading ending in `:`
+fn collapse_empty_sections(text: &str) -> String {
+    let paragraphs: Vec<&str> = text
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty() && !paragraph.ends_with(':'))
+        .collect();
+    paragraphs.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(tool: &str) -> ToolCall {
+        ToolCall {
+            tool: tool.to_string(),
+            arguments: "{}".to_string(),
+            response: "ok".to_string(),
+            cached: false,
+        }
+    }
+
+    #[test]
+    fn test_render_description() {
+        let template = DescriptionTemplate::default();
+        let tool_calls = [call("read_file"), call("write_file"), call("write_file")];
+        let message = template.render(&DescriptionInput {
+            job_id: "job-1",
+            task: Some((1, 2)),
+            prompt: "Fix the flaky test",
+            report: "Fixed the race in the cache test.\n\nThe test read the cache \
+                     before the writer flushed it.\n",
+            tool_calls: &tool_calls,
+        });
+        assert_eq!(
+            message,
+            "Agent changes for job: job-1 (task 1/2)\n\n\
+             Fixed the race in the cache test.\n\n\
+             The test read the cache before the writer flushed it.\n\n\
+             Tools used:\n- write_file (2 calls)\n- read_file"
+        );
+
+        // Empty sections leave no gaps
+        let message = template.render(&DescriptionInput {
+            job_id: "job-1",
+            task: None,
+            prompt: "Fix the flaky test",
+            report: "Done.",
+            tool_calls: &[],
+        });
+        assert_eq!(message, "Agent changes for job: job-1\n\nDone.");
+
+        let custom = DescriptionTemplate::new("fix: {summary}\n\nRequested: {prompt}").unwrap();
+        let message = custom.render(&DescriptionInput {
+            job_id: "job-1",
+            task: None,
+            prompt: "Fix the flaky test",
+            report: "Stabilize the cache test",
+            tool_calls: &[],
+        });
+        assert_eq!(message, "fix: Stabilize the cache test\n\nRequested: Fix the flaky test");
+
+        assert!(DescriptionTemplate::new("{summary}\n\n{diff}").is_err());
+        assert!(DescriptionTemplate::new("\n{summary}").is_err());
+    }
+}
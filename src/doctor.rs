@@ -0,0 +1,508 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::agent::AgentExecutor;
+use crate::credentials::{self, GitCredentials};
+use crate::git::GitRepo;
+
+/// Redis round trips slower than this are reported as a warning
+const SLOW_REDIS_LATENCY: Duration = Duration::from_millis(50);
+
+/// What `doctor` should check
+#[derive(Debug, Clone)]
+pub struct DoctorConfig {
+    pub redis_url: String,
+    pub allocator_api_url: String,
+    pub work_dir: String,
+    /// Remote to test git credentials against; the check is skipped without one
+    pub git_remote: Option<String>,
+    /// MCP server to list tools from; the check is skipped without one
+    pub mcp_url: Option<String>,
+    /// Minimum free space required in the work directory
+    pub min_free_mb: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl CheckStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "SKIP",
+        }
+    }
+}
+
+/// Outcome of a single diagnostic check
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// What to do about a failure or warning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    fn problem(name: &'static str, status: CheckStatus, detail: String, hint: &str) -> Self {
+        Self {
+            name,
+            status,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Run every check, in order
+pub async fn run_checks(config: &DoctorConfig) -> Vec<CheckResult> {
+    vec![
+        check_redis(&config.redis_url).await,
+        check_allocator(&config.allocator_api_url).await,
+        check_git(config.git_remote.clone()).await,
+        check_mcp(config.mcp_url.as_deref()).await,
+        check_hypervisor(),
+        check_work_dir(&config.work_dir, config.min_free_mb),
+    ]
+}
+
+/// What a worker checks before it takes its first job, so a misconfigured
+/// worker fails at startup instead of failing every job it takes
+#[derive(Debug, Clone)]
+pub struct PreflightConfig {
+    /// Minimum free space required in the work directory
+    pub min_free_mb: u64,
+    /// Boot the default guest and run its self-test
+    pub boot_sandbox: bool,
+}
+
+/// Run the startup checks of a worker with these dependencies
+pub async fn preflight(
+    config: &PreflightConfig,
+    allocator_api_url: &str,
+    work_dir: &str,
+    git_credentials: &GitCredentials,
+    executor: &AgentExecutor,
+) -> Vec<CheckResult> {
+    let mut results = vec![
+        check_allocator(allocator_api_url).await,
+        check_git_credentials(git_credentials),
+        check_work_dir(work_dir, config.min_free_mb),
+    ];
+    if config.boot_sandbox {
+        results.push(check_sandbox(executor).await);
+    }
+    results
+}
+
+async fn check_redis(redis_url: &str) -> CheckResult {
+    let ping = async {
+        let client = redis::Client::open(redis_url).context("Invalid Redis URL")?;
+        let mut connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .context("Failed to connect")?;
+
+        let started = Instant::now();
+        redis::cmd("PING")
+            .query_async::<String>(&mut connection)
+            .await
+            .context("PING failed")?;
+        Ok::<_, anyhow::Error>(started.elapsed())
+    };
+
+    match tokio::time::timeout(Duration::from_secs(5), ping).await {
+        Ok(Ok(latency)) if latency > SLOW_REDIS_LATENCY => CheckResult::problem(
+            "redis",
+            CheckStatus::Warn,
+            format!("PING took {:.1?}", latency),
+            "High latency slows every queue operation; run the worker closer to Redis",
+        ),
+        Ok(Ok(latency)) => CheckResult::pass("redis", format!("PING took {:.1?}", latency)),
+        Ok(Err(e)) => CheckResult::problem(
+            "redis",
+            CheckStatus::Fail,
+            format!("{:#}", e),
+            "Check REDIS_URL and that Redis is running and reachable",
+        ),
+        Err(_) => CheckResult::problem(
+            "redis",
+            CheckStatus::Fail,
+            "No response within 5s".to_string(),
+            "Check REDIS_URL and any firewall between the worker and Redis",
+        ),
+    }
+}
+
+/// Dependencies a worker checks before each dequeue, so that jobs wait out
+/// an outage instead of failing through their retries
+#[derive(Debug, Clone)]
+pub struct HealthGate {
+    pub allocator_api_url: String,
+    pub mcp_url: Option<String>,
+    /// Remote on the git host jobs clone from
+    pub git_remote: Option<String>,
+}
+
+impl HealthGate {
+    /// Checks of the dependencies that are down
+    pub async fn failures(&self) -> Vec<CheckResult> {
+        let (allocator, mcp, git) = tokio::join!(
+            check_allocator(&self.allocator_api_url),
+            check_mcp(self.mcp_url.as_deref()),
+            check_git(self.git_remote.clone()),
+        );
+        [allocator, mcp, git]
+            .into_iter()
+            .filter(|result| result.status == CheckStatus::Fail)
+            .collect()
+    }
+}
+
+async fn check_allocator(allocator_api_url: &str) -> CheckResult {
+    let url = format!("{}/health", allocator_api_url.trim_end_matches('/'));
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build();
+
+    let response = match client {
+        Ok(client) => client.get(&url).send().await,
+        Err(e) => {
+            return CheckResult::problem(
+                "allocator",
+                CheckStatus::Fail,
+                e.to_string(),
+                "Failed to build an HTTP client",
+            )
+        }
+    };
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            CheckResult::pass("allocator", format!("GET {} returned {}", url, response.status()))
+        }
+        Ok(response) => CheckResult::problem(
+            "allocator",
+            CheckStatus::Fail,
+            format!("GET {} returned {}", url, response.status()),
+            "The allocator is reachable but unhealthy; check its logs",
+        ),
+        Err(e) => CheckResult::problem(
+            "allocator",
+            CheckStatus::Fail,
+            format!("GET {} failed: {}", url, e),
+            "Check ALLOCATOR_API_URL and that the allocator service is running",
+        ),
+    }
+}
+
+async fn check_git(git_remote: Option<String>) -> CheckResult {
+    let Some(remote) = git_remote else {
+        return CheckResult {
+            name: "git",
+            status: CheckStatus::Skip,
+            detail: "No --git-remote given".to_string(),
+            hint: None,
+        };
+    };
+
+    let url = remote.clone();
+    let access = tokio::task::spawn_blocking(move || GitRepo::check_remote_access(&url)).await;
+
+    match access {
+        Ok(Ok(refs)) => CheckResult::pass("git", format!("{} advertised {} refs", remote, refs)),
+        Ok(Err(e)) => CheckResult::problem(
+            "git",
+            CheckStatus::Fail,
+            format!("{}: {:#}", remote, e),
+            "Make sure ssh-agent is running with a key that can read this remote (ssh-add -l)",
+        ),
+        Err(e) => CheckResult::problem(
+            "git",
+            CheckStatus::Fail,
+            e.to_string(),
+            "The git check panicked",
+        ),
+    }
+}
+
+async fn check_mcp(mcp_url: Option<&str>) -> CheckResult {
+    let Some(mcp_url) = mcp_url else {
+        return CheckResult {
+            name: "mcp",
+            status: CheckStatus::Skip,
+            detail: "No --mcp-url given".to_string(),
+            hint: None,
+        };
+    };
+
+    let url = format!("{}/tools", mcp_url.trim_end_matches('/'));
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build();
+
+    let response = match client {
+        Ok(client) => client.get(&url).send().await,
+        Err(e) => {
+            return CheckResult::problem(
+                "mcp",
+                CheckStatus::Fail,
+                e.to_string(),
+                "Failed to build an HTTP client",
+            )
+        }
+    };
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            CheckResult::pass("mcp", format!("GET {} returned {}", url, response.status()))
+        }
+        Ok(response) => CheckResult::problem(
+            "mcp",
+            CheckStatus::Fail,
+            format!("GET {} returned {}", url, response.status()),
+            "The MCP server is reachable but can't list its tools; check its logs",
+        ),
+        Err(e) => CheckResult::problem(
+            "mcp",
+            CheckStatus::Fail,
+            format!("GET {} failed: {}", url, e),
+            "Check the MCP server URL and that the server is running",
+        ),
+    }
+}
+
+fn check_git_credentials(git_credentials: &GitCredentials) -> CheckResult {
+    let problems = git_credentials.problems();
+    if !problems.is_empty() {
+        return CheckResult::problem(
+            "git_credentials",
+            CheckStatus::Fail,
+            problems.join("; "),
+            "Fix the key paths in the credentials file or start ssh-agent for the worker",
+        );
+    }
+    if !credentials::ssh_agent_available() {
+        return CheckResult::problem(
+            "git_credentials",
+            CheckStatus::Warn,
+            "No ssh-agent running; repositories no credential matches can't be \
+             cloned over SSH"
+                .to_string(),
+            "Start ssh-agent with SSH_AUTH_SOCK set, or map the repositories in \
+             --git-credentials",
+        );
+    }
+    CheckResult::pass("git_credentials", "Credentials and ssh-agent usable".to_string())
+}
+
+async fn check_sandbox(executor: &AgentExecutor) -> CheckResult {
+    let hypervisor = check_hypervisor();
+    if hypervisor.status == CheckStatus::Fail {
+        return hypervisor;
+    }
+    match executor.self_test(None).await {
+        Ok(report) if report.failures().is_empty() => CheckResult::pass(
+            "sandbox",
+            format!("Guest booted and passed {} self-test check(s)", report.checks.len()),
+        ),
+        Ok(report) => CheckResult::problem(
+            "sandbox",
+            CheckStatus::Fail,
+            format!(
+                "Guest failed self-test check(s) {}",
+                report
+                    .failures()
+                    .iter()
+                    .map(|check| check.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            "The embedded guest is broken; rebuild the worker",
+        ),
+        Err(e) => CheckResult::problem(
+            "sandbox",
+            CheckStatus::Fail,
+            format!("{:#}", e),
+            "Make sure the worker's user can open the hypervisor and the guest was built",
+        ),
+    }
+}
+
+fn check_hypervisor() -> CheckResult {
+    if hyperlight_host::is_hypervisor_present() {
+        CheckResult::pass("hypervisor", "Hypervisor available for Hyperlight".to_string())
+    } else {
+        CheckResult::problem(
+            "hypervisor",
+            CheckStatus::Fail,
+            "No supported hypervisor found".to_string(),
+            "Enable KVM (/dev/kvm) or MSHV and make sure the worker's user can open it",
+        )
+    }
+}
+
+fn check_work_dir(work_dir: &str, min_free_mb: u64) -> CheckResult {
+    let path = Path::new(work_dir);
+    if let Err(e) = probe_writable(path) {
+        return CheckResult::problem(
+            "work_dir",
+            CheckStatus::Fail,
+            format!("{}: {:#}", work_dir, e),
+            "Point WORK_DIR at a directory the worker's user can write to",
+        );
+    }
+
+    match free_space_mb(path) {
+        Ok(free_mb) if free_mb < min_free_mb => CheckResult::problem(
+            "work_dir",
+            CheckStatus::Fail,
+            format!("{} is writable but only {} MB free", work_dir, free_mb),
+            "Free up space or move WORK_DIR; clones of large repositories will fail",
+        ),
+        Ok(free_mb) => CheckResult::pass(
+            "work_dir",
+            format!("{} is writable with {} MB free", work_dir, free_mb),
+        ),
+        Err(e) => CheckResult::problem(
+            "work_dir",
+            CheckStatus::Warn,
+            format!("{} is writable; free space unknown: {:#}", work_dir, e),
+            "Check free space manually",
+        ),
+    }
+}
+
+fn probe_writable(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path).context("Failed to create directory")?;
+    let probe = path.join(".doctor-probe");
+    std::fs::write(&probe, b"ok").context("Failed to write probe file")?;
+    std::fs::remove_file(&probe).context("Failed to remove probe file")?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // statvfs field widths vary by platform
+fn free_space_mb(path: &Path) -> Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("Invalid path")?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid NUL-terminated string and stat is a valid out pointer
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("statvfs failed");
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64 / (1024 * 1024))
+}
+
+#[cfg(not(unix))]
+fn free_space_mb(_path: &Path) -> Result<u64> {
+    bail!("not supported on this platform")
+}
+
+/// Fail if any check failed
+pub fn ensure_passed(results: &[CheckResult]) -> Result<()> {
+    let failed = results
+        .iter()
+        .filter(|result| result.status == CheckStatus::Fail)
+        .count();
+    if failed > 0 {
+        bail!("{} check(s) failed", failed);
+    }
+    Ok(())
+}
+
+/// Fail with the detail and hint of every failed check, so all of what's
+/// wrong can be fixed before the next start
+pub fn ensure_ready(results: &[CheckResult]) -> Result<()> {
+    let failures: Vec<String> = results
+        .iter()
+        .filter(|result| result.status == CheckStatus::Fail)
+        .map(|result| match &result.hint {
+            Some(hint) => format!("{}: {} ({})", result.name, result.detail, hint),
+            None => format!("{}: {}", result.name, result.detail),
+        })
+        .collect();
+    if !failures.is_empty() {
+        bail!("{} check(s) failed:\n  {}", failures.len(), failures.join("\n  "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_work_dir_check_reports_free_space() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let work_dir = dir.path().join("work");
+        let work_dir = work_dir.to_str().unwrap();
+
+        assert_eq!(check_work_dir(work_dir, 0).status, CheckStatus::Pass);
+        assert_eq!(check_work_dir(work_dir, u64::MAX).status, CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_health_gate_reports_only_failures() {
+        // Nothing listens on port 1, so the connection is refused at once
+        let gate = HealthGate {
+            allocator_api_url: "http://127.0.0.1:1".to_string(),
+            mcp_url: None,
+            git_remote: None,
+        };
+        let failures = gate.failures().await;
+        let names: Vec<&str> = failures.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["allocator"]);
+    }
+
+    #[test]
+    fn test_only_failures_fail_the_run() {
+        let warn = CheckResult::problem("redis", CheckStatus::Warn, String::new(), "");
+        let skip = CheckResult {
+            name: "git",
+            status: CheckStatus::Skip,
+            detail: String::new(),
+            hint: None,
+        };
+        assert!(ensure_passed(&[warn.clone(), skip]).is_ok());
+
+        let fail = CheckResult::problem("allocator", CheckStatus::Fail, String::new(), "");
+        assert!(ensure_passed(&[warn.clone(), fail.clone()]).is_err());
+
+        let work_dir = CheckResult::problem(
+            "work_dir",
+            CheckStatus::Fail,
+            "/var/lib/worker is read-only".to_string(),
+            "Point WORK_DIR at a writable directory",
+        );
+        let error = ensure_ready(&[warn, fail, work_dir]).unwrap_err().to_string();
+        assert!(error.starts_with("2 check(s) failed:\n  allocator: "));
+        assert!(error.ends_with(
+            "\n  work_dir: /var/lib/worker is read-only (Point WORK_DIR at a writable directory)"
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::queue::{now_secs, Job, PayloadTooLarge, RateLimited, ReliableQueue};
+
+/// Subdirectory of the drop directory enqueued job files are moved to
+pub const PROCESSED_DIR: &str = "processed";
+/// Subdirectory of the drop directory invalid job files are moved to, each
+/// next to a `.error` file saying what's wrong with it
+pub const REJECTED_DIR: &str = "rejected";
+
+pub struct DropDirConfig {
+    pub dir: PathBuf,
+    /// How often the directory is scanned for new job files
+    pub poll_interval: Duration,
+}
+
+/// Enqueues jobs from files dropped into a directory, for systems that can
+/// write files but can't talk to Redis
+///
+/// Each `.json` file holds one job, in the same format as `enqueue --file`
+/// or a Kafka message. A file is moved to `processed/` once its job is in
+/// Redis, and jobs whose ID was already enqueued are skipped, so a file
+/// picked up again after a crash enqueues its job once. Files whose name
+/// starts with a dot are ignored: writers should create a file under such a
+/// name and rename it once it's complete.
+pub struct DropDir {
+    config: DropDirConfig,
+    queue: ReliableQueue,
+}
+
+impl DropDir {
+    pub fn new(config: DropDirConfig, queue: ReliableQueue) -> Result<Self> {
+        for subdir in [PROCESSED_DIR, REJECTED_DIR] {
+            let path = config.dir.join(subdir);
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+        }
+        Ok(Self { config, queue })
+    }
+
+    /// Scan the directory and enqueue its jobs until `shutdown` resolves
+    pub async fn run(&mut self, shutdown: impl std::future::Future<Output = ()>) -> Result<()> {
+        info!("Ingesting jobs dropped into {}", self.config.dir.display());
+        tokio::pin!(shutdown);
+
+        loop {
+            if let Err(e) = self.scan().await {
+                if e.downcast_ref::<RateLimited>().is_some() {
+                    warn!("{:#}, retrying in {:?}", e, self.config.poll_interval);
+                } else {
+                    error!("Failed to ingest dropped jobs: {:#}", e);
+                }
+            }
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = tokio::time::sleep(self.config.poll_interval) => {}
+            }
+        }
+
+        info!("Drop directory ingest stopped");
+        Ok(())
+    }
+
+    /// Enqueue the job of every file in the directory, in order of name,
+    /// returning how many were enqueued
+    ///
+    /// Stops at the first job that couldn't be enqueued, such as while
+    /// Redis is down, leaving it and the rest for the next scan.
+    pub async fn scan(&mut self) -> Result<usize> {
+        let mut enqueued = 0;
+        for path in job_files(&self.config.dir)? {
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let job = match read_job(&path) {
+                Ok(job) => job,
+                Err(e) => {
+                    warn!("Rejecting job file {}: {:#}", name, e);
+                    reject(&path, &self.config.dir, &e)?;
+                    continue;
+                }
+            };
+
+            match self.queue.enqueue_unique(&job).await {
+                Ok(true) => {
+                    info!("Enqueued job {} from {}", job.id, name);
+                    enqueued += 1;
+                }
+                Ok(false) => info!("Job {} from {} was already enqueued", job.id, name),
+                Err(e) if e.downcast_ref::<PayloadTooLarge>().is_some() => {
+                    warn!("Rejecting job file {}: {}", name, e);
+                    reject(&path, &self.config.dir, &e)?;
+                    continue;
+                }
+                Err(e) => return Err(e.context(format!("Failed to enqueue job from {}", name))),
+            }
+            move_into(&path, &self.config.dir.join(PROCESSED_DIR))?;
+        }
+        Ok(enqueued)
+    }
+}
+
+/// Job files waiting in `dir`, sorted by name
+fn job_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_file() && !name.starts_with('.') && name.ends_with(".json") {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Parse and validate the job in the file at `path`
+fn read_job(path: &Path) -> Result<Job> {
+    let contents = std::fs::read(path).context("Failed to read the file")?;
+    let job: Job = serde_json::from_slice(&contents).context("The file is not a job")?;
+    job.validate()?;
+    Ok(job)
+}
+
+/// Move the file at `path` into `dir`, keeping its name unless a file of
+/// that name is already there
+fn move_into(path: &Path, dir: &Path) -> Result<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut target = dir.join(&*name);
+    if target.exists() {
+        target = dir.join(format!("{}-{}", now_secs(), name));
+    }
+    std::fs::rename(path, &target)
+        .with_context(|| format!("Failed to move {} to {}", path.display(), target.display()))?;
+    Ok(target)
+}
+
+/// Move the job file at `path` into the rejected directory of `drop_dir`,
+/// with why it was rejected
+fn reject(path: &Path, drop_dir: &Path, error: &anyhow::Error) -> Result<()> {
+    let target = move_into(path, &drop_dir.join(REJECTED_DIR))?;
+    let mut error_file = target.into_os_string();
+    error_file.push(".error");
+    std::fs::write(&error_file, format!("{:#}\n", error))
+        .with_context(|| format!("Failed to write {}", Path::new(&error_file).display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let job = r#"{"id":"job-1","repo_url":"git@github.com:org/app.git",
+                      "branch":"main","prompt":"Fix it"}"#;
+        std::fs::write(dir.path().join("b.json"), job).unwrap();
+        std::fs::write(dir.path().join("a.json"), r#"{"id":"job-2"}"#).unwrap();
+        std::fs::write(dir.path().join(".c.json"), job).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        std::fs::create_dir(dir.path().join(PROCESSED_DIR)).unwrap();
+        std::fs::create_dir(dir.path().join(REJECTED_DIR)).unwrap();
+
+        let files = job_files(dir.path()).unwrap();
+        assert_eq!(
+            files,
+            [dir.path().join("a.json"), dir.path().join("b.json")]
+        );
+        assert_eq!(read_job(&files[1]).unwrap().id, "job-1");
+
+        let error = read_job(&files[0]).unwrap_err();
+        reject(&files[0], dir.path(), &error).unwrap();
+        let rejected = dir.path().join(REJECTED_DIR);
+        assert!(rejected.join("a.json").exists());
+        assert!(std::fs::read_to_string(rejected.join("a.json.error"))
+            .unwrap()
+            .starts_with("The file is not a job"));
+
+        // A file dropped again under a processed name doesn't replace it
+        let processed = dir.path().join(PROCESSED_DIR);
+        assert_eq!(
+            move_into(&files[1], &processed).unwrap(),
+            processed.join("b.json")
+        );
+        std::fs::write(dir.path().join("b.json"), job).unwrap();
+        let target = move_into(&dir.path().join("b.json"), &processed).unwrap();
+        assert_ne!(target, processed.join("b.json"));
+        assert!(job_files(dir.path()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Coarse classification of why a job failed, keyed by the stage that failed
+///
+/// Attach it to an error with `.context(ErrorClass::Clone)` and recover it
+/// later with [`ErrorClass::of`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Allocator,
+    /// Checking the job's prompts against the prompt policy
+    Policy,
+    Clone,
+    Checkout,
+    Context,
+    Agent,
+    Commit,
+    Push,
+    Cleanup,
+    Timeout,
+    Cancelled,
+    Internal,
+}
+
+impl ErrorClass {
+    /// Find the class attached to an error, defaulting to `Internal`
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<ErrorClass>()
+            .copied()
+            .unwrap_or(ErrorClass::Internal)
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "allocator" => Some(ErrorClass::Allocator),
+            "policy" => Some(ErrorClass::Policy),
+            "clone" => Some(ErrorClass::Clone),
+            "checkout" => Some(ErrorClass::Checkout),
+            "context" => Some(ErrorClass::Context),
+            "agent" => Some(ErrorClass::Agent),
+            "commit" => Some(ErrorClass::Commit),
+            "push" => Some(ErrorClass::Push),
+            "cleanup" => Some(ErrorClass::Cleanup),
+            "timeout" => Some(ErrorClass::Timeout),
+            "cancelled" => Some(ErrorClass::Cancelled),
+            "internal" => Some(ErrorClass::Internal),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Allocator => "allocator",
+            ErrorClass::Policy => "policy",
+            ErrorClass::Clone => "clone",
+            ErrorClass::Checkout => "checkout",
+            ErrorClass::Context => "context",
+            ErrorClass::Agent => "agent",
+            ErrorClass::Commit => "commit",
+            ErrorClass::Push => "push",
+            ErrorClass::Cleanup => "cleanup",
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::Cancelled => "cancelled",
+            ErrorClass::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_error_class_survives_context_chain() {
+        let error = Err::<(), _>(anyhow!("connection reset"))
+            .context("Failed to push changes")
+            .context(ErrorClass::Push)
+            .context("Job failed")
+            .unwrap_err();
+
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Push);
+    }
+
+    #[test]
+    fn test_unclassified_error_is_internal() {
+        let error = anyhow!("something unexpected");
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Internal);
+    }
+}
@@ -1,42 +1,292 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use git2::{
-    BranchType, Cred, FetchOptions, RemoteCallbacks, Repository,
+    ApplyLocation, BranchType, CertificateCheckStatus, Cred, Diff, DiffFormat, Direction,
+    FetchOptions, Remote, RemoteCallbacks, Repository, Signature,
 };
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tracing::{debug, info};
 
+use crate::credentials::GitCredential;
+use crate::git_auth::known_hosts_name;
+
+/// How much a clone may download and how long it may take, so a job aimed
+/// at a huge repository by mistake can't fill the worker's disk or hold it
+/// for hours
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CloneBudget {
+    pub max_bytes: Option<u64>,
+    pub max_duration: Option<Duration>,
+    /// Download rate clones are throttled to, so a worker sharing a host
+    /// with latency-sensitive services doesn't saturate its network
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+impl CloneBudget {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_duration.is_none() && self.max_bytes_per_sec.is_none()
+    }
+
+    /// The tighter of two budgets, limit by limit
+    pub fn min(self, other: Self) -> Self {
+        fn tighter<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        Self {
+            max_bytes: tighter(self.max_bytes, other.max_bytes),
+            max_duration: tighter(self.max_duration, other.max_duration),
+            max_bytes_per_sec: tighter(self.max_bytes_per_sec, other.max_bytes_per_sec),
+        }
+    }
+
+    /// How long a clone that has received `bytes` in `elapsed` has to pause
+    /// to get back under the rate limit, if it's over it
+    pub fn throttle(&self, bytes: u64, elapsed: Duration) -> Option<Duration> {
+        let rate = self.max_bytes_per_sec.filter(|rate| *rate > 0)?;
+        let allowed = Duration::from_secs_f64(bytes as f64 / rate as f64);
+        allowed.checked_sub(elapsed).filter(|pause| !pause.is_zero())
+    }
+
+    /// The limit a clone that has received `bytes` in `elapsed` is over, if any
+    pub fn check(&self, bytes: u64, elapsed: Duration) -> Option<CloneBudgetExceeded> {
+        if let Some(max_bytes) = self.max_bytes.filter(|max_bytes| bytes > *max_bytes) {
+            return Some(CloneBudgetExceeded::Size { max_bytes });
+        }
+        if let Some(max_duration) = self.max_duration.filter(|max| elapsed > *max) {
+            return Some(CloneBudgetExceeded::Duration { max_duration });
+        }
+        None
+    }
+}
+
+/// Returned by clones that were aborted for exceeding their `CloneBudget`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneBudgetExceeded {
+    Size { max_bytes: u64 },
+    Duration { max_duration: Duration },
+}
+
+impl std::fmt::Display for CloneBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloneBudgetExceeded::Size { max_bytes } => write!(
+                f,
+                "Repository is larger than the clone budget of {} MB",
+                max_bytes / (1024 * 1024)
+            ),
+            CloneBudgetExceeded::Duration { max_duration } => write!(
+                f,
+                "Clone took longer than the budget of {}s",
+                max_duration.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CloneBudgetExceeded {}
+
+/// SSH host key presented by a git host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostKey {
+    /// Host as known_hosts names it
+    pub name: String,
+    /// e.g. `ssh-ed25519`
+    pub key_type: String,
+    pub key: Vec<u8>,
+}
+
+impl HostKey {
+    pub fn encoded(&self) -> String {
+        BASE64.encode(&self.key)
+    }
+
+    pub fn known_hosts_line(&self) -> String {
+        format!("{} {} {}", self.name, self.key_type, self.encoded())
+    }
+}
+
 pub struct GitRepo {
     repo: Repository,
     repo_path: PathBuf,
+    credential: GitCredential,
+}
+
+/// Remote callbacks authenticating with `credential`
+fn callbacks(credential: &GitCredential) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |_url, username_from_url, _allowed_types| {
+        debug!("Git credentials callback");
+        let username = username_from_url.unwrap_or("git");
+        match credential {
+            GitCredential::SshAgent => Cred::ssh_key_from_agent(username),
+            GitCredential::SshKey {
+                private_key,
+                public_key,
+                passphrase,
+            } => Cred::ssh_key(
+                username,
+                public_key.as_deref(),
+                private_key,
+                passphrase.as_deref(),
+            ),
+            GitCredential::Token { username, token } => Cred::userpass_plaintext(username, token),
+            GitCredential::GitHubApp(_) => Err(git2::Error::from_str(
+                "GitHub App credentials must be exchanged for a token first",
+            )),
+        }
+    });
+    callbacks
 }
 
 impl GitRepo {
     /// Clone a repository to a temporary directory
     pub fn clone(repo_url: &str, target_dir: &Path) -> Result<Self> {
-        info!("Cloning repository: {} to {:?}", repo_url, target_dir);
+        Self::clone_with(repo_url, target_dir, &GitCredential::default())
+    }
 
-        // Setup callbacks for authentication
-        let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, _allowed_types| {
-            debug!("Git credentials callback");
-            Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
-        });
+    /// Clone a repository with `credential` (see `GitCredentials::resolve`),
+    /// which later fetches and pushes reuse
+    pub fn clone_with(
+        repo_url: &str,
+        target_dir: &Path,
+        credential: &GitCredential,
+    ) -> Result<Self> {
+        Self::clone_within(repo_url, target_dir, credential, &CloneBudget::default())
+    }
+
+    /// Like `clone_with`, aborting with `CloneBudgetExceeded` once the clone
+    /// downloads more or takes longer than `budget` allows
+    pub fn clone_within(
+        repo_url: &str,
+        target_dir: &Path,
+        credential: &GitCredential,
+        budget: &CloneBudget,
+    ) -> Result<Self> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        Self::clone_cancellable(repo_url, target_dir, credential, budget, cancelled)
+    }
 
+    /// Like `clone_within`, also aborting at the next progress report once
+    /// `cancelled` is set
+    pub fn clone_cancellable(
+        repo_url: &str,
+        target_dir: &Path,
+        credential: &GitCredential,
+        budget: &CloneBudget,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        info!("Cloning repository: {} to {:?}", repo_url, target_dir);
+
+        let exceeded = Arc::new(Mutex::new(None));
+        let mut callbacks = callbacks(credential);
+        {
+            let budget = *budget;
+            let exceeded = exceeded.clone();
+            let cancelled = cancelled.clone();
+            let started = Instant::now();
+            callbacks.transfer_progress(move |progress| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return false;
+                }
+                let bytes = progress.received_bytes() as u64;
+                if let Some(over) = budget.check(bytes, started.elapsed()) {
+                    *exceeded.lock().unwrap() = Some(over);
+                    return false;
+                }
+                // Not reading from the socket while paused lets TCP flow
+                // control slow the server down
+                if let Some(pause) = budget.throttle(bytes, started.elapsed()) {
+                    std::thread::sleep(pause);
+                }
+                true
+            });
+        }
         let mut fetch_options = FetchOptions::new();
         fetch_options.remote_callbacks(callbacks);
 
         let mut builder = git2::build::RepoBuilder::new();
         builder.fetch_options(fetch_options);
 
-        let repo = builder
-            .clone(repo_url, target_dir)
-            .context("Failed to clone repository")?;
+        let repo = match builder.clone(repo_url, target_dir) {
+            Ok(repo) => repo,
+            Err(e) => {
+                if let Some(over) = exceeded.lock().unwrap().take() {
+                    return Err(over.into());
+                }
+                if cancelled.load(Ordering::Relaxed) {
+                    bail!("Clone of {} was cancelled", repo_url);
+                }
+                return Err(e).context("Failed to clone repository");
+            }
+        };
 
         info!("Successfully cloned repository to {:?}", target_dir);
 
         Ok(Self {
             repo,
             repo_path: target_dir.to_path_buf(),
+            credential: credential.clone(),
+        })
+    }
+
+    /// Connect to a remote with the worker's credentials without cloning it
+    /// Returns the number of refs the remote advertises
+    pub fn check_remote_access(repo_url: &str) -> Result<usize> {
+        Self::check_remote_access_with(repo_url, &GitCredential::SshAgent)
+    }
+
+    /// Like `check_remote_access`, authenticating with `credential`
+    pub fn check_remote_access_with(repo_url: &str, credential: &GitCredential) -> Result<usize> {
+        let mut remote = Remote::create_detached(repo_url)
+            .context("Invalid remote URL")?;
+        let connection = remote
+            .connect_auth(Direction::Fetch, Some(callbacks(credential)), None)
+            .context("Failed to connect to remote")?;
+        let refs = connection.list().context("Failed to list remote refs")?.len();
+
+        Ok(refs)
+    }
+
+    /// Read the host key an SSH git host presents, without authenticating
+    pub fn scan_host_key(host: &str, port: Option<u16>) -> Result<HostKey> {
+        let name = known_hosts_name(host, port);
+        let url = match port {
+            Some(port) => format!("ssh://git@{}:{}/", host, port),
+            None => format!("ssh://git@{}/", host),
+        };
+
+        let scanned = Arc::new(Mutex::new(None));
+        let mut callbacks = RemoteCallbacks::new();
+        let captured = scanned.clone();
+        callbacks.certificate_check(move |cert, _host| {
+            if let Some(hostkey) = cert.as_hostkey() {
+                if let (Some(key), Some(key_type)) = (hostkey.hostkey(), hostkey.hostkey_type()) {
+                    *captured.lock().unwrap() = Some((key_type.name().to_string(), key.to_vec()));
+                }
+            }
+            // The key is all that's needed, so hang up before authenticating
+            Err::<CertificateCheckStatus, _>(git2::Error::from_str("Host key read"))
+        });
+
+        let mut remote = Remote::create_detached(url.as_str()).context("Invalid host")?;
+        let connected = remote.connect_auth(Direction::Fetch, Some(callbacks), None);
+        let Some((key_type, key)) = scanned.lock().unwrap().take() else {
+            match connected {
+                Err(e) => bail!("Failed to read host key of {}: {}", name, e),
+                Ok(_) => bail!("{} presented no SSH host key", name),
+            }
+        };
+        Ok(HostKey {
+            name,
+            key_type,
+            key,
         })
     }
 
@@ -48,6 +298,7 @@ impl GitRepo {
         Ok(Self {
             repo,
             repo_path: repo_path.to_path_buf(),
+            credential: GitCredential::default(),
         })
     }
 
@@ -103,46 +354,165 @@ impl GitRepo {
         Ok(())
     }
 
-    /// Commit changes
-    pub fn commit(&self, message: &str) -> Result<()> {
+    /// Render the staged changes as a patch against HEAD
+    pub fn staged_diff(&self) -> Result<String> {
+        let head_tree = self.repo.head()?.peel_to_tree()?;
+        let index = self.repo.index()?;
+        let diff = self
+            .repo
+            .diff_tree_to_index(Some(&head_tree), Some(&index), None)?;
+        render_patch(&diff)
+    }
+
+    /// Render the changes committed since `commit_sha` as a patch
+    pub fn diff_since(&self, commit_sha: &str) -> Result<String> {
+        let base_tree = self
+            .repo
+            .revparse_single(commit_sha)
+            .and_then(|object| object.peel_to_tree())
+            .with_context(|| format!("Failed to resolve commit {}", commit_sha))?;
+        let head_tree = self.repo.head()?.peel_to_tree()?;
+        let diff = self
+            .repo
+            .diff_tree_to_tree(Some(&base_tree), Some(&head_tree), None)?;
+        render_patch(&diff)
+    }
+
+    /// Apply a patch rendered by `staged_diff` to the working tree and index
+    pub fn apply(&self, patch: &str) -> Result<()> {
+        let diff = Diff::from_buffer(patch.as_bytes()).context("Failed to parse patch")?;
+        self.repo
+            .apply(&diff, ApplyLocation::Both, None)
+            .context("Failed to apply patch")?;
+        Ok(())
+    }
+
+    /// Commit changes, returning the new commit SHA
+    pub fn commit(&self, message: &str) -> Result<String> {
+        let signature = self.repo.signature()?;
+        self.commit_with(message, &signature)
+    }
+
+    /// Commit changes as the given author, returning the new commit SHA
+    pub fn commit_as(&self, message: &str, name: &str, email: &str) -> Result<String> {
+        let signature = Signature::now(name, email).context("Invalid commit author")?;
+        self.commit_with(message, &signature)
+    }
+
+    fn commit_with(&self, message: &str, signature: &Signature) -> Result<String> {
         info!("Creating commit with message: {}", message);
 
         let mut index = self.repo.index()?;
         let tree_id = index.write_tree()?;
         let tree = self.repo.find_tree(tree_id)?;
 
-        let signature = self.repo.signature()?;
         let parent_commit = self.repo.head()?.peel_to_commit()?;
 
-        self.repo.commit(
+        let commit_id = self.repo.commit(
             Some("HEAD"),
-            &signature,
-            &signature,
+            signature,
+            signature,
             message,
             &tree,
             &[&parent_commit],
         )?;
 
-        info!("Successfully created commit");
+        info!("Successfully created commit {}", commit_id);
+        Ok(commit_id.to_string())
+    }
+
+    /// Attach `note` to commit `commit_sha` under `notes_ref`, replacing any
+    /// note it had there; the note is signed as the commit was
+    pub fn add_note(&self, notes_ref: &str, commit_sha: &str, note: &str) -> Result<()> {
+        let commit = self
+            .repo
+            .revparse_single(commit_sha)
+            .and_then(|object| object.peel_to_commit())
+            .with_context(|| format!("Failed to resolve commit {}", commit_sha))?;
+        let signature = commit.committer().to_owned();
+        self.repo
+            .note(&signature, &signature, Some(notes_ref), commit.id(), note, true)
+            .with_context(|| format!("Failed to add note to {}", commit_sha))?;
+        Ok(())
+    }
+
+    /// Push the notes under `notes_ref` on top of the remote's, so the notes
+    /// other clones pushed there are kept
+    pub fn push_notes_with(&self, notes_ref: &str, credential: &GitCredential) -> Result<()> {
+        let notes = match self.repo.notes(Some(notes_ref)) {
+            Ok(notes) => notes
+                .map(|note| {
+                    let (_, target) = note?;
+                    let note = self.repo.find_note(Some(notes_ref), target)?;
+                    let signature = note.committer().to_owned();
+                    Ok((target, note.message().unwrap_or_default().to_string(), signature))
+                })
+                .collect::<std::result::Result<Vec<_>, git2::Error>>()?,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(()),
+            Err(e) => return Err(e).context("Failed to read notes"),
+        };
+
+        let mut remote = self.repo.find_remote("origin")
+            .context("Failed to find origin remote")?;
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks(credential));
+        // Replaces the local notes, which are added again below
+        let refspec = format!("+{}:{}", notes_ref, notes_ref);
+        if let Err(e) = remote.fetch(&[&refspec], Some(&mut fetch_options), None) {
+            debug!("Remote has no notes under {}: {}", notes_ref, e);
+        }
+        for (target, note, signature) in &notes {
+            self.repo
+                .note(signature, signature, Some(notes_ref), *target, note, true)
+                .context("Failed to add note")?;
+        }
+
+        let mut push_options = git2::PushOptions::new();
+        push_options.remote_callbacks(callbacks(credential));
+        let refspec = format!("{}:{}", notes_ref, notes_ref);
+        remote.push(&[&refspec], Some(&mut push_options))
+            .context("Failed to push notes")?;
+
+        info!("Pushed {} notes under {}", notes.len(), notes_ref);
+        Ok(())
+    }
+
+    /// SHA of the commit HEAD points to
+    pub fn head_commit(&self) -> Result<String> {
+        let commit = self
+            .repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .context("Failed to resolve HEAD")?;
+        Ok(commit.id().to_string())
+    }
+
+    /// Create a branch at HEAD and switch to it, keeping the working tree
+    pub fn create_branch(&self, branch_name: &str) -> Result<()> {
+        info!("Creating branch: {}", branch_name);
+
+        let head = self.repo.head()?.peel_to_commit()?;
+        self.repo.branch(branch_name, &head, false)
+            .context("Failed to create branch")?;
+        self.repo.set_head(&format!("refs/heads/{}", branch_name))?;
+
         Ok(())
     }
 
     /// Push changes to remote
     pub fn push(&self, branch_name: &str) -> Result<()> {
+        self.push_with(branch_name, &self.credential)
+    }
+
+    /// Push changes to remote with a different credential than the clone's
+    pub fn push_with(&self, branch_name: &str, credential: &GitCredential) -> Result<()> {
         info!("Pushing branch: {}", branch_name);
 
         let mut remote = self.repo.find_remote("origin")
             .context("Failed to find origin remote")?;
 
-        // Setup callbacks for authentication
-        let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, _allowed_types| {
-            debug!("Git credentials callback for push");
-            Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
-        });
-
         let mut push_options = git2::PushOptions::new();
-        push_options.remote_callbacks(callbacks);
+        push_options.remote_callbacks(callbacks(credential));
 
         let refspec = format!("refs/heads/{}:refs/heads/{}", branch_name, branch_name);
 
@@ -159,14 +529,8 @@ impl GitRepo {
 
         let mut remote = self.repo.find_remote("origin")?;
 
-        // Setup callbacks for authentication
-        let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, _allowed_types| {
-            Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
-        });
-
         let mut fetch_options = FetchOptions::new();
-        fetch_options.remote_callbacks(callbacks);
+        fetch_options.remote_callbacks(callbacks(&self.credential));
 
         remote.fetch(&["refs/heads/*:refs/remotes/origin/*"], Some(&mut fetch_options), None)?;
 
@@ -174,6 +538,25 @@ impl GitRepo {
         Ok(())
     }
 
+    /// Keep paths matching `pattern` out of status and commits without
+    /// touching the repository's tracked `.gitignore`
+    pub fn exclude(&self, pattern: &str) -> Result<()> {
+        let exclude_path = self.repo.path().join("info").join("exclude");
+        if let Some(dir) = exclude_path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create .git/info")?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&exclude_path)
+            .context("Failed to open .git/info/exclude")?;
+        writeln!(file, "{}", pattern).context("Failed to write .git/info/exclude")?;
+
+        debug!("Excluded {} from commits", pattern);
+        Ok(())
+    }
+
     /// Get the repository path
     pub fn path(&self) -> &Path {
         &self.repo_path
@@ -185,3 +568,210 @@ impl GitRepo {
         Ok(!statuses.is_empty())
     }
 }
+
+fn render_patch(diff: &Diff) -> Result<String> {
+    let mut patch = String::new();
+    diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin());
+        }
+        patch.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })?;
+
+    Ok(patch)
+}
+
+/// Cancels a clone running on a blocking thread once whoever awaits it is
+/// gone
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A `GitRepo` whose operations run on tokio's blocking thread pool
+///
+/// git2 calls block for as long as the network or disk takes, which for large
+/// repositories would stall every other task on the executor thread.
+#[derive(Clone)]
+pub struct AsyncGitRepo {
+    repo: Arc<Mutex<GitRepo>>,
+    repo_path: PathBuf,
+}
+
+impl AsyncGitRepo {
+    /// Clone a repository like `GitRepo::clone_with`, off the executor
+    pub async fn clone_with(
+        repo_url: &str,
+        target_dir: &Path,
+        credential: &GitCredential,
+    ) -> Result<Self> {
+        Self::clone_within(repo_url, target_dir, credential, &CloneBudget::default()).await
+    }
+
+    /// Clone a repository like `GitRepo::clone_within`, off the executor
+    ///
+    /// libgit2 only reports progress when data arrives, so a server that
+    /// stalls mid-clone would never be noticed from inside the clone. The
+    /// time budget is therefore also enforced here. The clone is cancelled
+    /// when it runs over or this future is dropped, e.g. by a job timeout,
+    /// so it stops at its next progress report instead of going on writing
+    /// into `target_dir`.
+    pub async fn clone_within(
+        repo_url: &str,
+        target_dir: &Path,
+        credential: &GitCredential,
+        budget: &CloneBudget,
+    ) -> Result<Self> {
+        let repo_url = repo_url.to_string();
+        let target_dir = target_dir.to_path_buf();
+        let credential = credential.clone();
+        let budget = *budget;
+        let span = tracing::Span::current();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel = CancelOnDrop(cancelled.clone());
+        let clone = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            GitRepo::clone_cancellable(&repo_url, &target_dir, &credential, &budget, cancelled)
+        });
+        let repo = match budget.max_duration {
+            Some(max_duration) => tokio::time::timeout(max_duration, clone)
+                .await
+                .map_err(|_| CloneBudgetExceeded::Duration { max_duration })?,
+            None => clone.await,
+        }
+        .context("Git task failed")??;
+        Ok(Self::new(repo))
+    }
+
+    pub fn new(repo: GitRepo) -> Self {
+        Self {
+            repo_path: repo.path().to_path_buf(),
+            repo: Arc::new(Mutex::new(repo)),
+        }
+    }
+
+    /// Get the repository path
+    pub fn path(&self) -> &Path {
+        &self.repo_path
+    }
+
+    /// Run `operation` on the repository from a blocking thread, inside the
+    /// caller's tracing span so its log lines stay with the job's
+    pub async fn run<T, F>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&GitRepo) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let repo = Arc::clone(&self.repo);
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let repo = repo
+                .lock()
+                .map_err(|_| anyhow::anyhow!("A previous git operation panicked"))?;
+            operation(&repo)
+        })
+        .await
+        .context("Git task failed")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clone_budget() {
+        let budget = CloneBudget {
+            max_bytes: Some(100 * 1024 * 1024),
+            max_duration: None,
+            max_bytes_per_sec: None,
+        }
+        .min(CloneBudget {
+            max_bytes: Some(500 * 1024 * 1024),
+            max_duration: Some(Duration::from_secs(60)),
+            max_bytes_per_sec: Some(1024 * 1024),
+        });
+        assert_eq!(budget.max_bytes, Some(100 * 1024 * 1024));
+        assert_eq!(budget.max_duration, Some(Duration::from_secs(60)));
+        assert!(CloneBudget::default().min(CloneBudget::default()).is_unlimited());
+
+        assert_eq!(budget.check(1024, Duration::from_secs(1)), None);
+        let over = budget.check(200 * 1024 * 1024, Duration::from_secs(1)).unwrap();
+        assert_eq!(over.to_string(), "Repository is larger than the clone budget of 100 MB");
+        assert_eq!(
+            budget.check(1024, Duration::from_secs(61)),
+            Some(CloneBudgetExceeded::Duration {
+                max_duration: Duration::from_secs(60)
+            })
+        );
+
+        assert_eq!(budget.throttle(512 * 1024, Duration::from_secs(1)), None);
+        assert_eq!(
+            budget.throttle(3 * 1024 * 1024, Duration::from_secs(1)),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_cancelled_clone() {
+        let origin = tempfile::TempDir::new().unwrap();
+        let repo = Repository::init(origin.path()).unwrap();
+        std::fs::write(origin.path().join("README.md"), "hello").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("README.md")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+
+        let target = tempfile::TempDir::new().unwrap();
+        let error = GitRepo::clone_cancellable(
+            &format!("file://{}", origin.path().display()),
+            &target.path().join("repo"),
+            &GitCredential::SshAgent,
+            &CloneBudget::default(),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .err()
+        .expect("A cancelled clone should be aborted");
+        assert!(error.to_string().contains("was cancelled"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn test_clone_budget_aborts_stalled_clone() {
+        // A server that accepts the connection and doesn't answer until
+        // long after the budget, then hangs up so the clone thread ends
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let _stalled = listener.accept();
+            std::thread::sleep(Duration::from_secs(5));
+        });
+
+        let target = tempfile::TempDir::new().unwrap();
+        let budget = CloneBudget {
+            max_duration: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let error = AsyncGitRepo::clone_within(
+            &format!("http://127.0.0.1:{}/repo.git", port),
+            &target.path().join("repo"),
+            &GitCredential::SshAgent,
+            &budget,
+        )
+        .await
+        .err()
+        .expect("A stalled clone should be aborted");
+        assert_eq!(
+            error.downcast_ref::<CloneBudgetExceeded>(),
+            Some(&CloneBudgetExceeded::Duration {
+                max_duration: Duration::from_secs(1)
+            })
+        );
+    }
+}
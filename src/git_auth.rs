@@ -0,0 +1,336 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::credentials::GitCredentials;
+use crate::git::{GitRepo, HostKey};
+
+/// What `git-auth setup` should prepare
+#[derive(Debug, Clone)]
+pub struct GitAuthConfig {
+    /// Remotes to verify with an authenticated `ls-remote`; their SSH hosts
+    /// are provisioned too
+    pub remotes: Vec<String>,
+    /// Further SSH hosts to provision, as `host` or `host:port`
+    pub hosts: Vec<String>,
+    pub known_hosts: PathBuf,
+    /// Only check that every host is already known; never add keys
+    pub strict: bool,
+    pub credentials: GitCredentials,
+}
+
+/// `~/.ssh/known_hosts`, which libgit2 checks host keys against
+pub fn default_known_hosts() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| ".".into());
+    Path::new(&home).join(".ssh").join("known_hosts")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyStatus {
+    /// The host's key is already in known_hosts
+    Known,
+    /// The host's key was added to known_hosts
+    Added,
+    /// The host isn't in known_hosts and strict mode kept it out
+    Missing,
+    /// known_hosts has a different key for the host
+    Changed,
+    /// The host's key couldn't be read
+    Unreachable,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostReport {
+    pub host: String,
+    pub status: HostKeyStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteReport {
+    pub remote: String,
+    /// Refs the remote advertised, if it could be listed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GitAuthReport {
+    pub hosts: Vec<HostReport>,
+    pub remotes: Vec<RemoteReport>,
+}
+
+impl GitAuthReport {
+    /// Fail unless every host is known and every remote could be listed
+    pub fn ensure_ok(&self) -> Result<()> {
+        let hosts = self
+            .hosts
+            .iter()
+            .filter(|host| !matches!(host.status, HostKeyStatus::Known | HostKeyStatus::Added))
+            .count();
+        let remotes = self.remotes.iter().filter(|remote| remote.error.is_some()).count();
+        if hosts + remotes > 0 {
+            bail!(
+                "Git authentication isn't ready: {} host(s) without a trusted key, \
+                 {} remote(s) not readable",
+                hosts,
+                remotes
+            );
+        }
+        Ok(())
+    }
+}
+
+/// SSH host and port of a remote; `None` for HTTPS remotes and local paths
+pub fn ssh_host(repo_url: &str) -> Option<(String, Option<u16>)> {
+    if let Some(rest) = repo_url.strip_prefix("ssh://") {
+        let url = url::Url::parse(&format!("ssh://{}", rest)).ok()?;
+        return Some((url.host_str()?.to_string(), url.port()));
+    }
+    if repo_url.contains("://") {
+        return None;
+    }
+    // scp-like syntax: [user@]host:path
+    let (authority, _) = repo_url.split_once(':')?;
+    let host = authority.rsplit('@').next()?;
+    if host.is_empty() || host.contains('/') {
+        return None;
+    }
+    Some((host.to_string(), None))
+}
+
+/// How known_hosts names a host: `host`, or `[host]:port` off port 22
+pub fn known_hosts_name(host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) if port != 22 => format!("[{}]:{}", host, port),
+        _ => host.to_string(),
+    }
+}
+
+/// Keys recorded for `name` in known_hosts `contents`, as (type, base64 key)
+///
+/// Hashed entries can't be matched against a name and are ignored, as are
+/// `@cert-authority` and `@revoked` lines.
+pub fn known_keys(contents: &str, name: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('@'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let hosts = fields.next()?;
+            let key_type = fields.next()?;
+            let key = fields.next()?;
+            hosts
+                .split(',')
+                .any(|host| host == name)
+                .then(|| (key_type.to_string(), key.to_string()))
+        })
+        .collect()
+}
+
+/// Status of `key` against the keys known_hosts has for its host
+pub fn key_status(contents: &str, key: &HostKey) -> HostKeyStatus {
+    let known = known_keys(contents, &key.name);
+    if known.is_empty() {
+        HostKeyStatus::Missing
+    } else if known
+        .iter()
+        .any(|(key_type, encoded)| *key_type == key.key_type && *encoded == key.encoded())
+    {
+        HostKeyStatus::Known
+    } else {
+        HostKeyStatus::Changed
+    }
+}
+
+fn append_key(path: &Path, key: &HostKey) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", key.known_hosts_line())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+async fn provision_host(
+    config: &GitAuthConfig,
+    host: String,
+    port: Option<u16>,
+) -> Result<HostReport> {
+    let name = known_hosts_name(&host, port);
+    let scanned =
+        tokio::task::spawn_blocking(move || GitRepo::scan_host_key(&host, port)).await?;
+    let key = match scanned {
+        Ok(key) => key,
+        Err(e) => {
+            return Ok(HostReport {
+                host: name,
+                status: HostKeyStatus::Unreachable,
+                key_type: None,
+                error: Some(format!("{:#}", e)),
+            })
+        }
+    };
+
+    let contents = match std::fs::read_to_string(&config.known_hosts) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to read {}", config.known_hosts.display()))
+        }
+    };
+    let mut status = key_status(&contents, &key);
+    match status {
+        HostKeyStatus::Missing if !config.strict => {
+            append_key(&config.known_hosts, &key)?;
+            info!("Added {} key of {} to {}", key.key_type, name, config.known_hosts.display());
+            status = HostKeyStatus::Added;
+        }
+        HostKeyStatus::Changed => {
+            warn!("{} presents a {} key known_hosts doesn't list", name, key.key_type);
+        }
+        _ => {}
+    }
+
+    Ok(HostReport {
+        host: name,
+        status,
+        key_type: Some(key.key_type),
+        error: None,
+    })
+}
+
+/// Put the SSH host keys of the configured hosts in known_hosts, then list
+/// every remote with its credential
+///
+/// A host whose key differs from the recorded one is never updated: that
+/// is either a rotated key to confirm by hand or someone in the middle.
+pub async fn setup(config: &GitAuthConfig) -> Result<GitAuthReport> {
+    let mut hosts: Vec<(String, Option<u16>)> = Vec::new();
+    for host in &config.hosts {
+        let (name, port) = match host.rsplit_once(':') {
+            Some((name, port)) => (
+                name,
+                Some(port.parse().with_context(|| format!("Invalid port in {}", host))?),
+            ),
+            None => (host.as_str(), None),
+        };
+        hosts.push((name.to_string(), port));
+    }
+    hosts.extend(config.remotes.iter().filter_map(|remote| ssh_host(remote)));
+    hosts.sort();
+    hosts.dedup();
+
+    let mut report = GitAuthReport::default();
+    for (host, port) in hosts {
+        report.hosts.push(provision_host(config, host, port).await?);
+    }
+
+    for remote in &config.remotes {
+        let listed = match config.credentials.resolve(remote).await {
+            Ok(credential) => {
+                let url = remote.clone();
+                tokio::task::spawn_blocking(move || {
+                    GitRepo::check_remote_access_with(&url, &credential)
+                })
+                .await?
+            }
+            Err(e) => Err(e),
+        };
+        report.remotes.push(match listed {
+            Ok(refs) => RemoteReport {
+                remote: remote.clone(),
+                refs: Some(refs),
+                error: None,
+            },
+            Err(e) => RemoteReport {
+                remote: remote.clone(),
+                refs: None,
+                error: Some(format!("{:#}", e)),
+            },
+        });
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+    #[test]
+    fn test_known_hosts() {
+        assert_eq!(
+            ssh_host("git@github.com:org/repo.git"),
+            Some(("github.com".to_string(), None))
+        );
+        assert_eq!(
+            ssh_host("ssh://git@git.internal:2222/org/repo.git"),
+            Some(("git.internal".to_string(), Some(2222)))
+        );
+        assert_eq!(ssh_host("https://github.com/org/repo.git"), None);
+        assert_eq!(known_hosts_name("git.internal", Some(2222)), "[git.internal]:2222");
+        assert_eq!(known_hosts_name("github.com", Some(22)), "github.com");
+
+        let contents = "\
+# comment
+github.com,140.82.112.3 ssh-ed25519 AAAAC3Nz
+|1|hashed= ssh-rsa AAAAB3N0
+@cert-authority *.internal ssh-ed25519 AAAAC3N0
+[git.internal]:2222 ssh-rsa AAAAB3Nz
+";
+        let key = |name: &str, key_type: &str, encoded: &str| HostKey {
+            name: name.to_string(),
+            key_type: key_type.to_string(),
+            key: BASE64.decode(encoded).unwrap(),
+        };
+        assert_eq!(
+            known_keys(contents, "github.com"),
+            vec![("ssh-ed25519".to_string(), "AAAAC3Nz".to_string())]
+        );
+        assert_eq!(
+            key_status(contents, &key("github.com", "ssh-ed25519", "AAAAC3Nz")),
+            HostKeyStatus::Known
+        );
+        assert_eq!(
+            key_status(contents, &key("github.com", "ssh-ed25519", "AAAAC3N0")),
+            HostKeyStatus::Changed
+        );
+        assert_eq!(
+            key_status(contents, &key("[git.internal]:2222", "ssh-rsa", "AAAAB3Nz")),
+            HostKeyStatus::Known
+        );
+        assert_eq!(
+            key_status(contents, &key("gitlab.com", "ssh-ed25519", "AAAAC3Nz")),
+            HostKeyStatus::Missing
+        );
+
+        let report = GitAuthReport {
+            hosts: vec![HostReport {
+                host: "github.com".to_string(),
+                status: HostKeyStatus::Missing,
+                key_type: None,
+                error: None,
+            }],
+            remotes: Vec::new(),
+        };
+        assert!(report.ensure_ok().is_err());
+        assert!(GitAuthReport::default().ensure_ok().is_ok());
+    }
+}
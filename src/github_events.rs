@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::crypto;
+use crate::queue::Job;
+use crate::webhook;
+
+/// Comment prefix asking for a job, followed by the prompt
+pub const COMMAND: &str = "/agent do ";
+
+/// Authors of comments allowed to start jobs: anyone can comment on a
+/// public repository
+const TRUSTED_ASSOCIATIONS: [&str; 3] = ["OWNER", "MEMBER", "COLLABORATOR"];
+
+/// How GitHub webhook deliveries become jobs
+#[derive(Debug, Clone)]
+pub struct GitHubWebhookConfig {
+    /// Secret GitHub signs deliveries with
+    pub secret: String,
+    /// Label whose addition to an issue enqueues a job working on it
+    pub label: String,
+    /// Clone repositories over HTTPS rather than SSH, e.g. for GitHub App
+    /// credentials
+    pub clone_https: bool,
+}
+
+/// What a GitHub event asks of the worker
+#[derive(Debug)]
+pub enum EventAction {
+    Enqueue(Box<Job>),
+    /// The event doesn't ask for a job, for this reason
+    Ignore(&'static str),
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    full_name: String,
+    clone_url: String,
+    ssh_url: String,
+    default_branch: String,
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    number: u64,
+    title: String,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Comment {
+    body: String,
+    author_association: String,
+}
+
+#[derive(Deserialize)]
+struct Label {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct IssueCommentEvent {
+    action: String,
+    issue: Issue,
+    comment: Comment,
+    repository: Repository,
+}
+
+#[derive(Deserialize)]
+struct IssuesEvent {
+    action: String,
+    issue: Issue,
+    #[serde(default)]
+    label: Option<Label>,
+    repository: Repository,
+}
+
+/// Whether `header`, the `X-Hub-Signature-256` of a delivery, is the
+/// signature of `body` under `secret`
+pub fn verify_signature(secret: &str, body: &[u8], header: Option<&str>) -> bool {
+    let Some(header) = header else {
+        return false;
+    };
+    crypto::constant_time_eq(&webhook::signature(secret, body), header)
+}
+
+/// The job asked for by the `event` delivery `delivery_id`: an `issue_comment`
+/// starting with `/agent do `, or an `issues` event adding the configured
+/// label
+///
+/// The job ID is derived from the delivery, so a redelivery is recognized
+/// as a duplicate. The agent works on a branch of its own for the issue,
+/// starting from the repository's default branch.
+pub fn parse_event(
+    config: &GitHubWebhookConfig,
+    event: &str,
+    delivery_id: &str,
+    body: &[u8],
+) -> Result<EventAction> {
+    let (issue, repository, prompt) = match event {
+        "issue_comment" => {
+            let event: IssueCommentEvent =
+                serde_json::from_slice(body).context("Invalid issue_comment event")?;
+            if event.action != "created" {
+                return Ok(EventAction::Ignore("Only new comments start jobs"));
+            }
+            let Some(request) = event.comment.body.trim().strip_prefix(COMMAND) else {
+                return Ok(EventAction::Ignore("The comment isn't an /agent do command"));
+            };
+            if !TRUSTED_ASSOCIATIONS.contains(&event.comment.author_association.as_str()) {
+                return Ok(EventAction::Ignore(
+                    "Only owners, members and collaborators can start jobs",
+                ));
+            }
+            let prompt = format!(
+                "{}\n\nAsked in a comment on #{} of {}, \"{}\":\n\n{}",
+                request.trim(),
+                event.issue.number,
+                event.repository.full_name,
+                event.issue.title,
+                event.issue.body.as_deref().unwrap_or_default()
+            );
+            (event.issue, event.repository, prompt)
+        }
+        "issues" => {
+            let event: IssuesEvent =
+                serde_json::from_slice(body).context("Invalid issues event")?;
+            if event.action != "labeled"
+                || event.label.is_none_or(|label| label.name != config.label)
+            {
+                return Ok(EventAction::Ignore("Only adding the agent label starts jobs"));
+            }
+            let prompt = format!(
+                "{}\n\n{}",
+                event.issue.title,
+                event.issue.body.as_deref().unwrap_or_default()
+            );
+            (event.issue, event.repository, prompt)
+        }
+        "ping" => return Ok(EventAction::Ignore("Pong")),
+        _ => return Ok(EventAction::Ignore("Unsupported event")),
+    };
+
+    let job = Job {
+        id: format!("github-{}", delivery_id),
+        repo_url: if config.clone_https {
+            repository.clone_url
+        } else {
+            repository.ssh_url
+        },
+        base_branch: repository.default_branch,
+        target_branch: Some(format!("agent/issue-{}", issue.number)),
+        prompt: prompt.trim_end().to_string(),
+        labels: BTreeMap::from([
+            ("source".to_string(), "github".to_string()),
+            ("github_issue".to_string(), issue.number.to_string()),
+        ]),
+        ..Default::default()
+    };
+    job.validate()?;
+    Ok(EventAction::Enqueue(Box::new(job)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event() {
+        let config = GitHubWebhookConfig {
+            secret: "secret".to_string(),
+            label: "agent".to_string(),
+            clone_https: false,
+        };
+        let repository = r#"{"full_name": "org/app", "clone_url": "https://github.com/org/app.git",
+            "ssh_url": "git@github.com:org/app.git", "default_branch": "main"}"#;
+        let comment = |body: &str, association: &str| {
+            format!(
+                r#"{{"action": "created", "issue": {{"number": 12, "title": "Build fails",
+                    "body": "On main"}}, "comment": {{"body": "{}", "author_association": "{}"}},
+                    "repository": {}}}"#,
+                body, association, repository
+            )
+        };
+
+        let body = comment("/agent do Fix the build", "MEMBER");
+        let EventAction::Enqueue(job) =
+            parse_event(&config, "issue_comment", "d-1", body.as_bytes()).unwrap()
+        else {
+            panic!("comment didn't start a job");
+        };
+        assert_eq!(job.id, "github-d-1");
+        assert_eq!(job.repo_url, "git@github.com:org/app.git");
+        assert_eq!(job.base_branch, "main");
+        assert_eq!(job.target_branch.as_deref(), Some("agent/issue-12"));
+        assert_eq!(
+            job.prompt,
+            "Fix the build\n\nAsked in a comment on #12 of org/app, \"Build fails\":\n\nOn main"
+        );
+
+        let outsider = comment("/agent do Fix the build", "NONE");
+        let chatter = comment("Thanks!", "OWNER");
+        for body in [outsider, chatter] {
+            assert!(matches!(
+                parse_event(&config, "issue_comment", "d-2", body.as_bytes()).unwrap(),
+                EventAction::Ignore(_)
+            ));
+        }
+
+        let labeled = |label: &str| {
+            format!(
+                r#"{{"action": "labeled", "issue": {{"number": 7, "title": "Add a README",
+                    "body": null}}, "label": {{"name": "{}"}}, "repository": {}}}"#,
+                label, repository
+            )
+        };
+        let config = GitHubWebhookConfig {
+            clone_https: true,
+            ..config
+        };
+        let EventAction::Enqueue(job) =
+            parse_event(&config, "issues", "d-3", labeled("agent").as_bytes()).unwrap()
+        else {
+            panic!("label didn't start a job");
+        };
+        assert_eq!(job.repo_url, "https://github.com/org/app.git");
+        assert_eq!(job.prompt, "Add a README");
+        assert!(matches!(
+            parse_event(&config, "issues", "d-4", labeled("bug").as_bytes()).unwrap(),
+            EventAction::Ignore(_)
+        ));
+
+        assert!(parse_event(&config, "issues", "d-5", b"{}").is_err());
+        assert!(matches!(
+            parse_event(&config, "push", "d-6", b"{}").unwrap(),
+            EventAction::Ignore("Unsupported event")
+        ));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let body = br#"{"action": "created"}"#;
+        let signature = webhook::signature("secret", body);
+        assert!(verify_signature("secret", body, Some(&signature)));
+        assert!(!verify_signature("other", body, Some(&signature)));
+        assert!(!verify_signature("secret", b"{}", Some(&signature)));
+        assert!(!verify_signature("secret", body, None));
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::service::interceptor::{InterceptedService, Interceptor};
+use tonic::{Request, Response, Status};
+use tracing::{error, info};
+
+use crate::crypto::{self, PayloadCipher};
+use crate::joblog::JobLogStream;
+use crate::queue::{
+    self, CancelOutcome, ContextSource, JobState, PayloadTooLarge, RateLimited, ReliableQueue,
+};
+
+/// Types and service stubs generated from `proto/jobs.proto`
+pub mod proto {
+    tonic::include_proto!("agentworker.v1");
+}
+
+use proto::job_service_server::{JobService, JobServiceServer};
+
+/// gRPC implementation of the job management API
+pub struct JobServiceImpl {
+    redis_url: String,
+    queue_name: String,
+    queue: ReliableQueue,
+    logs: JobLogStream,
+}
+
+impl JobServiceImpl {
+    pub async fn new(redis_url: &str, queue_name: &str) -> Result<Self> {
+        Ok(Self {
+            redis_url: redis_url.to_string(),
+            queue_name: queue_name.to_string(),
+            queue: ReliableQueue::new(redis_url, queue_name, 5).await?,
+            logs: JobLogStream::new(redis_url, queue_name).await?,
+        })
+    }
+
+    /// Encrypt job payloads with this cipher
+    pub fn with_cipher(mut self, cipher: Option<PayloadCipher>) -> Self {
+        self.queue = self.queue.with_cipher(cipher);
+        self
+    }
+}
+
+/// Rejects calls that don't carry `authorization: Bearer <api_token>`
+#[derive(Clone)]
+pub struct TokenInterceptor {
+    expected: String,
+}
+
+impl TokenInterceptor {
+    pub fn new(api_token: &str) -> Self {
+        Self {
+            expected: format!("Bearer {}", api_token),
+        }
+    }
+}
+
+impl Interceptor for TokenInterceptor {
+    fn call(&mut self, request: Request<()>) -> std::result::Result<Request<()>, Status> {
+        let authorized = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| crypto::constant_time_eq(value, &self.expected));
+
+        if authorized {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("Missing or invalid API token"))
+        }
+    }
+}
+
+/// Wrap the service with token authentication
+pub fn service(
+    job_service: JobServiceImpl,
+    api_token: &str,
+) -> InterceptedService<JobServiceServer<JobServiceImpl>, TokenInterceptor> {
+    JobServiceServer::with_interceptor(job_service, TokenInterceptor::new(api_token))
+}
+
+/// Serve the gRPC API on `addr` until `shutdown` resolves
+pub async fn serve(
+    addr: SocketAddr,
+    job_service: JobServiceImpl,
+    api_token: &str,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<()> {
+    info!("gRPC server listening on {}", addr);
+
+    tonic::transport::Server::builder()
+        .add_service(service(job_service, api_token))
+        .serve_with_shutdown(addr, shutdown)
+        .await
+        .context("gRPC server failed")?;
+
+    info!("gRPC server stopped");
+    Ok(())
+}
+
+fn internal(e: anyhow::Error) -> Status {
+    if let Some(limited) = e.downcast_ref::<RateLimited>() {
+        return Status::resource_exhausted(limited.to_string());
+    }
+    if let Some(too_large) = e.downcast_ref::<PayloadTooLarge>() {
+        return Status::invalid_argument(too_large.to_string());
+    }
+    error!("gRPC request failed: {:#}", e);
+    Status::internal(format!("{:#}", e))
+}
+
+impl From<proto::Job> for queue::Job {
+    fn from(job: proto::Job) -> Self {
+        Self {
+            id: job.id,
+            repo_url: job.repo_url,
+            base_branch: job.branch,
+            target_branch: job.target_branch,
+            prompt: job.prompt,
+            tasks: job.tasks,
+            mcp_connection_url: job.mcp_connection_url,
+            context: job
+                .context
+                .into_iter()
+                // Files without a source are rejected before conversion
+                .filter_map(|file| {
+                    let source = match file.source? {
+                        proto::context_file::Source::RedisKey(key) => ContextSource::RedisKey(key),
+                        proto::context_file::Source::Url(url) => ContextSource::Url(url),
+                    };
+                    Some(queue::ContextFile {
+                        name: file.name,
+                        source,
+                    })
+                })
+                .collect(),
+            required_capabilities: job.required_capabilities,
+            labels: job.labels,
+            deadline: job.deadline,
+            concurrency_group: job.concurrency_group,
+            replay_of: None,
+            parent: None,
+            on_success: Vec::new(),
+            options: Default::default(),
+        }
+    }
+}
+
+impl From<JobState> for proto::JobState {
+    fn from(state: JobState) -> Self {
+        match state {
+            JobState::Pending => proto::JobState::Pending,
+            JobState::Running => proto::JobState::Running,
+            JobState::AwaitingApproval => proto::JobState::AwaitingApproval,
+            JobState::Succeeded => proto::JobState::Succeeded,
+            JobState::Failed => proto::JobState::Failed,
+            JobState::Cancelled => proto::JobState::Cancelled,
+        }
+    }
+}
+
+impl From<queue::JobStatus> for proto::JobStatus {
+    fn from(status: queue::JobStatus) -> Self {
+        Self {
+            job_id: status.job_id,
+            state: status
+                .state
+                .map_or(proto::JobState::Unspecified, proto::JobState::from)
+                .into(),
+            attempts: status.attempts,
+            enqueued_at: status.enqueued_at,
+            started_at: status.started_at,
+            finished_at: status.finished_at,
+            worker: status.worker,
+            last_error: status.last_error,
+            commit_sha: status.commit_sha,
+            cancel_requested: status.cancel_requested,
+            dead_reason: status.dead_reason.map(|reason| reason.as_str().to_string()),
+            failures: status
+                .failures
+                .into_iter()
+                .map(|failure| proto::AttemptFailure {
+                    attempt: failure.attempt,
+                    class: failure.class.as_str().to_string(),
+                    error: failure.error,
+                    failed_at: failure.failed_at,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl JobService for JobServiceImpl {
+    async fn enqueue(
+        &self,
+        request: Request<proto::EnqueueRequest>,
+    ) -> std::result::Result<Response<proto::EnqueueResponse>, Status> {
+        let job = request
+            .into_inner()
+            .job
+            .ok_or_else(|| Status::invalid_argument("Missing job"))?;
+        if job.context.iter().any(|file| file.source.is_none()) {
+            return Err(Status::invalid_argument("Context file without a source"));
+        }
+        let job: queue::Job = job.into();
+        job.validate()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        self.queue.clone().enqueue(&job).await.map_err(internal)?;
+        Ok(Response::new(proto::EnqueueResponse { job_id: job.id }))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<proto::GetStatusRequest>,
+    ) -> std::result::Result<Response<proto::JobStatus>, Status> {
+        let job_id = request.into_inner().job_id;
+
+        match self.queue.clone().get_status(&job_id).await.map_err(internal)? {
+            Some(status) => Ok(Response::new(status.into())),
+            None => Err(Status::not_found(format!(
+                "No status recorded for job {}",
+                job_id
+            ))),
+        }
+    }
+
+    async fn cancel(
+        &self,
+        request: Request<proto::CancelRequest>,
+    ) -> std::result::Result<Response<proto::CancelResponse>, Status> {
+        let job_id = request.into_inner().job_id;
+
+        let outcome = match self.queue.clone().cancel(&job_id).await.map_err(internal)? {
+            CancelOutcome::Removed => proto::CancelOutcome::Removed,
+            CancelOutcome::Flagged => proto::CancelOutcome::Flagged,
+            CancelOutcome::NotFound => {
+                return Err(Status::not_found(format!(
+                    "Job {} is neither pending nor in flight",
+                    job_id
+                )))
+            }
+        };
+
+        Ok(Response::new(proto::CancelResponse {
+            outcome: outcome.into(),
+        }))
+    }
+
+    type StreamLogsStream = ReceiverStream<std::result::Result<proto::LogEntry, Status>>;
+
+    async fn stream_logs(
+        &self,
+        request: Request<proto::StreamLogsRequest>,
+    ) -> std::result::Result<Response<Self::StreamLogsStream>, Status> {
+        let proto::StreamLogsRequest { job_id, follow } = request.into_inner();
+
+        // Blocking reads would stall every other call sharing the
+        // connection, so followers get one of their own
+        let logs = if follow {
+            JobLogStream::new(&self.redis_url, &self.queue_name)
+                .await
+                .map_err(internal)?
+        } else {
+            self.logs.clone()
+        };
+        let mut queue = self.queue.clone();
+
+        let (sender, receiver) = mpsc::channel(64);
+        tokio::spawn(async move {
+            let mut last_id = "0".to_string();
+            loop {
+                let block_ms = if follow { Some(5000) } else { None };
+                let entries = match logs.read_after(&job_id, &last_id, block_ms).await {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        let _ = sender.send(Err(internal(e))).await;
+                        return;
+                    }
+                };
+
+                if let Some(entry) = entries.last() {
+                    last_id = entry.id.clone();
+                }
+                let caught_up = entries.is_empty();
+                for entry in entries {
+                    let entry = proto::LogEntry {
+                        id: entry.id,
+                        timestamp: entry.timestamp,
+                        level: entry.level,
+                        message: entry.message,
+                    };
+                    if sender.send(Ok(entry)).await.is_err() {
+                        // Client went away
+                        return;
+                    }
+                }
+                if !caught_up {
+                    continue;
+                }
+
+                // Caught up: stop unless following a job that is still active
+                if !follow {
+                    return;
+                }
+                let finished = match queue.get_status(&job_id).await {
+                    Ok(status) => status
+                        .and_then(|status| status.state)
+                        .is_some_and(|state| state.is_terminal()),
+                    Err(e) => {
+                        let _ = sender.send(Err(internal(e))).await;
+                        return;
+                    }
+                };
+                if finished {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
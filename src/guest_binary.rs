@@ -5,6 +5,51 @@ pub const GUEST_BINARY: &[u8] = include_bytes!(concat!(
     "/guest/target/release/libagent_guest.so"
 ));
 
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Agent profile of `GUEST_BINARY`, run for jobs that don't pick a profile
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Further guests embedded at build time through `GUEST_PROFILES`
+include!(concat!(env!("OUT_DIR"), "/guest_profiles.rs"));
+
+/// Embedded guest binary of an agent profile, the default one when `None`
+pub fn guest_binary(profile: Option<&str>) -> Option<&'static [u8]> {
+    match profile {
+        None | Some(DEFAULT_PROFILE) => Some(GUEST_BINARY),
+        Some(profile) => EXTRA_PROFILES
+            .iter()
+            .find(|(name, _)| *name == profile)
+            .map(|(_, binary)| *binary),
+    }
+}
+
+/// Names of every embedded agent profile, the default one first
+pub fn profiles() -> Vec<&'static str> {
+    std::iter::once(DEFAULT_PROFILE)
+        .chain(EXTRA_PROFILES.iter().map(|(name, _)| *name))
+        .collect()
+}
+
+/// Hex SHA-256 of the embedded guest binary of an agent profile, the
+/// default one when `None`, so a job's behavior can be tied to the exact
+/// sandbox build that produced it
+pub fn sha256(profile: Option<&str>) -> Option<&'static str> {
+    static DIGESTS: OnceLock<BTreeMap<&'static str, String>> = OnceLock::new();
+    let digests = DIGESTS.get_or_init(|| {
+        profiles()
+            .into_iter()
+            .filter_map(|name| {
+                let digest = Sha256::digest(guest_binary(Some(name))?);
+                Some((name, digest.iter().map(|byte| format!("{:02x}", byte)).collect()))
+            })
+            .collect()
+    });
+    digests.get(profile.unwrap_or(DEFAULT_PROFILE)).map(String::as_str)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -13,4 +58,24 @@ mod tests {
     fn guest_binary_is_not_empty() {
         assert!(!GUEST_BINARY.is_empty(), "Guest binary should be embedded");
     }
+
+    #[test]
+    fn guest_binary_by_profile() {
+        assert_eq!(guest_binary(None), Some(GUEST_BINARY));
+        assert_eq!(guest_binary(Some(DEFAULT_PROFILE)), Some(GUEST_BINARY));
+        assert_eq!(guest_binary(Some("no-such-profile")), None);
+        assert_eq!(profiles()[0], DEFAULT_PROFILE);
+        for profile in profiles() {
+            assert!(guest_binary(Some(profile)).is_some_and(|binary| !binary.is_empty()));
+        }
+    }
+
+    #[test]
+    fn guest_binary_sha256() {
+        let digest = sha256(None).unwrap();
+        assert_eq!(digest.len(), 64);
+        assert!(digest.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_eq!(sha256(Some(DEFAULT_PROFILE)), Some(digest));
+        assert_eq!(sha256(Some("no-such-profile")), None);
+    }
 }
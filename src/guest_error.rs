@@ -0,0 +1,103 @@
+//! Error codes the guest reports its failures with, shared with the guest
+//! crate through `#[path]`, so it must only use `core`
+//!
+//! Hyperlight carries a guest's error back to the host as a message, so the
+//! guest prefixes its message with the code in brackets, as in
+//! `[agent:bad_prompt] The prompt is empty`, and the host finds the code in
+//! whatever error the call failed with.
+
+use core::fmt;
+
+const PREFIX: &str = "[agent:";
+
+/// Why the guest gave up on a job, telling the worker whether another
+/// attempt could succeed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestErrorCode {
+    /// A tool or the MCP server the agent needs couldn't be reached
+    ToolUnavailable,
+    /// The agent ran out of the steps, tokens or calls it may spend
+    BudgetExceeded,
+    /// The prompt can't be worked on as given
+    BadPrompt,
+}
+
+impl GuestErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GuestErrorCode::ToolUnavailable => "tool_unavailable",
+            GuestErrorCode::BudgetExceeded => "budget_exceeded",
+            GuestErrorCode::BadPrompt => "bad_prompt",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "tool_unavailable" => Some(GuestErrorCode::ToolUnavailable),
+            "budget_exceeded" => Some(GuestErrorCode::BudgetExceeded),
+            "bad_prompt" => Some(GuestErrorCode::BadPrompt),
+            _ => None,
+        }
+    }
+
+    /// Whether another attempt at the job could go differently: tools come
+    /// back, but the same prompt under the same budget fails the same way
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, GuestErrorCode::ToolUnavailable)
+    }
+
+    /// Find the code of a message written by [`GuestError`], and the rest
+    /// of the message after it
+    pub fn find(message: &str) -> Option<(Self, &str)> {
+        let start = message.find(PREFIX)? + PREFIX.len();
+        let (code, rest) = message[start..].split_once(']')?;
+        Some((Self::parse(code)?, rest.trim_start()))
+    }
+}
+
+/// A failure of the guest with its code, displayed as the message the host
+/// finds the code in
+#[derive(Debug, Clone, Copy)]
+pub struct GuestError<'a> {
+    pub code: GuestErrorCode,
+    pub message: &'a str,
+}
+
+impl fmt::Display for GuestError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}] {}", PREFIX, self.code.as_str(), self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_error_code() {
+        let message = GuestError {
+            code: GuestErrorCode::BadPrompt,
+            message: "The prompt is empty",
+        }
+        .to_string();
+        assert_eq!(message, "[agent:bad_prompt] The prompt is empty");
+        // Hyperlight wraps the guest's message in its own
+        let wrapped = format!("Guest error occurred GuestError: {}", message);
+        assert_eq!(
+            GuestErrorCode::find(&wrapped),
+            Some((GuestErrorCode::BadPrompt, "The prompt is empty"))
+        );
+        assert_eq!(GuestErrorCode::find("Guest aborted: out of memory"), None);
+        assert_eq!(GuestErrorCode::find("[agent:unknown] Something"), None);
+
+        assert!(GuestErrorCode::ToolUnavailable.is_retryable());
+        assert!(!GuestErrorCode::BudgetExceeded.is_retryable());
+        for code in [
+            GuestErrorCode::ToolUnavailable,
+            GuestErrorCode::BudgetExceeded,
+            GuestErrorCode::BadPrompt,
+        ] {
+            assert_eq!(GuestErrorCode::parse(code.as_str()), Some(code));
+        }
+    }
+}
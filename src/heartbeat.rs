@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::queue::now_secs;
+
+/// How often a running worker refreshes its registration
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Registrations without a heartbeat for this long are reported as stale
+pub const STALE_AFTER_SECS: u64 = 3 * HEARTBEAT_INTERVAL.as_secs();
+
+/// A worker's registration, refreshed on every heartbeat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerInfo {
+    pub worker_id: String,
+    pub hostname: String,
+    pub started_at: u64,
+    pub last_heartbeat: u64,
+    /// Job the worker was running at its last heartbeat
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_job: Option<String>,
+    /// Number of jobs the worker runs at a time
+    pub concurrency: usize,
+}
+
+impl WorkerInfo {
+    /// Whether the worker has missed enough heartbeats to be presumed dead
+    pub fn is_stale(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_heartbeat) > STALE_AFTER_SECS
+    }
+}
+
+/// Redis-backed registry of the workers serving a queue
+#[derive(Clone)]
+pub struct WorkerRegistry {
+    connection: ConnectionManager,
+    key: String,
+}
+
+impl WorkerRegistry {
+    pub async fn new(redis_url: &str, queue_name: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url)
+            .context("Failed to create Redis client")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("Failed to connect to Redis")?;
+
+        Ok(Self {
+            connection,
+            key: format!("{}_workers", queue_name),
+        })
+    }
+
+    /// Record (or refresh) a worker's registration
+    pub async fn heartbeat(&self, info: &WorkerInfo) -> Result<()> {
+        let info_json = serde_json::to_string(info)
+            .context("Failed to serialize worker info")?;
+
+        self.connection
+            .clone()
+            .hset::<_, _, _, ()>(&self.key, &info.worker_id, &info_json)
+            .await
+            .context("Failed to record worker heartbeat")?;
+
+        debug!("Heartbeat from worker {}", info.worker_id);
+        Ok(())
+    }
+
+    /// Remove a worker's registration, e.g. on clean shutdown
+    pub async fn deregister(&self, worker_id: &str) -> Result<()> {
+        self.connection
+            .clone()
+            .hdel::<_, _, ()>(&self.key, worker_id)
+            .await
+            .context("Failed to deregister worker")?;
+
+        debug!("Deregistered worker {}", worker_id);
+        Ok(())
+    }
+
+    /// Every registered worker, stale or not, ordered by worker ID
+    pub async fn list(&self) -> Result<Vec<WorkerInfo>> {
+        let entries: HashMap<String, String> = self
+            .connection
+            .clone()
+            .hgetall(&self.key)
+            .await
+            .context("Failed to read worker registry")?;
+
+        let mut workers = Vec::new();
+        for (worker_id, info_json) in entries {
+            match serde_json::from_str::<WorkerInfo>(&info_json) {
+                Ok(info) => workers.push(info),
+                Err(e) => warn!("Ignoring malformed registration of {}: {}", worker_id, e),
+            }
+        }
+        workers.sort_by(|a, b| a.worker_id.cmp(&b.worker_id));
+
+        Ok(workers)
+    }
+}
+
+/// Background task keeping a worker's registration fresh
+pub struct Heartbeat {
+    registry: WorkerRegistry,
+    worker_id: String,
+    current_job: Arc<Mutex<Option<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl Heartbeat {
+    /// Register the worker and refresh its registration every `HEARTBEAT_INTERVAL`
+    pub fn spawn(registry: WorkerRegistry, info: WorkerInfo) -> Self {
+        let worker_id = info.worker_id.clone();
+        let current_job = Arc::new(Mutex::new(None));
+
+        let task = {
+            let registry = registry.clone();
+            let current_job = current_job.clone();
+            let mut info = info;
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    info.last_heartbeat = now_secs();
+                    info.current_job = current_job.lock().unwrap().clone();
+                    // Best-effort: a missed beat only makes the worker look stale
+                    if let Err(e) = registry.heartbeat(&info).await {
+                        warn!("Failed to send heartbeat: {:#}", e);
+                    }
+                }
+            })
+        };
+
+        Self {
+            registry,
+            worker_id,
+            current_job,
+            task,
+        }
+    }
+
+    /// Report the job the worker is running, reported with the next heartbeat
+    pub fn set_current_job(&self, job_id: Option<&str>) {
+        *self.current_job.lock().unwrap() = job_id.map(str::to_string);
+    }
+
+    /// Stop heartbeating and remove the registration
+    pub async fn stop(self) {
+        self.task.abort();
+        if let Err(e) = self.registry.deregister(&self.worker_id).await {
+            warn!("Failed to deregister worker {}: {:#}", self.worker_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_goes_stale_after_missed_heartbeats() {
+        let info = WorkerInfo {
+            worker_id: "worker-1".to_string(),
+            hostname: "host".to_string(),
+            started_at: 1_000,
+            last_heartbeat: 1_000,
+            current_job: None,
+            concurrency: 1,
+        };
+
+        assert!(!info.is_stale(1_000 + STALE_AFTER_SECS));
+        assert!(info.is_stale(1_001 + STALE_AFTER_SECS));
+        // Clock skew between hosts must not underflow
+        assert!(!info.is_stale(0));
+    }
+}
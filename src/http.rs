@@ -0,0 +1,245 @@
+use anyhow::{bail, Context, Result};
+use reqwest::redirect::Policy;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use url::{Host, Url};
+
+use crate::trace;
+
+/// Redirects `download_public` follows before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// User agent the worker's requests carry unless configured otherwise
+pub const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Connection pooling and timeouts of the HTTP client the worker talks to
+/// the allocator and MCP servers with
+///
+/// One client is shared by every request, so connections to a host are
+/// reused across jobs instead of each job opening its own; under high
+/// concurrency the pool bounds how many idle sockets are kept open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpClientConfig {
+    /// Idle connections kept open per host
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle connection is kept before it's closed
+    pub pool_idle_timeout: Duration,
+    /// Interval of TCP keep-alive probes on open connections; off when unset
+    pub tcp_keepalive: Option<Duration>,
+    pub connect_timeout: Duration,
+    /// How long a whole request may take; unlimited when unset, as MCP
+    /// tools can run for minutes
+    pub timeout: Option<Duration>,
+    pub user_agent: String,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            connect_timeout: Duration::from_secs(10),
+            timeout: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+}
+
+impl HttpClientConfig {
+    /// Build a client with these settings; clones of it share its pool
+    pub fn build(&self) -> Result<reqwest::Client> {
+        self.builder().build().context("Failed to create HTTP client")
+    }
+
+    fn builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive)
+            .connect_timeout(self.connect_timeout)
+            .user_agent(&self.user_agent);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder
+    }
+}
+
+/// Check that `url` is HTTPS and its host only resolves to public
+/// addresses, so a URL taken from a job can't reach the worker's own
+/// network, such as a cloud metadata endpoint
+/// Returns the addresses checked, so the request can be pinned to them
+pub async fn ensure_public_https(url: &Url) -> Result<Vec<SocketAddr>> {
+    if url.scheme() != "https" {
+        bail!("Only https URLs are allowed, not {}", url.scheme());
+    }
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses: Vec<SocketAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![(ip, port).into()],
+        Some(Host::Ipv6(ip)) => vec![(ip, port).into()],
+        Some(Host::Domain(domain)) => tokio::net::lookup_host((domain, port))
+            .await
+            .with_context(|| format!("Failed to resolve {}", domain))?
+            .collect(),
+        None => bail!("URL has no host"),
+    };
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        bail!(
+            "{} resolves to non-public address {}",
+            url.host_str().unwrap_or_default(),
+            address.ip()
+        );
+    }
+    Ok(addresses)
+}
+
+/// Download `url`, which someone outside the worker chose, such as a job's
+/// context file
+///
+/// Every hop, redirects included, must pass `ensure_public_https`, and is
+/// connected to at the addresses that were checked, so a DNS answer that
+/// changes in between can't point it elsewhere. The body is read until
+/// `max_bytes`, and everything must finish within `timeout`.
+pub async fn download_public(
+    config: &HttpClientConfig,
+    url: &str,
+    timeout: Duration,
+    max_bytes: usize,
+) -> Result<Vec<u8>> {
+    let mut url = Url::parse(url).context("Invalid URL")?;
+    let download = async {
+        for _ in 0..=MAX_REDIRECTS {
+            let addresses = ensure_public_https(&url).await?;
+            let mut builder = config.builder().redirect(Policy::none()).no_proxy();
+            if let Some(Host::Domain(domain)) = url.host() {
+                builder = builder.resolve_to_addrs(domain, &addresses);
+            }
+            let client = builder.build().context("Failed to create HTTP client")?;
+            let mut response = trace::propagate(client.get(url.clone()))
+                .send()
+                .await?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .context("Redirect has no location")?
+                    .to_str()
+                    .context("Redirect location is not valid")?;
+                url = url.join(location).context("Invalid redirect location")?;
+                continue;
+            }
+            response = response.error_for_status()?;
+
+            if let Some(length) = response.content_length() {
+                if length > max_bytes as u64 {
+                    bail!("Response is {} bytes, over the limit of {} bytes", length, max_bytes);
+                }
+            }
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                if body.len() + chunk.len() > max_bytes {
+                    bail!("Response is over the limit of {} bytes", max_bytes);
+                }
+                body.extend_from_slice(&chunk);
+            }
+            return Ok(body);
+        }
+        bail!("More than {} redirects", MAX_REDIRECTS)
+    };
+    tokio::time::timeout(timeout, download)
+        .await
+        .map_err(|_| anyhow::anyhow!("Download took longer than {}s", timeout.as_secs()))?
+}
+
+/// Whether `ip` is routable on the internet, rather than loopback,
+/// private, link-local, or otherwise reserved
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space (100.64.0.0/10) and "this network"
+                || (a == 100 && (b & 0xc0) == 64)
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local (fc00::/7) and link-local (fe80::/10)
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_client() {
+        let config = HttpClientConfig::default();
+        assert!(config.user_agent.starts_with("redis-agent-worker/"));
+        config.build().unwrap();
+
+        let config = HttpClientConfig {
+            pool_max_idle_per_host: 0,
+            tcp_keepalive: None,
+            timeout: Some(Duration::from_secs(30)),
+            user_agent: "worker-7".to_string(),
+            ..config
+        };
+        config.build().unwrap();
+
+        let config = HttpClientConfig {
+            user_agent: "bad\nagent".to_string(),
+            ..config
+        };
+        assert!(config.build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_public_https() {
+        for url in [
+            "http://93.184.215.14/design.md",
+            "https://169.254.169.254/latest/meta-data/",
+            "https://127.0.0.1/",
+            "https://10.1.2.3/",
+            "https://100.64.0.1/",
+            "https://[::1]/",
+            "https://[fd00::1]/",
+            "https://[::ffff:192.168.1.1]/",
+            "https://localhost/",
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert!(ensure_public_https(&url).await.is_err(), "{} should be refused", url);
+        }
+
+        let url = Url::parse("https://93.184.215.14/design.md").unwrap();
+        assert_eq!(
+            ensure_public_https(&url).await.unwrap(),
+            vec!["93.184.215.14:443".parse().unwrap()]
+        );
+        assert!(is_public("2606:4700::1111".parse().unwrap()));
+
+        let config = HttpClientConfig::default();
+        let error = download_public(&config, "https://127.0.0.1:8443/", Duration::from_secs(5), 1)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("non-public"), "{:#}", error);
+    }
+}
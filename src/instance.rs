@@ -1,12 +1,84 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::error::ErrorClass;
+use crate::ledger::InstanceLedger;
+use crate::trace;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instance {
     pub id: String,
     pub mcp_connection_url: String,
     pub api_url: String,
+    /// Capabilities advertised by the allocator for this instance
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub capabilities: BTreeMap<String, String>,
+}
+
+impl Instance {
+    /// List the required capabilities this instance does not satisfy
+    pub fn missing_capabilities(&self, required: &BTreeMap<String, String>) -> Vec<String> {
+        required
+            .iter()
+            .filter(|(key, value)| self.capabilities.get(*key) != Some(*value))
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BorrowRequest<'a> {
+    capabilities: &'a BTreeMap<String, String>,
+    /// Instances the worker won't run jobs on, which the allocator should
+    /// hand out only if it has no others
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    exclude: &'a [String],
+}
+
+/// The allocator kept handing out instances the worker has quarantined
+///
+/// That's no fault of the job, so the worker puts the job back without
+/// counting the attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnlyQuarantinedInstances {
+    pub attempts: usize,
+}
+
+impl std::fmt::Display for OnlyQuarantinedInstances {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Allocator handed out only quarantined instances in {} attempts",
+            self.attempts
+        )
+    }
+}
+
+impl std::error::Error for OnlyQuarantinedInstances {}
+
+/// Outcome of the job an instance was used for, reported on return so the
+/// allocator can quarantine instances that keep producing failures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobOutcome {
+    pub job_id: String,
+    pub success: bool,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_class: Option<ErrorClass>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReturnRequest<'a> {
+    #[serde(flatten)]
+    instance: &'a Instance,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<&'a JobOutcome>,
 }
 
 #[derive(Clone)]
@@ -23,14 +95,63 @@ impl InstanceAllocator {
         }
     }
 
+    /// Talk to the allocator with `client`, sharing its connection pool
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
     /// Borrow an instance from the allocator
-    pub async fn borrow_instance(&self) -> Result<Instance> {
+    /// The required capabilities are sent to the allocator and checked against
+    /// the instance it hands back; a mismatching instance is returned immediately
+    pub async fn borrow_instance(
+        &self,
+        required_capabilities: &BTreeMap<String, String>,
+    ) -> Result<Instance> {
+        self.borrow_excluding(required_capabilities, &[]).await
+    }
+
+    /// Borrow an instance that isn't in `quarantine`
+    ///
+    /// The allocator is told which instances to leave out, and any it hands
+    /// out anyway are returned straight away. After `attempts` of those this
+    /// fails with `OnlyQuarantinedInstances`.
+    pub async fn borrow_outside(
+        &self,
+        required_capabilities: &BTreeMap<String, String>,
+        quarantine: &Mutex<InstanceQuarantine>,
+        attempts: usize,
+    ) -> Result<Instance> {
+        for _ in 0..attempts {
+            let exclude = quarantine.lock().unwrap().quarantined(Instant::now());
+            let instance = self.borrow_excluding(required_capabilities, &exclude).await?;
+            if !quarantine.lock().unwrap().is_quarantined(&instance.id, Instant::now()) {
+                return Ok(instance);
+            }
+            info!("Instance {} is quarantined, returning it", instance.id);
+            if let Err(e) = self.return_instance(&instance, None).await {
+                warn!("Failed to return quarantined instance {}: {:#}", instance.id, e);
+            }
+        }
+        Err(OnlyQuarantinedInstances { attempts }.into())
+    }
+
+    async fn borrow_excluding(
+        &self,
+        required_capabilities: &BTreeMap<String, String>,
+        exclude: &[String],
+    ) -> Result<Instance> {
         info!("Requesting instance from allocator");
+        if !required_capabilities.is_empty() {
+            debug!("Required capabilities: {:?}", required_capabilities);
+        }
 
         let url = format!("{}/borrow", self.allocator_api_url);
-        let response = self
-            .client
-            .post(&url)
+        let response = trace::propagate(self.client.post(&url))
+            .json(&BorrowRequest {
+                capabilities: required_capabilities,
+                exclude,
+            })
             .send()
             .await
             .context("Failed to send borrow request")?;
@@ -49,18 +170,38 @@ impl InstanceAllocator {
         info!("Successfully borrowed instance: {}", instance.id);
         debug!("Instance details: {:?}", instance);
 
+        let missing = instance.missing_capabilities(required_capabilities);
+        if !missing.is_empty() {
+            warn!(
+                "Instance {} does not satisfy required capabilities: {}",
+                instance.id,
+                missing.join(", ")
+            );
+            if let Err(e) = self.return_instance(&instance, None).await {
+                warn!("Failed to return unsuitable instance {}: {:#}", instance.id, e);
+            }
+            anyhow::bail!(
+                "Allocator returned instance {} missing required capabilities: {}",
+                instance.id,
+                missing.join(", ")
+            );
+        }
+
         Ok(instance)
     }
 
-    /// Return an instance to the allocator
-    pub async fn return_instance(&self, instance: &Instance) -> Result<()> {
+    /// Return an instance to the allocator, optionally reporting the outcome
+    /// of the job it was used for
+    pub async fn return_instance(
+        &self,
+        instance: &Instance,
+        outcome: Option<&JobOutcome>,
+    ) -> Result<()> {
         info!("Returning instance: {}", instance.id);
 
         let url = format!("{}/return", self.allocator_api_url);
-        let response = self
-            .client
-            .post(&url)
-            .json(instance)
+        let response = trace::propagate(self.client.post(&url))
+            .json(&ReturnRequest { instance, outcome })
             .send()
             .await
             .context("Failed to send return request")?;
@@ -77,50 +218,235 @@ impl InstanceAllocator {
     }
 }
 
-/// RAII guard for automatic instance return
-pub struct InstanceGuard {
-    instance: Option<Instance>,
+/// Instances that failed several jobs in a row, which this worker won't run
+/// jobs on for a cooldown
+///
+/// Kept in memory by each worker, so one bad instance can't fail job after
+/// job while the allocator keeps handing it out. Only failures the instance
+/// can be blamed for count; any success starts the count over.
+#[derive(Debug)]
+pub struct InstanceQuarantine {
+    /// Failures in a row that quarantine an instance
+    threshold: u32,
+    cooldown: Duration,
+    failures: HashMap<String, u32>,
+    /// Quarantined instances and when they may be used again
+    until: HashMap<String, Instant>,
+}
+
+impl InstanceQuarantine {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            failures: HashMap::new(),
+            until: HashMap::new(),
+        }
+    }
+
+    /// Record whether a job on `instance_id` failed because of the
+    /// instance, returning whether that put the instance in quarantine
+    pub fn record(&mut self, instance_id: &str, failed: bool, now: Instant) -> bool {
+        if !failed {
+            self.failures.remove(instance_id);
+            return false;
+        }
+        let failures = self.failures.entry(instance_id.to_string()).or_default();
+        *failures += 1;
+        if *failures < self.threshold {
+            return false;
+        }
+        self.failures.remove(instance_id);
+        self.until.insert(instance_id.to_string(), now + self.cooldown);
+        true
+    }
+
+    /// Whether `instance_id` is in quarantine at `now`
+    pub fn is_quarantined(&mut self, instance_id: &str, now: Instant) -> bool {
+        self.until.retain(|_, until| *until > now);
+        self.until.contains_key(instance_id)
+    }
+
+    /// Every instance in quarantine at `now`, sorted
+    pub fn quarantined(&mut self, now: Instant) -> Vec<String> {
+        self.until.retain(|_, until| *until > now);
+        let mut ids: Vec<String> = self.until.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+}
+
+enum ReturnCommand {
+    Return(Instance),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Background task owned by the worker that returns instances released by
+/// dropped guards, so `Drop` never has to block or build its own runtime
+pub struct InstanceReturner {
     allocator: InstanceAllocator,
+    ledger: Option<InstanceLedger>,
+    sender: mpsc::UnboundedSender<ReturnCommand>,
+    handle: JoinHandle<()>,
 }
 
-impl InstanceGuard {
-    pub fn new(instance: Instance, allocator: InstanceAllocator) -> Self {
+impl InstanceReturner {
+    /// Spawn the cleanup task on the current runtime
+    /// When a ledger is given, returned instances are removed from it
+    pub fn spawn(allocator: InstanceAllocator, ledger: Option<InstanceLedger>) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let task_allocator = allocator.clone();
+        let task_ledger = ledger.clone();
+
+        let handle = tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                match command {
+                    ReturnCommand::Return(instance) => {
+                        match task_allocator.return_instance(&instance, None).await {
+                            Ok(()) => release_from_ledger(task_ledger.as_ref(), &instance).await,
+                            Err(e) => {
+                                error!("Failed to return dropped instance {}: {:#}", instance.id, e)
+                            }
+                        }
+                    }
+                    ReturnCommand::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+            debug!("Instance returner stopped");
+        });
+
         Self {
-            instance: Some(instance),
             allocator,
+            ledger,
+            sender,
+            handle,
+        }
+    }
+
+    /// Wrap a borrowed instance in a guard that returns it through this task
+    pub fn guard(&self, instance: Instance) -> InstanceGuard {
+        InstanceGuard {
+            instance: Some(instance),
+            allocator: self.allocator.clone(),
+            ledger: self.ledger.clone(),
+            returns: self.sender.clone(),
         }
     }
 
+    /// Wait until every instance released so far has been returned
+    pub async fn flush(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.sender.send(ReturnCommand::Flush(done_tx)).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// Flush pending returns and stop the cleanup task
+    pub async fn shutdown(self) {
+        self.flush().await;
+        drop(self.sender);
+        if let Err(e) = self.handle.await {
+            error!("Instance returner task failed: {}", e);
+        }
+    }
+}
+
+/// RAII guard for automatic instance return
+pub struct InstanceGuard {
+    instance: Option<Instance>,
+    allocator: InstanceAllocator,
+    ledger: Option<InstanceLedger>,
+    returns: mpsc::UnboundedSender<ReturnCommand>,
+}
+
+impl InstanceGuard {
     pub fn instance(&self) -> &Instance {
         self.instance.as_ref().unwrap()
     }
 
-    /// Manually return the instance
-    pub async fn return_instance(mut self) -> Result<()> {
+    /// Manually return the instance, reporting the job outcome if known
+    pub async fn return_instance(mut self, outcome: Option<&JobOutcome>) -> Result<()> {
         if let Some(instance) = self.instance.take() {
-            self.allocator.return_instance(&instance).await?;
+            self.allocator.return_instance(&instance, outcome).await?;
+            release_from_ledger(self.ledger.as_ref(), &instance).await;
         }
         Ok(())
     }
 }
 
+async fn release_from_ledger(ledger: Option<&InstanceLedger>, instance: &Instance) {
+    if let Some(ledger) = ledger {
+        if let Err(e) = ledger.release(&instance.id).await {
+            warn!("Failed to release instance {} from ledger: {:#}", instance.id, e);
+        }
+    }
+}
+
 impl Drop for InstanceGuard {
     fn drop(&mut self) {
-        if let Some(instance) = &self.instance {
-            // Try to return the instance even on panic
-            // We can't make this async in Drop, so we spawn a blocking task
-            let instance = instance.clone();
-            let allocator_url = self.allocator.allocator_api_url.clone();
-
-            std::thread::spawn(move || {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(async {
-                    let allocator = InstanceAllocator::new(allocator_url);
-                    if let Err(e) = allocator.return_instance(&instance).await {
-                        eprintln!("Failed to return instance in Drop: {}", e);
-                    }
-                });
-            });
+        if let Some(instance) = self.instance.take() {
+            // Hand the instance to the worker's cleanup task, which returns it
+            // asynchronously and is flushed on shutdown
+            let id = instance.id.clone();
+            if self.returns.send(ReturnCommand::Return(instance)).is_err() {
+                error!("Instance returner is gone, instance {} was not returned", id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance_with(capabilities: &[(&str, &str)]) -> Instance {
+        Instance {
+            id: "instance-1".to_string(),
+            mcp_connection_url: "http://mcp.example.com".to_string(),
+            api_url: "http://api.example.com".to_string(),
+            capabilities: capabilities
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
         }
     }
+
+    #[test]
+    fn test_missing_capabilities() {
+        let instance = instance_with(&[("gpu", "true"), ("region", "us-east-1")]);
+
+        let mut required = BTreeMap::new();
+        assert!(instance.missing_capabilities(&required).is_empty());
+
+        required.insert("gpu".to_string(), "true".to_string());
+        assert!(instance.missing_capabilities(&required).is_empty());
+
+        required.insert("region".to_string(), "eu-west-1".to_string());
+        required.insert("toolchain".to_string(), "rust".to_string());
+        assert_eq!(
+            instance.missing_capabilities(&required),
+            vec!["region=eu-west-1".to_string(), "toolchain=rust".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_instance_quarantine() {
+        let mut quarantine = InstanceQuarantine::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        // A success in between starts the count over
+        assert!(!quarantine.record("instance-1", true, now));
+        assert!(!quarantine.record("instance-1", false, now));
+        assert!(!quarantine.record("instance-1", true, now));
+        assert!(!quarantine.is_quarantined("instance-1", now));
+
+        assert!(quarantine.record("instance-1", true, now));
+        assert!(quarantine.is_quarantined("instance-1", now));
+        assert!(!quarantine.is_quarantined("instance-2", now));
+        assert_eq!(quarantine.quarantined(now), ["instance-1"]);
+        assert!(!quarantine.is_quarantined("instance-1", now + Duration::from_secs(61)));
+        assert!(quarantine.quarantined(now + Duration::from_secs(61)).is_empty());
+    }
 }
@@ -0,0 +1,215 @@
+use anyhow::{Context, Result};
+use redis::aio::ConnectionManager;
+use redis::streams::{StreamMaxlen, StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::queue::now_secs;
+
+/// Upper bound on the number of lines kept per job
+const MAX_LINES_PER_JOB: usize = 10_000;
+/// How long a job's log stream is kept after its last line
+const LOG_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// A single log line recorded for a job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub id: String,
+    pub timestamp: u64,
+    pub level: String,
+    pub message: String,
+}
+
+/// Per-job log lines stored in Redis streams (`{queue_name}_logs:{job_id}`)
+#[derive(Clone)]
+pub struct JobLogStream {
+    connection: ConnectionManager,
+    queue_name: String,
+}
+
+impl JobLogStream {
+    pub async fn new(redis_url: &str, queue_name: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url)
+            .context("Failed to create Redis client")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("Failed to connect to Redis")?;
+
+        Ok(Self {
+            connection,
+            queue_name: queue_name.to_string(),
+        })
+    }
+
+    fn key(&self, job_id: &str) -> String {
+        format!("{}_logs:{}", self.queue_name, job_id)
+    }
+
+    /// Append a line to a job's log stream
+    pub async fn append(&self, job_id: &str, level: &str, message: &str) -> Result<()> {
+        let key = self.key(job_id);
+        let timestamp = now_secs().to_string();
+
+        redis::pipe()
+            .xadd_maxlen(
+                &key,
+                StreamMaxlen::Approx(MAX_LINES_PER_JOB),
+                "*",
+                &[("ts", timestamp.as_str()), ("level", level), ("msg", message)],
+            )
+            .ignore()
+            .expire(&key, LOG_RETENTION_SECS)
+            .ignore()
+            .query_async::<()>(&mut self.connection.clone())
+            .await
+            .context("Failed to append job log line")?;
+
+        Ok(())
+    }
+
+    /// Read log lines recorded after `last_id` (use `"0"` for the beginning)
+    /// With `block_ms`, waits up to that long for new lines to arrive
+    pub async fn read_after(
+        &self,
+        job_id: &str,
+        last_id: &str,
+        block_ms: Option<usize>,
+    ) -> Result<Vec<LogEntry>> {
+        let mut options = StreamReadOptions::default().count(500);
+        if let Some(ms) = block_ms {
+            options = options.block(ms);
+        }
+
+        let reply: Option<StreamReadReply> = self
+            .connection
+            .clone()
+            .xread_options(&[self.key(job_id)], &[last_id], &options)
+            .await
+            .context("Failed to read job logs")?;
+
+        Ok(reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids)
+            .map(|entry| LogEntry {
+                timestamp: entry
+                    .get::<String>("ts")
+                    .and_then(|ts| ts.parse().ok())
+                    .unwrap_or(0),
+                level: entry.get("level").unwrap_or_default(),
+                message: entry.get("msg").unwrap_or_default(),
+                id: entry.id,
+            })
+            .collect())
+    }
+}
+
+struct JobLogLine {
+    job_id: String,
+    level: String,
+    message: String,
+}
+
+/// Tracing layer that forwards events emitted inside a span carrying a
+/// `job_id` field to that job's log stream
+pub struct JobLogLayer {
+    sender: mpsc::UnboundedSender<JobLogLine>,
+}
+
+/// Receiving end of a [`JobLogLayer`], drained into Redis by [`JobLogWriter::spawn`]
+pub struct JobLogWriter {
+    receiver: mpsc::UnboundedReceiver<JobLogLine>,
+}
+
+/// Create a layer and the writer that persists what it captures
+pub fn job_log_layer() -> (JobLogLayer, JobLogWriter) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (JobLogLayer { sender }, JobLogWriter { receiver })
+}
+
+impl JobLogWriter {
+    /// Start persisting captured lines to the given stream
+    pub fn spawn(mut self, stream: JobLogStream) {
+        tokio::spawn(async move {
+            while let Some(line) = self.receiver.recv().await {
+                // Logging from here would feed back into the layer, so
+                // failures are only reported on stderr
+                if let Err(e) = stream.append(&line.job_id, &line.level, &line.message).await {
+                    eprintln!("Failed to write log line for job {}: {:#}", line.job_id, e);
+                }
+            }
+        });
+    }
+}
+
+struct JobId(String);
+
+#[derive(Default)]
+struct JobIdVisitor(Option<String>);
+
+impl Visit for JobIdVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "job_id" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "job_id" {
+            // Display-formatted fields (`%job.id`) arrive here unquoted
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl<S> Layer<S> for JobLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let mut visitor = JobIdVisitor::default();
+        attrs.record(&mut visitor);
+        if let (Some(job_id), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(JobId(job_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let Some(job_id) = ctx.event_scope(event).and_then(|scope| {
+            scope
+                .from_root()
+                .find_map(|span| span.extensions().get::<JobId>().map(|id| id.0.clone()))
+        }) else {
+            return;
+        };
+
+        let mut message = MessageVisitor::default();
+        event.record(&mut message);
+
+        let _ = self.sender.send(JobLogLine {
+            job_id,
+            level: event.metadata().level().to_string(),
+            message: message.0,
+        });
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::{BorrowedMessage, Message};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::queue::{Job, PayloadTooLarge, RateLimited, ReliableQueue};
+
+/// How long to wait before retrying a message that couldn't be enqueued
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+pub struct KafkaBridgeConfig {
+    /// Comma-separated `host:port` list of Kafka brokers
+    pub brokers: String,
+    pub topic: String,
+    /// Consumer group; bridges in one group split the topic's partitions
+    pub group_id: String,
+}
+
+/// Enqueues jobs consumed from a Kafka topic
+///
+/// Each message holds one job as JSON, in the same format as `enqueue --file`.
+/// A message's offset is committed only once its job is in Redis, and jobs
+/// whose ID was already enqueued are skipped, so a message redelivered after
+/// a crash or rebalance enqueues its job exactly once.
+pub struct KafkaBridge {
+    consumer: StreamConsumer,
+    queue: ReliableQueue,
+    topic: String,
+}
+
+impl KafkaBridge {
+    pub fn new(config: KafkaBridgeConfig, queue: ReliableQueue) -> Result<Self> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &config.brokers)
+            .set("group.id", &config.group_id)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .context("Failed to create Kafka consumer")?;
+        consumer
+            .subscribe(&[&config.topic])
+            .with_context(|| format!("Failed to subscribe to {}", config.topic))?;
+
+        Ok(Self {
+            consumer,
+            queue,
+            topic: config.topic,
+        })
+    }
+
+    /// Consume and enqueue jobs until `shutdown` resolves
+    pub async fn run(&mut self, shutdown: impl std::future::Future<Output = ()>) -> Result<()> {
+        info!("Bridging Kafka topic {} to the queue", self.topic);
+        tokio::pin!(shutdown);
+        // Messages borrow the consumer, so enqueue through a handle of our own
+        let mut queue = self.queue.clone();
+
+        'consume: loop {
+            let message = tokio::select! {
+                _ = &mut shutdown => break,
+                message = self.consumer.recv() => message,
+            };
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Failed to receive Kafka message: {}", e);
+                    continue;
+                }
+            };
+
+            // Hold the message until its job is enqueued; committing later
+            // offsets first would lose it
+            while let Err(e) = forward(&mut queue, &message).await {
+                if e.downcast_ref::<RateLimited>().is_some() {
+                    warn!("{}, retrying in {:?}", e, RETRY_DELAY);
+                } else {
+                    error!("Failed to enqueue job from Kafka: {:#}", e);
+                }
+                tokio::select! {
+                    _ = &mut shutdown => break 'consume,
+                    _ = tokio::time::sleep(RETRY_DELAY) => {}
+                }
+            }
+
+            if let Err(e) = self.consumer.commit_message(&message, CommitMode::Async) {
+                // The job is enqueued, so a redelivery is only skipped as a duplicate
+                warn!("Failed to commit Kafka offset: {}", e);
+            }
+        }
+
+        info!("Kafka bridge stopped");
+        Ok(())
+    }
+}
+
+/// Enqueue the job in `message`
+/// Malformed and oversized messages are logged and skipped since retrying
+/// can't fix them
+async fn forward(queue: &mut ReliableQueue, message: &BorrowedMessage<'_>) -> Result<()> {
+    let location = format!(
+        "{}/{}@{}",
+        message.topic(),
+        message.partition(),
+        message.offset()
+    );
+    let job = match parse_job(message.payload()) {
+        Ok(job) => job,
+        Err(e) => {
+            warn!("Skipping invalid job message at {}: {:#}", location, e);
+            return Ok(());
+        }
+    };
+
+    match queue.enqueue_unique(&job).await {
+        Ok(true) => info!("Enqueued job {} from {}", job.id, location),
+        Ok(false) => info!("Job {} from {} was already enqueued", job.id, location),
+        Err(e) if e.downcast_ref::<PayloadTooLarge>().is_some() => {
+            warn!("Skipping job message at {}: {}", location, e)
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Parse and validate the job carried by a message payload
+fn parse_job(payload: Option<&[u8]>) -> Result<Job> {
+    let payload = payload.context("Message has no payload")?;
+    let job: Job = serde_json::from_slice(payload).context("Message is not a job")?;
+    job.validate()?;
+    Ok(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_job() {
+        let job = parse_job(Some(
+            br#"{"id":"job-1","repo_url":"git@github.com:org/app.git",
+                 "branch":"main","prompt":"Fix it"}"#,
+        ))
+        .unwrap();
+        assert_eq!(job.id, "job-1");
+
+        assert!(parse_job(None).is_err());
+        assert!(parse_job(Some(b"not json")).is_err());
+        assert!(parse_job(Some(br#"{"id":"job-1"}"#)).is_err());
+    }
+}
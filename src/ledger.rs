@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{debug, warn};
+
+use crate::instance::Instance;
+use crate::queue::now_secs;
+
+/// An instance recorded as borrowed by a worker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeldInstance {
+    pub worker_id: String,
+    pub instance: Instance,
+    pub borrowed_at: u64,
+}
+
+/// Redis-backed record of the instances each worker currently holds
+/// Entries outlive a crashed worker, so a restarted worker can find and
+/// return instances it never gave back
+#[derive(Clone)]
+pub struct InstanceLedger {
+    connection: ConnectionManager,
+    key: String,
+    worker_id: String,
+}
+
+impl InstanceLedger {
+    pub async fn new(redis_url: &str, queue_name: &str, worker_id: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url)
+            .context("Failed to create Redis client")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("Failed to connect to Redis")?;
+
+        Ok(Self {
+            connection,
+            key: format!("{}_instances", queue_name),
+            worker_id: worker_id.to_string(),
+        })
+    }
+
+    /// Record that this worker now holds the instance
+    pub async fn record(&self, instance: &Instance) -> Result<()> {
+        let entry = HeldInstance {
+            worker_id: self.worker_id.clone(),
+            instance: instance.clone(),
+            borrowed_at: now_secs(),
+        };
+        let entry_json = serde_json::to_string(&entry)
+            .context("Failed to serialize held instance")?;
+
+        self.connection
+            .clone()
+            .hset::<_, _, _, ()>(&self.key, &instance.id, &entry_json)
+            .await
+            .context("Failed to record held instance")?;
+
+        debug!("Recorded instance {} as held by {}", instance.id, self.worker_id);
+        Ok(())
+    }
+
+    /// Forget an instance once it has been returned to the allocator
+    pub async fn release(&self, instance_id: &str) -> Result<()> {
+        self.connection
+            .clone()
+            .hdel::<_, _, ()>(&self.key, instance_id)
+            .await
+            .context("Failed to release held instance")?;
+
+        debug!("Released instance {} from ledger", instance_id);
+        Ok(())
+    }
+
+    /// Instances recorded as held by this worker
+    pub async fn held_by_self(&self) -> Result<Vec<HeldInstance>> {
+        let entries: HashMap<String, String> = self
+            .connection
+            .clone()
+            .hgetall(&self.key)
+            .await
+            .context("Failed to read instance ledger")?;
+
+        let mut held = Vec::new();
+        for (instance_id, entry_json) in entries {
+            match serde_json::from_str::<HeldInstance>(&entry_json) {
+                Ok(entry) if entry.worker_id == self.worker_id => held.push(entry),
+                Ok(_) => {}
+                Err(e) => warn!("Ignoring malformed ledger entry {}: {}", instance_id, e),
+            }
+        }
+
+        Ok(held)
+    }
+}
@@ -1,6 +1,52 @@
 pub mod agent;
+pub mod agent_metadata;
+pub mod backup;
+pub mod bench;
+pub mod cache;
+pub mod config;
+pub mod credentials;
+pub mod crypto;
+pub mod describe;
+pub mod doctor;
+pub mod dropdir;
+pub mod email;
+pub mod error;
 pub mod git;
+pub mod git_auth;
+pub mod github;
+pub mod github_events;
+pub mod grpc;
 pub mod guest_binary;
+pub mod guest_error;
+pub mod heartbeat;
+pub mod http;
 pub mod instance;
+pub mod joblog;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod ledger;
+pub mod llm;
+pub mod logfile;
+pub mod maintenance;
+pub mod memo;
+pub mod metrics;
+pub mod migrate;
+pub mod namespace;
+pub mod policy;
+pub mod prompt_policy;
+pub mod redis_timeout;
 pub mod queue;
+pub mod replication;
+pub mod repo_map;
+pub mod result;
+pub mod retry;
+pub mod scratch;
+pub mod server;
+pub mod staleness;
+pub mod tenant;
+pub mod timeline;
+pub mod tool_schema;
+pub mod trace;
+pub mod webhook;
+pub mod workdir;
 pub mod worker;
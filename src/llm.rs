@@ -0,0 +1,265 @@
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{Client, StatusCode};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::trace::TRACEPARENT;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long a failing provider is skipped after its first failure, doubled
+/// with each further one in a row
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(5);
+const MAX_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+/// How long a rate-limited provider is skipped when it doesn't say
+const RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// An LLM endpoint the guest's completion requests are sent to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LlmProvider {
+    pub name: String,
+    pub url: String,
+    /// Sent as a bearer token, read from `LLM_API_KEY_<NAME>`
+    pub api_key: Option<String>,
+}
+
+impl FromStr for LlmProvider {
+    type Err = anyhow::Error;
+
+    /// `<name>=<url>`; the API key is read from `LLM_API_KEY_<NAME>`, with
+    /// the name upper-cased and dashes turned into underscores
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((name, url)) = s.split_once('=') else {
+            bail!("Invalid LLM provider {}; expected <name>=<url>", s);
+        };
+        if name.is_empty() {
+            bail!("Invalid LLM provider {}: the name is empty", s);
+        }
+        url::Url::parse(url).with_context(|| format!("Invalid URL of LLM provider {}", name))?;
+        let key_var = format!("LLM_API_KEY_{}", name.to_uppercase().replace('-', "_"));
+        Ok(Self {
+            name: name.to_string(),
+            url: url.to_string(),
+            api_key: std::env::var(key_var).ok().filter(|key| !key.is_empty()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Health {
+    /// Failures since the last success
+    failures: u32,
+    /// Skip the provider until then
+    unhealthy_until: Option<Instant>,
+}
+
+/// Why a provider didn't answer a request
+enum Failure {
+    /// Rate limited, with how long the provider asked to wait
+    RateLimited(Option<Duration>),
+    Error(anyhow::Error),
+}
+
+/// Tokens a completion response says it used: `usage.total_tokens` in the
+/// OpenAI format, or `usage.input_tokens` plus `usage.output_tokens` in the
+/// Anthropic one
+pub fn tokens_used(response: &str) -> Option<u64> {
+    let response: serde_json::Value = serde_json::from_str(response).ok()?;
+    let usage = response.get("usage")?;
+    let count = |field: &str| usage.get(field).and_then(serde_json::Value::as_u64);
+    count("total_tokens").or_else(|| match (count("input_tokens"), count("output_tokens")) {
+        (None, None) => None,
+        (input, output) => Some(input.unwrap_or(0) + output.unwrap_or(0)),
+    })
+}
+
+/// Sends completion requests to the first healthy provider of an ordered
+/// list, failing over to the next one when a provider errors or rate-limits
+///
+/// A provider that fails is skipped for a cooldown, so later requests of
+/// the same job go straight to a fallback until the primary recovers.
+/// Providers must speak the same API: the guest's request body is passed
+/// through unchanged.
+#[derive(Debug)]
+pub struct LlmRouter {
+    client: Client,
+    providers: Vec<LlmProvider>,
+    health: Mutex<Vec<Health>>,
+}
+
+impl LlmRouter {
+    pub fn new(providers: Vec<LlmProvider>) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            client,
+            health: Mutex::new(vec![Health::default(); providers.len()]),
+            providers,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Indices of the providers in the order to try them at `now`: healthy
+    /// ones as configured, then the ones cooling down, soonest to recover
+    /// first, so a request is still tried when every provider is failing
+    fn order(&self, now: Instant) -> Vec<usize> {
+        let health = self.health.lock().unwrap();
+        let (mut healthy, mut cooling): (Vec<usize>, Vec<usize>) = (0..self.providers.len())
+            .partition(|&index| health[index].unhealthy_until.is_none_or(|until| until <= now));
+        cooling.sort_by_key(|&index| health[index].unhealthy_until);
+        healthy.append(&mut cooling);
+        healthy
+    }
+
+    fn record_success(&self, index: usize) {
+        self.health.lock().unwrap()[index] = Health::default();
+    }
+
+    /// Put the provider on cooldown, returning for how long
+    fn record_failure(&self, index: usize, failure: &Failure, now: Instant) -> Duration {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[index];
+        health.failures += 1;
+        let cooldown = match failure {
+            Failure::RateLimited(retry_after) => retry_after.unwrap_or(RATE_LIMIT_COOLDOWN),
+            Failure::Error(_) => FAILURE_COOLDOWN
+                .saturating_mul(1 << (health.failures - 1).min(16))
+                .min(MAX_COOLDOWN),
+        };
+        health.unhealthy_until = Some(now + cooldown);
+        cooldown
+    }
+
+    async fn send(
+        &self,
+        provider: &LlmProvider,
+        body: &str,
+        traceparent: Option<&str>,
+    ) -> std::result::Result<String, Failure> {
+        let mut request = self
+            .client
+            .post(&provider.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+        if let Some(key) = &provider.api_key {
+            request = request.bearer_auth(key);
+        }
+        if let Some(traceparent) = traceparent {
+            request = request.header(TRACEPARENT, traceparent);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Failure::Error(anyhow!("Request failed: {}", e)))?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs);
+            return Err(Failure::RateLimited(retry_after));
+        }
+        if !status.is_success() {
+            return Err(Failure::Error(anyhow!("Provider answered {}", status)));
+        }
+        response
+            .text()
+            .await
+            .map_err(|e| Failure::Error(anyhow!("Failed to read response: {}", e)))
+    }
+
+    /// Send a completion request to the first provider that answers it,
+    /// returning that provider's name and its response
+    pub async fn complete(
+        &self,
+        body: &str,
+        traceparent: Option<&str>,
+    ) -> Result<(String, String)> {
+        if self.providers.is_empty() {
+            bail!("No LLM provider configured");
+        }
+
+        let mut errors = Vec::new();
+        for index in self.order(Instant::now()) {
+            let provider = &self.providers[index];
+            match self.send(provider, body, traceparent).await {
+                Ok(response) => {
+                    self.record_success(index);
+                    if index > 0 {
+                        info!("LLM request served by fallback provider {}", provider.name);
+                    }
+                    return Ok((provider.name.clone(), response));
+                }
+                Err(failure) => {
+                    let cooldown = self.record_failure(index, &failure, Instant::now());
+                    let reason = match failure {
+                        Failure::RateLimited(_) => "rate limited".to_string(),
+                        Failure::Error(e) => format!("{:#}", e),
+                    };
+                    warn!(
+                        "LLM provider {} failed, skipping it for {:?}: {}",
+                        provider.name, cooldown, reason
+                    );
+                    errors.push(format!("{}: {}", provider.name, reason));
+                }
+            }
+        }
+        bail!("Every LLM provider failed: {}", errors.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failover_order() {
+        let provider = |s: &str| s.parse::<LlmProvider>().unwrap();
+        let router = LlmRouter::new(vec![
+            provider("primary=https://llm.internal/v1/chat/completions"),
+            provider("fallback=https://llm-eu.internal/v1/chat/completions"),
+            provider("last-resort=http://localhost:8000/v1/chat/completions"),
+        ])
+        .unwrap();
+        assert!("primary".parse::<LlmProvider>().is_err());
+        assert!("primary=not a url".parse::<LlmProvider>().is_err());
+
+        let now = Instant::now();
+        assert_eq!(router.order(now), [0, 1, 2]);
+
+        // Rate limited primary is skipped until it may be asked again
+        let cooldown =
+            router.record_failure(0, &Failure::RateLimited(Some(Duration::from_secs(60))), now);
+        assert_eq!(cooldown, Duration::from_secs(60));
+        assert_eq!(router.order(now), [1, 2, 0]);
+        assert_eq!(router.order(now + Duration::from_secs(61)), [0, 1, 2]);
+
+        // Repeated errors back off further each time
+        let error = || Failure::Error(anyhow!("Provider answered 503"));
+        assert_eq!(router.record_failure(1, &error(), now), FAILURE_COOLDOWN);
+        assert_eq!(router.record_failure(1, &error(), now), FAILURE_COOLDOWN * 2);
+        assert_eq!(router.order(now), [2, 1, 0]);
+
+        router.record_success(1);
+        assert_eq!(router.order(now), [1, 2, 0]);
+    }
+
+    #[test]
+    fn test_tokens_used() {
+        let openai = r#"{"choices": [], "usage": {"prompt_tokens": 90, "total_tokens": 120}}"#;
+        assert_eq!(tokens_used(openai), Some(120));
+        let anthropic = r#"{"content": [], "usage": {"input_tokens": 90, "output_tokens": 30}}"#;
+        assert_eq!(tokens_used(anthropic), Some(120));
+        assert_eq!(tokens_used(r#"{"choices": []}"#), None);
+        assert_eq!(tokens_used("not json"), None);
+    }
+}
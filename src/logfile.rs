@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
+use std::path::PathBuf;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+/// How often the log file is rotated regardless of its size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    /// Only rotate when the file reaches its maximum size
+    Never,
+}
+
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    pub rotation: LogRotation,
+    /// Rotate once the file grows past this many bytes
+    pub max_size: Option<u64>,
+    /// Rotated files to keep as `<path>.1` (newest) to `<path>.<max_files>`
+    pub max_files: usize,
+}
+
+impl LogFileConfig {
+    /// Open the log file, creating its directory if needed
+    pub fn appender(&self) -> Result<BasicRollingFileAppender> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create log directory {}", dir.display()))?;
+        }
+
+        let mut condition = match self.rotation {
+            LogRotation::Hourly => RollingConditionBasic::new().hourly(),
+            LogRotation::Daily => RollingConditionBasic::new().daily(),
+            LogRotation::Never => RollingConditionBasic::new(),
+        };
+        if let Some(max_size) = self.max_size {
+            condition = condition.max_size(max_size);
+        }
+
+        BasicRollingFileAppender::new(&self.path, condition, self.max_files)
+            .with_context(|| format!("Failed to open log file {}", self.path.display()))
+    }
+
+    /// Writer that appends to the log file from a background thread, so
+    /// logging never blocks on disk; buffered lines are flushed when the
+    /// guard is dropped
+    pub fn writer(&self) -> Result<(NonBlocking, WorkerGuard)> {
+        Ok(tracing_appender::non_blocking(self.appender()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_size_based_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/worker.log");
+        let config = LogFileConfig {
+            path: path.clone(),
+            rotation: LogRotation::Never,
+            max_size: Some(50),
+            max_files: 2,
+        };
+
+        let mut appender = config.appender().unwrap();
+        for line in ["a", "b", "c", "d"] {
+            appender.write_all(format!("{}\n", line.repeat(80)).as_bytes()).unwrap();
+        }
+        appender.flush().unwrap();
+
+        let read = |suffix: &str| {
+            std::fs::read_to_string(format!("{}{}", path.display(), suffix)).ok()
+        };
+        assert!(read("").unwrap().starts_with('d'));
+        assert!(read(".1").unwrap().starts_with('c'));
+        assert!(read(".2").unwrap().starts_with('b'));
+        assert_eq!(read(".3"), None);
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A recurring period during which a queue's workers take no jobs, stored
+/// in `{queue_name}_maintenance`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub name: String,
+    /// When the window opens, as a five-field cron expression in UTC
+    pub schedule: String,
+    /// How long the window stays open each time
+    pub duration_secs: u64,
+}
+
+impl MaintenanceWindow {
+    pub fn new(name: &str, schedule: &str, duration_secs: u64) -> Result<Self> {
+        CronSchedule::parse(schedule)
+            .with_context(|| format!("Invalid schedule of maintenance window {}", name))?;
+        if duration_secs == 0 {
+            bail!("Maintenance window {} must last at least a second", name);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            schedule: schedule.to_string(),
+            duration_secs,
+        })
+    }
+
+    /// When the window closes, if it is open at `now` (Unix time)
+    ///
+    /// Looks back over the window's duration for the latest minute the
+    /// schedule matched, so overlapping openings extend it.
+    pub fn open_until(&self, now: u64) -> Result<Option<u64>> {
+        let schedule = CronSchedule::parse(&self.schedule)?;
+        let current_minute = now - now % 60;
+        let earliest = now.saturating_sub(self.duration_secs - 1);
+        let mut minute = current_minute;
+        while minute + 60 > earliest {
+            if schedule.matches(minute) {
+                let until = minute + self.duration_secs;
+                return Ok((until > now).then_some(until));
+            }
+            let Some(previous) = minute.checked_sub(60) else {
+                break;
+            };
+            minute = previous;
+        }
+        Ok(None)
+    }
+}
+
+/// The first of `windows` open at `now`, with when it closes
+///
+/// A window with an invalid schedule is treated as closed.
+pub fn open_window(windows: &[MaintenanceWindow], now: u64) -> Option<(&MaintenanceWindow, u64)> {
+    windows.iter().find_map(|window| match window.open_until(now) {
+        Ok(until) => until.map(|until| (window, until)),
+        Err(_) => None,
+    })
+}
+
+/// A parsed cron expression: minute, hour, day of month, month, day of week
+///
+/// Each field is `*`, a number, a range `a-b` or a comma-separated list of
+/// these, optionally with a step such as `*/15`. Day of week runs from 0
+/// (Sunday) to 6, with 7 also meaning Sunday. As in cron, when both day
+/// fields are restricted a day matching either one matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    fn parse(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            bail!(
+                "Expected five fields (minute hour day month weekday), got {}",
+                fields.len()
+            );
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7).context("Invalid day of week")?;
+        // Sunday is both 0 and 7
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59).context("Invalid minute")?,
+            hours: parse_field(hours, 0, 23).context("Invalid hour")?,
+            days: parse_field(days, 1, 31).context("Invalid day of month")?,
+            months: parse_field(months, 1, 12).context("Invalid month")?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    /// Whether the schedule fires in the minute containing `time` (Unix time)
+    fn matches(&self, time: u64) -> bool {
+        let days_since_epoch = time / 86_400;
+        let seconds_of_day = time % 86_400;
+        let (_, month, day) = civil_date(days_since_epoch);
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch + 4) % 7;
+
+        let bit = |set: u64, value: u64| set & (1 << value) != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => bit(self.days, day),
+            (true, false) => bit(self.weekdays, weekday),
+            (false, false) => bit(self.days, day) || bit(self.weekdays, weekday),
+        };
+        bit(self.minutes, seconds_of_day % 3600 / 60)
+            && bit(self.hours, seconds_of_day / 3600)
+            && bit(self.months, month)
+            && day_matches
+    }
+}
+
+/// Values a cron field allows, as a bit set
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().context("Invalid step")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            bail!("Step must not be zero: {}", part);
+        }
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                None => {
+                    let value: u64 = range.parse()?;
+                    // `5/10` means from 5 to the end in steps of 10
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            bail!("{} is outside {}-{}", part, min, max);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// Year, month (1-12) and day of month (1-31) of a day counted from
+/// 1970-01-01
+pub fn civil_date(days_since_epoch: u64) -> (u64, u64, u64) {
+    // Days since 0000-03-01, so leap days fall at the end of the year
+    let days = days_since_epoch + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    // Years start in March, so January and February belong to the next one
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maintenance_window_schedule() {
+        // Friday 2024-03-01 18:00 UTC, the day after a leap day
+        let friday_evening = 1_709_316_000;
+        assert_eq!(civil_date(friday_evening / 86_400), (2024, 3, 1));
+        assert_eq!(civil_date((friday_evening - 86_400) / 86_400), (2024, 2, 29));
+        assert_eq!(civil_date(0), (1970, 1, 1));
+
+        // Release freeze from Friday 18:00 for the weekend
+        let freeze = MaintenanceWindow::new("release-freeze", "0 18 * * 5", 2 * 86_400).unwrap();
+        assert_eq!(freeze.open_until(friday_evening - 1).unwrap(), None);
+        let until = Some(friday_evening + 2 * 86_400);
+        assert_eq!(freeze.open_until(friday_evening).unwrap(), until);
+        assert_eq!(freeze.open_until(friday_evening + 86_400).unwrap(), until);
+        assert_eq!(freeze.open_until(friday_evening + 2 * 86_400).unwrap(), None);
+
+        let nightly = MaintenanceWindow::new("nightly", "*/30 2-3 * * *", 600).unwrap();
+        let windows = [freeze.clone(), nightly];
+        // Thursday 02:30:59
+        let night = friday_evening - 2 * 86_400 + 8 * 3600 + 30 * 60 + 59;
+        let (window, until) = open_window(&windows, night).unwrap();
+        assert_eq!((window.name.as_str(), until), ("nightly", night - 59 + 600));
+        assert!(open_window(&windows, night + 600).is_none());
+
+        // Either day field matches when both are restricted
+        let schedule = CronSchedule::parse("0 18 1 * 1").unwrap();
+        assert!(schedule.matches(friday_evening));
+        assert!(schedule.matches(friday_evening + 3 * 86_400));
+        assert!(!schedule.matches(friday_evening + 86_400));
+        assert!(CronSchedule::parse("0 18 * * 7").unwrap().matches(friday_evening + 2 * 86_400));
+
+        assert!(MaintenanceWindow::new("bad", "0 24 * * *", 60).is_err());
+        assert!(MaintenanceWindow::new("bad", "0 18 * *", 60).is_err());
+        assert!(MaintenanceWindow::new("bad", "*/0 * * * *", 60).is_err());
+        assert!(MaintenanceWindow::new("bad", "0 18 * * 5", 0).is_err());
+    }
+}
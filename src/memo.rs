@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+/// Which calls of an agent run are answered from earlier identical ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoConfig {
+    /// MCP tools whose responses are reused, `*` for every tool
+    pub tools: Vec<String>,
+    /// Reuse LLM responses to identical requests
+    pub llm: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum CallKey {
+    Tool { tool: String, arguments: String },
+    Llm { request: String },
+}
+
+/// Responses of one agent run's calls, by name and arguments
+///
+/// Agent loops often repeat the same lookup. Tools the config doesn't
+/// memoize may change what the memoized ones would return, such as a write
+/// changing a later read, so calling one forgets every tool response.
+#[derive(Debug, Default)]
+pub struct CallMemo {
+    config: MemoConfig,
+    responses: HashMap<CallKey, String>,
+    hits: usize,
+}
+
+impl CallMemo {
+    pub fn new(config: MemoConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Forget everything, for the next agent run
+    pub fn clear(&mut self) {
+        self.responses.clear();
+        self.hits = 0;
+    }
+
+    /// Calls answered from the memo since it was last cleared
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    fn memoizes(&self, tool: &str) -> bool {
+        self.config.tools.iter().any(|name| name == "*" || name == tool)
+    }
+
+    fn lookup(&mut self, key: &CallKey) -> Option<String> {
+        let response = self.responses.get(key).cloned();
+        if response.is_some() {
+            self.hits += 1;
+        }
+        response
+    }
+
+    /// Response of an earlier identical call of `tool`, about to be called
+    pub fn tool_response(&mut self, tool: &str, arguments: &str) -> Option<String> {
+        if !self.memoizes(tool) {
+            self.responses.retain(|key, _| matches!(key, CallKey::Llm { .. }));
+            return None;
+        }
+        self.lookup(&CallKey::Tool {
+            tool: tool.to_string(),
+            arguments: arguments.to_string(),
+        })
+    }
+
+    pub fn record_tool(&mut self, tool: &str, arguments: &str, response: &str) {
+        if self.memoizes(tool) {
+            let key = CallKey::Tool {
+                tool: tool.to_string(),
+                arguments: arguments.to_string(),
+            };
+            self.responses.insert(key, response.to_string());
+        }
+    }
+
+    /// Response of an earlier identical LLM request
+    pub fn llm_response(&mut self, request: &str) -> Option<String> {
+        if !self.config.llm {
+            return None;
+        }
+        self.lookup(&CallKey::Llm {
+            request: request.to_string(),
+        })
+    }
+
+    pub fn record_llm(&mut self, request: &str, response: &str) {
+        if self.config.llm {
+            let key = CallKey::Llm {
+                request: request.to_string(),
+            };
+            self.responses.insert(key, response.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_memo() {
+        let mut memo = CallMemo::new(MemoConfig {
+            tools: vec!["read_file".to_string()],
+            llm: true,
+        });
+        let args = r#"{"path":"src/lib.rs"}"#;
+        assert_eq!(memo.tool_response("read_file", args), None);
+        memo.record_tool("read_file", args, "fn main() {}");
+        assert_eq!(memo.tool_response("read_file", args).as_deref(), Some("fn main() {}"));
+        assert_eq!(memo.tool_response("read_file", r#"{"path":"README.md"}"#), None);
+
+        memo.record_llm("{\"prompt\":\"hi\"}", "hello");
+        // A write may change what the read returns; the LLM response stays
+        assert_eq!(memo.tool_response("write_file", args), None);
+        memo.record_tool("write_file", args, "ok");
+        assert_eq!(memo.tool_response("read_file", args), None);
+        assert_eq!(memo.llm_response("{\"prompt\":\"hi\"}").as_deref(), Some("hello"));
+        assert_eq!(memo.hits(), 2);
+
+        memo.clear();
+        assert_eq!(memo.llm_response("{\"prompt\":\"hi\"}"), None);
+
+        let mut off = CallMemo::new(MemoConfig::default());
+        off.record_llm("{}", "cached");
+        assert_eq!(off.llm_response("{}"), None);
+        let mut all = CallMemo::new(MemoConfig {
+            tools: vec!["*".to_string()],
+            llm: false,
+        });
+        all.record_tool("write_file", "{}", "ok");
+        assert_eq!(all.tool_response("write_file", "{}").as_deref(), Some("ok"));
+    }
+}
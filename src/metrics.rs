@@ -0,0 +1,497 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::queue::QueueStats;
+
+/// Upper bounds of the stage duration histogram buckets, in seconds
+pub const BUCKETS: [f64; 12] = [
+    0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0,
+];
+
+/// Upper bounds of the guest memory histogram buckets, in MiB
+pub const MEMORY_BUCKETS_MB: [u64; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// A timed step of processing a job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Borrowing an instance from the allocator
+    Borrow,
+    Clone,
+    /// Fetching, checking out the base branch and downloading context files
+    Checkout,
+    /// One agent execution; jobs with several tasks record one per task
+    Agent,
+    Commit,
+    Push,
+    /// Removing the checkout
+    Cleanup,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 7] = [
+        Stage::Borrow,
+        Stage::Clone,
+        Stage::Checkout,
+        Stage::Agent,
+        Stage::Commit,
+        Stage::Push,
+        Stage::Cleanup,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Borrow => "borrow",
+            Stage::Clone => "clone",
+            Stage::Checkout => "checkout",
+            Stage::Agent => "agent",
+            Stage::Commit => "commit",
+            Stage::Push => "push",
+            Stage::Cleanup => "cleanup",
+        }
+    }
+}
+
+/// Cumulative histogram of one stage's durations
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StageHistogram {
+    /// Observations at or below each of `BUCKETS`, in the same order
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_secs: f64,
+}
+
+/// What one agent execution took from its Hyperlight sandbox
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SandboxMetrics {
+    /// Resident memory the sandbox added to the worker process, which is
+    /// mostly the guest's memory; 0 where `/proc` isn't available
+    pub guest_memory_bytes: u64,
+    /// Time the guest ran, without booting the sandbox
+    pub execution_ms: u64,
+    /// Host functions the guest called
+    pub host_calls: u64,
+    /// Page faults while the guest ran; Linux only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_faults: Option<u64>,
+}
+
+impl SandboxMetrics {
+    /// Metrics of a job whose tasks ran `self` and `other`: the peak memory
+    /// and the total of everything else
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            guest_memory_bytes: self.guest_memory_bytes.max(other.guest_memory_bytes),
+            execution_ms: self.execution_ms + other.execution_ms,
+            host_calls: self.host_calls + other.host_calls,
+            page_faults: match (self.page_faults, other.page_faults) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+            },
+        }
+    }
+}
+
+/// Sandbox metrics of every agent execution of the queue's workers
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SandboxStats {
+    pub executions: u64,
+    pub execution_secs: f64,
+    pub host_calls: u64,
+    pub page_faults: u64,
+    /// Executions using at most each of `MEMORY_BUCKETS_MB`, in the same order
+    pub memory_buckets: Vec<u64>,
+    pub memory_bytes_sum: u64,
+}
+
+/// Hash fields incremented by one agent execution's sandbox metrics
+pub(crate) fn sandbox_observation_fields(metrics: &SandboxMetrics) -> Vec<(String, u64)> {
+    let mut fields: Vec<(String, u64)> = MEMORY_BUCKETS_MB
+        .iter()
+        .filter(|bound| metrics.guest_memory_bytes <= **bound * 1024 * 1024)
+        .map(|bound| (format!("memory:le:{}", bound), 1))
+        .collect();
+    fields.push(("executions".to_string(), 1));
+    fields.push(("execution_ms".to_string(), metrics.execution_ms));
+    fields.push(("host_calls".to_string(), metrics.host_calls));
+    fields.push(("page_faults".to_string(), metrics.page_faults.unwrap_or(0)));
+    fields.push(("memory_bytes".to_string(), metrics.guest_memory_bytes));
+    fields
+}
+
+/// Rebuild the sandbox stats from the hash `sandbox_observation_fields` fills
+pub(crate) fn sandbox_stats_from_hash(fields: &HashMap<String, u64>) -> SandboxStats {
+    let field = |name: &str| fields.get(name).copied().unwrap_or(0);
+    SandboxStats {
+        executions: field("executions"),
+        execution_secs: field("execution_ms") as f64 / 1000.0,
+        host_calls: field("host_calls"),
+        page_faults: field("page_faults"),
+        memory_buckets: MEMORY_BUCKETS_MB
+            .iter()
+            .map(|bound| field(&format!("memory:le:{}", bound)))
+            .collect(),
+        memory_bytes_sum: field("memory_bytes"),
+    }
+}
+
+/// Hash fields incremented by one observation of `stage`
+pub(crate) fn observation_fields(stage: Stage, duration: Duration) -> Vec<(String, u64)> {
+    let secs = duration.as_secs_f64();
+    let mut fields: Vec<(String, u64)> = BUCKETS
+        .iter()
+        .filter(|bound| secs <= **bound)
+        .map(|bound| (format!("{}:le:{}", stage.as_str(), bound), 1))
+        .collect();
+    fields.push((format!("{}:count", stage.as_str()), 1));
+    fields.push((
+        format!("{}:sum_ms", stage.as_str()),
+        duration.as_millis() as u64,
+    ));
+    fields
+}
+
+/// Rebuild every stage's histogram from the hash `observation_fields` fills
+pub(crate) fn histograms_from_hash(
+    fields: &HashMap<String, u64>,
+) -> BTreeMap<Stage, StageHistogram> {
+    let field = |name: String| fields.get(&name).copied().unwrap_or(0);
+    Stage::ALL
+        .iter()
+        .map(|stage| {
+            let name = stage.as_str();
+            let histogram = StageHistogram {
+                buckets: BUCKETS
+                    .iter()
+                    .map(|bound| field(format!("{}:le:{}", name, bound)))
+                    .collect(),
+                count: field(format!("{}:count", name)),
+                sum_secs: field(format!("{}:sum_ms", name)) as f64 / 1000.0,
+            };
+            (*stage, histogram)
+        })
+        .collect()
+}
+
+/// How a label's values are attached to the metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelValues {
+    /// The value itself
+    Full,
+    /// The one of this many buckets the value hashes to, `hash-0` to
+    /// `hash-{n-1}`, which bounds the series the label can create
+    Hashed(u32),
+    /// The label is left out
+    Off,
+}
+
+impl FromStr for LabelValues {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(LabelValues::Full),
+            "off" => Ok(LabelValues::Off),
+            _ => match s.strip_prefix("hashed:").map(str::parse) {
+                Some(Ok(buckets)) if buckets > 0 => Ok(LabelValues::Hashed(buckets)),
+                _ => bail!("Unknown label values {}; expected full, off or hashed:<buckets>", s),
+            },
+        }
+    }
+}
+
+impl LabelValues {
+    /// How `value` is rendered, or `None` if the label is left out
+    fn render(&self, value: &str) -> Option<String> {
+        match self {
+            LabelValues::Full => Some(value.to_string()),
+            LabelValues::Hashed(buckets) => {
+                let digest = Sha256::digest(value.as_bytes());
+                let hash = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+                Some(format!("hash-{}", hash % u64::from(*buckets)))
+            }
+            LabelValues::Off => None,
+        }
+    }
+}
+
+/// Which labels a queue's metrics carry and how, so deployments with
+/// thousands of queues or tenants don't create series for each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricLabels {
+    pub queue: LabelValues,
+    pub tenant: LabelValues,
+}
+
+impl Default for MetricLabels {
+    fn default() -> Self {
+        Self {
+            queue: LabelValues::Full,
+            tenant: LabelValues::Off,
+        }
+    }
+}
+
+impl MetricLabels {
+    /// Labels of the series of a tenant's queue, e.g. `queue="agent_jobs"`
+    pub fn render(&self, queue_name: &str, tenant: Option<&str>) -> String {
+        let labels = [
+            ("queue", self.queue.render(queue_name)),
+            ("tenant", tenant.and_then(|tenant| self.tenant.render(tenant))),
+        ];
+        labels
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{}=\"{}\"", name, value?)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// `labels` and `extra` as the label set of a series, if either has any
+fn label_set(labels: &str, extra: &str) -> String {
+    match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{{{}}}", labels),
+        (true, false) => format!("{{{}}}", extra),
+        (false, false) => format!("{{{},{}}}", labels, extra),
+    }
+}
+
+/// Queue statistics, stage durations and sandbox usage in the Prometheus
+/// text format, with `labels` (see `MetricLabels::render`) on every series
+pub fn render_prometheus(
+    labels: &str,
+    stats: &QueueStats,
+    stages: &BTreeMap<Stage, StageHistogram>,
+    sandbox: &SandboxStats,
+) -> String {
+    let mut out = String::new();
+    let queue_labels = label_set(labels, "");
+    let gauges = [
+        ("agent_worker_jobs_pending", "Jobs waiting in the queue", stats.pending),
+        ("agent_worker_jobs_processing", "Jobs being processed", stats.processing),
+        ("agent_worker_jobs_delayed", "Failed jobs waiting to be retried", stats.delayed),
+        ("agent_worker_jobs_dead", "Jobs in the dead-letter queue", stats.dead),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{}{} {}", name, queue_labels, value);
+    }
+    if let Some(bytes) = stats.memory_bytes {
+        let name = "agent_worker_queue_memory_bytes";
+        let _ = writeln!(out, "# HELP {} Approximate memory used by the queue's job lists", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{}{} {}", name, queue_labels, bytes);
+    }
+    // Zero while nothing is pending, so alerts on it resolve once the queue drains
+    let name = "agent_worker_oldest_pending_job_age_seconds";
+    let _ = writeln!(out, "# HELP {} Time the longest-waiting pending job has been queued", name);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(
+        out,
+        "{}{} {}",
+        name,
+        queue_labels,
+        stats.oldest_pending_secs.unwrap_or(0)
+    );
+    let counters = [
+        (
+            "agent_worker_jobs_succeeded_total",
+            "Jobs acknowledged as successful",
+            stats.succeeded,
+        ),
+        (
+            "agent_worker_job_attempts_failed_total",
+            "Failed job attempts",
+            stats.failed,
+        ),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{}{} {}", name, queue_labels, value);
+    }
+
+    let name = "agent_worker_stage_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Time spent in each stage of processing a job", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (stage, histogram) in stages {
+        let stage_label = format!("stage=\"{}\"", stage.as_str());
+        for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+            let bucket_labels = format!("{},le=\"{}\"", stage_label, bound);
+            let _ = writeln!(out, "{}_bucket{} {}", name, label_set(labels, &bucket_labels), count);
+        }
+        let inf_labels = format!("{},le=\"+Inf\"", stage_label);
+        let _ = writeln!(
+            out,
+            "{}_bucket{} {}",
+            name,
+            label_set(labels, &inf_labels),
+            histogram.count
+        );
+        let stage_labels = label_set(labels, &stage_label);
+        let _ = writeln!(out, "{}_sum{} {}", name, stage_labels, histogram.sum_secs);
+        let _ = writeln!(out, "{}_count{} {}", name, stage_labels, histogram.count);
+    }
+
+    let name = "agent_worker_sandbox_guest_memory_bytes";
+    let _ = writeln!(out, "# HELP {} Memory each agent execution's sandbox used", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, count) in MEMORY_BUCKETS_MB.iter().zip(&sandbox.memory_buckets) {
+        let bucket_labels = format!("le=\"{}\"", bound * 1024 * 1024);
+        let _ = writeln!(out, "{}_bucket{} {}", name, label_set(labels, &bucket_labels), count);
+    }
+    let inf_labels = label_set(labels, "le=\"+Inf\"");
+    let _ = writeln!(out, "{}_bucket{} {}", name, inf_labels, sandbox.executions);
+    let _ = writeln!(out, "{}_sum{} {}", name, queue_labels, sandbox.memory_bytes_sum);
+    let _ = writeln!(out, "{}_count{} {}", name, queue_labels, sandbox.executions);
+
+    let counters = [
+        (
+            "agent_worker_sandbox_execution_seconds_total",
+            "Time guests ran, without booting their sandboxes",
+            sandbox.execution_secs.to_string(),
+        ),
+        (
+            "agent_worker_sandbox_host_calls_total",
+            "Host functions called by guests",
+            sandbox.host_calls.to_string(),
+        ),
+        (
+            "agent_worker_sandbox_page_faults_total",
+            "Page faults while guests ran",
+            sandbox.page_faults.to_string(),
+        ),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{}{} {}", name, queue_labels, value);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_stage_histograms() {
+        let mut hash: HashMap<String, u64> = HashMap::new();
+        for millis in [200, 3_000, 45_000] {
+            let duration = Duration::from_millis(millis);
+            for (field, increment) in observation_fields(Stage::Clone, duration) {
+                *hash.entry(field).or_default() += increment;
+            }
+        }
+
+        let histograms = histograms_from_hash(&hash);
+        let clone = &histograms[&Stage::Clone];
+        assert_eq!(clone.count, 3);
+        assert_eq!(clone.sum_secs, 48.2);
+        // Bounds 0.1, 0.5, 1, 2.5, 5, 10, 30, 60, ...
+        assert_eq!(&clone.buckets[..8], &[0, 1, 1, 1, 2, 2, 2, 3]);
+        assert_eq!(
+            histograms[&Stage::Push],
+            StageHistogram {
+                buckets: vec![0; BUCKETS.len()],
+                ..Default::default()
+            }
+        );
+
+        let text = render_prometheus(
+            &MetricLabels::default().render("agent_jobs", Some("acme")),
+            &QueueStats::default(),
+            &histograms,
+            &SandboxStats::default(),
+        );
+        let series = |suffix: &str, labels: &str, value: u64| {
+            format!(
+                "{}_{}{{queue=\"agent_jobs\",stage=\"clone\"{}}} {}\n",
+                "agent_worker_stage_duration_seconds", suffix, labels, value
+            )
+        };
+        assert!(text.contains(&series("bucket", ",le=\"5\"", 2)));
+        assert!(text.contains(&series("count", "", 3)));
+        assert!(text.contains("agent_worker_jobs_pending{queue=\"agent_jobs\"} 0\n"));
+        let age = "agent_worker_oldest_pending_job_age_seconds{queue=\"agent_jobs\"} 0\n";
+        assert!(text.contains(age));
+    }
+
+    #[test]
+    fn test_sandbox_stats() {
+        let mib = 1024 * 1024;
+        let first = SandboxMetrics {
+            guest_memory_bytes: 40 * mib,
+            execution_ms: 1_500,
+            host_calls: 4,
+            page_faults: Some(100),
+        };
+        let second = SandboxMetrics {
+            guest_memory_bytes: 300 * mib,
+            execution_ms: 500,
+            host_calls: 2,
+            page_faults: None,
+        };
+        assert_eq!(
+            first.merge(second),
+            SandboxMetrics {
+                guest_memory_bytes: 300 * mib,
+                execution_ms: 2_000,
+                host_calls: 6,
+                page_faults: Some(100),
+            }
+        );
+
+        let mut hash: HashMap<String, u64> = HashMap::new();
+        for metrics in [first, second] {
+            for (field, increment) in sandbox_observation_fields(&metrics) {
+                *hash.entry(field).or_default() += increment;
+            }
+        }
+        let stats = sandbox_stats_from_hash(&hash);
+        assert_eq!((stats.executions, stats.host_calls, stats.page_faults), (2, 6, 100));
+        assert_eq!(stats.execution_secs, 2.0);
+        // Bounds 16, 32, 64, 128, 256, 512, ...
+        assert_eq!(&stats.memory_buckets[..6], &[0, 0, 1, 1, 1, 2]);
+
+        let labels = MetricLabels::default().render("agent_jobs", None);
+        let text = render_prometheus(&labels, &QueueStats::default(), &BTreeMap::new(), &stats);
+        let bucket = "agent_worker_sandbox_guest_memory_bytes_bucket\
+                      {queue=\"agent_jobs\",le=\"67108864\"} 1\n";
+        assert!(text.contains(bucket));
+        assert!(text.contains("agent_worker_sandbox_host_calls_total{queue=\"agent_jobs\"} 6\n"));
+    }
+
+    #[test]
+    fn test_metric_labels() {
+        let labels = MetricLabels {
+            queue: LabelValues::Off,
+            tenant: "hashed:16".parse().unwrap(),
+        };
+        let rendered = labels.render("agent_jobs:acme", Some("acme"));
+        assert!(rendered.starts_with("tenant=\"hash-"));
+        assert_eq!(rendered, labels.render("agent_jobs:acme", Some("acme")));
+        assert_eq!(labels.render("agent_jobs", None), "");
+        let buckets: BTreeSet<String> = (0..1000)
+            .map(|tenant| labels.render("agent_jobs", Some(&tenant.to_string())))
+            .collect();
+        assert_eq!(buckets.len(), 16);
+
+        let stats = SandboxStats::default();
+        let text = render_prometheus("", &QueueStats::default(), &BTreeMap::new(), &stats);
+        assert!(text.contains("agent_worker_jobs_pending 0\n"));
+
+        assert_eq!("full".parse::<LabelValues>().unwrap(), LabelValues::Full);
+        assert!("hashed:0".parse::<LabelValues>().is_err());
+        assert!("sampled".parse::<LabelValues>().is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{info, warn};
+
+use crate::queue::{Job, ReliableQueue};
+
+/// How `migrate` should treat the source queue
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MigrateOptions {
+    /// Remove jobs from the source once they are in the destination
+    pub move_jobs: bool,
+    /// Also migrate in-flight jobs (only safe once the source workers are stopped)
+    pub include_processing: bool,
+    /// Only report what would be migrated
+    pub dry_run: bool,
+}
+
+/// What a migration did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MigrationReport {
+    pub pending: usize,
+    pub processing: usize,
+    /// Jobs an earlier, interrupted migration had already copied
+    pub already_present: usize,
+    /// Jobs left in the source because the destination has a different job with their ID
+    pub skipped_duplicates: Vec<String>,
+    pub dry_run: bool,
+}
+
+/// Copy or move jobs from `source` into `destination`
+///
+/// Jobs are validated before they are written and never overwrite a different
+/// job with the same ID in the destination. When moving, each pending job is
+/// parked on the source's migration list until it has been written to the
+/// destination, so an interrupted migration can be rerun without losing or
+/// duplicating jobs.
+pub async fn migrate(
+    source: &mut ReliableQueue,
+    destination: &mut ReliableQueue,
+    options: MigrateOptions,
+) -> Result<MigrationReport> {
+    // Job ID -> serialized job for everything already in the destination
+    let mut existing = BTreeMap::new();
+    let destination_jobs = destination
+        .list_pending()
+        .await?
+        .into_iter()
+        .chain(destination.list_processing().await?);
+    for job in destination_jobs {
+        existing.insert(job.id.clone(), serde_json::to_string(&job)?);
+    }
+
+    let mut report = MigrationReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+
+    if options.move_jobs && !options.dry_run {
+        move_pending(source, destination, &mut existing, &mut report).await?;
+    } else {
+        let jobs = source.list_pending().await?;
+        for job in &jobs {
+            job.validate()?;
+        }
+        for job in jobs {
+            if copy_job(source, destination, &job, &mut existing, &mut report, options).await? {
+                report.pending += 1;
+            }
+        }
+    }
+
+    if options.include_processing {
+        let jobs = source.list_processing().await?;
+        for job in &jobs {
+            job.validate()?;
+        }
+        for job in jobs {
+            if !copy_job(source, destination, &job, &mut existing, &mut report, options).await? {
+                continue;
+            }
+            if options.move_jobs
+                && !options.dry_run
+                && !source.remove_processing(&job).await?
+            {
+                warn!("Job {} finished in the source while being migrated", job.id);
+            }
+            report.processing += 1;
+        }
+    }
+
+    info!(
+        "Migrated {} pending and {} in-flight jobs ({} duplicates skipped)",
+        report.pending,
+        report.processing,
+        report.skipped_duplicates.len()
+    );
+    Ok(report)
+}
+
+/// Whether a job is new to the destination, already there, or clashes with another job
+enum Presence {
+    New,
+    AlreadyPresent,
+    Duplicate,
+}
+
+fn presence(existing: &BTreeMap<String, String>, job: &Job, job_json: &str) -> Presence {
+    match existing.get(&job.id) {
+        None => Presence::New,
+        Some(present) if present == job_json => Presence::AlreadyPresent,
+        Some(_) => Presence::Duplicate,
+    }
+}
+
+/// Copy one job into the destination, returning whether it was (or would be) written
+async fn copy_job(
+    source: &mut ReliableQueue,
+    destination: &mut ReliableQueue,
+    job: &Job,
+    existing: &mut BTreeMap<String, String>,
+    report: &mut MigrationReport,
+    options: MigrateOptions,
+) -> Result<bool> {
+    let job_json = serde_json::to_string(job)?;
+    match presence(existing, job, &job_json) {
+        Presence::New => {}
+        Presence::AlreadyPresent => {
+            report.already_present += 1;
+            return Ok(false);
+        }
+        Presence::Duplicate => {
+            report.skipped_duplicates.push(job.id.clone());
+            return Ok(false);
+        }
+    }
+
+    if !options.dry_run {
+        let status = source.raw_status(&job.id).await?;
+        destination.import_job(job, &status).await?;
+    }
+    existing.insert(job.id.clone(), job_json);
+    Ok(true)
+}
+
+async fn move_pending(
+    source: &mut ReliableQueue,
+    destination: &mut ReliableQueue,
+    existing: &mut BTreeMap<String, String>,
+    report: &mut MigrationReport,
+) -> Result<()> {
+    // Duplicates go back to the end of the source queue, so stop once one
+    // comes around again instead of cycling through them forever
+    let mut returned = BTreeSet::new();
+
+    while let Some(job_json) = source.begin_migration().await? {
+        let job = match source.decode_job(&job_json) {
+            Ok(job) => job,
+            Err(e) => {
+                source.abort_migration(&job_json).await?;
+                return Err(e).context("Refusing to migrate malformed job");
+            }
+        };
+
+        if returned.contains(&job.id) {
+            source.abort_migration(&job_json).await?;
+            break;
+        }
+        // Compared as plain JSON, since the stored payload may be encrypted
+        let plain_json = serde_json::to_string(&job)?;
+        match presence(existing, &job, &plain_json) {
+            Presence::New => {}
+            Presence::AlreadyPresent => {
+                // Copied before an interrupted run could remove it here
+                source.complete_migration(&job_json, &job.id).await?;
+                report.already_present += 1;
+                continue;
+            }
+            Presence::Duplicate => {
+                source.abort_migration(&job_json).await?;
+                returned.insert(job.id.clone());
+                report.skipped_duplicates.push(job.id);
+                continue;
+            }
+        }
+        if let Err(e) = job.validate() {
+            source.abort_migration(&job_json).await?;
+            return Err(e);
+        }
+
+        let status = source.raw_status(&job.id).await?;
+        destination.import_job(&job, &status).await?;
+        source.complete_migration(&job_json, &job.id).await?;
+        existing.insert(job.id.clone(), plain_json);
+        report.pending += 1;
+    }
+
+    Ok(())
+}
+
+/// Refuse to migrate a queue onto itself
+pub fn ensure_distinct(
+    source_url: &str,
+    source_queue: &str,
+    destination_url: &str,
+    destination_queue: &str,
+) -> Result<()> {
+    if source_url == destination_url && source_queue == destination_queue {
+        bail!("Source and destination are the same queue");
+    }
+    Ok(())
+}
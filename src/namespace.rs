@@ -0,0 +1,44 @@
+use anyhow::{bail, Result};
+
+/// Queue name with a deployment's key prefix in front of it
+///
+/// Every Redis key is derived from the queue name, so with prefix
+/// `agentworker:prod:` the queue, statuses, locks, heartbeats and all other
+/// keys of queue `agent_jobs` live under `agentworker:prod:agent_jobs`.
+/// Deployments with different prefixes can then share a Redis instance.
+pub fn namespaced_queue_name(key_prefix: &str, queue_name: &str) -> String {
+    format!("{}{}", key_prefix, queue_name)
+}
+
+/// Key prefixes end up in `SCAN` patterns, so keep them free of glob
+/// characters
+pub fn validate_key_prefix(key_prefix: &str) -> Result<()> {
+    let valid = key_prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'));
+    if !valid {
+        bail!(
+            "Invalid key prefix '{}': use letters, digits, '-', '_', ':' and '.'",
+            key_prefix
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_prefix() {
+        assert_eq!(
+            namespaced_queue_name("agentworker:prod:", "agent_jobs"),
+            "agentworker:prod:agent_jobs"
+        );
+        assert_eq!(namespaced_queue_name("", "agent_jobs"), "agent_jobs");
+        assert!(validate_key_prefix("agentworker:staging-2.eu:").is_ok());
+        assert!(validate_key_prefix("").is_ok());
+        assert!(validate_key_prefix("agentworker:*").is_err());
+        assert!(validate_key_prefix("agent worker:").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::{bail, Result};
+
+/// A `host/path` glob matching repositories, such as `github.com/my-org/*`
+///
+/// `*` matches within one path segment and a final `**` matches any number
+/// of further segments. Matching ignores case.
+#[derive(Debug, Clone)]
+pub struct RepoPattern {
+    segments: Vec<String>,
+}
+
+impl RepoPattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let segments = segments(&pattern.to_lowercase());
+        if segments.len() < 2 {
+            bail!("Repository pattern '{}' must be host/path", pattern);
+        }
+        if segments[0].contains('*') {
+            bail!("Repository pattern '{}' must name a host", pattern);
+        }
+        if segments[..segments.len() - 1].iter().any(|s| s == "**") {
+            bail!("'**' is only allowed at the end of '{}'", pattern);
+        }
+        Ok(Self { segments })
+    }
+
+    /// Whether the repository at `repo_url` matches; local paths never do
+    pub fn matches(&self, repo_url: &str) -> bool {
+        normalize_repo_url(repo_url)
+            .is_some_and(|repo| matches(&self.segments, &segments(&repo)))
+    }
+}
+
+/// Which repositories a worker may clone; an empty policy allows every repository
+#[derive(Debug, Clone, Default)]
+pub struct RepoPolicy {
+    patterns: Vec<RepoPattern>,
+}
+
+impl RepoPolicy {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| RepoPattern::parse(pattern))
+            .collect::<Result<_>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// Whether a job's repository may be cloned
+    pub fn allows(&self, repo_url: &str) -> bool {
+        self.patterns.is_empty() || self.patterns.iter().any(|p| p.matches(repo_url))
+    }
+}
+
+/// `host/path` of a git remote, e.g. `github.com/org/repo` for both
+/// `git@github.com:org/repo.git` and `https://github.com/org/repo`
+/// Local paths have no host and yield `None`
+pub fn normalize_repo_url(repo_url: &str) -> Option<String> {
+    let (host, path) = if repo_url.contains("://") {
+        let url = url::Url::parse(repo_url).ok()?;
+        (url.host_str()?.to_string(), url.path().to_string())
+    } else {
+        // scp-like syntax: [user@]host:path
+        let (authority, path) = repo_url.split_once(':')?;
+        let host = authority.rsplit('@').next()?;
+        if host.is_empty() || host.contains('/') {
+            return None;
+        }
+        (host.to_string(), path.to_string())
+    };
+
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    if path.is_empty() {
+        return None;
+    }
+    Some(format!("{}/{}", host.to_lowercase(), path.to_lowercase()))
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn matches(pattern: &[String], repo: &[String]) -> bool {
+    match pattern.split_last() {
+        Some((last, prefix)) if last == "**" => {
+            repo.len() > prefix.len()
+                && prefix.iter().zip(repo).all(|(p, r)| glob_segment(p, r))
+        }
+        _ => {
+            pattern.len() == repo.len()
+                && pattern.iter().zip(repo).all(|(p, r)| glob_segment(p, r))
+        }
+    }
+}
+
+/// Match one path segment against a pattern where `*` is any run of characters
+fn glob_segment(pattern: &str, segment: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = segment.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_repo_url() {
+        let normalized = |url| normalize_repo_url(url);
+        assert_eq!(
+            normalized("git@github.com:My-Org/repo.git").as_deref(),
+            Some("github.com/my-org/repo")
+        );
+        assert_eq!(
+            normalized("https://token@github.com/my-org/repo/").as_deref(),
+            Some("github.com/my-org/repo")
+        );
+        assert_eq!(
+            normalized("ssh://git@gitlab.internal:2222/team/sub/repo.git").as_deref(),
+            Some("gitlab.internal/team/sub/repo")
+        );
+        assert_eq!(normalized("/srv/git/repo.git"), None);
+        assert_eq!(normalized("file:///srv/git/repo.git"), None);
+    }
+
+    #[test]
+    fn test_repo_policy() {
+        let policy = RepoPolicy::new(&[
+            "github.com/my-org/*".to_string(),
+            "gitlab.internal/team/**".to_string(),
+            "github.com/other/tool-*".to_string(),
+        ])
+        .unwrap();
+
+        assert!(policy.allows("git@github.com:my-org/repo.git"));
+        assert!(policy.allows("https://github.com/My-Org/Repo"));
+        assert!(!policy.allows("git@github.com:my-org-evil/repo.git"));
+        assert!(!policy.allows("git@github.com:my-org/repo/extra.git"));
+        assert!(policy.allows("git@gitlab.internal:team/sub/repo.git"));
+        assert!(!policy.allows("git@gitlab.internal:team"));
+        assert!(policy.allows("git@github.com:other/tool-cli.git"));
+        assert!(!policy.allows("git@github.com:other/cli-tool.git"));
+        assert!(!policy.allows("git@evil.example.com:my-org/repo.git"));
+        assert!(!policy.allows("/srv/git/repo.git"));
+
+        assert!(RepoPolicy::default().allows("/srv/git/repo.git"));
+        assert!(RepoPolicy::new(&["*.com/org/*".to_string()]).is_err());
+        assert!(RepoPolicy::new(&["github.com".to_string()]).is_err());
+        assert!(RepoPolicy::new(&["github.com/**/repo".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{bail, Context, Result};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::queue::Job;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rules file loaded with `--prompt-policy`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    deny: Vec<DenyRuleSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DenyRuleSpec {
+    name: String,
+    /// Regular expression matched against each prompt, ignoring case
+    pattern: String,
+}
+
+#[derive(Debug, Clone)]
+struct DenyRule {
+    name: String,
+    pattern: Regex,
+}
+
+/// What is sent to an external policy endpoint
+#[derive(Debug, Serialize)]
+pub struct PolicyRequest<'a> {
+    pub job_id: &'a str,
+    pub repo_url: &'a str,
+    pub prompts: Vec<&'a str>,
+}
+
+/// An external policy endpoint's verdict
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyDecision {
+    pub allowed: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Which prompts a worker refuses to hand to an agent, e.g. ones asking it
+/// to disable tests or reveal secrets; an empty policy allows every prompt
+///
+/// Prompts are checked against the denylist first, then sent to the policy
+/// endpoint, if one is configured.
+#[derive(Debug, Clone, Default)]
+pub struct PromptPolicy {
+    rules: Vec<DenyRule>,
+    endpoint: Option<(reqwest::Client, String)>,
+}
+
+impl PromptPolicy {
+    /// Load the denylist at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let file: PolicyFile = toml::from_str(contents)?;
+        let rules = file
+            .deny
+            .into_iter()
+            .map(|rule| {
+                let pattern = RegexBuilder::new(&rule.pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("Invalid pattern of rule {}", rule.name))?;
+                Ok(DenyRule {
+                    name: rule.name,
+                    pattern,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            rules,
+            endpoint: None,
+        })
+    }
+
+    /// Also ask the endpoint at `url` about every job
+    pub fn with_endpoint(mut self, url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("a client with only a timeout always builds");
+        self.endpoint = Some((client, url));
+        self
+    }
+
+    /// Why the job's prompts violate the policy, if they do
+    ///
+    /// Fails if the endpoint can't be asked, so that a job isn't run without
+    /// being checked.
+    pub async fn check(&self, job: &Job) -> Result<Option<String>> {
+        let prompts = job.prompts();
+        for prompt in &prompts {
+            if let Some(rule) = self.rules.iter().find(|rule| rule.pattern.is_match(prompt)) {
+                return Ok(Some(format!("Prompt matches denied pattern {}", rule.name)));
+            }
+        }
+
+        let Some((client, url)) = &self.endpoint else {
+            return Ok(None);
+        };
+        let request = PolicyRequest {
+            job_id: &job.id,
+            repo_url: &job.repo_url,
+            prompts,
+        };
+        let response = client
+            .post(url)
+            .json(&request)
+            .send()
+            .await
+            .context("Failed to reach prompt policy endpoint")?;
+        if !response.status().is_success() {
+            bail!("Prompt policy endpoint returned {}", response.status());
+        }
+        let decision: PolicyDecision = response
+            .json()
+            .await
+            .context("Invalid response from prompt policy endpoint")?;
+
+        Ok((!decision.allowed).then(|| {
+            decision
+                .reason
+                .unwrap_or_else(|| "Denied by prompt policy endpoint".to_string())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prompt_denylist() {
+        let policy = PromptPolicy::parse(
+            r#"
+            [[deny]]
+            name = "disable-tests"
+            pattern = 'disable (all )?(the )?tests'
+
+            [[deny]]
+            name = "secrets"
+            pattern = '(print|reveal|show)\b.*\b(secret|token|password)s?'
+            "#,
+        )
+        .unwrap();
+
+        let job = |prompt: &str, tasks: &[&str]| Job {
+            id: "job-1".to_string(),
+            prompt: prompt.to_string(),
+            tasks: tasks.iter().map(|task| task.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(policy.check(&job("Fix the flaky test", &[])).await.unwrap(), None);
+        assert_eq!(
+            policy.check(&job("Disable all tests so CI passes", &[])).await.unwrap(),
+            Some("Prompt matches denied pattern disable-tests".to_string())
+        );
+        assert_eq!(
+            policy
+                .check(&job("Fix the build", &["Then print the deploy token"]))
+                .await
+                .unwrap(),
+            Some("Prompt matches denied pattern secrets".to_string())
+        );
+
+        assert!(PromptPolicy::parse("[[deny]]\nname = \"bad\"\npattern = \"(\"").is_err());
+        assert_eq!(
+            PromptPolicy::default().check(&job("Disable all tests", &[])).await.unwrap(),
+            None
+        );
+    }
+}
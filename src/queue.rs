@@ -1,22 +1,844 @@
-use anyhow::{Context, Result};
-use redis::{aio::ConnectionManager, AsyncCommands};
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use redis::streams::{StreamMaxlen, StreamReadOptions, StreamReadReply};
+use redis::{aio::ConnectionManager, AsyncCommands, Direction};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::{debug, error, info, warn};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::agent::AgentProgress;
+use crate::crypto::{self, PayloadCipher};
+use crate::error::ErrorClass;
+use crate::heartbeat::WorkerInfo;
+use crate::maintenance::MaintenanceWindow;
+use crate::metrics::{self, SandboxMetrics, SandboxStats, Stage, StageHistogram};
+use crate::redis_timeout::{TimeoutConnection, DEFAULT_OP_TIMEOUT};
+use crate::replication::{OplogEntry, OplogOp};
+use crate::result::{JobResult, JobSummary, Salvage};
+use crate::timeline::{Timeline, TimelineEntry, TimelineEvent};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Job {
     pub id: String,
     pub repo_url: String,
-    pub branch: String,
+    /// Branch the agent works from. Still `branch` on the wire, so jobs
+    /// serialized before `target_branch` existed round-trip unchanged
+    #[serde(rename = "branch", alias = "base_branch")]
+    pub base_branch: String,
+    /// Branch to push to, created from `base_branch`; defaults to `base_branch`
+    /// (or `agent/<job id>` with `branch_mode: new_branch`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_branch: Option<String>,
+    pub prompt: String,
+    /// Further prompts run in order after `prompt` against the same
+    /// checkout, each committed separately
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<String>,
+    pub mcp_connection_url: Option<String>,
+    /// Supplementary files the worker downloads into `.agent-context/`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub context: Vec<ContextFile>,
+    /// Capabilities the borrowed instance must provide (e.g. `gpu=true`, `region=us-east-1`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub required_capabilities: BTreeMap<String, String>,
+    /// Free-form labels for filtering and routing (e.g. `team=payments`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Unix time after which the job is not worth starting; it is moved
+    /// to the dead-letter queue instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+    /// Jobs in the same group never run at the same time, on any worker
+    /// (e.g. one job per deployment environment)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency_group: Option<String>,
+    /// ID of the job this one is a replay of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_of: Option<String>,
+    /// ID of the job whose success enqueued this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Jobs the worker enqueues once this one succeeds, e.g. a test-fixing
+    /// agent after a feature agent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_success: Vec<FollowUp>,
+    /// Per-job overrides of worker behavior
+    #[serde(default, skip_serializing_if = "JobOptions::is_default")]
+    pub options: JobOptions,
+}
+
+/// Template of a job enqueued when its parent succeeds
+///
+/// The follow-up runs against the parent's repository, starting from the
+/// branch the parent pushed to, and finds the parent's commit in
+/// `.agent-context/parent.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FollowUp {
     pub prompt: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<String>,
+    /// Branch to push to; defaults to the branch the parent pushed to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_branch: Option<String>,
+    /// Defaults to the parent's MCP server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub mcp_connection_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub context: Vec<ContextFile>,
+    /// Added to the parent's labels
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "JobOptions::is_default")]
+    pub options: JobOptions,
+    /// Further stages of the pipeline
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_success: Vec<FollowUp>,
+}
+
+/// What a follow-up job is told about its parent, as
+/// `.agent-context/parent.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentContext {
+    pub job_id: String,
+    /// The parent's last commit; unset if its agent changed nothing
+    pub commit_sha: Option<String>,
+    pub branch: String,
+    /// The parent agent's final report
+    pub summary: String,
+}
+
+/// Context file name of a follow-up job's `ParentContext`
+pub const PARENT_CONTEXT_FILE: &str = "parent.json";
+
+/// Per-job overrides of how the worker runs a job
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobOptions {
+    /// Fail an attempt that runs longer than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Give up after this many retries instead of retrying indefinitely
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// Abort the clone once it has downloaded more than this many MB;
+    /// only tightens the worker's own limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_repo_mb: Option<u64>,
+    /// Abort a clone that takes longer than this; only tightens the
+    /// worker's own limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_clone_secs: Option<u64>,
+    /// Run the agent and record its diff, but don't commit or push
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+    /// Only analyze the repository: the agent can't write files, nothing is
+    /// committed or pushed, and the agent's report is the job's result
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+    /// Author of the agent's commit instead of the repository's configured identity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_author: Option<CommitAuthor>,
+    #[serde(default, skip_serializing_if = "BranchMode::is_default")]
+    pub branch_mode: BranchMode,
+    /// MCP tools the agent may call; every tool when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_tools: Option<Vec<String>>,
+    /// Embedded guest the agent runs in, such as a lighter one for small
+    /// jobs; the default guest when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_profile: Option<String>,
+    /// Run the agent even if the worker's result cache holds a result for
+    /// an identical job
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_cache: bool,
+    /// Commit locally but push only once the changes are approved with
+    /// `approve`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_approval: bool,
+    /// Set by `approve`: push the changes stored while the job awaited
+    /// approval instead of running the agent again
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub approved: bool,
+}
+
+impl JobOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitAuthor {
+    pub name: String,
+    pub email: String,
+}
+
+/// A file made available to the agent under `.agent-context/<name>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextFile {
+    /// File name; a plain name, not a path
+    pub name: String,
+    #[serde(flatten)]
+    pub source: ContextSource,
+}
+
+/// Where a context file is downloaded from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextSource {
+    /// A Redis string key, e.g. one written by `enqueue --context-file`
+    RedisKey(String),
+    /// An HTTP(S) URL, e.g. a presigned S3 URL
+    Url(String),
+}
+
+/// Where the agent's commit is pushed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchMode {
+    /// Push to the job's branch
+    #[default]
+    Direct,
+    /// Push to a new `agent/<job id>` branch created from the job's base branch
+    NewBranch,
+}
+
+impl BranchMode {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Job {
+    /// Check that the fields a worker needs to run the job are filled in
+    pub fn validate(&self) -> Result<()> {
+        let required = [
+            ("id", &self.id),
+            ("repo_url", &self.repo_url),
+            ("base_branch", &self.base_branch),
+            ("prompt", &self.prompt),
+        ];
+        for (field, value) in required {
+            if value.trim().is_empty() {
+                bail!("Job '{}' is missing {}", self.id, field);
+            }
+        }
+        if self
+            .concurrency_group
+            .as_ref()
+            .is_some_and(|group| group.trim().is_empty())
+        {
+            bail!("Job '{}' has an empty concurrency group", self.id);
+        }
+        if let Some(index) = self.tasks.iter().position(|task| task.trim().is_empty()) {
+            bail!("Job '{}' has an empty task at position {}", self.id, index + 1);
+        }
+        self.validate_context()?;
+        for index in 0..self.on_success.len() {
+            self.follow_up(index).validate()?;
+        }
+        Ok(())
+    }
+
+    /// Check that context file names can't escape the context directory and
+    /// that files read from Redis come from the job's own context keys
+    ///
+    /// The queue isn't known here, so a key only has to end like one of the
+    /// job's; the worker checks the whole key before reading it.
+    pub fn validate_context(&self) -> Result<()> {
+        for file in &self.context {
+            let plain_name = !file.name.is_empty()
+                && !file.name.starts_with('.')
+                && !file.name.contains(['/', '\\']);
+            if !plain_name {
+                bail!("Job '{}' has an invalid context file name '{}'", self.id, file.name);
+            }
+            if let ContextSource::RedisKey(key) = &file.source {
+                if !key.ends_with(&format!("_context:{}:{}", self.id, file.name)) {
+                    bail!(
+                        "Job '{}' reads context file '{}' from key '{}', which isn't its own",
+                        self.id,
+                        file.name,
+                        key
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Branch the job's commits are pushed to
+    pub fn push_branch(&self) -> String {
+        match (&self.target_branch, self.options.branch_mode) {
+            (Some(branch), _) => branch.clone(),
+            (None, BranchMode::Direct) => self.base_branch.clone(),
+            (None, BranchMode::NewBranch) => format!("agent/{}", self.id),
+        }
+    }
+
+    /// The job `on_success[index]` describes, with the ID `<id>.<index + 1>`
+    ///
+    /// The ID is derived from the parent's, so a parent that succeeds twice
+    /// (e.g. redelivered after a crash) can't enqueue its follow-ups twice.
+    pub fn follow_up(&self, index: usize) -> Job {
+        let template = self.on_success[index].clone();
+        let mut labels = self.labels.clone();
+        labels.extend(template.labels);
+        Job {
+            id: format!("{}.{}", self.id, index + 1),
+            repo_url: self.repo_url.clone(),
+            base_branch: self.push_branch(),
+            target_branch: template.target_branch,
+            prompt: template.prompt,
+            tasks: template.tasks,
+            mcp_connection_url: template
+                .mcp_connection_url
+                .or_else(|| self.mcp_connection_url.clone()),
+            context: template.context,
+            required_capabilities: self.required_capabilities.clone(),
+            labels,
+            deadline: None,
+            // Follow-ups act on what their parent did, so they share its group
+            concurrency_group: self.concurrency_group.clone(),
+            replay_of: None,
+            parent: Some(self.id.clone()),
+            on_success: template.on_success,
+            options: template.options,
+        }
+    }
+
+    /// Every prompt the agent runs for this job, in order
+    pub fn prompts(&self) -> Vec<&str> {
+        std::iter::once(self.prompt.as_str())
+            .chain(self.tasks.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Whether the job's deadline passed before `now`
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.deadline.is_some_and(|deadline| now > deadline)
+    }
+
+    /// How long the job is leased to the worker that dequeues it
+    pub fn lease_secs(&self) -> u64 {
+        self.options.timeout_secs.unwrap_or(DEFAULT_LEASE_SECS)
+    }
+
+    /// Whether the job carries every label in `selector`
+    pub fn matches_labels(&self, selector: &BTreeMap<String, String>) -> bool {
+        selector
+            .iter()
+            .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+}
+
+/// How often a worker with a label selector looks for a matching job
+const SELECTOR_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a job without a timeout is leased to the worker that dequeued it
+pub const DEFAULT_LEASE_SECS: u64 = 2 * 60 * 60;
+
+/// Atomically move the oldest pending job of KEYS[1], or with ARGV[6..] the
+/// oldest carrying every label in them (key, value pairs), to KEYS[2] and
+/// lease it, returning it and its attempt number
+/// The lease goes to the status hash ARGV[1] followed by the job's ID: state
+/// ARGV[5], worker ARGV[4] unless empty, and a lease deadline of ARGV[2]
+/// (now) plus the job's timeout, or ARGV[3] seconds without one. Encrypted
+/// jobs can't be read, so they are returned with attempt 0 and no lease,
+/// and never match labels
+const DEQUEUE_SCRIPT: &str = r#"
+local job = false
+if #ARGV < 6 then
+  job = redis.call('RPOPLPUSH', KEYS[1], KEYS[2])
+else
+  local jobs = redis.call('LRANGE', KEYS[1], 0, -1)
+  for i = #jobs, 1, -1 do
+    local ok, decoded = pcall(cjson.decode, jobs[i])
+    if ok and type(decoded) == 'table' then
+      local labels = decoded['labels']
+      local matches = true
+      for j = 6, #ARGV, 2 do
+        if type(labels) ~= 'table' or labels[ARGV[j]] ~= ARGV[j + 1] then
+          matches = false
+          break
+        end
+      end
+      if matches then
+        redis.call('LREM', KEYS[1], -1, jobs[i])
+        redis.call('LPUSH', KEYS[2], jobs[i])
+        job = jobs[i]
+        break
+      end
+    end
+  end
+end
+if not job then
+  return false
+end
+
+local ok, decoded = pcall(cjson.decode, job)
+if not ok or type(decoded) ~= 'table' or type(decoded['id']) ~= 'string' then
+  return {job, 0}
+end
+local key = ARGV[1] .. decoded['id']
+local lease = tonumber(ARGV[3])
+local options = decoded['options']
+if type(options) == 'table' and type(options['timeout_secs']) == 'number' then
+  lease = options['timeout_secs']
+end
+local attempt = redis.call('HINCRBY', key, 'attempts', 1)
+redis.call('HSET', key, 'state', ARGV[5], 'started_at', ARGV[2],
+  'lease_deadline', string.format('%d', tonumber(ARGV[2]) + lease))
+if ARGV[4] ~= '' then
+  redis.call('HSET', key, 'worker', ARGV[4])
+end
+-- Progress belongs to the attempt that reported it
+redis.call('HDEL', key, 'progress_step', 'progress_percent', 'progress_message', 'retry_at')
+return {job, attempt}
+"#;
+
+/// Count ARGV[2] enqueues against the per-minute limit stored in KEYS[1]
+/// using the window counter KEYS[2], returning the limit if it would be
+/// exceeded and 0 otherwise. ARGV[1] is the window length in seconds
+const RATE_LIMIT_SCRIPT: &str = r#"
+local limit = tonumber(redis.call('GET', KEYS[1]))
+if not limit then
+  return 0
+end
+local count = tonumber(redis.call('GET', KEYS[2]) or '0')
+if count + tonumber(ARGV[2]) > limit then
+  return limit
+end
+redis.call('INCRBY', KEYS[2], ARGV[2])
+redis.call('EXPIRE', KEYS[2], ARGV[1])
+return 0
+"#;
+
+/// Enqueue the job ARGV[1] with ID ARGV[3] unless its status hash KEYS[2]
+/// already exists, returning 1 if it was enqueued and 0 otherwise
+/// KEYS[1] is the queue and KEYS[3..] the label sets the ID is added to;
+/// ARGV[2] is the enqueue time and ARGV[4] the pending state
+const ENQUEUE_UNIQUE_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[2]) == 1 then
+  return 0
+end
+redis.call('LPUSH', KEYS[1], ARGV[1])
+redis.call('HSET', KEYS[2], 'state', ARGV[4], 'enqueued_at', ARGV[2], 'job', ARGV[1])
+for i = 3, #KEYS do
+  redis.call('SADD', KEYS[i], ARGV[3])
+end
+return 1
+"#;
+
+/// Set the state of the status hash KEYS[1] to ARGV[2] if it is ARGV[1],
+/// returning 1 if it was changed and 0 otherwise
+const TRANSITION_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], 'state') ~= ARGV[1] then
+  return 0
+end
+redis.call('HSET', KEYS[1], 'state', ARGV[2])
+return 1
+"#;
+
+/// Take the lock KEYS[1] for ARGV[1] for ARGV[2] seconds, or extend it if
+/// ARGV[1] already holds it, returning 1 if ARGV[1] holds it and 0 otherwise
+const LOCK_GROUP_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  redis.call('EXPIRE', KEYS[1], ARGV[2])
+  return 1
+end
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'EX', ARGV[2]) then
+  return 1
+end
+return 0
+"#;
+
+/// Delete the lock KEYS[1] if ARGV[1] holds it
+const UNLOCK_GROUP_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// Move up to ARGV[2] jobs of the sorted set KEYS[1] whose score is at most
+/// ARGV[1] onto the queue KEYS[2], returning how many were moved
+const PROMOTE_DELAYED_SCRIPT: &str = r#"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, job in ipairs(due) do
+  redis.call('ZREM', KEYS[1], job)
+  redis.call('LPUSH', KEYS[2], job)
+end
+return #due
+"#;
+
+/// Delayed jobs moved onto the queue per call of `promote_delayed`
+const PROMOTE_BATCH: usize = 100;
+
+/// Oplog entries kept for replicas that fall behind
+const OPLOG_MAX_LEN: usize = 100_000;
+
+/// Job summaries kept in `{queue_name}_events` for dashboards to read
+const EVENTS_MAX_LEN: usize = 10_000;
+
+/// Quarantined entries kept in `{queue_name}_invalid`
+const INVALID_MAX_LEN: usize = 1_000;
+
+/// Elements `MEMORY USAGE` samples of each list, like Redis' own default
+const MEMORY_SAMPLES: usize = 5;
+
+/// Returned by `enqueue` and `enqueue_batch` when the queue's rate limit
+/// would be exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    pub per_minute: u64,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limit of {} jobs per minute exceeded", self.per_minute)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Payload sizes of the jobs on a queue that are warned about or rejected,
+/// stored in `{queue_name}_payload_limits`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadLimits {
+    /// Enqueues of larger payloads are logged as warnings
+    pub warn_bytes: Option<usize>,
+    /// Enqueues of larger payloads fail with `PayloadTooLarge`
+    pub max_bytes: Option<usize>,
+}
+
+/// Returned by the enqueue methods when a job's payload is larger than the
+/// queue's limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadTooLarge {
+    pub job_id: String,
+    pub bytes: usize,
+    pub max_bytes: usize,
+}
+
+impl std::fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Payload of job {} is {} bytes, over the limit of {} bytes",
+            self.job_id, self.bytes, self.max_bytes
+        )
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// Redis key `store_context` writes a job's context file to
+pub fn context_key(queue_name: &str, job_id: &str, name: &str) -> String {
+    format!("{}_context:{}:{}", queue_name, job_id, name)
+}
+
+/// Every queue whose name starts with `key_prefix` and that has had a job
+/// enqueued, found by scanning for job status keys
+pub async fn discover_queues(redis_url: &str, key_prefix: &str) -> Result<BTreeSet<String>> {
+    let client = redis::Client::open(redis_url).context("Failed to create Redis client")?;
+    let mut connection = ConnectionManager::new(client)
+        .await
+        .context("Failed to connect to Redis")?;
+
+    let mut queues = BTreeSet::new();
+    let mut iter: redis::AsyncIter<String> = connection
+        .scan_match(format!("{}*_status:*", key_prefix))
+        .await
+        .context("Failed to scan for queues")?;
+    while let Some(key) = iter.next_item().await {
+        if let Some((queue_name, _)) = key.split_once("_status:") {
+            queues.insert(queue_name.to_string());
+        }
+    }
+    Ok(queues)
+}
+
+/// Current Unix time in seconds
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Lifecycle state of a job as recorded in its status hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    Running,
+    /// Committed but not pushed until `approve` or `reject` is called
+    AwaitingApproval,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::AwaitingApproval => "awaiting_approval",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether the job has finished and will not run again
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            JobState::Succeeded | JobState::Failed | JobState::Cancelled
+        )
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "pending" => Some(JobState::Pending),
+            "running" => Some(JobState::Running),
+            "awaiting_approval" => Some(JobState::AwaitingApproval),
+            "succeeded" => Some(JobState::Succeeded),
+            "failed" => Some(JobState::Failed),
+            "cancelled" => Some(JobState::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// Snapshot of a job's status hash (`{queue_name}_status:{job_id}`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub job_id: String,
+    pub state: Option<JobState>,
+    pub attempts: u32,
+    pub enqueued_at: Option<u64>,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub worker: Option<String>,
+    pub last_error: Option<String>,
+    pub commit_sha: Option<String>,
+    pub cancel_requested: bool,
+    /// Why the job was moved to the dead-letter queue, if it was
+    pub dead_reason: Option<DeadReason>,
+    /// The last progress its agent reported
+    pub progress: Option<AgentProgress>,
+    /// Branch the work of its last failed attempt was salvaged to
+    pub salvage_branch: Option<String>,
+    /// When a failed job waiting out its retry delay is due
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<u64>,
+    /// Until when the worker that dequeued the job last holds it; a job
+    /// still processing after that is presumed abandoned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_deadline: Option<u64>,
+    /// Why each failed attempt failed, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<AttemptFailure>,
+}
+
+/// Why one attempt at a job failed, kept in its status hash as
+/// `failure:{attempt}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttemptFailure {
+    pub attempt: u32,
+    pub class: ErrorClass,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+/// Why a job was moved to the dead-letter queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadReason {
+    /// Its deadline passed before a worker could start it
+    Expired,
+    /// Its repository isn't allowed by the worker's repository policy
+    RepoNotAllowed,
+    /// One of its prompts violates the worker's prompt policy
+    PromptPolicy,
+    /// It asks for an agent profile the worker has no guest for
+    UnknownAgentProfile,
+    /// Its repository is too large or too slow to clone within its budget
+    CloneBudget,
+    /// Its agent failed with an error code another attempt wouldn't change,
+    /// such as a bad prompt
+    GuestError,
+    /// It failed on every attempt its retry limit allows
+    RetriesExhausted,
+}
+
+impl DeadReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeadReason::Expired => "expired",
+            DeadReason::RepoNotAllowed => "repo_not_allowed",
+            DeadReason::PromptPolicy => "prompt_policy",
+            DeadReason::UnknownAgentProfile => "unknown_agent_profile",
+            DeadReason::CloneBudget => "clone_budget",
+            DeadReason::GuestError => "guest_error",
+            DeadReason::RetriesExhausted => "retries_exhausted",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "expired" => Some(DeadReason::Expired),
+            "repo_not_allowed" => Some(DeadReason::RepoNotAllowed),
+            "prompt_policy" => Some(DeadReason::PromptPolicy),
+            "unknown_agent_profile" => Some(DeadReason::UnknownAgentProfile),
+            "clone_budget" => Some(DeadReason::CloneBudget),
+            "guest_error" => Some(DeadReason::GuestError),
+            "retries_exhausted" => Some(DeadReason::RetriesExhausted),
+            _ => None,
+        }
+    }
+}
+
+/// A job in the dead-letter queue (`{queue_name}_dead`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub job: Job,
+    pub reason: DeadReason,
+    pub dead_at: u64,
+    /// Specifics the reason alone doesn't give, such as the policy rule a
+    /// prompt broke
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Why the job's earlier attempts failed, if it ran before
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<AttemptFailure>,
+}
+
+/// A queue entry that couldn't be read as a job, moved to
+/// `{queue_name}_invalid` so it doesn't block the jobs behind it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvalidEntry {
+    /// The entry as it was queued, base64-encoded if it isn't UTF-8
+    pub payload: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+    /// Why the entry isn't a job
+    pub error: String,
+    pub quarantined_at: u64,
+}
+
+impl InvalidEntry {
+    fn new(entry: &[u8], error: &str) -> Self {
+        let (payload, base64) = match std::str::from_utf8(entry) {
+            Ok(payload) => (payload.to_string(), false),
+            Err(_) => (BASE64.encode(entry), true),
+        };
+        Self {
+            payload,
+            base64,
+            error: error.to_string(),
+            quarantined_at: now_secs(),
+        }
+    }
+
+    /// The entry's raw bytes
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        if !self.base64 {
+            return Ok(self.payload.clone().into_bytes());
+        }
+        BASE64
+            .decode(&self.payload)
+            .context("Invalid entry payload is not base64")
+    }
+}
+
+/// Depths of a queue's lists and its lifetime job counters
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueStats {
+    pub pending: usize,
+    pub processing: usize,
+    /// Failed jobs waiting for their retry delay to pass
+    #[serde(default)]
+    pub delayed: usize,
+    /// Jobs in the dead-letter queue
+    pub dead: usize,
+    /// Jobs acknowledged as successful
+    pub succeeded: u64,
+    /// Failed attempts, including ones that were retried
+    pub failed: u64,
+    /// Seconds the longest-waiting pending job has been queued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oldest_pending_secs: Option<u64>,
+    /// Approximate bytes used by the queue's job lists, if Redis reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+}
+
+/// What `ReliableQueue::cancel` did with a job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    /// The job was still pending and has been removed from the queue
+    Removed,
+    /// The job is in flight and has been flagged for cooperative cancellation
+    Flagged,
+    /// The job is neither pending nor in flight
+    NotFound,
+}
+
+impl JobStatus {
+    fn from_hash(job_id: &str, mut fields: HashMap<String, String>) -> Self {
+        let timestamp = |value: Option<String>| value.and_then(|v| v.parse().ok());
+        let mut failures: Vec<AttemptFailure> = fields
+            .iter()
+            .filter(|(field, _)| field.starts_with("failure:"))
+            .filter_map(|(_, json)| serde_json::from_str(json).ok())
+            .collect();
+        failures.sort_by_key(|failure| failure.attempt);
+        Self {
+            job_id: job_id.to_string(),
+            state: fields.get("state").and_then(|s| JobState::parse(s)),
+            attempts: fields
+                .get("attempts")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            enqueued_at: timestamp(fields.remove("enqueued_at")),
+            started_at: timestamp(fields.remove("started_at")),
+            finished_at: timestamp(fields.remove("finished_at")),
+            worker: fields.remove("worker"),
+            last_error: fields.remove("last_error"),
+            commit_sha: fields.remove("commit_sha"),
+            cancel_requested: fields.get("cancel_requested").is_some_and(|v| v == "1"),
+            dead_reason: fields.get("dead_reason").and_then(|s| DeadReason::parse(s)),
+            salvage_branch: fields.remove("salvage_branch"),
+            retry_at: timestamp(fields.remove("retry_at")),
+            lease_deadline: timestamp(fields.remove("lease_deadline")),
+            progress: fields.remove("progress_step").map(|step| AgentProgress {
+                step,
+                percent: fields
+                    .get("progress_percent")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+                message: fields.remove("progress_message").unwrap_or_default(),
+            }),
+            failures,
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct ReliableQueue {
-    connection: ConnectionManager,
+    connection: TimeoutConnection,
     queue_name: String,
     processing_queue_name: String,
     timeout_seconds: u64,
+    worker_id: Option<String>,
+    label_selector: BTreeMap<String, String>,
+    cipher: Option<PayloadCipher>,
+    /// How long stored results are kept; forever when unset
+    result_ttl: Option<Duration>,
 }
 
 impl ReliableQueue {
@@ -29,65 +851,1726 @@ impl ReliableQueue {
             .context("Failed to create Redis client")?;
         let connection = ConnectionManager::new(client)
             .await
-            .context("Failed to connect to Redis")?;
+            .context("Failed to connect to Redis")?;
+
+        Ok(Self {
+            connection: TimeoutConnection::new(connection, Some(DEFAULT_OP_TIMEOUT)),
+            queue_name: queue_name.to_string(),
+            processing_queue_name: format!("{}_processing", queue_name),
+            timeout_seconds,
+            worker_id: None,
+            label_selector: BTreeMap::new(),
+            cipher: None,
+            result_ttl: None,
+        })
+    }
+
+    /// Name of the main queue list
+    pub fn queue_name(&self) -> &str {
+        &self.queue_name
+    }
+
+    /// Record this worker's ID in the status of jobs it dequeues, and keep
+    /// them in the worker's own `{queue_name}_processing:{worker_id}` list
+    pub fn with_worker_id(mut self, worker_id: &str) -> Self {
+        self.worker_id = Some(worker_id.to_string());
+        self.processing_queue_name =
+            format!("{}:{}", self.shared_processing_queue_name(), worker_id);
+        self
+    }
+
+    /// Only dequeue jobs carrying every one of these labels
+    pub fn with_label_selector(mut self, selector: BTreeMap<String, String>) -> Self {
+        self.label_selector = selector;
+        self
+    }
+
+    /// Fail Redis commands that take longer than `timeout`, plus the time a
+    /// blocking dequeue waits for, with a
+    /// [`RedisTimeout`](crate::redis_timeout::RedisTimeout); wait for them
+    /// indefinitely when unset
+    pub fn with_op_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connection.set_timeout(timeout);
+        self
+    }
+
+    /// Encrypt job payloads with this cipher; payloads are plain JSON without one
+    pub fn with_cipher(mut self, cipher: Option<PayloadCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// This queue's connection and settings, for another queue such as one
+    /// of its tenants'
+    pub fn for_queue(&self, queue_name: &str) -> Self {
+        let queue = Self {
+            queue_name: queue_name.to_string(),
+            processing_queue_name: format!("{}_processing", queue_name),
+            ..self.clone()
+        };
+        match &self.worker_id {
+            Some(worker_id) => queue.with_worker_id(worker_id),
+            None => queue,
+        }
+    }
+
+    /// Expire stored results after `ttl`; they are kept forever when unset
+    pub fn with_result_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.result_ttl = ttl;
+        self
+    }
+
+    /// Serialize a value as stored in Redis
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String> {
+        let json = serde_json::to_string(value).context("Failed to serialize payload")?;
+        match &self.cipher {
+            Some(cipher) => cipher.seal(&json),
+            None => Ok(json),
+        }
+    }
+
+    /// Deserialize a value stored in Redis
+    fn decode<T: DeserializeOwned>(&self, payload: &str) -> Result<T> {
+        let json = self.open(payload)?;
+        serde_json::from_str(&json).context("Failed to deserialize payload")
+    }
+
+    /// The JSON of a value stored in Redis, decrypted if it's encrypted
+    fn open(&self, payload: &str) -> Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.open(payload),
+            None if crypto::is_encrypted(payload) => {
+                bail!("Payload is encrypted; set JOB_ENCRYPTION_KEY to read it")
+            }
+            None => Ok(payload.to_string()),
+        }
+    }
+
+    /// A job as it is stored in the queue's lists
+    pub fn encode_job(&self, job: &Job) -> Result<String> {
+        self.encode(job).context("Failed to serialize job")
+    }
+
+    /// Parse a job as it is stored in the queue's lists
+    pub fn decode_job(&self, job_json: &str) -> Result<Job> {
+        self.decode(job_json).context("Failed to deserialize job")
+    }
+
+    fn status_key(&self, job_id: &str) -> String {
+        format!("{}_status:{}", self.queue_name, job_id)
+    }
+
+    /// Best-effort update of a job's status hash
+    /// Status is informational, so a failed write never fails the queue operation
+    async fn update_status(&mut self, job_id: &str, fields: &[(&str, String)]) {
+        let key = self.status_key(job_id);
+        if let Err(e) = self
+            .connection
+            .hset_multiple::<_, _, _, ()>(&key, fields)
+            .await
+        {
+            warn!("Failed to update status for job {}: {}", job_id, e);
+        }
+    }
+
+    /// Get the recorded status of a job, if any
+    pub async fn get_status(&mut self, job_id: &str) -> Result<Option<JobStatus>> {
+        let fields: HashMap<String, String> = self
+            .connection
+            .hgetall(self.status_key(job_id))
+            .await
+            .context("Failed to read job status")?;
+
+        if fields.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(JobStatus::from_hash(job_id, fields)))
+    }
+
+    /// Recorded statuses of several jobs, read in pipelined batches; jobs
+    /// without a status are left out
+    pub async fn get_statuses(&mut self, job_ids: &[String]) -> Result<Vec<JobStatus>> {
+        let mut statuses = Vec::with_capacity(job_ids.len());
+        for chunk in job_ids.chunks(500) {
+            let mut pipe = redis::pipe();
+            for job_id in chunk {
+                pipe.hgetall(self.status_key(job_id));
+            }
+            let hashes: Vec<HashMap<String, String>> = pipe
+                .query_async(&mut self.connection)
+                .await
+                .context("Failed to read job statuses")?;
+
+            statuses.extend(
+                chunk
+                    .iter()
+                    .zip(hashes)
+                    .filter(|(_, fields)| !fields.is_empty())
+                    .map(|(job_id, fields)| JobStatus::from_hash(job_id, fields)),
+            );
+        }
+        Ok(statuses)
+    }
+
+    /// Record why an attempt failed, as the job's last error and in its
+    /// attempt history
+    pub async fn record_failure(&mut self, job: &Job, class: ErrorClass, error: &str) {
+        let attempts: Result<Option<u32>, _> = self
+            .connection
+            .hget(self.status_key(&job.id), "attempts")
+            .await;
+        let attempt = attempts.unwrap_or_else(|e| {
+            warn!("Failed to read attempts of job {}: {}", job.id, e);
+            None
+        });
+        let failure = AttemptFailure {
+            attempt: attempt.unwrap_or(0),
+            class,
+            error: error.to_string(),
+            failed_at: now_secs(),
+        };
+
+        let mut fields = vec![("last_error", error.to_string())];
+        let failure_field = format!("failure:{}", failure.attempt);
+        match serde_json::to_string(&failure) {
+            Ok(json) => fields.push((&failure_field, json)),
+            Err(e) => warn!("Failed to serialize failure of job {}: {}", job.id, e),
+        }
+        self.update_status(&job.id, &fields).await;
+        self.increment_counter("failed").await;
+    }
+
+    /// Get the definition a job was enqueued with, if it is still recorded
+    pub async fn get_job(&mut self, job_id: &str) -> Result<Option<Job>> {
+        let job_json: Option<String> = self
+            .connection
+            .hget(self.status_key(job_id), "job")
+            .await
+            .context("Failed to read job definition")?;
+
+        job_json
+            .map(|json| self.decode_job(&json))
+            .transpose()
+    }
+
+    /// Record the commit pushed for a job
+    pub async fn record_commit(&mut self, job: &Job, commit_sha: &str) {
+        self.update_status(&job.id, &[("commit_sha", commit_sha.to_string())])
+            .await;
+    }
+
+    /// Record the progress a job's agent reported
+    pub async fn record_progress(&mut self, job_id: &str, progress: &AgentProgress) {
+        self.update_status(
+            job_id,
+            &[
+                ("progress_step", progress.step.clone()),
+                ("progress_percent", progress.percent.to_string()),
+                ("progress_message", progress.message.clone()),
+            ],
+        )
+        .await;
+    }
+
+    fn counters_key(&self) -> String {
+        format!("{}_counters", self.queue_name)
+    }
+
+    /// Set of the IDs of every job ever enqueued with this label
+    fn label_key(&self, key: &str, value: &str) -> String {
+        format!("{}_labels:{}={}", self.queue_name, key, value)
+    }
+
+    fn index_labels(&self, pipe: &mut redis::Pipeline, job: &Job) {
+        for (key, value) in &job.labels {
+            pipe.sadd(self.label_key(key, value), &job.id).ignore();
+        }
+    }
+
+    /// Best-effort increment of one of the queue's lifetime counters
+    async fn increment_counter(&mut self, counter: &str) {
+        if let Err(e) = self
+            .connection
+            .hincr::<_, _, _, ()>(self.counters_key(), counter, 1)
+            .await
+        {
+            warn!("Failed to increment {} counter: {}", counter, e);
+        }
+    }
+
+    fn stage_durations_key(&self) -> String {
+        format!("{}_stage_durations", self.queue_name)
+    }
+
+    /// Best-effort record of how long a stage of processing a job took
+    pub async fn record_stage_duration(&mut self, stage: Stage, duration: Duration) {
+        let mut pipe = redis::pipe();
+        for (field, increment) in metrics::observation_fields(stage, duration) {
+            pipe.hincr(self.stage_durations_key(), field, increment).ignore();
+        }
+        if let Err(e) = pipe.query_async::<()>(&mut self.connection).await {
+            warn!("Failed to record {} duration: {}", stage.as_str(), e);
+        }
+    }
+
+    /// Histograms of every stage's durations across the queue's workers
+    pub async fn stage_histograms(&mut self) -> Result<BTreeMap<Stage, StageHistogram>> {
+        let fields: HashMap<String, u64> = self
+            .connection
+            .hgetall(self.stage_durations_key())
+            .await
+            .context("Failed to read stage durations")?;
+        Ok(metrics::histograms_from_hash(&fields))
+    }
+
+    fn sandbox_metrics_key(&self) -> String {
+        format!("{}_sandbox_metrics", self.queue_name)
+    }
+
+    /// Best-effort record of what an agent execution took from its sandbox
+    pub async fn record_sandbox_metrics(&mut self, metrics: &SandboxMetrics) {
+        let mut pipe = redis::pipe();
+        for (field, increment) in metrics::sandbox_observation_fields(metrics) {
+            pipe.hincr(self.sandbox_metrics_key(), field, increment).ignore();
+        }
+        if let Err(e) = pipe.query_async::<()>(&mut self.connection).await {
+            warn!("Failed to record sandbox metrics: {}", e);
+        }
+    }
+
+    /// Sandbox usage of every agent execution across the queue's workers
+    pub async fn sandbox_stats(&mut self) -> Result<SandboxStats> {
+        let fields: HashMap<String, u64> = self
+            .connection
+            .hgetall(self.sandbox_metrics_key())
+            .await
+            .context("Failed to read sandbox metrics")?;
+        Ok(metrics::sandbox_stats_from_hash(&fields))
+    }
+
+    fn events_key(&self) -> String {
+        format!("{}_events", self.queue_name)
+    }
+
+    /// Best-effort append of a finished job's summary to `{queue_name}_events`
+    pub async fn publish_summary(&mut self, summary: &JobSummary) {
+        let summary_json = match serde_json::to_string(summary) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Failed to serialize summary of job {}: {}", summary.job_id, e);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .connection
+            .xadd_maxlen::<_, _, _, _, ()>(
+                self.events_key(),
+                StreamMaxlen::Approx(EVENTS_MAX_LEN),
+                "*",
+                &[
+                    ("event", "job.summary"),
+                    ("job_id", summary.job_id.as_str()),
+                    ("summary", summary_json.as_str()),
+                ],
+            )
+            .await
+        {
+            warn!("Failed to publish summary of job {}: {}", summary.job_id, e);
+        }
+    }
+
+    fn timeline_key(&self, job_id: &str) -> String {
+        format!("{}_timeline:{}", self.queue_name, job_id)
+    }
+
+    /// Best-effort record of a lifecycle event on the job's timeline
+    pub async fn record_event(&mut self, job_id: &str, event: TimelineEvent) {
+        let entry = match serde_json::to_string(&TimelineEntry::now(event)) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Failed to serialize timeline event: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .connection
+            .rpush::<_, _, ()>(self.timeline_key(job_id), entry)
+            .await
+        {
+            warn!("Failed to record {} for job {}: {}", event.as_str(), job_id, e);
+        }
+    }
+
+    /// Lifecycle events recorded for a job, if any
+    pub async fn timeline(&mut self, job_id: &str) -> Result<Option<Timeline>> {
+        let entries: Vec<String> = self
+            .connection
+            .lrange(self.timeline_key(job_id), 0, -1)
+            .await
+            .context("Failed to read job timeline")?;
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        let entries = entries
+            .iter()
+            .map(|entry| serde_json::from_str(entry).context("Failed to parse timeline event"))
+            .collect::<Result<Vec<TimelineEntry>>>()?;
+        Ok(Some(Timeline::new(job_id, entries)))
+    }
+
+    fn rate_limit_key(&self) -> String {
+        format!("{}_rate_limit", self.queue_name)
+    }
+
+    /// Limit how many jobs may be enqueued per minute, or lift the limit
+    pub async fn set_rate_limit(&mut self, per_minute: Option<u64>) -> Result<()> {
+        let key = self.rate_limit_key();
+        match per_minute {
+            Some(limit) => self.connection.set::<_, _, ()>(&key, limit).await,
+            None => self.connection.del::<_, ()>(&key).await,
+        }
+        .context("Failed to set rate limit")
+    }
+
+    fn payload_limits_key(&self) -> String {
+        format!("{}_payload_limits", self.queue_name)
+    }
+
+    /// Set the payload sizes warned about and rejected on enqueue; limits
+    /// that are `None` are removed
+    pub async fn set_payload_limits(&mut self, limits: PayloadLimits) -> Result<()> {
+        let key = self.payload_limits_key();
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        if let Some(bytes) = limits.warn_bytes {
+            pipe.hset(&key, "warn_bytes", bytes).ignore();
+        }
+        if let Some(bytes) = limits.max_bytes {
+            pipe.hset(&key, "max_bytes", bytes).ignore();
+        }
+        pipe.query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to set payload limits")
+    }
+
+    pub async fn payload_limits(&mut self) -> Result<PayloadLimits> {
+        let fields: HashMap<String, usize> = self
+            .connection
+            .hgetall(self.payload_limits_key())
+            .await
+            .context("Failed to read payload limits")?;
+        Ok(PayloadLimits {
+            warn_bytes: fields.get("warn_bytes").copied(),
+            max_bytes: fields.get("max_bytes").copied(),
+        })
+    }
+
+    /// Fail with `PayloadTooLarge` if any of the encoded jobs is over the
+    /// queue's limit, warning about those over its warning threshold
+    async fn check_payload_sizes(&mut self, jobs: &[(&Job, &str)]) -> Result<()> {
+        let limits = self.payload_limits().await?;
+        for (job, job_json) in jobs {
+            let bytes = job_json.len();
+            if let Some(max_bytes) = limits.max_bytes.filter(|max_bytes| bytes > *max_bytes) {
+                return Err(PayloadTooLarge {
+                    job_id: job.id.clone(),
+                    bytes,
+                    max_bytes,
+                }
+                .into());
+            }
+            if let Some(warn_bytes) = limits.warn_bytes.filter(|warn_bytes| bytes > *warn_bytes) {
+                warn!(
+                    "Payload of job {} is {} bytes, over the warning threshold of {} bytes",
+                    job.id, bytes, warn_bytes
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn oplog_key(&self) -> String {
+        format!("{}_oplog", self.queue_name)
+    }
+
+    fn oplog_enabled_key(&self) -> String {
+        format!("{}_oplog_enabled", self.queue_name)
+    }
+
+    fn replica_offset_key(&self) -> String {
+        format!("{}_replica_offset", self.queue_name)
+    }
+
+    /// Start or stop recording the queue's enqueues, acks, nacks, failures
+    /// and cancellations in `{queue_name}_oplog` for replicas; stopping
+    /// discards the oplog
+    pub async fn set_oplog_enabled(&mut self, enabled: bool) -> Result<()> {
+        if enabled {
+            self.connection
+                .set::<_, _, ()>(self.oplog_enabled_key(), 1)
+                .await
+        } else {
+            self.connection
+                .del::<_, ()>(&[self.oplog_enabled_key(), self.oplog_key()])
+                .await
+        }
+        .context("Failed to change oplog setting")
+    }
+
+    pub async fn oplog_enabled(&mut self) -> Result<bool> {
+        self.connection
+            .exists(self.oplog_enabled_key())
+            .await
+            .context("Failed to read oplog setting")
+    }
+
+    fn append_op(&self, pipe: &mut redis::Pipeline, op: OplogOp, job_id: &str, job_json: &str) {
+        let timestamp = now_secs().to_string();
+        pipe.xadd_maxlen(
+            self.oplog_key(),
+            StreamMaxlen::Approx(OPLOG_MAX_LEN),
+            "*",
+            &[
+                ("op", op.as_str()),
+                ("job_id", job_id),
+                ("job", job_json),
+                ("ts", timestamp.as_str()),
+            ],
+        )
+        .ignore();
+    }
+
+    /// Best-effort append to the oplog, if it is on
+    async fn record_op(&mut self, op: OplogOp, job_id: &str, job_json: &str) {
+        match self.oplog_enabled().await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                warn!("Failed to record {} of job {}: {:#}", op.as_str(), job_id, e);
+                return;
+            }
+        }
+        let mut pipe = redis::pipe();
+        self.append_op(&mut pipe, op, job_id, job_json);
+        if let Err(e) = pipe.query_async::<()>(&mut self.connection).await {
+            warn!("Failed to record {} of job {}: {}", op.as_str(), job_id, e);
+        }
+    }
+
+    /// Oplog entries recorded after `after` (`"0"` for the beginning), at
+    /// most `count` of them
+    /// With `block_ms`, waits up to that long for new entries to arrive
+    pub async fn read_oplog(
+        &mut self,
+        after: &str,
+        count: usize,
+        block_ms: Option<usize>,
+    ) -> Result<Vec<OplogEntry>> {
+        let mut options = StreamReadOptions::default().count(count);
+        if let Some(ms) = block_ms {
+            options = options.block(ms);
+        }
+
+        let reply: Option<StreamReadReply> = self
+            .connection
+            .xread_options(&[self.oplog_key()], &[after], &options)
+            .await
+            .context("Failed to read oplog")?;
+
+        reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids)
+            .map(|entry| {
+                let op: String = entry.get("op").unwrap_or_default();
+                let Some(op) = OplogOp::parse(&op) else {
+                    bail!("Unknown operation '{}' in oplog entry {}", op, entry.id);
+                };
+                Ok(OplogEntry {
+                    op,
+                    job_id: entry.get("job_id").unwrap_or_default(),
+                    job: entry.get("job").unwrap_or_default(),
+                    timestamp: entry
+                        .get::<String>("ts")
+                        .and_then(|ts| ts.parse().ok())
+                        .unwrap_or(0),
+                    id: entry.id,
+                })
+            })
+            .collect()
+    }
+
+    /// ID of the newest entry trimmed from the oplog, if any were
+    pub async fn oplog_trimmed_through(&mut self) -> Result<Option<String>> {
+        let key = self.oplog_key();
+        let exists: bool = self
+            .connection
+            .exists(&key)
+            .await
+            .context("Failed to read oplog")?;
+        if !exists {
+            return Ok(None);
+        }
+
+        let info: HashMap<String, redis::Value> = redis::cmd("XINFO")
+            .arg("STREAM")
+            .arg(&key)
+            .query_async(&mut self.connection)
+            .await
+            .context("Failed to read oplog")?;
+        Ok(info
+            .get("max-deleted-entry-id")
+            .and_then(|id| redis::from_redis_value::<String>(id).ok())
+            .filter(|id| id != "0-0"))
+    }
+
+    /// ID of the last oplog entry applied to this queue as a replica, `"0"`
+    /// if none was
+    pub async fn replica_offset(&mut self) -> Result<String> {
+        let offset: Option<String> = self
+            .connection
+            .get(self.replica_offset_key())
+            .await
+            .context("Failed to read replica offset")?;
+        Ok(offset.unwrap_or_else(|| "0".to_string()))
+    }
+
+    /// Apply a primary's oplog entries to this queue as its replica,
+    /// atomically with the offset they bring it to
+    ///
+    /// The replica keeps every job that was enqueued and hasn't finished as
+    /// pending, including jobs in flight on the primary, so they run again
+    /// if the replica takes over.
+    pub async fn apply_oplog(&mut self, entries: &[OplogEntry]) -> Result<()> {
+        let Some(last) = entries.last() else {
+            return Ok(());
+        };
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for entry in entries {
+            let status_key = self.status_key(&entry.job_id);
+            let finished = match entry.op {
+                OplogOp::Enqueue => {
+                    pipe.lpush(&self.queue_name, &entry.job)
+                        .ignore()
+                        .hset_multiple(
+                            &status_key,
+                            &[
+                                ("state", JobState::Pending.as_str()),
+                                ("enqueued_at", entry.timestamp.to_string().as_str()),
+                                ("job", entry.job.as_str()),
+                            ],
+                        )
+                        .ignore();
+                    // Labels can only be indexed with the payload's key
+                    if let Ok(job) = self.decode_job(&entry.job) {
+                        self.index_labels(&mut pipe, &job);
+                    }
+                    continue;
+                }
+                OplogOp::Nack => {
+                    pipe.hincr(&status_key, "attempts", 1).ignore();
+                    continue;
+                }
+                OplogOp::Ack => JobState::Succeeded,
+                OplogOp::Fail => JobState::Failed,
+                OplogOp::Cancel => JobState::Cancelled,
+            };
+            pipe.lrem(&self.queue_name, 1, &entry.job)
+                .ignore()
+                .hset_multiple(
+                    &status_key,
+                    &[
+                        ("state", finished.as_str().to_string()),
+                        ("finished_at", entry.timestamp.to_string()),
+                    ],
+                )
+                .ignore();
+        }
+        pipe.set(self.replica_offset_key(), &last.id).ignore();
+
+        pipe.query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to apply oplog entries")?;
+        debug!("Applied {} oplog entries up to {}", entries.len(), last.id);
+        Ok(())
+    }
+
+    /// Jobs that may be enqueued per minute, if limited
+    pub async fn rate_limit(&mut self) -> Result<Option<u64>> {
+        self.connection
+            .get(self.rate_limit_key())
+            .await
+            .context("Failed to read rate limit")
+    }
+
+    fn group_lock_key(&self, group: &str) -> String {
+        format!("{}_group:{}", self.queue_name, group)
+    }
+
+    /// Take the fleet-wide lock of a concurrency group for a job, or extend
+    /// it if the job already holds it. Returns false if another job holds it
+    ///
+    /// The lock expires after `ttl` unless extended, so a group whose worker
+    /// died frees up on its own.
+    pub async fn lock_group(&mut self, group: &str, job_id: &str, ttl: Duration) -> Result<bool> {
+        let locked: i32 = redis::Script::new(LOCK_GROUP_SCRIPT)
+            .key(self.group_lock_key(group))
+            .arg(job_id)
+            .arg(ttl.as_secs().max(1))
+            .invoke_async(&mut self.connection)
+            .await
+            .context("Failed to lock concurrency group")?;
+        Ok(locked == 1)
+    }
+
+    /// Release a concurrency group's lock, unless another job took it over
+    pub async fn unlock_group(&mut self, group: &str, job_id: &str) -> Result<()> {
+        redis::Script::new(UNLOCK_GROUP_SCRIPT)
+            .key(self.group_lock_key(group))
+            .arg(job_id)
+            .invoke_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to unlock concurrency group")
+    }
+
+    /// Job currently holding a concurrency group's lock
+    pub async fn group_holder(&mut self, group: &str) -> Result<Option<String>> {
+        self.connection
+            .get(self.group_lock_key(group))
+            .await
+            .context("Failed to read concurrency group lock")
+    }
+
+    fn maintenance_key(&self) -> String {
+        format!("{}_maintenance", self.queue_name)
+    }
+
+    /// Add a maintenance window, or replace the one with the same name
+    pub async fn set_maintenance_window(&mut self, window: &MaintenanceWindow) -> Result<()> {
+        let window_json =
+            serde_json::to_string(window).context("Failed to serialize maintenance window")?;
+        self.connection
+            .hset::<_, _, _, ()>(self.maintenance_key(), &window.name, window_json)
+            .await
+            .context("Failed to store maintenance window")
+    }
+
+    /// Remove a maintenance window, returning whether it existed
+    pub async fn remove_maintenance_window(&mut self, name: &str) -> Result<bool> {
+        let removed: u64 = self
+            .connection
+            .hdel(self.maintenance_key(), name)
+            .await
+            .context("Failed to remove maintenance window")?;
+        Ok(removed > 0)
+    }
+
+    /// The queue's maintenance windows, by name
+    pub async fn maintenance_windows(&mut self) -> Result<Vec<MaintenanceWindow>> {
+        let windows: BTreeMap<String, String> = self
+            .connection
+            .hgetall(self.maintenance_key())
+            .await
+            .context("Failed to read maintenance windows")?;
+        windows
+            .values()
+            .map(|json| serde_json::from_str(json).context("Failed to parse maintenance window"))
+            .collect()
+    }
+
+    /// Count `count` enqueues against the rate limit, failing with
+    /// `RateLimited` if they would exceed it
+    async fn check_rate_limit(&mut self, count: usize) -> Result<()> {
+        let window = now_secs() / 60;
+        let limit: u64 = redis::Script::new(RATE_LIMIT_SCRIPT)
+            .key(self.rate_limit_key())
+            .key(format!("{}_rate:{}", self.queue_name, window))
+            .arg(60)
+            .arg(count)
+            .invoke_async(&mut self.connection)
+            .await
+            .context("Failed to check rate limit")?;
+
+        if limit > 0 {
+            return Err(RateLimited { per_minute: limit }.into());
+        }
+        Ok(())
+    }
+
+    fn result_key(&self, job_id: &str) -> String {
+        format!("{}_result:{}", self.queue_name, job_id)
+    }
+
+    /// Store a context file for a job, returning the key to reference it by
+    pub async fn store_context(
+        &mut self,
+        job_id: &str,
+        name: &str,
+        contents: &[u8],
+    ) -> Result<String> {
+        let key = context_key(&self.queue_name, job_id, name);
+        let stored = match &self.cipher {
+            Some(cipher) => cipher.seal_bytes(contents)?.into_bytes(),
+            None => contents.to_vec(),
+        };
+        self.connection
+            .set::<_, _, ()>(&key, stored)
+            .await
+            .context("Failed to store context file")?;
+
+        debug!("Stored context file {} for job: {}", name, job_id);
+        Ok(key)
+    }
+
+    /// Read a context file stored in Redis
+    pub async fn get_context(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let stored: Option<Vec<u8>> = self
+            .connection
+            .get(key)
+            .await
+            .context("Failed to read context file")?;
+        let Some(stored) = stored else {
+            return Ok(None);
+        };
+        let encrypted = std::str::from_utf8(&stored).is_ok_and(crypto::is_encrypted);
+        match &self.cipher {
+            Some(cipher) => {
+                let sealed = String::from_utf8(stored).context("Context file is not encrypted")?;
+                cipher.open_bytes(&sealed).map(Some)
+            }
+            None if encrypted => {
+                bail!("Context file is encrypted; set JOB_ENCRYPTION_KEY to read it")
+            }
+            None => Ok(Some(stored)),
+        }
+    }
+
+    /// Store the result of a completed job
+    pub async fn store_result(&mut self, result: &JobResult) -> Result<()> {
+        let result_json = self.encode(result)
+            .context("Failed to serialize job result")?;
+
+        let key = self.result_key(&result.job_id);
+        // Approving pushes the stored changes, so they mustn't expire first
+        match self.result_ttl.filter(|_| !result.awaiting_approval) {
+            Some(ttl) => self
+                .connection
+                .set_ex::<_, _, ()>(key, &result_json, ttl.as_secs().max(1))
+                .await,
+            None => self.connection.set::<_, _, ()>(key, &result_json).await,
+        }
+        .context("Failed to store job result")?;
+
+        debug!("Stored result for job: {}", result.job_id);
+        Ok(())
+    }
+
+    fn cached_result_key(&self, cache_key: &str) -> String {
+        format!("{}_cache:{}", self.queue_name, cache_key)
+    }
+
+    /// Cache a job's result under its content address for `ttl`
+    pub async fn cache_result(
+        &mut self,
+        cache_key: &str,
+        result: &JobResult,
+        ttl: Duration,
+    ) -> Result<()> {
+        let result_json = self.encode(result)
+            .context("Failed to serialize job result")?;
+
+        self.connection
+            .set_ex::<_, _, ()>(self.cached_result_key(cache_key), &result_json, ttl.as_secs())
+            .await
+            .context("Failed to cache job result")?;
+
+        debug!("Cached result of job {} as {}", result.job_id, cache_key);
+        Ok(())
+    }
+
+    /// Result cached under a content address, if it hasn't expired
+    pub async fn cached_result(&mut self, cache_key: &str) -> Result<Option<JobResult>> {
+        let result_json: Option<String> = self
+            .connection
+            .get(self.cached_result_key(cache_key))
+            .await
+            .context("Failed to read cached result")?;
+
+        result_json
+            .map(|json| self.decode(&json).context("Failed to deserialize cached result"))
+            .transpose()
+    }
+
+    /// Get the stored result of a job, if it has completed
+    pub async fn get_result(&mut self, job_id: &str) -> Result<Option<JobResult>> {
+        let result_json: Option<String> = self
+            .connection
+            .get(self.result_key(job_id))
+            .await
+            .context("Failed to read job result")?;
+
+        result_json
+            .map(|json| self.decode(&json).context("Failed to deserialize job result"))
+            .transpose()
+    }
+
+    fn checkpoint_key(&self, job_id: &str) -> String {
+        format!("{}_checkpoint:{}", self.queue_name, job_id)
+    }
+
+    /// Keep the changes of a job whose push failed for `ttl`, so its next
+    /// attempt pushes them instead of running the agent again
+    pub async fn store_checkpoint(&mut self, result: &JobResult, ttl: Duration) -> Result<()> {
+        let result_json = self.encode(result)
+            .context("Failed to serialize push checkpoint")?;
+
+        self.connection
+            .set_ex::<_, _, ()>(self.checkpoint_key(&result.job_id), &result_json, ttl.as_secs())
+            .await
+            .context("Failed to store push checkpoint")?;
+
+        debug!("Stored push checkpoint for job: {}", result.job_id);
+        Ok(())
+    }
+
+    /// Changes a job committed before its push failed, if they are still kept
+    pub async fn checkpoint(&mut self, job_id: &str) -> Result<Option<JobResult>> {
+        let result_json: Option<String> = self
+            .connection
+            .get(self.checkpoint_key(job_id))
+            .await
+            .context("Failed to read push checkpoint")?;
+
+        result_json
+            .map(|json| self.decode(&json).context("Failed to deserialize push checkpoint"))
+            .transpose()
+    }
+
+    /// Drop a job's push checkpoint once its changes are pushed
+    pub async fn clear_checkpoint(&mut self, job_id: &str) -> Result<()> {
+        self.connection
+            .del::<_, ()>(self.checkpoint_key(job_id))
+            .await
+            .context("Failed to remove push checkpoint")
+    }
+
+    fn salvage_key(&self, job_id: &str) -> String {
+        format!("{}_salvage:{}", self.queue_name, job_id)
+    }
+
+    /// Store the work salvaged from a failed attempt, replacing that of any
+    /// earlier attempt
+    pub async fn store_salvage(&mut self, salvage: &Salvage) -> Result<()> {
+        let salvage_json = self.encode(salvage)
+            .context("Failed to serialize salvaged work")?;
+
+        self.connection
+            .set::<_, _, ()>(self.salvage_key(&salvage.job_id), &salvage_json)
+            .await
+            .context("Failed to store salvaged work")?;
+        self.update_status(&salvage.job_id, &[("salvage_branch", salvage.branch.clone())])
+            .await;
+
+        debug!("Stored salvaged work for job: {}", salvage.job_id);
+        Ok(())
+    }
+
+    /// Get the work salvaged from a job's last failed attempt, if any
+    pub async fn get_salvage(&mut self, job_id: &str) -> Result<Option<Salvage>> {
+        let salvage_json: Option<String> = self
+            .connection
+            .get(self.salvage_key(job_id))
+            .await
+            .context("Failed to read salvaged work")?;
+
+        salvage_json
+            .map(|json| self.decode(&json).context("Failed to deserialize salvaged work"))
+            .transpose()
+    }
+
+    /// Find a job by ID in one of the queue's lists, returning its raw JSON
+    /// Entries that fail to deserialize are skipped
+    async fn find_in_list(&mut self, list: &str, job_id: &str) -> Result<Option<(String, Job)>> {
+        let entries: Vec<String> = self
+            .connection
+            .lrange(list, 0, -1)
+            .await
+            .with_context(|| format!("Failed to read list {}", list))?;
+
+        Ok(entries.into_iter().find_map(|job_json| {
+            self.decode_job(&job_json)
+                .ok()
+                .filter(|job| job.id == job_id)
+                .map(|job| (job_json, job))
+        }))
+    }
+
+    /// Cancel a job: pending jobs are removed from the queue, in-flight jobs
+    /// are flagged and stopped by the worker at its next checkpoint
+    pub async fn cancel(&mut self, job_id: &str) -> Result<CancelOutcome> {
+        let queue_name = self.queue_name.clone();
+        if let Some((job_json, _)) = self.find_in_list(&queue_name, job_id).await? {
+            let removed: i32 = self
+                .connection
+                .lrem(&self.queue_name, 1, &job_json)
+                .await
+                .context("Failed to remove job from queue")?;
+
+            if removed > 0 {
+                self.mark_cancelled_pending(job_id, &job_json).await;
+                return Ok(CancelOutcome::Removed);
+            }
+        }
+
+        let delayed: Vec<String> = self
+            .connection
+            .zrange(self.delayed_queue_name(), 0, -1)
+            .await
+            .context("Failed to read delayed jobs")?;
+        let delayed_json = delayed.into_iter().find(|job_json| {
+            self.decode_job(job_json)
+                .is_ok_and(|job| job.id == job_id)
+        });
+        if let Some(job_json) = delayed_json {
+            let removed: i32 = self
+                .connection
+                .zrem(self.delayed_queue_name(), &job_json)
+                .await
+                .context("Failed to remove delayed job")?;
+            if removed > 0 {
+                self.mark_cancelled_pending(job_id, &job_json).await;
+                return Ok(CancelOutcome::Removed);
+            }
+        }
+
+        if self.find_processing(job_id).await?.is_some() {
+            self.connection
+                .hset::<_, _, _, ()>(self.status_key(job_id), "cancel_requested", "1")
+                .await
+                .context("Failed to flag job for cancellation")?;
+            info!("Flagged in-flight job for cancellation: {}", job_id);
+            return Ok(CancelOutcome::Flagged);
+        }
+
+        Ok(CancelOutcome::NotFound)
+    }
+
+    async fn mark_cancelled_pending(&mut self, job_id: &str, job_json: &str) {
+        self.update_status(
+            job_id,
+            &[
+                ("state", JobState::Cancelled.as_str().to_string()),
+                ("finished_at", now_secs().to_string()),
+            ],
+        )
+        .await;
+        self.record_event(job_id, TimelineEvent::Cancelled).await;
+        self.record_op(OplogOp::Cancel, job_id, job_json).await;
+        info!("Cancelled pending job: {}", job_id);
+    }
+
+    /// Check whether cancellation has been requested for an in-flight job
+    pub async fn is_cancel_requested(&mut self, job_id: &str) -> Result<bool> {
+        let flag: Option<String> = self
+            .connection
+            .hget(self.status_key(job_id), "cancel_requested")
+            .await
+            .context("Failed to read cancellation flag")?;
+        Ok(flag.as_deref() == Some("1"))
+    }
+
+    /// Remove a job that has exhausted its retries from the processing queue
+    pub async fn fail(&mut self, job: &Job) -> Result<()> {
+        let job_json = self.encode_job(job)?;
+
+        self.connection
+            .lrem::<_, _, ()>(&self.processing_queue_name, 1, &job_json)
+            .await
+            .context("Failed to remove job from processing queue")?;
+
+        self.update_status(
+            &job.id,
+            &[
+                ("state", JobState::Failed.as_str().to_string()),
+                ("finished_at", now_secs().to_string()),
+            ],
+        )
+        .await;
+        self.record_event(&job.id, TimelineEvent::Failed).await;
+        self.record_op(OplogOp::Fail, &job.id, &job_json).await;
+
+        error!("Giving up on job: {}", job.id);
+        Ok(())
+    }
+
+    /// Statuses of jobs given up on at or after `since` (Unix seconds),
+    /// oldest first; dead-lettered jobs are listed by `list_dead` instead
+    pub async fn failed_since(&mut self, since: u64) -> Result<Vec<JobStatus>> {
+        let prefix = format!("{}_status:", self.queue_name);
+        let mut job_ids = Vec::new();
+        {
+            let mut iter: redis::AsyncIter<String> = self
+                .connection
+                .scan_match(format!("{}*", prefix))
+                .await
+                .context("Failed to scan job statuses")?;
+            while let Some(key) = iter.next_item().await {
+                job_ids.push(key[prefix.len()..].to_string());
+            }
+        }
+
+        let mut failed: Vec<JobStatus> = self
+            .get_statuses(&job_ids)
+            .await?
+            .into_iter()
+            .filter(|status| {
+                status.state == Some(JobState::Failed)
+                    && status.dead_reason.is_none()
+                    && status.finished_at.is_some_and(|at| at >= since)
+            })
+            .collect();
+
+        failed.sort_by_key(|status| status.finished_at);
+        Ok(failed)
+    }
+
+    fn dead_queue_name(&self) -> String {
+        format!("{}_dead", self.queue_name)
+    }
+
+    fn invalid_queue_name(&self) -> String {
+        format!("{}_invalid", self.queue_name)
+    }
+
+    /// Move an in-flight job to the dead-letter queue instead of running it
+    pub async fn dead_letter(&mut self, job: &Job, reason: DeadReason) -> Result<()> {
+        self.dead_letter_with_detail(job, reason, None).await
+    }
+
+    /// Like `dead_letter`, recording `detail` in the dead letter and as the
+    /// job's last error
+    pub async fn dead_letter_with_detail(
+        &mut self,
+        job: &Job,
+        reason: DeadReason,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        let job_json = self.encode_job(job)?;
+        let dead_at = now_secs();
+        let failures = match self.get_status(&job.id).await {
+            Ok(status) => status.map(|status| status.failures).unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to read failures of job {}: {:#}", job.id, e);
+                Vec::new()
+            }
+        };
+        let letter = DeadLetter {
+            job: job.clone(),
+            reason,
+            dead_at,
+            detail: detail.map(str::to_string),
+            failures,
+        };
+        let letter_json = self.encode(&letter)
+            .context("Failed to serialize dead letter")?;
+
+        redis::pipe()
+            .atomic()
+            .lrem(&self.processing_queue_name, 1, &job_json)
+            .ignore()
+            .lpush(self.dead_queue_name(), &letter_json)
+            .ignore()
+            .query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to move job to the dead-letter queue")?;
+
+        self.update_status(
+            &job.id,
+            &[
+                ("state", JobState::Failed.as_str().to_string()),
+                ("finished_at", dead_at.to_string()),
+                ("dead_reason", reason.as_str().to_string()),
+            ],
+        )
+        .await;
+        if let Some(detail) = detail {
+            self.update_status(&job.id, &[("last_error", detail.to_string())])
+                .await;
+        }
+        self.record_event(&job.id, TimelineEvent::DeadLettered).await;
+        self.record_op(OplogOp::Fail, &job.id, &job_json).await;
+
+        warn!("Moved job {} to the dead-letter queue ({})", job.id, reason.as_str());
+        Ok(())
+    }
+
+    /// Jobs in the dead-letter queue, oldest first
+    pub async fn list_dead(&mut self) -> Result<Vec<DeadLetter>> {
+        let entries: Vec<String> = self
+            .connection
+            .lrange(self.dead_queue_name(), 0, -1)
+            .await
+            .context("Failed to read dead-letter queue")?;
+
+        Ok(entries
+            .iter()
+            .rev()
+            .filter_map(|letter_json| match self.decode(letter_json) {
+                Ok(letter) => Some(letter),
+                Err(e) => {
+                    warn!("Skipping malformed dead letter: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Remove every job that can be read from the dead-letter queue,
+    /// returning them oldest first
+    ///
+    /// Letters that can't be decoded, such as encrypted ones without the
+    /// key, are left in the queue rather than lost.
+    pub async fn drain_dead(&mut self) -> Result<Vec<DeadLetter>> {
+        let entries: Vec<String> = self
+            .connection
+            .lrange(self.dead_queue_name(), 0, -1)
+            .await
+            .context("Failed to read dead-letter queue")?;
+        let letters: Vec<(String, DeadLetter)> = entries
+            .into_iter()
+            .rev()
+            .filter_map(|letter_json| match self.decode(&letter_json) {
+                Ok(letter) => Some((letter_json, letter)),
+                Err(e) => {
+                    warn!("Leaving unreadable dead letter in the queue: {:#}", e);
+                    None
+                }
+            })
+            .collect();
+        if letters.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (letter_json, _) in &letters {
+            pipe.lrem(self.dead_queue_name(), 1, letter_json);
+        }
+        let removed: Vec<usize> = pipe
+            .query_async(&mut self.connection)
+            .await
+            .context("Failed to drain dead-letter queue")?;
+
+        // A letter another client drained first isn't returned twice
+        Ok(letters
+            .into_iter()
+            .zip(removed)
+            .filter(|(_, removed)| *removed > 0)
+            .map(|((_, letter), _)| letter)
+            .collect())
+    }
+
+    /// Move job `job_id` from the dead-letter queue back to the pending
+    /// queue with its attempts reset, so its retry limit starts over
+    /// Returns whether the job was in the dead-letter queue
+    pub async fn redrive_dead(&mut self, job_id: &str) -> Result<bool> {
+        let entries: Vec<String> = self
+            .connection
+            .lrange(self.dead_queue_name(), 0, -1)
+            .await
+            .context("Failed to read dead-letter queue")?;
+        let Some((letter_json, letter)) = entries.into_iter().find_map(|letter_json| {
+            let letter: DeadLetter = self.decode(&letter_json).ok()?;
+            (letter.job.id == job_id).then_some((letter_json, letter))
+        }) else {
+            return Ok(false);
+        };
+
+        let removed: usize = self
+            .connection
+            .lrem(self.dead_queue_name(), 1, &letter_json)
+            .await
+            .context("Failed to remove job from the dead-letter queue")?;
+        if removed == 0 {
+            // Redriven or drained by someone else in the meantime
+            return Ok(false);
+        }
+        self.connection
+            .hdel::<_, _, ()>(self.status_key(job_id), &["attempts", "dead_reason", "retry_at"])
+            .await
+            .context("Failed to reset job status")?;
+        if let Err(e) = self.enqueue(&letter.job).await {
+            // Keep the job dead rather than lose it, e.g. when rate limited
+            let _: redis::RedisResult<()> =
+                self.connection.rpush(self.dead_queue_name(), &letter_json).await;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Get dead-letter queue length
+    pub async fn dead_len(&mut self) -> Result<usize> {
+        let len: usize = self
+            .connection
+            .llen(self.dead_queue_name())
+            .await
+            .context("Failed to get dead-letter queue length")?;
+        Ok(len)
+    }
+
+    /// Remove a cancelled in-flight job from the processing queue
+    pub async fn finish_cancelled(&mut self, job: &Job) -> Result<()> {
+        let job_json = self.encode_job(job)?;
+
+        self.connection
+            .lrem::<_, _, ()>(&self.processing_queue_name, 1, &job_json)
+            .await
+            .context("Failed to remove job from processing queue")?;
+
+        self.update_status(
+            &job.id,
+            &[
+                ("state", JobState::Cancelled.as_str().to_string()),
+                ("finished_at", now_secs().to_string()),
+            ],
+        )
+        .await;
+        self.record_event(&job.id, TimelineEvent::Cancelled).await;
+        self.record_op(OplogOp::Cancel, &job.id, &job_json).await;
+
+        info!("Cancelled job: {}", job.id);
+        Ok(())
+    }
+
+    /// Remove a job whose changes await approval from the processing queue,
+    /// leaving its result stored for review
+    pub async fn await_approval(&mut self, job: &Job) -> Result<()> {
+        let job_json = self.encode_job(job)?;
+
+        self.connection
+            .lrem::<_, _, ()>(&self.processing_queue_name, 1, &job_json)
+            .await
+            .context("Failed to remove job from processing queue")?;
+
+        self.update_status(
+            &job.id,
+            &[("state", JobState::AwaitingApproval.as_str().to_string())],
+        )
+        .await;
+        self.record_event(&job.id, TimelineEvent::AwaitingApproval).await;
+
+        info!("Job awaits approval: {}", job.id);
+        Ok(())
+    }
+
+    /// Atomically move a job from state `from` to `to`, returning whether
+    /// it was in state `from`
+    async fn transition(&mut self, job_id: &str, from: JobState, to: JobState) -> Result<bool> {
+        redis::Script::new(TRANSITION_SCRIPT)
+            .key(self.status_key(job_id))
+            .arg(from.as_str())
+            .arg(to.as_str())
+            .invoke_async(&mut self.connection)
+            .await
+            .context("Failed to update job state")
+    }
+
+    /// Enqueue a job awaiting approval again for a worker to push its stored
+    /// changes, returning false if it doesn't await approval
+    pub async fn approve(&mut self, job_id: &str) -> Result<bool> {
+        let job = self
+            .get_job(job_id)
+            .await?
+            .with_context(|| format!("No job definition recorded for job {}", job_id))?;
+        // Claim the job first, so approving it twice can't push twice
+        if !self
+            .transition(job_id, JobState::AwaitingApproval, JobState::Pending)
+            .await?
+        {
+            return Ok(false);
+        }
+        self.record_event(job_id, TimelineEvent::Approved).await;
+
+        let approved = Job {
+            options: JobOptions {
+                require_approval: false,
+                approved: true,
+                ..job.options.clone()
+            },
+            ..job
+        };
+        if let Err(e) = self.enqueue(&approved).await {
+            // Leave it awaiting approval, so it can be approved again
+            self.update_status(
+                job_id,
+                &[("state", JobState::AwaitingApproval.as_str().to_string())],
+            )
+            .await;
+            return Err(e);
+        }
+
+        info!("Approved job: {}", job_id);
+        Ok(true)
+    }
+
+    /// Discard the changes of a job awaiting approval, marking it cancelled;
+    /// returns false if it doesn't await approval
+    pub async fn reject(&mut self, job_id: &str) -> Result<bool> {
+        if !self
+            .transition(job_id, JobState::AwaitingApproval, JobState::Cancelled)
+            .await?
+        {
+            return Ok(false);
+        }
+        self.update_status(job_id, &[("finished_at", now_secs().to_string())])
+            .await;
+        self.record_event(job_id, TimelineEvent::Rejected).await;
+
+        info!("Rejected job: {}", job_id);
+        Ok(true)
+    }
+
+    /// Reliably dequeue a job, moving it from the main queue to this
+    /// worker's processing list and leasing it in one step
+    ///
+    /// The lease, the job's worker, attempt number and lease deadline in its
+    /// status hash, is written by the same script that moves the job, so
+    /// crash recovery never finds a job without one. Redis can't read
+    /// encrypted payloads, so those are leased right after instead.
+    pub async fn dequeue(&mut self) -> Result<Option<Job>> {
+        debug!("Attempting to dequeue job from {}", self.queue_name);
+        // A timeout of 0 waits for a job indefinitely
+        let deadline = (self.timeout_seconds > 0)
+            .then(|| Instant::now() + Duration::from_secs(self.timeout_seconds));
+
+        loop {
+            self.promote_delayed().await?;
+            if let Some((entry, attempt)) = self.lease_next().await? {
+                match self.take_dequeued(&entry, attempt).await? {
+                    Some(job) => return Ok(Some(job)),
+                    // The entry was quarantined; take the next one
+                    None => continue,
+                }
+            }
+
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining.is_some_and(|remaining| remaining.is_zero()) {
+                debug!("No job available in queue");
+                return Ok(None);
+            }
+            if !self.label_selector.is_empty() {
+                // Nothing to block on for a job with the right labels
+                let poll = remaining.map_or(SELECTOR_POLL_INTERVAL, |remaining| {
+                    remaining.min(SELECTOR_POLL_INTERVAL)
+                });
+                tokio::time::sleep(poll).await;
+                continue;
+            }
+
+            // Don't block past the moment the next delayed retry is due
+            let mut wait = remaining.map_or(0, |remaining| remaining.as_secs().max(1));
+            if let Some(due_at) = self.next_delayed_at().await? {
+                let due_in = due_at.saturating_sub(now_secs()).max(1);
+                wait = if wait == 0 { due_in } else { wait.min(due_in) };
+            }
+            // Block until a job arrives without taking it: moving the tail of
+            // the queue back onto its tail leaves the queue as it was
+            let _: Option<String> = self
+                .connection
+                .blmove(
+                    &self.queue_name,
+                    &self.queue_name,
+                    Direction::Right,
+                    Direction::Right,
+                    wait as f64,
+                )
+                .await
+                .context("Failed to wait for a job")?;
+        }
+    }
+
+    /// Like `dequeue`, but return at once when no job is pending
+    pub async fn try_dequeue(&mut self) -> Result<Option<Job>> {
+        self.promote_delayed().await?;
+        while let Some((entry, attempt)) = self.lease_next().await? {
+            if let Some(job) = self.take_dequeued(&entry, attempt).await? {
+                return Ok(Some(job));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Move the oldest pending job, or the oldest matching the label
+    /// selector, to the processing list and lease it, returning it and its
+    /// attempt number, which is 0 for an encrypted job that isn't leased yet
+    ///
+    /// The entry is returned as raw bytes, as a malformed one may not even
+    /// be UTF-8.
+    async fn lease_next(&mut self) -> Result<Option<(Vec<u8>, u32)>> {
+        let script = redis::Script::new(DEQUEUE_SCRIPT);
+        let mut invocation = script.key(&self.queue_name);
+        invocation
+            .key(&self.processing_queue_name)
+            .arg(self.status_key(""))
+            .arg(now_secs())
+            .arg(DEFAULT_LEASE_SECS)
+            .arg(self.worker_id.as_deref().unwrap_or_default())
+            .arg(JobState::Running.as_str());
+        for (key, value) in &self.label_selector {
+            invocation.arg(key).arg(value);
+        }
+        invocation
+            .invoke_async(&mut self.connection)
+            .await
+            .context("Failed to dequeue job")
+    }
+
+    /// Parse an entry `lease_next` moved to the processing list, or
+    /// quarantine it and return `None` if it isn't a job
+    ///
+    /// An encrypted entry this worker can't decrypt still fails: that's
+    /// more likely a missing or wrong key than a bad entry, and the key
+    /// would be wrong for every job.
+    async fn take_dequeued(&mut self, entry: &[u8], attempt: u32) -> Result<Option<Job>> {
+        let parsed = match std::str::from_utf8(entry) {
+            Ok(job_json) => {
+                debug!("Dequeued job: {}", job_json);
+                let json = self.open(job_json).context("Failed to deserialize job")?;
+                serde_json::from_str::<Job>(&json).map_err(|e| e.to_string())
+            }
+            Err(e) => Err(format!("Entry is not UTF-8: {}", e)),
+        };
+        let job = match parsed {
+            Ok(job) => job,
+            Err(error) => {
+                self.quarantine(entry, &error).await?;
+                return Ok(None);
+            }
+        };
+
+        info!("Successfully dequeued job: {}", job.id);
+        if attempt == 0 {
+            self.mark_running(&job).await;
+        }
+        self.record_event(&job.id, TimelineEvent::Dequeued).await;
+        Ok(Some(job))
+    }
+
+    /// Move an entry that isn't a job from the processing list to
+    /// `{queue_name}_invalid`, keeping its raw bytes and why it was rejected
+    async fn quarantine(&mut self, entry: &[u8], error: &str) -> Result<()> {
+        let invalid_json = self.encode(&InvalidEntry::new(entry, error))?;
+        redis::pipe()
+            .atomic()
+            .lrem(&self.processing_queue_name, 1, entry)
+            .ignore()
+            .lpush(self.invalid_queue_name(), invalid_json)
+            .ignore()
+            .ltrim(self.invalid_queue_name(), 0, INVALID_MAX_LEN as isize - 1)
+            .ignore()
+            .query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to quarantine invalid queue entry")?;
+
+        warn!(
+            "Moved an entry that isn't a job to {}: {}",
+            self.invalid_queue_name(),
+            error
+        );
+        Ok(())
+    }
+
+    /// Entries quarantined because they couldn't be read as jobs, newest
+    /// first
+    pub async fn list_invalid(&mut self) -> Result<Vec<InvalidEntry>> {
+        let entries: Vec<String> = self
+            .connection
+            .lrange(self.invalid_queue_name(), 0, -1)
+            .await
+            .context("Failed to read invalid entries")?;
 
-        Ok(Self {
-            connection,
-            queue_name: queue_name.to_string(),
-            processing_queue_name: format!("{}_processing", queue_name),
-            timeout_seconds,
-        })
+        Ok(entries
+            .iter()
+            .filter_map(|invalid_json| match self.decode(invalid_json) {
+                Ok(invalid) => Some(invalid),
+                Err(e) => {
+                    warn!("Skipping unreadable invalid entry: {}", e);
+                    None
+                }
+            })
+            .collect())
     }
 
-    /// Reliably dequeue a job using RPOPLPUSH pattern
-    /// This moves the job from the main queue to a processing queue
-    pub async fn dequeue(&mut self) -> Result<Option<Job>> {
-        debug!("Attempting to dequeue job from {}", self.queue_name);
+    /// Lease a job the dequeue script couldn't read, as that script would
+    async fn mark_running(&mut self, job: &Job) {
+        let now = now_secs();
+        let mut fields = vec![
+            ("state", JobState::Running.as_str().to_string()),
+            ("started_at", now.to_string()),
+            ("lease_deadline", (now + job.lease_secs()).to_string()),
+        ];
+        if let Some(worker_id) = &self.worker_id {
+            fields.push(("worker", worker_id.clone()));
+        }
+        self.update_status(&job.id, &fields).await;
 
-        // Use BRPOPLPUSH for blocking reliable dequeue
-        let result: Option<String> = self
+        if let Err(e) = self
+            .connection
+            .hincr::<_, _, _, ()>(self.status_key(&job.id), "attempts", 1)
+            .await
+        {
+            warn!("Failed to count attempt for job {}: {}", job.id, e);
+        }
+        // Progress belongs to the attempt that reported it
+        if let Err(e) = self
             .connection
-            .brpoplpush(
-                &self.queue_name,
-                &self.processing_queue_name,
-                self.timeout_seconds as f64,
+            .hdel::<_, _, ()>(
+                self.status_key(&job.id),
+                &["progress_step", "progress_percent", "progress_message", "retry_at"],
             )
             .await
-            .context("Failed to execute BRPOPLPUSH")?;
-
-        match result {
-            Some(job_json) => {
-                debug!("Dequeued job: {}", job_json);
-                let job: Job = serde_json::from_str(&job_json)
-                    .context("Failed to deserialize job")?;
-                info!("Successfully dequeued job: {}", job.id);
-                Ok(Some(job))
-            }
-            None => {
-                debug!("No job available in queue");
-                Ok(None)
-            }
+        {
+            warn!("Failed to clear progress of job {}: {}", job.id, e);
         }
     }
 
     /// Enqueue a job to the main queue
     pub async fn enqueue(&mut self, job: &Job) -> Result<()> {
-        let job_json = serde_json::to_string(job)
-            .context("Failed to serialize job")?;
+        let job_json = self.encode_job(job)?;
+        self.check_payload_sizes(&[(job, &job_json)]).await?;
+        self.check_rate_limit(1).await?;
+        let oplog = self.oplog_enabled().await?;
 
-        self.connection
-            .lpush::<_, _, ()>(&self.queue_name, &job_json)
+        let mut pipe = redis::pipe();
+        pipe.atomic().lpush(&self.queue_name, &job_json).ignore();
+        self.index_labels(&mut pipe, job);
+        if oplog {
+            self.append_op(&mut pipe, OplogOp::Enqueue, &job.id, &job_json);
+        }
+        pipe.query_async::<()>(&mut self.connection)
             .await
             .context("Failed to enqueue job")?;
 
+        self.update_status(
+            &job.id,
+            &[
+                ("state", JobState::Pending.as_str().to_string()),
+                ("enqueued_at", now_secs().to_string()),
+                ("job", job_json),
+            ],
+        )
+        .await;
+        self.record_event(&job.id, TimelineEvent::Enqueued).await;
+
         info!("Enqueued job: {}", job.id);
         Ok(())
     }
 
+    /// Enqueue a job unless a job with its ID was enqueued before, for
+    /// sources that may deliver the same job more than once
+    /// Returns whether the job was enqueued
+    pub async fn enqueue_unique(&mut self, job: &Job) -> Result<bool> {
+        let job_json = self.encode_job(job)?;
+        let status_key = self.status_key(&job.id);
+
+        // Duplicates don't count against the rate limit
+        let seen: bool = self
+            .connection
+            .exists(&status_key)
+            .await
+            .context("Failed to check for duplicate job")?;
+        if seen {
+            debug!("Skipping duplicate job: {}", job.id);
+            return Ok(false);
+        }
+        self.check_payload_sizes(&[(job, &job_json)]).await?;
+        self.check_rate_limit(1).await?;
+
+        let script = redis::Script::new(ENQUEUE_UNIQUE_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(&self.queue_name).key(&status_key);
+        for (key, value) in &job.labels {
+            invocation.key(self.label_key(key, value));
+        }
+        let enqueued: bool = invocation
+            .arg(&job_json)
+            .arg(now_secs())
+            .arg(&job.id)
+            .arg(JobState::Pending.as_str())
+            .invoke_async(&mut self.connection)
+            .await
+            .context("Failed to enqueue job")?;
+
+        if enqueued {
+            self.record_event(&job.id, TimelineEvent::Enqueued).await;
+            self.record_op(OplogOp::Enqueue, &job.id, &job_json).await;
+            info!("Enqueued job: {}", job.id);
+        } else {
+            debug!("Skipping duplicate job: {}", job.id);
+        }
+        Ok(enqueued)
+    }
+
+    /// Enqueue several jobs in one atomic round trip
+    /// Jobs are dequeued in the order given
+    pub async fn enqueue_batch(&mut self, jobs: &[Job]) -> Result<()> {
+        if jobs.is_empty() {
+            return Ok(());
+        }
+        let encoded: Vec<String> = jobs
+            .iter()
+            .map(|job| self.encode_job(job))
+            .collect::<Result<_>>()?;
+        let sizes: Vec<(&Job, &str)> = jobs
+            .iter()
+            .zip(&encoded)
+            .map(|(job, job_json)| (job, job_json.as_str()))
+            .collect();
+        self.check_payload_sizes(&sizes).await?;
+        self.check_rate_limit(jobs.len()).await?;
+        let oplog = self.oplog_enabled().await?;
+
+        let enqueued_at = now_secs().to_string();
+        let event = serde_json::to_string(&TimelineEntry::now(TimelineEvent::Enqueued))
+            .context("Failed to serialize timeline event")?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (job, job_json) in jobs.iter().zip(&encoded) {
+            pipe.lpush(&self.queue_name, job_json)
+                .ignore()
+                .hset_multiple(
+                    self.status_key(&job.id),
+                    &[
+                        ("state", JobState::Pending.as_str()),
+                        ("enqueued_at", enqueued_at.as_str()),
+                        ("job", job_json.as_str()),
+                    ],
+                )
+                .ignore()
+                .rpush(self.timeline_key(&job.id), &event)
+                .ignore();
+            self.index_labels(&mut pipe, job);
+            if oplog {
+                self.append_op(&mut pipe, OplogOp::Enqueue, &job.id, job_json);
+            }
+        }
+
+        pipe.query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to enqueue jobs")?;
+
+        info!("Enqueued {} jobs", jobs.len());
+        Ok(())
+    }
+
     /// Acknowledge successful job processing by removing from processing queue
     pub async fn ack(&mut self, job: &Job) -> Result<()> {
-        let job_json = serde_json::to_string(job)
-            .context("Failed to serialize job")?;
+        let job_json = self.encode_job(job)?;
 
         let removed: i32 = self
             .connection
@@ -96,6 +2579,17 @@ impl ReliableQueue {
             .context("Failed to remove job from processing queue")?;
 
         if removed > 0 {
+            self.update_status(
+                &job.id,
+                &[
+                    ("state", JobState::Succeeded.as_str().to_string()),
+                    ("finished_at", now_secs().to_string()),
+                ],
+            )
+            .await;
+            self.increment_counter("succeeded").await;
+            self.record_event(&job.id, TimelineEvent::Acked).await;
+            self.record_op(OplogOp::Ack, &job.id, &job_json).await;
             info!("Successfully acknowledged job: {}", job.id);
         } else {
             warn!("Job not found in processing queue: {}", job.id);
@@ -104,10 +2598,22 @@ impl ReliableQueue {
         Ok(())
     }
 
-    /// Move a failed job back to the main queue for retry
-    pub async fn nack(&mut self, job: &Job) -> Result<()> {
-        let job_json = serde_json::to_string(job)
-            .context("Failed to serialize job")?;
+    /// Record why an attempt failed and move the job back to the main
+    /// queue for retry
+    pub async fn nack(&mut self, job: &Job, class: ErrorClass, error: &str) -> Result<()> {
+        self.nack_after(job, class, error, Duration::ZERO).await
+    }
+
+    /// Like `nack`, but keep the job out of the queue until `delay` has
+    /// passed
+    pub async fn nack_after(
+        &mut self,
+        job: &Job,
+        class: ErrorClass,
+        error: &str,
+        delay: Duration,
+    ) -> Result<()> {
+        let job_json = self.encode_job(job)?;
 
         // Remove from processing queue
         let removed: i32 = self
@@ -117,13 +2623,29 @@ impl ReliableQueue {
             .context("Failed to remove job from processing queue")?;
 
         if removed > 0 {
-            // Re-enqueue to main queue
-            self.connection
-                .lpush::<_, _, ()>(&self.queue_name, &job_json)
-                .await
-                .context("Failed to re-enqueue job")?;
+            self.record_failure(job, class, error).await;
+
+            let mut fields = vec![("state", JobState::Pending.as_str().to_string())];
+            if delay.is_zero() {
+                // Re-enqueue to main queue
+                self.connection
+                    .lpush::<_, _, ()>(&self.queue_name, &job_json)
+                    .await
+                    .context("Failed to re-enqueue job")?;
+                warn!("Job moved back to main queue for retry: {}", job.id);
+            } else {
+                let retry_at = now_secs() + delay.as_secs().max(1);
+                self.connection
+                    .zadd::<_, _, _, ()>(self.delayed_queue_name(), &job_json, retry_at)
+                    .await
+                    .context("Failed to delay job")?;
+                fields.push(("retry_at", retry_at.to_string()));
+                warn!("Job {} will be retried in {}s", job.id, delay.as_secs().max(1));
+            }
 
-            warn!("Job moved back to main queue for retry: {}", job.id);
+            self.update_status(&job.id, &fields).await;
+            self.record_event(&job.id, TimelineEvent::Nacked).await;
+            self.record_op(OplogOp::Nack, &job.id, &job_json).await;
         } else {
             error!("Job not found in processing queue during NACK: {}", job.id);
         }
@@ -131,15 +2653,387 @@ impl ReliableQueue {
         Ok(())
     }
 
-    /// Recover jobs from processing queue (e.g., after a crash)
+    /// Sorted set of nacked jobs waiting out their retry delay, scored by
+    /// when they are due
+    fn delayed_queue_name(&self) -> String {
+        format!("{}_delayed", self.queue_name)
+    }
+
+    /// Move delayed jobs whose retry is due onto the main queue
+    pub async fn promote_delayed(&mut self) -> Result<usize> {
+        let promoted: usize = redis::Script::new(PROMOTE_DELAYED_SCRIPT)
+            .key(self.delayed_queue_name())
+            .key(&self.queue_name)
+            .arg(now_secs())
+            .arg(PROMOTE_BATCH)
+            .invoke_async(&mut self.connection)
+            .await
+            .context("Failed to promote delayed jobs")?;
+        if promoted > 0 {
+            debug!("Promoted {} delayed jobs", promoted);
+        }
+        Ok(promoted)
+    }
+
+    /// When the next delayed job is due, if any
+    async fn next_delayed_at(&mut self) -> Result<Option<u64>> {
+        let next: Vec<(String, u64)> = self
+            .connection
+            .zrange_withscores(self.delayed_queue_name(), 0, 0)
+            .await
+            .context("Failed to read delayed jobs")?;
+        Ok(next.into_iter().next().map(|(_, due_at)| due_at))
+    }
+
+    /// Delayed jobs, the one due first first, with when they are due
+    pub async fn list_delayed(&mut self) -> Result<Vec<(Job, u64)>> {
+        let entries: Vec<(String, u64)> = self
+            .connection
+            .zrange_withscores(self.delayed_queue_name(), 0, -1)
+            .await
+            .context("Failed to read delayed jobs")?;
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|(job_json, due_at)| match self.decode_job(&job_json) {
+                Ok(job) => Some((job, due_at)),
+                Err(e) => {
+                    warn!("Skipping malformed delayed job: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Put a dequeued job back at the end of the main queue without counting
+    /// the attempt, e.g. because another job of its concurrency group runs
+    pub async fn postpone(&mut self, job: &Job) -> Result<()> {
+        let job_json = self.encode_job(job)?;
+
+        let removed: i32 = self
+            .connection
+            .lrem(&self.processing_queue_name, 1, &job_json)
+            .await
+            .context("Failed to remove job from processing queue")?;
+        if removed == 0 {
+            warn!("Job not found in processing queue while postponing: {}", job.id);
+            return Ok(());
+        }
+
+        let status_key = self.status_key(&job.id);
+        redis::pipe()
+            .atomic()
+            .lpush(&self.queue_name, &job_json)
+            .ignore()
+            .hset(&status_key, "state", JobState::Pending.as_str())
+            .ignore()
+            .hincr(&status_key, "attempts", -1)
+            .ignore()
+            .query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to postpone job")?;
+        self.record_event(&job.id, TimelineEvent::Postponed).await;
+
+        debug!("Postponed job: {}", job.id);
+        Ok(())
+    }
+
+    /// Move a single job from the processing queue back to the main queue
+    /// Returns false if the job is not in the processing queue
+    pub async fn requeue(&mut self, job_id: &str) -> Result<bool> {
+        let Some((list, job_json)) = self.find_processing(job_id).await? else {
+            return Ok(false);
+        };
+
+        let removed: i32 = self
+            .connection
+            .lrem(&list, 1, &job_json)
+            .await
+            .context("Failed to remove job from processing queue")?;
+
+        if removed == 0 {
+            // Acked or nacked by its worker since we looked
+            return Ok(false);
+        }
+
+        self.connection
+            .lpush::<_, _, ()>(&self.queue_name, &job_json)
+            .await
+            .context("Failed to re-enqueue job")?;
+
+        self.update_status(job_id, &[("state", JobState::Pending.as_str().to_string())])
+            .await;
+
+        info!("Requeued job: {}", job_id);
+        Ok(true)
+    }
+
+    fn migrating_queue_name(&self) -> String {
+        format!("{}_migrating", self.queue_name)
+    }
+
+    /// Raw status hash of a job, empty if none is recorded
+    pub async fn raw_status(&mut self, job_id: &str) -> Result<HashMap<String, String>> {
+        self.connection
+            .hgetall(self.status_key(job_id))
+            .await
+            .context("Failed to read job status")
+    }
+
+    /// Add a job migrated from another queue, keeping its status history
+    /// The job is always imported as pending, even if it was in flight
+    pub async fn import_job(&mut self, job: &Job, status: &HashMap<String, String>) -> Result<()> {
+        let job_json = self.encode_job(job)?;
+        let status_key = self.status_key(&job.id);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().lpush(&self.queue_name, &job_json).ignore();
+        if !status.is_empty() {
+            let fields: Vec<(&String, &String)> = status.iter().collect();
+            pipe.hset_multiple(&status_key, &fields).ignore();
+        }
+        pipe.hset(&status_key, "state", JobState::Pending.as_str())
+            .ignore()
+            .hdel(&status_key, "cancel_requested")
+            .ignore();
+        if !status.contains_key("enqueued_at") {
+            pipe.hset(&status_key, "enqueued_at", now_secs()).ignore();
+        }
+        self.index_labels(&mut pipe, job);
+
+        pipe.query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to import job")?;
+
+        debug!("Imported job: {}", job.id);
+        Ok(())
+    }
+
+    /// Put a job back on the processing list with its status copied as-is,
+    /// as it was when backed up. `recover` moves it back to pending if its
+    /// worker is gone
+    pub async fn restore_processing(
+        &mut self,
+        job: &Job,
+        status: &HashMap<String, String>,
+    ) -> Result<()> {
+        let job_json = self.encode_job(job)?;
+
+        let mut pipe = redis::pipe();
+        // Appended, so restoring in list order keeps the original order
+        pipe.atomic()
+            .rpush(&self.processing_queue_name, &job_json)
+            .ignore();
+        if !status.is_empty() {
+            let fields: Vec<(&String, &String)> = status.iter().collect();
+            pipe.hset_multiple(self.status_key(&job.id), &fields).ignore();
+        }
+        self.index_labels(&mut pipe, job);
+
+        pipe.query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to restore in-flight job")?;
+
+        debug!("Restored in-flight job: {}", job.id);
+        Ok(())
+    }
+
+    /// Move the next pending job onto the migration list and return its raw JSON
+    /// Jobs left there by an interrupted migration are returned first
+    pub async fn begin_migration(&mut self) -> Result<Option<String>> {
+        let migrating = self.migrating_queue_name();
+
+        let leftover: Option<String> = self
+            .connection
+            .lindex(&migrating, -1)
+            .await
+            .context("Failed to read migration list")?;
+        if leftover.is_some() {
+            return Ok(leftover);
+        }
+
+        self.connection
+            .rpoplpush(&self.queue_name, &migrating)
+            .await
+            .context("Failed to take job for migration")
+    }
+
+    /// Drop a job from the migration list once it is safely in its new queue
+    pub async fn complete_migration(&mut self, job_json: &str, job_id: &str) -> Result<()> {
+        redis::pipe()
+            .atomic()
+            .lrem(self.migrating_queue_name(), 1, job_json)
+            .ignore()
+            .del(self.status_key(job_id))
+            .ignore()
+            .query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to complete migration")?;
+        Ok(())
+    }
+
+    /// Put a job that was not migrated back at the end of the queue
+    pub async fn abort_migration(&mut self, job_json: &str) -> Result<()> {
+        redis::pipe()
+            .atomic()
+            .lrem(self.migrating_queue_name(), 1, job_json)
+            .ignore()
+            .lpush(&self.queue_name, job_json)
+            .ignore()
+            .query_async::<()>(&mut self.connection)
+            .await
+            .context("Failed to return job to queue")?;
+        Ok(())
+    }
+
+    /// Remove an in-flight job from the processing queue without finishing it
+    /// Returns false if it was no longer there
+    pub async fn remove_processing(&mut self, job: &Job) -> Result<bool> {
+        let job_json = self.encode_job(job)?;
+
+        for list in self.processing_lists().await? {
+            let removed: i32 = self
+                .connection
+                .lrem(&list, 1, &job_json)
+                .await
+                .context("Failed to remove job from processing queue")?;
+            if removed > 0 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Delete every key belonging to this queue, including per-job status,
+    /// results and logs. Only meant for throwaway queues such as `bench`'s
+    pub async fn clear(&mut self) -> Result<()> {
+        let mut keys = vec![
+            self.queue_name.clone(),
+            self.shared_processing_queue_name(),
+            self.delayed_queue_name(),
+            self.migrating_queue_name(),
+            self.dead_queue_name(),
+            self.counters_key(),
+            self.rate_limit_key(),
+            self.payload_limits_key(),
+            self.oplog_key(),
+            self.oplog_enabled_key(),
+            self.replica_offset_key(),
+            self.maintenance_key(),
+            self.stage_durations_key(),
+            self.sandbox_metrics_key(),
+            self.events_key(),
+            format!("{}_workers", self.queue_name),
+            format!("{}_tenants", self.queue_name),
+            format!("{}_webhook_failures", self.queue_name),
+            self.invalid_queue_name(),
+        ];
+        let kinds = [
+            "status", "result", "logs", "labels", "context", "rate", "timeline", "cache",
+            "salvage", "processing", "group", "checkpoint",
+        ];
+        for kind in kinds {
+            let pattern = format!("{}_{}:*", self.queue_name, kind);
+            let mut iter: redis::AsyncIter<String> = self
+                .connection
+                .scan_match(&pattern)
+                .await
+                .context("Failed to scan queue keys")?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+
+        for chunk in keys.chunks(500) {
+            self.connection
+                .del::<_, ()>(chunk)
+                .await
+                .context("Failed to delete queue keys")?;
+        }
+
+        info!("Cleared queue {} ({} keys)", self.queue_name, keys.len());
+        Ok(())
+    }
+
+    /// Move the jobs of workers that are gone back to the main queue, e.g.
+    /// after a crash
+    ///
+    /// A worker is gone once it has no registration in `{queue_name}_workers`
+    /// or has missed its heartbeats, so live workers keep their jobs. Jobs on
+    /// the shared processing list, dequeued without a worker ID, are always
+    /// recovered.
     pub async fn recover_stalled_jobs(&mut self) -> Result<usize> {
-        info!("Recovering stalled jobs from processing queue");
+        self.recover_stalled_jobs_sparing(&BTreeSet::new()).await
+    }
+
+    /// Like `recover_stalled_jobs`, also leaving the jobs of the live
+    /// workers in `also_live`, such as fair workers, which register with the
+    /// shared queue while taking jobs from its tenants' queues
+    pub async fn recover_stalled_jobs_sparing(
+        &mut self,
+        also_live: &BTreeSet<String>,
+    ) -> Result<usize> {
+        info!("Recovering stalled jobs from processing queues");
+
+        let mut live = self.live_workers().await?;
+        live.extend(also_live.iter().cloned());
+
+        let shard_prefix = format!("{}:", self.shared_processing_queue_name());
+        let mut recovered = 0;
+        for list in self.processing_lists().await? {
+            if let Some(worker_id) = list.strip_prefix(&shard_prefix) {
+                if live.contains(worker_id) {
+                    debug!("Leaving the jobs of live worker {}", worker_id);
+                    continue;
+                }
+            }
+            recovered += self.drain_processing(&list).await?;
+        }
+
+        if recovered > 0 {
+            info!("Recovered {} stalled jobs", recovered);
+        } else {
+            debug!("No stalled jobs to recover");
+        }
+
+        Ok(recovered)
+    }
+
+    /// IDs of the workers registered in `{queue_name}_workers` that haven't
+    /// missed their heartbeats
+    pub async fn live_workers(&mut self) -> Result<BTreeSet<String>> {
+        let registrations: HashMap<String, String> = self
+            .connection
+            .hgetall(format!("{}_workers", self.queue_name))
+            .await
+            .context("Failed to read worker registry")?;
+        let now = now_secs();
+        Ok(registrations
+            .values()
+            .filter_map(|info_json| serde_json::from_str::<WorkerInfo>(info_json).ok())
+            .filter(|info| !info.is_stale(now))
+            .map(|info| info.worker_id)
+            .collect())
+    }
+
+    /// Move every job on this queue's own processing list back to the main
+    /// queue, for a worker restarting under the same ID before its previous
+    /// registration went stale
+    pub async fn recover_own_jobs(&mut self) -> Result<usize> {
+        let list = self.processing_queue_name.clone();
+        let recovered = self.drain_processing(&list).await?;
+        if recovered > 0 {
+            info!("Recovered {} jobs left by a previous run of this worker", recovered);
+        }
+        Ok(recovered)
+    }
 
+    async fn drain_processing(&mut self, list: &str) -> Result<usize> {
         let mut recovered = 0;
         loop {
             let job_json: Option<String> = self
                 .connection
-                .rpoplpush(&self.processing_queue_name, &self.queue_name)
+                .rpoplpush(list, &self.queue_name)
                 .await
                 .context("Failed to recover job")?;
 
@@ -148,13 +3042,6 @@ impl ReliableQueue {
                 None => break,
             }
         }
-
-        if recovered > 0 {
-            info!("Recovered {} stalled jobs", recovered);
-        } else {
-            debug!("No stalled jobs to recover");
-        }
-
         Ok(recovered)
     }
 
@@ -168,8 +3055,7 @@ impl ReliableQueue {
 
         match result {
             Some(job_json) => {
-                let job: Job = serde_json::from_str(&job_json)
-                    .context("Failed to deserialize job")?;
+                let job = self.decode_job(&job_json)?;
                 Ok(Some(job))
             }
             None => Ok(None),
@@ -186,13 +3072,249 @@ impl ReliableQueue {
         Ok(len)
     }
 
-    /// Get processing queue length
+    /// Get processing queue length, summed over every worker's list
     pub async fn processing_len(&mut self) -> Result<usize> {
-        let len: usize = self
-            .connection
-            .llen(&self.processing_queue_name)
+        let lists = self.processing_lists().await?;
+        let mut pipe = redis::pipe();
+        for list in &lists {
+            pipe.llen(list);
+        }
+        let lens: Vec<usize> = pipe
+            .query_async(&mut self.connection)
             .await
             .context("Failed to get processing queue length")?;
-        Ok(len)
+        Ok(lens.iter().sum())
+    }
+
+    /// Get the pending and processing queue lengths
+    pub async fn depths(&mut self) -> Result<(usize, usize)> {
+        let pending = self.len().await?;
+        Ok((pending, self.processing_len().await?))
+    }
+
+    /// Whether no job is pending, processing or waiting out a retry delay
+    pub async fn is_drained(&mut self) -> Result<bool> {
+        let (pending, delayed): (usize, usize) = redis::pipe()
+            .llen(&self.queue_name)
+            .zcard(self.delayed_queue_name())
+            .query_async(&mut self.connection)
+            .await
+            .context("Failed to read queue depths")?;
+        Ok(pending + delayed == 0 && self.processing_len().await? == 0)
+    }
+
+    /// Get the queue depths, lifetime counters and age of the oldest pending job
+    pub async fn stats(&mut self) -> Result<QueueStats> {
+        let processing = self.processing_len().await?;
+        let oldest_pending_secs = self.oldest_pending_age(now_secs()).await?;
+        let memory_bytes = self.memory_usage().await;
+        let (pending, delayed, dead, counters): (usize, usize, usize, HashMap<String, u64>) =
+            redis::pipe()
+                .llen(&self.queue_name)
+                .zcard(self.delayed_queue_name())
+                .llen(self.dead_queue_name())
+                .hgetall(self.counters_key())
+                .query_async(&mut self.connection)
+                .await
+                .context("Failed to read queue stats")?;
+
+        Ok(QueueStats {
+            pending,
+            processing,
+            delayed,
+            dead,
+            succeeded: counters.get("succeeded").copied().unwrap_or(0),
+            failed: counters.get("failed").copied().unwrap_or(0),
+            oldest_pending_secs,
+            memory_bytes,
+        })
+    }
+
+    /// Approximate bytes used by the pending, processing, delayed and
+    /// dead-letter lists, sampled with `MEMORY USAGE`
+    ///
+    /// Best-effort: `None` if Redis doesn't allow the command, as some
+    /// managed services don't.
+    pub async fn memory_usage(&mut self) -> Option<u64> {
+        let mut keys = vec![
+            self.queue_name.clone(),
+            self.delayed_queue_name(),
+            self.dead_queue_name(),
+        ];
+        match self.processing_lists().await {
+            Ok(lists) => keys.extend(lists),
+            Err(e) => warn!("Failed to list processing queues: {:#}", e),
+        }
+
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.cmd("MEMORY")
+                .arg("USAGE")
+                .arg(key)
+                .arg("SAMPLES")
+                .arg(MEMORY_SAMPLES);
+        }
+        // Keys that don't exist use no memory
+        let usage: Vec<Option<u64>> = match pipe.query_async(&mut self.connection).await {
+            Ok(usage) => usage,
+            Err(e) => {
+                debug!("Failed to read memory usage of {}: {}", self.queue_name, e);
+                return None;
+            }
+        };
+        Some(usage.into_iter().flatten().sum())
+    }
+
+    /// Seconds since the job that has been pending longest was enqueued
+    pub async fn oldest_pending_age(&mut self, now: u64) -> Result<Option<u64>> {
+        // Jobs are pushed on the left and popped from the right
+        let oldest: Option<String> = self
+            .connection
+            .lindex(&self.queue_name, -1)
+            .await
+            .context("Failed to read oldest pending job")?;
+        let Some(job_json) = oldest else {
+            return Ok(None);
+        };
+
+        let job = self.decode_job(&job_json)?;
+        let enqueued_at = self.get_status(&job.id).await?.and_then(|status| status.enqueued_at);
+        Ok(enqueued_at.map(|enqueued_at| now.saturating_sub(enqueued_at)))
+    }
+
+    /// Workers that are currently running a job, according to the jobs' status
+    pub async fn busy_workers(&mut self) -> Result<BTreeSet<String>> {
+        let job_ids: Vec<String> = self
+            .list_processing()
+            .await?
+            .into_iter()
+            .map(|job| job.id)
+            .collect();
+
+        Ok(self
+            .get_statuses(&job_ids)
+            .await?
+            .into_iter()
+            .filter_map(|status| status.worker)
+            .collect())
+    }
+
+    /// IDs of every job enqueued with all the labels in `selector`
+    pub async fn jobs_with_labels(
+        &mut self,
+        selector: &BTreeMap<String, String>,
+    ) -> Result<BTreeSet<String>> {
+        let keys: Vec<String> = selector
+            .iter()
+            .map(|(key, value)| self.label_key(key, value))
+            .collect();
+        if keys.is_empty() {
+            return Ok(BTreeSet::new());
+        }
+
+        self.connection
+            .sinter(keys)
+            .await
+            .context("Failed to read label index")
+    }
+
+    /// Statistics of the jobs carrying every label in `selector`
+    /// Unlike `stats`, succeeded and failed count jobs, not attempts
+    pub async fn stats_with_labels(
+        &mut self,
+        selector: &BTreeMap<String, String>,
+    ) -> Result<QueueStats> {
+        let job_ids: Vec<String> = self.jobs_with_labels(selector).await?.into_iter().collect();
+        let mut stats = QueueStats::default();
+        for status in self.get_statuses(&job_ids).await? {
+            if status.dead_reason.is_some() {
+                stats.dead += 1;
+                continue;
+            }
+            match status.state {
+                Some(JobState::Pending) => stats.pending += 1,
+                Some(JobState::Running) => stats.processing += 1,
+                Some(JobState::Succeeded) => stats.succeeded += 1,
+                Some(JobState::Failed) => stats.failed += 1,
+                Some(JobState::AwaitingApproval | JobState::Cancelled) | None => {}
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Pending jobs, in the order they will be dequeued
+    pub async fn list_pending(&mut self) -> Result<Vec<Job>> {
+        let queue_name = self.queue_name.clone();
+        let mut jobs = self.read_jobs(&queue_name).await?;
+        // Jobs are pushed on the left and popped from the right
+        jobs.reverse();
+        Ok(jobs)
+    }
+
+    /// Jobs currently being processed by any worker
+    pub async fn list_processing(&mut self) -> Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+        for list in self.processing_lists().await? {
+            jobs.extend(self.read_jobs(&list).await?);
+        }
+        Ok(jobs)
+    }
+
+    /// Processing list of jobs dequeued without a worker ID
+    fn shared_processing_queue_name(&self) -> String {
+        format!("{}_processing", self.queue_name)
+    }
+
+    /// The shared processing list followed by every worker's own list
+    async fn processing_lists(&mut self) -> Result<Vec<String>> {
+        let shared = self.shared_processing_queue_name();
+        let pattern = format!("{}:*", shared);
+        let mut shards = Vec::new();
+        let mut iter: redis::AsyncIter<String> = self
+            .connection
+            .scan_match(&pattern)
+            .await
+            .context("Failed to scan processing queues")?;
+        while let Some(key) = iter.next_item().await {
+            shards.push(key);
+        }
+        // SCAN may return a key more than once
+        shards.sort();
+        shards.dedup();
+
+        let mut lists = vec![shared];
+        lists.extend(shards);
+        Ok(lists)
+    }
+
+    /// Find an in-flight job on any processing list, returning the list and
+    /// the job's raw JSON
+    async fn find_processing(&mut self, job_id: &str) -> Result<Option<(String, String)>> {
+        for list in self.processing_lists().await? {
+            if let Some((job_json, _)) = self.find_in_list(&list, job_id).await? {
+                return Ok(Some((list, job_json)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Deserialize every job in one of the queue's lists, skipping malformed entries
+    async fn read_jobs(&mut self, list: &str) -> Result<Vec<Job>> {
+        let entries: Vec<String> = self
+            .connection
+            .lrange(list, 0, -1)
+            .await
+            .with_context(|| format!("Failed to read list {}", list))?;
+
+        Ok(entries
+            .iter()
+            .filter_map(|job_json| match self.decode_job(job_json) {
+                Ok(job) => Some(job),
+                Err(e) => {
+                    warn!("Skipping malformed job in {}: {}", list, e);
+                    None
+                }
+            })
+            .collect())
     }
 }
@@ -0,0 +1,185 @@
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::{Arg, Cmd, Pipeline, RedisError, RedisFuture, Value};
+use std::time::Duration;
+
+/// How long a Redis command may take before the queue gives up on it
+pub const DEFAULT_OP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commands that block for the number of seconds in their last argument
+const BLOCKING_COMMANDS: [&str; 6] =
+    ["BRPOPLPUSH", "BLPOP", "BRPOP", "BLMOVE", "BZPOPMIN", "BZPOPMAX"];
+
+/// A Redis command that got no answer in time, e.g. because Redis is
+/// wedged or the network dropped its packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisTimeout {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for RedisTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Redis command {} timed out after {:?}", self.command, self.timeout)
+    }
+}
+
+impl std::error::Error for RedisTimeout {}
+
+/// Whether `error` is a Redis command timing out
+///
+/// A [`RedisTimeout`] reaches callers as the I/O error of a `RedisError`,
+/// which doesn't expose it as its source, so it's recognized by the
+/// `RedisError` instead.
+pub fn is_timeout(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.downcast_ref::<RedisError>().is_some_and(RedisError::is_timeout))
+}
+
+/// A Redis connection whose commands fail with a [`RedisTimeout`] when they
+/// take longer than a timeout, instead of hanging the caller
+///
+/// Blocking commands such as `BRPOPLPUSH` get the time they block for on
+/// top of the timeout; ones that block indefinitely have none.
+#[derive(Clone)]
+pub struct TimeoutConnection {
+    inner: ConnectionManager,
+    /// No timeout when unset
+    timeout: Option<Duration>,
+}
+
+impl TimeoutConnection {
+    pub fn new(inner: ConnectionManager, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// How long `cmd` may take, or `None` if it may take forever
+    fn limit(&self, cmd: &Cmd) -> Option<Duration> {
+        let timeout = self.timeout?;
+        match block_time(cmd) {
+            Some(Duration::ZERO) => None,
+            Some(block) => Some(timeout + block),
+            None => Some(timeout),
+        }
+    }
+}
+
+/// Arguments of `cmd` as text; keys and values that aren't UTF-8 are lossy
+fn args(cmd: &Cmd) -> Vec<String> {
+    cmd.args_iter()
+        .map(|arg| match arg {
+            Arg::Simple(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            Arg::Cursor => String::new(),
+        })
+        .collect()
+}
+
+/// How long `cmd` blocks for, with zero meaning indefinitely, if it blocks
+fn block_time(cmd: &Cmd) -> Option<Duration> {
+    let args = args(cmd);
+    let name = args.first()?.to_uppercase();
+    if BLOCKING_COMMANDS.contains(&name.as_str()) {
+        let secs: f64 = args.last()?.parse().ok()?;
+        return Some(Duration::from_secs_f64(secs.max(0.0)));
+    }
+    if name == "XREAD" || name == "XREADGROUP" {
+        let block = args.iter().position(|arg| arg.eq_ignore_ascii_case("BLOCK"))?;
+        let ms: u64 = args.get(block + 1)?.parse().ok()?;
+        return Some(Duration::from_millis(ms));
+    }
+    None
+}
+
+fn timed_out(command: String, timeout: Duration) -> RedisError {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        RedisTimeout { command, timeout },
+    )
+    .into()
+}
+
+impl ConnectionLike for TimeoutConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let limit = self.limit(cmd);
+        Box::pin(async move {
+            let request = self.inner.req_packed_command(cmd);
+            let Some(limit) = limit else {
+                return request.await;
+            };
+            tokio::time::timeout(limit, request).await.unwrap_or_else(|_| {
+                let command = args(cmd).into_iter().next().unwrap_or_default();
+                Err(timed_out(command.to_uppercase(), limit))
+            })
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let request = self.inner.req_packed_commands(pipeline, offset, count);
+            let Some(timeout) = self.timeout else {
+                return request.await;
+            };
+            tokio::time::timeout(timeout, request)
+                .await
+                .unwrap_or_else(|_| Err(timed_out("pipeline".to_string(), timeout)))
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner.get_db()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_block_time() {
+        assert_eq!(block_time(redis::cmd("LLEN").arg("jobs")), None);
+        assert_eq!(
+            block_time(redis::cmd("BRPOPLPUSH").arg("jobs").arg("processing").arg(5.0)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            block_time(redis::cmd("brpop").arg("jobs").arg(0)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            block_time(
+                redis::cmd("XREAD")
+                    .arg("COUNT")
+                    .arg(10)
+                    .arg("BLOCK")
+                    .arg(2000)
+                    .arg("STREAMS")
+                    .arg("oplog")
+                    .arg("0")
+            ),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_find_timeout() {
+        let error = Err::<(), _>(timed_out("LREM".to_string(), Duration::from_secs(3)))
+            .context("Failed to remove job from processing queue")
+            .unwrap_err();
+        assert!(is_timeout(&error));
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Redis command LREM timed out after 3s"
+        );
+        assert!(!is_timeout(&anyhow::anyhow!("Connection refused")));
+    }
+}
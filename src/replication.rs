@@ -0,0 +1,284 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::queue::ReliableQueue;
+
+/// Entries copied to the replica per round trip
+const BATCH_SIZE: usize = 500;
+/// How long a read waits for new entries before checking for shutdown
+const BLOCK_MS: usize = 5_000;
+/// Pause after a failed round before trying again
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Queue operation recorded in `{queue_name}_oplog`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OplogOp {
+    Enqueue,
+    Ack,
+    Nack,
+    Fail,
+    Cancel,
+}
+
+impl OplogOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OplogOp::Enqueue => "enqueue",
+            OplogOp::Ack => "ack",
+            OplogOp::Nack => "nack",
+            OplogOp::Fail => "fail",
+            OplogOp::Cancel => "cancel",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "enqueue" => Some(OplogOp::Enqueue),
+            "ack" => Some(OplogOp::Ack),
+            "nack" => Some(OplogOp::Nack),
+            "fail" => Some(OplogOp::Fail),
+            "cancel" => Some(OplogOp::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// One operation on the primary queue, as replayed on a replica
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OplogEntry {
+    /// Stream ID on the primary; entries are applied in ID order
+    pub id: String,
+    pub op: OplogOp,
+    pub job_id: String,
+    /// The job as stored in the queue's lists, still encrypted if payload
+    /// encryption is on
+    pub job: String,
+    pub timestamp: u64,
+}
+
+/// Whether stream ID `a` comes before `b`
+pub fn id_before(a: &str, b: &str) -> bool {
+    let parse = |id: &str| {
+        let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+        (ms.parse::<u64>().unwrap_or(0), seq.parse::<u64>().unwrap_or(0))
+    };
+    parse(a) < parse(b)
+}
+
+/// Where a replicator copies the primary's oplog to
+pub enum ReplicaTarget {
+    /// Queue of the same name on a secondary Redis, kept in the state the
+    /// primary's operations leave it in
+    Redis(Box<ReliableQueue>),
+    /// Append-only JSON-lines file, replayed with `replay_oplog`
+    File { path: PathBuf, file: File },
+}
+
+impl ReplicaTarget {
+    pub fn file(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(Self::File {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// ID of the last entry the target has, `0` if none
+    async fn offset(&mut self) -> Result<String> {
+        match self {
+            Self::Redis(queue) => queue.replica_offset().await,
+            Self::File { path, .. } => {
+                let file = File::open(&*path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                Ok(read_oplog(BufReader::new(file))?
+                    .pop()
+                    .map_or_else(|| "0".to_string(), |entry| entry.id))
+            }
+        }
+    }
+
+    async fn apply(&mut self, entries: &[OplogEntry]) -> Result<()> {
+        match self {
+            Self::Redis(queue) => queue.apply_oplog(entries).await,
+            Self::File { path, file } => {
+                let mut lines = Vec::new();
+                for entry in entries {
+                    serde_json::to_writer(&mut lines, entry)
+                        .context("Failed to serialize oplog entry")?;
+                    lines.push(b'\n');
+                }
+                // One write per batch, so a crash leaves at most a torn last line
+                file.write_all(&lines)
+                    .and_then(|_| file.sync_data())
+                    .with_context(|| format!("Failed to write {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Read an oplog file written by a replicator
+///
+/// A torn last line, left by a crash mid-write, is skipped; any other
+/// unparseable line rejects the file.
+pub fn read_oplog(input: impl BufRead) -> Result<Vec<OplogEntry>> {
+    let lines: Vec<String> = input
+        .lines()
+        .collect::<std::io::Result<_>>()
+        .context("Failed to read oplog")?;
+    let mut entries = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if index + 1 == lines.len() => {
+                warn!("Skipping incomplete last line {} of the oplog", index + 1);
+            }
+            Err(e) => bail!("Invalid oplog entry on line {}: {}", index + 1, e),
+        }
+    }
+    Ok(entries)
+}
+
+/// Apply the entries of an oplog file that `queue` doesn't have yet
+/// Returns the number of entries applied
+pub async fn replay_oplog(queue: &mut ReliableQueue, entries: &[OplogEntry]) -> Result<usize> {
+    let offset = queue.replica_offset().await?;
+    let pending: Vec<OplogEntry> = entries
+        .iter()
+        .filter(|entry| id_before(&offset, &entry.id))
+        .cloned()
+        .collect();
+    for chunk in pending.chunks(BATCH_SIZE) {
+        queue.apply_oplog(chunk).await?;
+    }
+    info!("Replayed {} oplog entries", pending.len());
+    Ok(pending.len())
+}
+
+/// Copies a queue's oplog to a replica as operations happen
+pub struct Replicator {
+    primary: ReliableQueue,
+    target: ReplicaTarget,
+    offset: String,
+}
+
+impl Replicator {
+    /// Resume after the last entry the target has
+    pub async fn new(primary: ReliableQueue, mut target: ReplicaTarget) -> Result<Self> {
+        let offset = target.offset().await?;
+        Ok(Self {
+            primary,
+            target,
+            offset,
+        })
+    }
+
+    /// Copy the entries recorded since the last call, waiting up to
+    /// `block_ms` for new ones; returns the number copied
+    pub async fn sync(&mut self, block_ms: Option<usize>) -> Result<usize> {
+        let entries = self.primary.read_oplog(&self.offset, BATCH_SIZE, block_ms).await?;
+        let Some(last) = entries.last() else {
+            return Ok(0);
+        };
+        self.target.apply(&entries).await?;
+        self.offset = last.id.clone();
+        Ok(entries.len())
+    }
+
+    /// Warn if entries the target hasn't seen were trimmed from the oplog,
+    /// in which case the replica is missing operations
+    async fn check_gap(&mut self) -> Result<()> {
+        let Some(trimmed) = self.primary.oplog_trimmed_through().await? else {
+            return Ok(());
+        };
+        if id_before(&self.offset, &trimmed) {
+            warn!(
+                "Oplog entries after {} were trimmed before they were replicated; \
+                 restore the replica from a fresh backup",
+                self.offset
+            );
+        }
+        Ok(())
+    }
+
+    /// Replicate until `shutdown` completes
+    pub async fn run(&mut self, shutdown: impl std::future::Future<Output = ()>) -> Result<()> {
+        tokio::pin!(shutdown);
+        self.check_gap().await?;
+        info!("Replicating {} from oplog entry {}", self.primary.queue_name(), self.offset);
+
+        loop {
+            let delay = tokio::select! {
+                _ = &mut shutdown => break,
+                synced = self.sync(Some(BLOCK_MS)) => match synced {
+                    Ok(_) => Duration::ZERO,
+                    Err(e) => {
+                        error!("Replication failed: {:#}", e);
+                        RETRY_INTERVAL
+                    }
+                },
+            };
+            if !delay.is_zero() {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+        }
+
+        info!("Replicator stopped at oplog entry {}", self.offset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_oplog() {
+        let entry = |id: &str, op| OplogEntry {
+            id: id.to_string(),
+            op,
+            job_id: "job-1".to_string(),
+            job: r#"{"id":"job-1"}"#.to_string(),
+            timestamp: 1_700_000_000,
+        };
+        let mut file = Vec::new();
+        let written = [
+            entry("1700000000000-0", OplogOp::Enqueue),
+            entry("1700000000000-1", OplogOp::Ack),
+        ];
+        for entry in written {
+            serde_json::to_writer(&mut file, &entry).unwrap();
+            file.push(b'\n');
+        }
+
+        let mut torn = file.clone();
+        torn.extend_from_slice(br#"{"id":"17000"#);
+        let entries = read_oplog(torn.as_slice()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1], entry("1700000000000-1", OplogOp::Ack));
+
+        let mut damaged = br#"{"id":"17000"#.to_vec();
+        damaged.push(b'\n');
+        damaged.extend_from_slice(&file);
+        assert!(read_oplog(damaged.as_slice()).is_err());
+
+        assert!(id_before("0", "1700000000000-0"));
+        assert!(id_before("1700000000000-9", "1700000000000-10"));
+        assert!(!id_before("1700000000001-0", "1700000000000-5"));
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Size the guest's `GetRepoMap` answer is kept under
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024;
+/// Entries walked before the rest of the tree is left out of the map
+const MAX_ENTRIES: usize = 50_000;
+/// Bytes of a single manifest included in the map
+const MANIFEST_MAX_BYTES: usize = 8 * 1024;
+/// Build output, dependencies and VCS data; listed but not descended into
+const SKIPPED_DIRS: &[&str] = &[".git", "target", "node_modules", ".venv", "__pycache__"];
+/// Files that tell the agent how a project is built, wherever they are
+const MANIFESTS: &[&str] = &[
+    "Cargo.toml",
+    "package.json",
+    "pyproject.toml",
+    "requirements.txt",
+    "go.mod",
+    "pom.xml",
+    "build.gradle",
+    "Gemfile",
+    "flake.nix",
+    "Makefile",
+    "Dockerfile",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LanguageCount {
+    pub language: String,
+    pub files: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Manifest {
+    pub path: String,
+    pub contents: String,
+    /// Only the first `MANIFEST_MAX_BYTES` of the file are included
+    pub truncated: bool,
+}
+
+/// Summary of a checkout handed to the guest, so it can orient itself
+/// without reading files one by one
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepoMap {
+    /// Files in the repository, skipped directories aside
+    pub files: usize,
+    /// Languages by number of files, most used first
+    pub languages: Vec<LanguageCount>,
+    pub manifests: Vec<Manifest>,
+    /// Paths relative to the repository root, directories ending in `/`
+    pub tree: Vec<String>,
+    /// Some of the tree or manifests were left out to stay under the size
+    /// limit
+    pub truncated: bool,
+}
+
+/// Language of a file, judged by its extension
+fn language(path: &Path) -> Option<&'static str> {
+    let language = match path.extension()?.to_str()? {
+        "rs" => "Rust",
+        "py" => "Python",
+        "js" | "mjs" | "cjs" | "jsx" => "JavaScript",
+        "ts" | "tsx" => "TypeScript",
+        "go" => "Go",
+        "java" => "Java",
+        "kt" | "kts" => "Kotlin",
+        "c" | "h" => "C",
+        "cc" | "cpp" | "cxx" | "hpp" => "C++",
+        "cs" => "C#",
+        "rb" => "Ruby",
+        "php" => "PHP",
+        "swift" => "Swift",
+        "sh" | "bash" => "Shell",
+        "nix" => "Nix",
+        "proto" => "Protobuf",
+        "html" => "HTML",
+        "css" | "scss" => "CSS",
+        "md" => "Markdown",
+        "toml" => "TOML",
+        "yml" | "yaml" => "YAML",
+        "json" => "JSON",
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Size `value` takes in the serialized map, separator included
+fn json_len(value: &impl Serialize) -> usize {
+    serde_json::to_string(value).map_or(0, |json| json.len()) + 1
+}
+
+impl RepoMap {
+    /// Walk the checkout at `root` and summarize it in no more than
+    /// `max_bytes` of JSON
+    ///
+    /// Symlinks are listed but never followed, so the map can't reach
+    /// outside the checkout. Manifests are kept before the tree, and the
+    /// tree is cut off at the size limit, keeping the shallowest entries.
+    pub fn build(root: &Path, max_bytes: usize) -> Result<Self> {
+        let mut map = Self::default();
+        let mut entries = Vec::new();
+        let mut manifests = Vec::new();
+        let mut languages: HashMap<&'static str, usize> = HashMap::new();
+
+        let mut dirs = vec![root.to_path_buf()];
+        // Breadth first, so a cut-off tree still shows the top levels
+        while !dirs.is_empty() {
+            let mut next = Vec::new();
+            for dir in dirs {
+                let mut children = std::fs::read_dir(&dir)
+                    .with_context(|| format!("Failed to read {}", dir.display()))?
+                    .collect::<std::io::Result<Vec<_>>>()
+                    .with_context(|| format!("Failed to read {}", dir.display()))?;
+                children.sort_by_key(|entry| entry.file_name());
+
+                for entry in children {
+                    if entries.len() >= MAX_ENTRIES {
+                        map.truncated = true;
+                        break;
+                    }
+                    let path = entry.path();
+                    let Ok(relative) = path.strip_prefix(root) else {
+                        continue;
+                    };
+                    let relative = relative.to_string_lossy().into_owned();
+                    let file_type = entry.file_type()?;
+
+                    if file_type.is_dir() {
+                        entries.push(format!("{}/", relative));
+                        let name = entry.file_name();
+                        if !SKIPPED_DIRS.iter().any(|skipped| name == *skipped) {
+                            next.push(path);
+                        }
+                        continue;
+                    }
+                    entries.push(relative.clone());
+                    if !file_type.is_file() {
+                        continue;
+                    }
+                    map.files += 1;
+                    if let Some(language) = language(&path) {
+                        *languages.entry(language).or_default() += 1;
+                    }
+                    let name = entry.file_name();
+                    if MANIFESTS.iter().any(|manifest| name == *manifest) {
+                        manifests.push((relative, path));
+                    }
+                }
+            }
+            dirs = next;
+        }
+
+        map.languages = languages
+            .into_iter()
+            .map(|(language, files)| LanguageCount {
+                language: language.to_string(),
+                files,
+            })
+            .collect();
+        map.languages
+            .sort_by(|a, b| b.files.cmp(&a.files).then_with(|| a.language.cmp(&b.language)));
+
+        let mut used = json_len(&map);
+        for (relative, path) in manifests {
+            let bytes = std::fs::read(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let truncated = bytes.len() > MANIFEST_MAX_BYTES;
+            let manifest = Manifest {
+                path: relative,
+                contents: String::from_utf8_lossy(&bytes[..bytes.len().min(MANIFEST_MAX_BYTES)])
+                    .into_owned(),
+                truncated,
+            };
+            // Leave at least half of the map to the tree
+            let size = json_len(&manifest);
+            if used + size > max_bytes / 2 {
+                map.truncated = true;
+                continue;
+            }
+            used += size;
+            map.manifests.push(manifest);
+        }
+
+        for entry in entries {
+            let size = json_len(&entry);
+            if used + size > max_bytes {
+                map.truncated = true;
+                break;
+            }
+            used += size;
+            map.tree.push(entry);
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_map() {
+        let root = std::env::temp_dir().join(format!("repo-map-{}", uuid::Uuid::new_v4()));
+        let write = |path: &str, contents: &str| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("Cargo.toml", "[package]\nname = \"demo\"\n");
+        write("src/main.rs", "fn main() {}\n");
+        write("src/lib.rs", "");
+        write("web/package.json", "{\"name\": \"demo-web\"}\n");
+        write("web/index.ts", "");
+        write("target/debug/demo.rs", "");
+        write(".git/HEAD", "ref: refs/heads/main\n");
+
+        let map = RepoMap::build(&root, DEFAULT_MAX_BYTES).unwrap();
+        assert_eq!(map.files, 5);
+        assert!(!map.truncated);
+        assert_eq!(
+            map.languages[0],
+            LanguageCount {
+                language: "Rust".to_string(),
+                files: 2
+            }
+        );
+        let manifests: Vec<&str> = map.manifests.iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(manifests, ["Cargo.toml", "web/package.json"]);
+        assert!(map.tree.contains(&"target/".to_string()));
+        assert!(!map.tree.iter().any(|path| path.starts_with("target/debug")));
+        assert!(!map.tree.iter().any(|path| path.starts_with(".git/HEAD")));
+
+        // Shallow entries survive the cut
+        let small = RepoMap::build(&root, 256).unwrap();
+        assert!(small.truncated);
+        assert!(small.manifests.is_empty());
+        assert!(small.tree.len() < map.tree.len());
+        assert!(serde_json::to_string(&small).unwrap().len() <= 256);
+        assert_eq!(small.tree.first().map(String::as_str), Some(".git/"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::metrics::SandboxMetrics;
+
+/// A single MCP tool invocation made by the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub tool: String,
+    pub arguments: String,
+    pub response: String,
+    /// Answered with the response of an earlier identical call
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+}
+
+/// Stored outcome of a completed job (`{queue_name}_result:{job_id}`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobResult {
+    pub job_id: String,
+    /// The agent's final report
+    pub summary: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    /// Patch of the changes the agent made, empty if it made none
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub diff: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pr_url: Option<String>,
+    /// Description of the changes for a pull request, rendered from the
+    /// agent's report with the worker's commit template
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_transcript: Vec<ToolCall>,
+    /// Per-task outcomes of a job with several prompts, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<TaskResult>,
+    /// The changes are committed but not pushed until the job is approved
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub awaiting_approval: bool,
+    /// Job whose cached result this is; the agent didn't run again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_from: Option<String>,
+    /// What the agent's executions took from their sandboxes, across tasks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<SandboxMetrics>,
+    /// LLM tokens the agent's completion requests used, across tasks, as
+    /// reported by the providers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_used: Option<u64>,
+    /// SHA-256 of the guest binary the agent ran in, to tie changes in
+    /// behavior to the sandbox build behind them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_sha256: Option<String>,
+    /// Version of the worker that ran the agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_version: Option<String>,
+}
+
+/// Changes a failed attempt's agent left uncommitted, pushed to a quarantine
+/// branch instead of being thrown away (`{queue_name}_salvage:{job_id}`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Salvage {
+    pub job_id: String,
+    pub attempt: u32,
+    /// Why the attempt failed
+    pub error: String,
+    pub branch: String,
+    pub commit_sha: String,
+    pub diff: String,
+}
+
+/// Outcome of one prompt of a multi-task job
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskResult {
+    pub prompt: String,
+    /// The agent's report for this task
+    pub summary: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub diff: String,
+}
+
+/// Size of a patch, as `git diff --stat` counts it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffStat {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+impl DiffStat {
+    /// Count the files and lines a unified diff changes
+    pub fn of(diff: &str) -> Self {
+        let mut stat = Self::default();
+        // Outside hunks, `---` and `+++` lines are file headers
+        let mut in_hunk = false;
+        for line in diff.lines() {
+            if line.starts_with("diff --git ") {
+                stat.files_changed += 1;
+                in_hunk = false;
+            } else if line.starts_with("@@") {
+                in_hunk = true;
+            } else if in_hunk && line.starts_with('+') {
+                stat.insertions += 1;
+            } else if in_hunk && line.starts_with('-') {
+                stat.deletions += 1;
+            }
+        }
+        stat
+    }
+}
+
+/// Everything about a finished job a dashboard needs, in one event: sent
+/// with its webhook event and added to `{queue_name}_events`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobSummary {
+    pub job_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    #[serde(flatten)]
+    pub diff: DiffStat,
+    /// From the worker starting on the job to its result being stored
+    pub duration_ms: u64,
+    /// Time spent in each stage, by stage name; stages that ran more than
+    /// once, such as `agent` for a job with several tasks, are summed
+    pub stages_ms: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_used: Option<u64>,
+    /// Calls the agent made to each MCP tool
+    pub tool_calls: BTreeMap<String, usize>,
+    /// Job whose cached result was reused; the agent didn't run again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_from: Option<String>,
+}
+
+impl JobSummary {
+    pub fn new(result: &JobResult, duration_ms: u64, stages_ms: BTreeMap<String, u64>) -> Self {
+        let mut tool_calls = BTreeMap::new();
+        for call in &result.tool_transcript {
+            *tool_calls.entry(call.tool.clone()).or_default() += 1;
+        }
+        Self {
+            job_id: result.job_id.clone(),
+            commit_sha: result.commit_sha.clone(),
+            diff: DiffStat::of(&result.diff),
+            duration_ms,
+            stages_ms,
+            tokens_used: result.tokens_used,
+            tool_calls,
+            cached_from: result.cached_from.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_summary() {
+        let diff = "\
+diff --git a/README.md b/README.md
+--- a/README.md
++++ b/README.md
+@@ -1,2 +1,2 @@
+-# Title
++# New title
+ Text
+diff --git a/src/new.rs b/src/new.rs
+new file mode 100644
+--- /dev/null
++++ b/src/new.rs
+@@ -0,0 +1,2 @@
++fn main() {}
++++ counter;
+";
+        let call = |tool: &str| ToolCall {
+            tool: tool.to_string(),
+            arguments: "{}".to_string(),
+            response: "ok".to_string(),
+            cached: false,
+        };
+        let result = JobResult {
+            job_id: "job-1".to_string(),
+            commit_sha: Some("4f2a".to_string()),
+            diff: diff.to_string(),
+            tool_transcript: vec![call("read_file"), call("write_file"), call("read_file")],
+            tokens_used: Some(1200),
+            ..Default::default()
+        };
+        let stages = BTreeMap::from([("agent".to_string(), 5000)]);
+        let summary = JobSummary::new(&result, 7000, stages);
+        assert_eq!(
+            summary.diff,
+            DiffStat {
+                files_changed: 2,
+                insertions: 3,
+                deletions: 1
+            }
+        );
+        assert_eq!(summary.tool_calls["read_file"], 2);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["insertions"], 3);
+        assert_eq!(json["stages_ms"]["agent"], 5000);
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{bail, Context, Result};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::ErrorClass;
+use crate::workdir::parse_duration;
+
+/// How long a job that failed with one class of error waits before it is
+/// retried, e.g. `push=10m` to ride out a git host's rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryDelay {
+    /// `None` for the `default` entry, which covers every unlisted class
+    pub class: Option<ErrorClass>,
+    pub delay: Duration,
+}
+
+impl FromStr for RetryDelay {
+    type Err = anyhow::Error;
+
+    /// `<error class>=<duration>` or `default=<duration>`, where the duration
+    /// is a number of seconds, minutes, hours or days such as `30s`
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((class, delay)) = s.split_once('=') else {
+            bail!("Invalid retry delay {}; expected <error class>=<duration>", s);
+        };
+        let class = match class {
+            "default" => None,
+            _ => match ErrorClass::parse(class) {
+                Some(class) => Some(class),
+                None => bail!("Unknown error class {} in retry delay {}", class, s),
+            },
+        };
+        let delay = parse_duration(delay).with_context(|| format!("Invalid retry delay {}", s))?;
+        Ok(Self { class, delay })
+    }
+}
+
+/// Exponential backoff between the attempts of a job that keeps failing:
+/// `base` after the first attempt, doubling with each further one up to `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Longest wait after `attempt` failed attempts, before jitter
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        self.base
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max)
+    }
+
+    /// How long to wait after `attempt` failed attempts: a random point in
+    /// the upper half of the ceiling, so jobs that failed together don't
+    /// all come back at once
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        ceiling / 2 + ceiling.mul_f64(random_fraction() / 2.0)
+    }
+}
+
+/// A number in `[0, 1)`, random enough to spread retries out
+fn random_fraction() -> f64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(now.as_nanos());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Retry delays by error class; failed jobs are retried right away unless a
+/// delay or backoff applies to their error
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    delays: HashMap<ErrorClass, Duration>,
+    default: Duration,
+    backoff: Option<Backoff>,
+}
+
+impl RetryPolicy {
+    /// Later entries for the same class replace earlier ones
+    pub fn new(delays: impl IntoIterator<Item = RetryDelay>) -> Self {
+        let mut policy = Self::default();
+        for entry in delays {
+            match entry.class {
+                Some(class) => {
+                    policy.delays.insert(class, entry.delay);
+                }
+                None => policy.default = entry.delay,
+            }
+        }
+        policy
+    }
+
+    /// Back off exponentially between a job's attempts, on top of the
+    /// delays by error class
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+
+    /// How long to wait before retrying a job that failed with `class`
+    pub fn delay(&self, class: ErrorClass) -> Duration {
+        self.delays.get(&class).copied().unwrap_or(self.default)
+    }
+
+    /// How long to wait before retrying a job whose `attempt`th attempt
+    /// failed with `class`: its class's delay or the backoff, whichever is
+    /// longer
+    pub fn delay_after(&self, class: ErrorClass, attempt: u32) -> Duration {
+        let backoff = self
+            .backoff
+            .map_or(Duration::ZERO, |backoff| backoff.delay(attempt));
+        self.delay(class).max(backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        let delays: Vec<RetryDelay> = ["push=10m", "allocator=30s", "default=5", "push=1h"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let policy = RetryPolicy::new(delays);
+        assert_eq!(policy.delay(ErrorClass::Push), Duration::from_secs(60 * 60));
+        assert_eq!(policy.delay(ErrorClass::Allocator), Duration::from_secs(30));
+        assert_eq!(policy.delay(ErrorClass::Agent), Duration::from_secs(5));
+        assert_eq!(RetryPolicy::default().delay(ErrorClass::Push), Duration::ZERO);
+
+        assert!("push".parse::<RetryDelay>().is_err());
+        assert!("network=30s".parse::<RetryDelay>().is_err());
+        assert!("push=soon".parse::<RetryDelay>().is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            base: Duration::from_secs(5),
+            max: Duration::from_secs(60),
+        };
+        assert_eq!(backoff.ceiling(1), Duration::from_secs(5));
+        assert_eq!(backoff.ceiling(3), Duration::from_secs(20));
+        assert_eq!(backoff.ceiling(5), Duration::from_secs(60));
+        assert_eq!(backoff.ceiling(u32::MAX), Duration::from_secs(60));
+        for attempt in 1..8 {
+            let delay = backoff.delay(attempt);
+            assert!(delay >= backoff.ceiling(attempt) / 2, "{:?}", delay);
+            assert!(delay <= backoff.ceiling(attempt), "{:?}", delay);
+        }
+
+        let policy = RetryPolicy::new(["push=10m".parse().unwrap()]).with_backoff(backoff);
+        assert_eq!(policy.delay_after(ErrorClass::Push, 4), Duration::from_secs(600));
+        assert!(policy.delay_after(ErrorClass::Clone, 4) >= Duration::from_secs(20));
+        assert_eq!(
+            RetryPolicy::default().delay_after(ErrorClass::Clone, 4),
+            Duration::ZERO
+        );
+    }
+}
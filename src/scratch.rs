@@ -0,0 +1,158 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// Directory under the work directory jobs' scratch directories are made in
+pub const SCRATCH_DIR: &str = "scratch";
+/// Bytes a job may keep in its scratch directory
+pub const MAX_SCRATCH_BYTES: u64 = 256 * 1024 * 1024;
+/// Bytes of a scratch file handed to the guest in one read
+pub const MAX_READ_BYTES: u64 = 4 * 1024 * 1024;
+
+/// A job's directory for intermediate artifacts and downloaded context,
+/// outside its checkout so nothing the agent puts there is committed
+///
+/// The guest names files by relative paths, which may not leave the
+/// directory; the worker deletes it once the job has been processed.
+#[derive(Debug, Clone)]
+pub struct ScratchDir {
+    root: PathBuf,
+}
+
+impl ScratchDir {
+    /// Create the scratch directory of job `job_id` under `work_dir`,
+    /// emptying one an earlier attempt left behind
+    pub fn create(work_dir: &Path, job_id: &str) -> Result<Self> {
+        let root = work_dir.join(SCRATCH_DIR).join(job_id);
+        if root.exists() {
+            std::fs::remove_dir_all(&root)
+                .with_context(|| format!("Failed to empty {}", root.display()))?;
+        }
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create {}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where `relative` is, refusing paths that could leave the directory
+    fn resolve(&self, relative: &str) -> Result<PathBuf> {
+        let path = Path::new(relative);
+        if relative.is_empty()
+            || !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("Invalid scratch path {}; expected a relative path without ..", relative);
+        }
+        Ok(self.root.join(path))
+    }
+
+    /// Files under the directory, as sorted paths relative to it
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read {}", dir.display()))?
+            {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() {
+                    if let Ok(relative) = entry.path().strip_prefix(&self.root) {
+                        files.push(relative.to_string_lossy().into_owned());
+                    }
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Bytes the files under the directory take
+    pub fn size(&self) -> Result<u64> {
+        self.list()?
+            .iter()
+            .map(|file| {
+                std::fs::metadata(self.root.join(file))
+                    .map(|metadata| metadata.len())
+                    .with_context(|| format!("Failed to read size of {}", file))
+            })
+            .sum()
+    }
+
+    /// Write `contents` to `relative`, replacing it, as long as the
+    /// directory stays under `MAX_SCRATCH_BYTES`
+    pub fn write(&self, relative: &str, contents: &[u8]) -> Result<()> {
+        let path = self.resolve(relative)?;
+        let replaced = std::fs::metadata(&path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map_or(0, |metadata| metadata.len());
+        let size = self.size()? - replaced + contents.len() as u64;
+        if size > MAX_SCRATCH_BYTES {
+            bail!(
+                "Writing {} would grow the scratch directory to {} bytes, over its {} byte limit",
+                relative,
+                size,
+                MAX_SCRATCH_BYTES
+            );
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&path, contents).with_context(|| format!("Failed to write {}", relative))
+    }
+
+    /// Read `relative`, if it's no larger than `MAX_READ_BYTES`
+    pub fn read(&self, relative: &str) -> Result<Vec<u8>> {
+        let path = self.resolve(relative)?;
+        let metadata =
+            std::fs::metadata(&path).with_context(|| format!("Failed to read {}", relative))?;
+        if !metadata.is_file() {
+            bail!("{} isn't a file", relative);
+        }
+        if metadata.len() > MAX_READ_BYTES {
+            bail!(
+                "{} has {} bytes, more than the {} a read returns",
+                relative,
+                metadata.len(),
+                MAX_READ_BYTES
+            );
+        }
+        std::fs::read(&path).with_context(|| format!("Failed to read {}", relative))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scratch_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let scratch = ScratchDir::create(dir.path(), "job-1").unwrap();
+        assert_eq!(scratch.path(), dir.path().join("scratch/job-1"));
+
+        scratch.write("notes.md", b"plan").unwrap();
+        scratch.write("context/api.json", b"{}").unwrap();
+        scratch.write("notes.md", b"new plan").unwrap();
+        assert_eq!(scratch.read("notes.md").unwrap(), b"new plan");
+        assert_eq!(scratch.list().unwrap(), ["context/api.json", "notes.md"]);
+        assert_eq!(scratch.size().unwrap(), 10);
+
+        for path in ["", "../job-2/notes.md", "/etc/passwd", "context/../../x"] {
+            assert!(scratch.write(path, b"x").is_err(), "{}", path);
+        }
+        assert!(scratch.read("context").is_err());
+        assert!(scratch.read("missing.md").is_err());
+
+        // A retry starts with an empty directory
+        let scratch = ScratchDir::create(dir.path(), "job-1").unwrap();
+        assert!(scratch.list().unwrap().is_empty());
+    }
+}
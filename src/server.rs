@@ -0,0 +1,364 @@
+use anyhow::{Context, Result};
+use axum::{
+    body::Bytes,
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info};
+
+use crate::crypto::{self, PayloadCipher};
+use crate::github_events::{self, EventAction, GitHubWebhookConfig};
+use crate::grpc::{self, JobServiceImpl};
+use crate::metrics::{self, MetricLabels};
+use crate::queue::{CancelOutcome, Job, PayloadTooLarge, RateLimited, ReliableQueue};
+
+/// Configuration for the HTTP API server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub redis_url: String,
+    pub queue_name: String,
+    pub listen_addr: SocketAddr,
+    /// Also serve the gRPC API on this address when set
+    pub grpc_listen_addr: Option<SocketAddr>,
+    /// Bearer token every request except `/health` must present
+    pub api_token: String,
+    /// Key job payloads are encrypted with, if any
+    pub cipher: Option<PayloadCipher>,
+    /// Turn GitHub webhook deliveries to `/github/webhook` into jobs
+    pub github_webhook: Option<GitHubWebhookConfig>,
+    /// Tenant whose queue `queue_name` is
+    pub tenant: Option<String>,
+    /// Labels of the series `/metrics` serves
+    pub metric_labels: MetricLabels,
+}
+
+#[derive(Clone)]
+struct AppState {
+    queue: ReliableQueue,
+    api_token: Arc<str>,
+    github_webhook: Option<Arc<GitHubWebhookConfig>>,
+    /// Label set of every series `/metrics` serves
+    metric_labels: Arc<str>,
+}
+
+/// Error returned by a handler, rendered as `{"error": "..."}`
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(limited) = e.downcast_ref::<RateLimited>() {
+            return Self::new(StatusCode::TOO_MANY_REQUESTS, limited.to_string());
+        }
+        if let Some(too_large) = e.downcast_ref::<PayloadTooLarge>() {
+            return Self::new(StatusCode::PAYLOAD_TOO_LARGE, too_large.to_string());
+        }
+        error!("API request failed: {:#}", e);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+/// Build the API router on top of an existing queue connection, with the
+/// GitHub webhook receiver if configured and `metric_labels` (see
+/// `MetricLabels::render`) on the series of `/metrics`
+pub fn router(
+    queue: ReliableQueue,
+    api_token: &str,
+    github_webhook: Option<GitHubWebhookConfig>,
+    metric_labels: &str,
+) -> Router {
+    let state = AppState {
+        queue,
+        api_token: Arc::from(api_token),
+        github_webhook: github_webhook.map(Arc::new),
+        metric_labels: Arc::from(metric_labels),
+    };
+
+    let api = Router::new()
+        .route("/jobs", post(enqueue_job))
+        .route("/jobs/:job_id", get(get_status))
+        .route("/jobs/:job_id/result", get(get_result))
+        .route("/jobs/:job_id/timeline", get(get_timeline))
+        .route("/jobs/:job_id/cancel", post(cancel_job))
+        .route("/jobs/:job_id/approve", post(approve_job))
+        .route("/jobs/:job_id/reject", post(reject_job))
+        .route("/stats", get(get_stats))
+        .route("/metrics", get(get_metrics))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token));
+
+    // GitHub can't send the API token; deliveries are signed instead
+    let mut public = Router::new().route("/health", get(health));
+    if state.github_webhook.is_some() {
+        public = public.route("/github/webhook", post(receive_github_webhook));
+    }
+
+    public.merge(api).with_state(state)
+}
+
+/// Serve the API (and the gRPC API, if configured) until SIGINT/SIGTERM
+pub async fn serve(config: ServerConfig) -> Result<()> {
+    let queue = ReliableQueue::new(&config.redis_url, &config.queue_name, 5)
+        .await?
+        .with_cipher(config.cipher.clone());
+    let metric_labels = config
+        .metric_labels
+        .render(&config.queue_name, config.tenant.as_deref());
+    let app = router(
+        queue,
+        &config.api_token,
+        config.github_webhook.clone(),
+        &metric_labels,
+    );
+
+    let listener = tokio::net::TcpListener::bind(config.listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}", config.listen_addr))?;
+    info!("API server listening on {}", config.listen_addr);
+
+    let http = async {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .context("API server failed")
+    };
+
+    let grpc = async {
+        match config.grpc_listen_addr {
+            Some(addr) => {
+                let job_service = JobServiceImpl::new(&config.redis_url, &config.queue_name)
+                    .await?
+                    .with_cipher(config.cipher.clone());
+                grpc::serve(addr, job_service, &config.api_token, shutdown_signal()).await
+            }
+            None => Ok(()),
+        }
+    };
+
+    tokio::try_join!(http, grpc)?;
+
+    info!("API server stopped");
+    Ok(())
+}
+
+/// Resolves on SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+async fn require_token(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| crypto::constant_time_eq(token, &state.api_token));
+
+    if !authorized {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "Missing or invalid API token")
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+async fn health() -> &'static str {
+    "OK"
+}
+
+async fn enqueue_job(
+    State(state): State<AppState>,
+    Json(job): Json<Job>,
+) -> ApiResult<impl IntoResponse> {
+    job.validate()
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    state.queue.clone().enqueue(&job).await?;
+    Ok((StatusCode::CREATED, Json(json!({ "job_id": job.id }))))
+}
+
+/// Enqueue the job a signed GitHub delivery asks for
+async fn receive_github_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResult<impl IntoResponse> {
+    let config = state
+        .github_webhook
+        .as_ref()
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "GitHub webhooks are disabled"))?;
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if !github_events::verify_signature(&config.secret, &body, header("X-Hub-Signature-256")) {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Missing or invalid signature"));
+    }
+    let (Some(event), Some(delivery_id)) = (header("X-GitHub-Event"), header("X-GitHub-Delivery"))
+    else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Missing X-GitHub-Event or X-GitHub-Delivery header",
+        ));
+    };
+
+    let action = github_events::parse_event(config, event, delivery_id, &body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+    match action {
+        EventAction::Enqueue(job) => {
+            let enqueued = state.queue.clone().enqueue_unique(&job).await?;
+            if enqueued {
+                info!("Enqueued job {} from GitHub {} event", job.id, event);
+            }
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(json!({ "job_id": job.id, "duplicate": !enqueued })),
+            ))
+        }
+        EventAction::Ignore(reason) => Ok((StatusCode::OK, Json(json!({ "ignored": reason })))),
+    }
+}
+
+async fn get_status(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    match state.queue.clone().get_status(&job_id).await? {
+        Some(status) => Ok(Json(status)),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No status recorded for job {}", job_id),
+        )),
+    }
+}
+
+async fn get_result(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    match state.queue.clone().get_result(&job_id).await? {
+        Some(result) => Ok(Json(result)),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No result stored for job {}", job_id),
+        )),
+    }
+}
+
+async fn get_timeline(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    match state.queue.clone().timeline(&job_id).await? {
+        Some(timeline) => Ok(Json(timeline)),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No timeline recorded for job {}", job_id),
+        )),
+    }
+}
+
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let outcome = match state.queue.clone().cancel(&job_id).await? {
+        CancelOutcome::Removed => "removed",
+        CancelOutcome::Flagged => "flagged",
+        CancelOutcome::NotFound => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("Job {} is neither pending nor in flight", job_id),
+            ))
+        }
+    };
+
+    Ok(Json(json!({ "job_id": job_id, "outcome": outcome })))
+}
+
+async fn approve_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    if !state.queue.clone().approve(&job_id).await? {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("Job {} is not awaiting approval", job_id),
+        ));
+    }
+    Ok(Json(json!({ "job_id": job_id, "outcome": "approved" })))
+}
+
+async fn reject_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    if !state.queue.clone().reject(&job_id).await? {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("Job {} is not awaiting approval", job_id),
+        ));
+    }
+    Ok(Json(json!({ "job_id": job_id, "outcome": "rejected" })))
+}
+
+async fn get_stats(State(state): State<AppState>) -> ApiResult<impl IntoResponse> {
+    Ok(Json(state.queue.clone().stats().await?))
+}
+
+/// Queue statistics, stage durations and sandbox usage for Prometheus to
+/// scrape
+async fn get_metrics(State(state): State<AppState>) -> ApiResult<impl IntoResponse> {
+    let mut queue = state.queue.clone();
+    let stats = queue.stats().await?;
+    let stages = queue.stage_histograms().await?;
+    let sandbox = queue.sandbox_stats().await?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render_prometheus(&state.metric_labels, &stats, &stages, &sandbox),
+    ))
+}
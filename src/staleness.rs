@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::queue::{now_secs, QueueStats, ReliableQueue};
+use crate::webhook;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct StalenessConfig {
+    /// URL alerts are POSTed to
+    pub url: String,
+    /// Key alerts are signed with, as webhook deliveries are
+    pub secret: Option<String>,
+    /// How long the oldest pending job may wait before alerting
+    pub threshold: Duration,
+}
+
+/// Payload POSTed when a queue's oldest pending job crosses the threshold
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StalenessAlert {
+    /// `queue.stale` once the threshold is exceeded, `queue.recovered` once
+    /// the oldest pending job is back under it
+    pub event: String,
+    pub queue: String,
+    pub pending: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oldest_pending_secs: Option<u64>,
+    pub threshold_secs: u64,
+    pub timestamp: u64,
+}
+
+/// Alerts when workers fall behind, judged by how long the oldest pending
+/// job has waited
+///
+/// Only changes are reported: one alert when the threshold is first
+/// exceeded and one when the queue recovers.
+pub struct StalenessMonitor {
+    client: reqwest::Client,
+    config: StalenessConfig,
+    stale: bool,
+}
+
+impl StalenessMonitor {
+    pub fn new(config: StalenessConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            client,
+            config,
+            stale: false,
+        })
+    }
+
+    /// The alert `stats` calls for, if the queue became stale or recovered
+    /// since the last check
+    pub fn check(&mut self, queue_name: &str, stats: &QueueStats) -> Option<StalenessAlert> {
+        let threshold_secs = self.config.threshold.as_secs();
+        let stale = stats.oldest_pending_secs.is_some_and(|secs| secs > threshold_secs);
+        if stale == self.stale {
+            return None;
+        }
+        self.stale = stale;
+
+        Some(StalenessAlert {
+            event: if stale { "queue.stale" } else { "queue.recovered" }.to_string(),
+            queue: queue_name.to_string(),
+            pending: stats.pending,
+            oldest_pending_secs: stats.oldest_pending_secs,
+            threshold_secs,
+            timestamp: now_secs(),
+        })
+    }
+
+    pub async fn send(&self, alert: &StalenessAlert) -> Result<()> {
+        let body = serde_json::to_vec(alert).context("Failed to serialize alert")?;
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header("Content-Type", "application/json")
+            .header("X-Agent-Worker-Event", &alert.event);
+        if let Some(secret) = &self.config.secret {
+            let signature = webhook::signature(secret, &body);
+            request = request.header("X-Agent-Worker-Signature-256", signature);
+        }
+
+        request
+            .body(body)
+            .send()
+            .await
+            .context("Failed to send alert")?
+            .error_for_status()
+            .context("Alert receiver rejected alert")?;
+        Ok(())
+    }
+
+    /// Check the queue every `interval` until `shutdown` completes
+    pub async fn run(
+        &mut self,
+        queue: &mut ReliableQueue,
+        interval: Duration,
+        shutdown: impl std::future::Future<Output = ()>,
+    ) -> Result<()> {
+        tokio::pin!(shutdown);
+
+        loop {
+            // A failed check is logged and retried at the next interval
+            match queue.stats().await {
+                Ok(stats) => {
+                    if let Some(alert) = self.check(queue.queue_name(), &stats) {
+                        warn!(
+                            "{}: oldest pending job has waited {}s (threshold {}s)",
+                            alert.event,
+                            alert.oldest_pending_secs.unwrap_or(0),
+                            alert.threshold_secs
+                        );
+                        if let Err(e) = self.send(&alert).await {
+                            error!("Failed to send {} alert: {:#}", alert.event, e);
+                            // Report the change again at the next check
+                            self.stale = !self.stale;
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to read queue stats: {:#}", e),
+            }
+
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+
+        info!("Staleness monitor stopped");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staleness_transitions() {
+        let mut monitor = StalenessMonitor::new(StalenessConfig {
+            url: "http://localhost:9/alerts".to_string(),
+            secret: None,
+            threshold: Duration::from_secs(600),
+        })
+        .unwrap();
+        let stats = |oldest_pending_secs| QueueStats {
+            pending: 3,
+            oldest_pending_secs,
+            ..Default::default()
+        };
+
+        assert_eq!(monitor.check("agent_jobs", &stats(None)), None);
+        assert_eq!(monitor.check("agent_jobs", &stats(Some(600))), None);
+
+        let alert = monitor.check("agent_jobs", &stats(Some(601))).unwrap();
+        assert_eq!(alert.event, "queue.stale");
+        assert_eq!((alert.pending, alert.oldest_pending_secs), (3, Some(601)));
+        // Still stale, nothing new to report
+        assert_eq!(monitor.check("agent_jobs", &stats(Some(900))), None);
+
+        let alert = monitor.check("agent_jobs", &stats(None)).unwrap();
+        assert_eq!(alert.event, "queue.recovered");
+        assert_eq!(monitor.check("agent_jobs", &stats(Some(30))), None);
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{bail, Context, Result};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::queue::{Job, ReliableQueue};
+
+/// How often a fair scheduler looks for new tenants
+const TENANT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a fair scheduler waits between rounds that found no job
+const FAIR_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queue holding a tenant's jobs
+///
+/// Every key of tenant `acme` on queue `agent_jobs` lives under the prefix
+/// `agent_jobs:acme`, so tenants never see each other's jobs, statuses,
+/// results, logs or workers.
+pub fn tenant_queue_name(queue_name: &str, tenant: &str) -> String {
+    format!("{}:{}", queue_name, tenant)
+}
+
+/// Tenant names are used in Redis keys, so keep them to a safe alphabet
+///
+/// `_` separates a queue's name from the kind of its other keys, so tenant
+/// `acme_dead` would get tenant `acme`'s dead-letter list as its queue.
+pub fn validate_tenant(tenant: &str) -> Result<()> {
+    let valid = !tenant.is_empty()
+        && tenant.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        bail!("Invalid tenant '{}': use letters, digits and '-'", tenant);
+    }
+    Ok(())
+}
+
+/// Redis set of the tenants that have used a queue (`{queue_name}_tenants`)
+#[derive(Clone)]
+pub struct TenantRegistry {
+    connection: ConnectionManager,
+    key: String,
+}
+
+impl TenantRegistry {
+    pub async fn new(redis_url: &str, queue_name: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url)
+            .context("Failed to create Redis client")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("Failed to connect to Redis")?;
+
+        Ok(Self {
+            connection,
+            key: format!("{}_tenants", queue_name),
+        })
+    }
+
+    /// Record that a tenant uses the queue
+    pub async fn register(&self, tenant: &str) -> Result<()> {
+        self.connection
+            .clone()
+            .sadd::<_, _, ()>(&self.key, tenant)
+            .await
+            .context("Failed to register tenant")?;
+
+        debug!("Registered tenant {}", tenant);
+        Ok(())
+    }
+
+    /// Every registered tenant
+    pub async fn list(&self) -> Result<BTreeSet<String>> {
+        self.connection
+            .clone()
+            .smembers(&self.key)
+            .await
+            .context("Failed to read tenants")
+    }
+}
+
+/// Dequeues from the tenants of a queue in turn instead of in arrival
+/// order, so a burst of one tenant's jobs doesn't hold up every other
+/// tenant's
+pub struct FairScheduler {
+    registry: TenantRegistry,
+    /// The shared queue, whose settings the tenants' queues get
+    base: ReliableQueue,
+    /// Each tenant's queue, sorted by tenant
+    queues: Vec<(String, ReliableQueue)>,
+    /// Tenant to try first on the next dequeue
+    next: usize,
+    refreshed_at: Option<Instant>,
+    /// How long a dequeue waits for a job; indefinitely when zero
+    timeout: Duration,
+}
+
+impl FairScheduler {
+    pub fn new(registry: TenantRegistry, base: ReliableQueue, timeout: Duration) -> Self {
+        Self {
+            registry,
+            base,
+            queues: Vec::new(),
+            next: 0,
+            refreshed_at: None,
+            timeout,
+        }
+    }
+
+    /// Take a job from the first tenant after the last one served that has
+    /// one, returning the tenant and its queue with it
+    pub async fn dequeue(&mut self) -> Result<Option<(String, ReliableQueue, Job)>> {
+        let deadline = (!self.timeout.is_zero()).then(|| Instant::now() + self.timeout);
+        loop {
+            self.refresh().await?;
+            for offset in 0..self.queues.len() {
+                let index = (self.next + offset) % self.queues.len();
+                let (tenant, queue) = &mut self.queues[index];
+                if let Some(job) = queue.try_dequeue().await? {
+                    let served = (tenant.clone(), queue.clone(), job);
+                    self.next = index + 1;
+                    return Ok(Some(served));
+                }
+            }
+
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining.is_some_and(|remaining| remaining.is_zero()) {
+                debug!("No job available for any tenant");
+                return Ok(None);
+            }
+            let poll = remaining.map_or(FAIR_POLL_INTERVAL, |remaining| {
+                remaining.min(FAIR_POLL_INTERVAL)
+            });
+            tokio::time::sleep(poll).await;
+        }
+    }
+
+    /// Whether no tenant has a job pending or in flight
+    pub async fn is_drained(&mut self) -> Result<bool> {
+        for (_, queue) in &mut self.queues {
+            if !queue.is_drained().await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Pick up tenants registered since the last refresh, recovering the
+    /// jobs a previous run of this worker or a dead worker left in their
+    /// queues
+    async fn refresh(&mut self) -> Result<()> {
+        if self
+            .refreshed_at
+            .is_some_and(|at| at.elapsed() < TENANT_REFRESH_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.refreshed_at = Some(Instant::now());
+
+        // Fair workers register with the shared queue, not the tenants'
+        let live = self.base.live_workers().await?;
+        for tenant in self.registry.list().await? {
+            // Registered before names were restricted; its queue may be
+            // another tenant's key
+            if let Err(e) = validate_tenant(&tenant) {
+                warn!("Not serving tenant: {:#}", e);
+                continue;
+            }
+            let Err(index) = self.queues.binary_search_by(|(name, _)| name.cmp(&tenant)) else {
+                continue;
+            };
+            let queue_name = tenant_queue_name(self.base.queue_name(), &tenant);
+            let mut queue = self.base.for_queue(&queue_name);
+            if let Err(e) = queue.recover_own_jobs().await {
+                warn!("Failed to recover jobs of tenant {}: {:#}", tenant, e);
+            }
+            if let Err(e) = queue.recover_stalled_jobs_sparing(&live).await {
+                warn!("Failed to recover stalled jobs of tenant {}: {:#}", tenant, e);
+            }
+            info!("Serving tenant {}", tenant);
+            self.queues.insert(index, (tenant, queue));
+            // Keep the turn with the tenant that had it
+            if index < self.next {
+                self.next += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_names() {
+        assert_eq!(tenant_queue_name("agent_jobs", "acme"), "agent_jobs:acme");
+        assert!(validate_tenant("team-payments-2").is_ok());
+        assert!(validate_tenant("").is_err());
+        assert!(validate_tenant("acme:other").is_err());
+        assert!(validate_tenant("acme*").is_err());
+
+        // A tenant's queue must never be another tenant's dead-letter,
+        // delayed or quarantine list
+        for kind in ["dead", "delayed", "invalid", "processing"] {
+            let key = format!("{}_{}", tenant_queue_name("agent_jobs", "acme"), kind);
+            let tenant = format!("acme_{}", kind);
+            assert!(validate_tenant(&tenant).is_err());
+            assert_eq!(tenant_queue_name("agent_jobs", &tenant), key);
+        }
+    }
+}
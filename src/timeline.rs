@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A point in a job's life, recorded on its timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineEvent {
+    Enqueued,
+    Dequeued,
+    InstanceBorrowed,
+    CloneDone,
+    AgentStarted,
+    AgentFinished,
+    Pushed,
+    /// Committed, but waiting for `approve` or `reject` before pushing
+    AwaitingApproval,
+    Approved,
+    Rejected,
+    Acked,
+    /// Moved back to the queue for a retry
+    Nacked,
+    /// Put back unstarted because its concurrency group was busy
+    Postponed,
+    /// Given up on after its last attempt
+    Failed,
+    DeadLettered,
+    Cancelled,
+}
+
+impl TimelineEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimelineEvent::Enqueued => "enqueued",
+            TimelineEvent::Dequeued => "dequeued",
+            TimelineEvent::InstanceBorrowed => "instance_borrowed",
+            TimelineEvent::CloneDone => "clone_done",
+            TimelineEvent::AgentStarted => "agent_started",
+            TimelineEvent::AgentFinished => "agent_finished",
+            TimelineEvent::Pushed => "pushed",
+            TimelineEvent::AwaitingApproval => "awaiting_approval",
+            TimelineEvent::Approved => "approved",
+            TimelineEvent::Rejected => "rejected",
+            TimelineEvent::Acked => "acked",
+            TimelineEvent::Nacked => "nacked",
+            TimelineEvent::Postponed => "postponed",
+            TimelineEvent::Failed => "failed",
+            TimelineEvent::DeadLettered => "dead_lettered",
+            TimelineEvent::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// One event as stored in `{queue_name}_timeline:{job_id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub event: TimelineEvent,
+    /// Unix time in milliseconds
+    pub at_ms: u64,
+}
+
+impl TimelineEntry {
+    pub fn now(event: TimelineEvent) -> Self {
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        Self { event, at_ms }
+    }
+}
+
+/// An event along with how long after the previous one it happened
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineStep {
+    pub event: TimelineEvent,
+    pub at_ms: u64,
+    pub since_start_ms: u64,
+    pub since_previous_ms: u64,
+}
+
+/// Every recorded event of a job, in the order they happened
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timeline {
+    pub job_id: String,
+    pub steps: Vec<TimelineStep>,
+}
+
+impl Timeline {
+    /// Events come from different hosts, so a skewed clock can put one
+    /// slightly before its predecessor; such gaps count as zero
+    pub fn new(job_id: &str, entries: Vec<TimelineEntry>) -> Self {
+        let start = entries.first().map_or(0, |entry| entry.at_ms);
+        let mut previous = start;
+        let steps = entries
+            .into_iter()
+            .map(|entry| {
+                let step = TimelineStep {
+                    event: entry.event,
+                    at_ms: entry.at_ms,
+                    since_start_ms: entry.at_ms.saturating_sub(start),
+                    since_previous_ms: entry.at_ms.saturating_sub(previous),
+                };
+                previous = entry.at_ms;
+                step
+            })
+            .collect();
+
+        Self {
+            job_id: job_id.to_string(),
+            steps,
+        }
+    }
+
+    /// Time from the first event to the last
+    pub fn total_ms(&self) -> u64 {
+        self.steps.last().map_or(0, |step| step.since_start_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline_durations() {
+        let entry = |event, at_ms| TimelineEntry { event, at_ms };
+        let timeline = Timeline::new(
+            "job-1",
+            vec![
+                entry(TimelineEvent::Enqueued, 1_000),
+                entry(TimelineEvent::Dequeued, 4_500),
+                // Recorded by a worker whose clock is behind
+                entry(TimelineEvent::InstanceBorrowed, 4_400),
+                entry(TimelineEvent::Acked, 9_000),
+            ],
+        );
+
+        let gaps: Vec<(u64, u64)> = timeline
+            .steps
+            .iter()
+            .map(|step| (step.since_start_ms, step.since_previous_ms))
+            .collect();
+        assert_eq!(gaps, [(0, 0), (3_500, 3_500), (3_400, 0), (8_000, 4_600)]);
+        assert_eq!(timeline.total_ms(), 8_000);
+        assert_eq!(
+            serde_json::to_string(&TimelineEvent::InstanceBorrowed).unwrap(),
+            "\"instance_borrowed\""
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use jsonschema::Validator;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Violations listed in a structured error, the first ones found
+const MAX_VIOLATIONS: usize = 10;
+
+/// JSON Schemas MCP tool responses are checked against before the guest
+/// sees them, keyed by tool name; tools without a schema are not checked
+#[derive(Default)]
+pub struct ToolSchemas {
+    validators: HashMap<String, Validator>,
+}
+
+impl std::fmt::Debug for ToolSchemas {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut tools: Vec<&String> = self.validators.keys().collect();
+        tools.sort();
+        f.debug_struct("ToolSchemas").field("tools", &tools).finish()
+    }
+}
+
+/// What the guest gets instead of a response that doesn't match its schema
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidToolResponse {
+    pub error: &'static str,
+    pub tool: String,
+    pub message: String,
+    /// JSON pointer into the response and what is wrong there
+    pub violations: Vec<SchemaViolation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub message: String,
+}
+
+impl ToolSchemas {
+    /// Load every `<tool name>.json` schema in `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read tool schemas from {}", dir.display()))?;
+
+        let mut schemas = Self::default();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(tool) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let schema: Value = serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid JSON in {}", path.display()))?;
+            schemas
+                .insert(tool, &schema)
+                .with_context(|| format!("Invalid schema {}", path.display()))?;
+        }
+        Ok(schemas)
+    }
+
+    pub fn insert(&mut self, tool: &str, schema: &Value) -> Result<()> {
+        let validator =
+            jsonschema::validator_for(schema).map_err(|e| anyhow::anyhow!("{}", e))?;
+        self.validators.insert(tool.to_string(), validator);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// Check a tool's response against its schema
+    pub fn validate(&self, tool: &str, response: &str) -> Result<(), InvalidToolResponse> {
+        let Some(validator) = self.validators.get(tool) else {
+            return Ok(());
+        };
+        let invalid = |message: String, violations| InvalidToolResponse {
+            error: "invalid_tool_response",
+            tool: tool.to_string(),
+            message,
+            violations,
+        };
+
+        let response: Value = serde_json::from_str(response).map_err(|e| {
+            invalid(format!("Tool {} returned a response that isn't JSON: {}", tool, e), Vec::new())
+        })?;
+        let violations: Vec<SchemaViolation> = validator
+            .iter_errors(&response)
+            .take(MAX_VIOLATIONS)
+            .map(|error| SchemaViolation {
+                path: error.instance_path.to_string(),
+                message: error.to_string(),
+            })
+            .collect();
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(invalid(
+            format!("Tool {} returned a response that doesn't match its schema", tool),
+            violations,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tool_response_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema = json!({
+            "type": "object",
+            "required": ["content"],
+            "properties": {
+                "content": {"type": "string"},
+                "line_count": {"type": "integer", "minimum": 0}
+            }
+        });
+        std::fs::write(dir.path().join("read_file.json"), schema.to_string()).unwrap();
+        std::fs::write(dir.path().join("README.md"), "Not a schema").unwrap();
+        let schemas = ToolSchemas::load(dir.path()).unwrap();
+        assert_eq!(schemas.len(), 1);
+
+        assert!(schemas.validate("read_file", r#"{"content": "fn main() {}"}"#).is_ok());
+        // Unknown tools pass through unchecked
+        assert!(schemas.validate("write_file", "ok").is_ok());
+
+        let error = schemas
+            .validate("read_file", r#"{"content": 42, "line_count": -1}"#)
+            .unwrap_err();
+        let paths: Vec<&str> = error.violations.iter().map(|v| v.path.as_str()).collect();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains(&"/content") && paths.contains(&"/line_count"));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap()["error"],
+            "invalid_tool_response"
+        );
+
+        let error = schemas.validate("read_file", "<html>502 Bad Gateway</html>").unwrap_err();
+        assert!(error.message.contains("isn't JSON"), "{}", error.message);
+        assert!(error.violations.is_empty());
+
+        std::fs::write(dir.path().join("broken.json"), r#"{"type": "nope"}"#).unwrap();
+        assert!(ToolSchemas::load(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::future::Future;
+
+/// HTTP header carrying the trace context, per W3C Trace Context
+pub const TRACEPARENT: &str = "traceparent";
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// W3C trace context of one job, shared by every call made on its behalf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits
+    trace_id: String,
+    /// 16 lowercase hex digits identifying the worker's span of the trace
+    span_id: String,
+}
+
+impl TraceContext {
+    /// Start a new, sampled trace
+    pub fn new() -> Self {
+        let span = uuid::Uuid::new_v4().simple().to_string();
+        Self {
+            trace_id: uuid::Uuid::new_v4().simple().to_string(),
+            span_id: span[..16].to_string(),
+        }
+    }
+
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// Value of the `traceparent` header
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `future` with `trace` as the current trace context
+pub async fn scope<F: Future>(trace: TraceContext, future: F) -> F::Output {
+    CURRENT.scope(trace, future).await
+}
+
+/// Trace context of the job being handled, if any
+pub fn current() -> Option<TraceContext> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+/// Add the current `traceparent` header to an outgoing request
+pub fn propagate(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match current() {
+        Some(trace) => request.header(TRACEPARENT, trace.traceparent()),
+        None => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trace_scope() {
+        let trace = TraceContext::new();
+        let traceparent = trace.traceparent();
+        let parts: Vec<&str> = traceparent.split('-').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!((parts[0], parts[3]), ("00", "01"));
+        assert_eq!(parts[1], trace.trace_id());
+        assert_eq!((parts[1].len(), parts[2].len()), (32, 16));
+        assert_ne!(trace.trace_id(), TraceContext::new().trace_id());
+
+        assert_eq!(current(), None);
+        let inner = scope(trace.clone(), async { current() }).await;
+        assert_eq!(inner, Some(trace));
+    }
+}
@@ -0,0 +1,384 @@
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::maintenance::civil_date;
+use crate::queue::{now_secs, DeadReason, JobState};
+use crate::result::JobSummary;
+
+/// Delay before the first retry; doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Prefix of the CloudEvents `type` of job events, e.g. with `job.failed`
+pub const CLOUDEVENTS_TYPE_PREFIX: &str = "io.github.r33drichards.redis-agent-worker.";
+const CLOUDEVENTS_CONTENT_TYPE: &str = "application/cloudevents+json; charset=utf-8";
+
+/// Payload POSTed when a job reaches a terminal state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobEvent {
+    /// `job.succeeded`, `job.failed` or `job.cancelled`
+    pub event: String,
+    pub job_id: String,
+    pub state: JobState,
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_reason: Option<DeadReason>,
+    /// W3C trace ID of the job, for following it across systems
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Diff statistics, stage durations, tokens and tool calls of a
+    /// succeeded job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<JobSummary>,
+}
+
+impl JobEvent {
+    pub fn new(job_id: &str, state: JobState) -> Self {
+        Self {
+            event: format!("job.{}", state.as_str()),
+            job_id: job_id.to_string(),
+            state,
+            timestamp: now_secs(),
+            commit_sha: None,
+            error: None,
+            dead_reason: None,
+            trace_id: None,
+            summary: None,
+        }
+    }
+}
+
+/// A job event as a CloudEvents 1.0 event in structured JSON mode, so it
+/// can be routed by event brokers without an adapter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloudEvent {
+    pub specversion: String,
+    /// The delivery ID, which stays the same across retries
+    pub id: String,
+    pub source: String,
+    /// `CLOUDEVENTS_TYPE_PREFIX` followed by the event, e.g. `job.failed`
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// ID of the job
+    pub subject: String,
+    /// When the job reached its state, in RFC 3339
+    pub time: String,
+    pub datacontenttype: String,
+    pub data: JobEvent,
+}
+
+impl CloudEvent {
+    pub fn new(event: &JobEvent, source: &str, delivery_id: &str) -> Self {
+        Self {
+            specversion: "1.0".to_string(),
+            id: delivery_id.to_string(),
+            source: source.to_string(),
+            event_type: format!("{}{}", CLOUDEVENTS_TYPE_PREFIX, event.event),
+            subject: event.job_id.clone(),
+            time: rfc3339(event.timestamp),
+            datacontenttype: "application/json".to_string(),
+            data: event.clone(),
+        }
+    }
+}
+
+/// A Unix timestamp in RFC 3339, in UTC
+fn rfc3339(timestamp: u64) -> String {
+    let (year, month, day) = civil_date(timestamp / 86_400);
+    let seconds = timestamp % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Body format of webhook deliveries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WebhookFormat {
+    /// The `JobEvent` itself
+    #[default]
+    Native,
+    /// A `CloudEvent` carrying the `JobEvent` as its data
+    CloudEvents,
+}
+
+impl FromStr for WebhookFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "native" => Ok(WebhookFormat::Native),
+            "cloudevents" => Ok(WebhookFormat::CloudEvents),
+            _ => bail!("Unknown webhook format {}; expected native or cloudevents", s),
+        }
+    }
+}
+
+/// A delivery that failed every attempt, kept in `{queue_name}_webhook_failures`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedDelivery {
+    pub delivery_id: String,
+    pub url: String,
+    pub payload: JobEvent,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: u64,
+}
+
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key deliveries are signed with
+    pub secret: String,
+    /// Attempts per delivery before it is recorded as failed
+    pub max_attempts: u32,
+    pub format: WebhookFormat,
+    /// CloudEvents `source` of the events; `/redis-agent-worker/{queue_name}`
+    /// when unset
+    pub source: Option<String>,
+}
+
+/// `X-Agent-Worker-Signature-256` header value: the hex HMAC-SHA256 of the
+/// body keyed with the webhook secret, in the format GitHub uses
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Delivers job events to a webhook from a background task, so slow or
+/// failing receivers never hold up the worker
+///
+/// Each delivery carries an `X-Agent-Worker-Delivery` ID that stays the same
+/// across retries, so receivers can drop duplicates.
+pub struct WebhookNotifier {
+    sender: mpsc::UnboundedSender<JobEvent>,
+    handle: JoinHandle<()>,
+}
+
+impl WebhookNotifier {
+    /// Spawn the delivery task on the current runtime
+    pub async fn spawn(redis_url: &str, queue_name: &str, config: WebhookConfig) -> Result<Self> {
+        let failures = WebhookFailures::new(redis_url, queue_name).await?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to create HTTP client")?;
+        let source = config
+            .source
+            .clone()
+            .unwrap_or_else(|| format!("/redis-agent-worker/{}", queue_name));
+        let (sender, mut receiver) = mpsc::unbounded_channel::<JobEvent>();
+
+        let handle = tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let delivery_id = uuid::Uuid::new_v4().to_string();
+                let Err(e) = deliver(&client, &config, &source, &delivery_id, &event).await else {
+                    continue;
+                };
+
+                error!(
+                    "Giving up on webhook delivery {} for job {}: {:#}",
+                    delivery_id, event.job_id, e
+                );
+                let failure = FailedDelivery {
+                    delivery_id,
+                    url: config.url.clone(),
+                    payload: event,
+                    attempts: config.max_attempts,
+                    last_error: format!("{:#}", e),
+                    failed_at: now_secs(),
+                };
+                if let Err(e) = failures.record(&failure).await {
+                    error!("Failed to record failed webhook delivery: {:#}", e);
+                }
+            }
+            debug!("Webhook notifier stopped");
+        });
+
+        Ok(Self { sender, handle })
+    }
+
+    /// Queue an event for delivery
+    pub fn notify(&self, event: JobEvent) {
+        if self.sender.send(event).is_err() {
+            error!("Webhook notifier is gone, event was not delivered");
+        }
+    }
+
+    /// Deliver every queued event, retries included, and stop the task
+    pub async fn shutdown(self) {
+        drop(self.sender);
+        if let Err(e) = self.handle.await {
+            error!("Webhook notifier task failed: {}", e);
+        }
+    }
+}
+
+/// POST `event`, retrying with exponential backoff
+async fn deliver(
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    source: &str,
+    delivery_id: &str,
+    event: &JobEvent,
+) -> Result<()> {
+    let (body, content_type) = match config.format {
+        WebhookFormat::Native => (serde_json::to_vec(event), "application/json"),
+        WebhookFormat::CloudEvents => (
+            serde_json::to_vec(&CloudEvent::new(event, source, delivery_id)),
+            CLOUDEVENTS_CONTENT_TYPE,
+        ),
+    };
+    let body = body.context("Failed to serialize event")?;
+    let signature = signature(&config.secret, &body);
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=config.max_attempts {
+        let sent = client
+            .post(&config.url)
+            .header("Content-Type", content_type)
+            .header("X-Agent-Worker-Event", &event.event)
+            .header("X-Agent-Worker-Delivery", delivery_id)
+            .header("X-Agent-Worker-Signature-256", &signature)
+            .body(body.clone())
+            .send()
+            .await
+            .context("Failed to send webhook")
+            .and_then(|response| {
+                response
+                    .error_for_status()
+                    .context("Webhook receiver rejected delivery")
+            });
+
+        match sent {
+            Ok(_) => {
+                info!("Delivered {} for job {}", event.event, event.job_id);
+                return Ok(());
+            }
+            Err(e) if attempt == config.max_attempts => return Err(e),
+            Err(e) => {
+                warn!(
+                    "Webhook delivery {} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                    delivery_id, attempt, config.max_attempts, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+
+    anyhow::bail!("Webhook delivery was never attempted")
+}
+
+/// Redis list of deliveries that exhausted their retries, newest first
+#[derive(Clone)]
+pub struct WebhookFailures {
+    connection: ConnectionManager,
+    key: String,
+}
+
+impl WebhookFailures {
+    pub async fn new(redis_url: &str, queue_name: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url)
+            .context("Failed to create Redis client")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("Failed to connect to Redis")?;
+
+        Ok(Self {
+            connection,
+            key: format!("{}_webhook_failures", queue_name),
+        })
+    }
+
+    async fn record(&self, failure: &FailedDelivery) -> Result<()> {
+        let json = serde_json::to_string(failure).context("Failed to serialize failure")?;
+        self.connection
+            .clone()
+            .lpush::<_, _, ()>(&self.key, json)
+            .await
+            .context("Failed to record webhook failure")
+    }
+
+    /// Every recorded failure, newest first
+    pub async fn list(&self) -> Result<Vec<FailedDelivery>> {
+        let entries: Vec<String> = self
+            .connection
+            .clone()
+            .lrange(&self.key, 0, -1)
+            .await
+            .context("Failed to read webhook failures")?;
+
+        entries
+            .iter()
+            .map(|json| {
+                serde_json::from_str(json).context("Failed to deserialize webhook failure")
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // Example from GitHub's webhook validation docs
+        assert_eq!(
+            signature("It's a Secret to Everybody", b"Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+        assert_eq!(
+            JobEvent::new("job-1", JobState::Cancelled).event,
+            "job.cancelled"
+        );
+    }
+
+    #[test]
+    fn test_cloud_event() {
+        let event = JobEvent {
+            timestamp: 1_709_316_000,
+            ..JobEvent::new("job-1", JobState::Failed)
+        };
+        let cloud_event = CloudEvent::new(&event, "/redis-agent-worker/agent_jobs", "d-1");
+        let json = serde_json::to_value(&cloud_event).unwrap();
+        assert_eq!(json["specversion"], "1.0");
+        assert_eq!(json["id"], "d-1");
+        assert_eq!(json["source"], "/redis-agent-worker/agent_jobs");
+        assert_eq!(
+            json["type"],
+            "io.github.r33drichards.redis-agent-worker.job.failed"
+        );
+        assert_eq!(json["subject"], "job-1");
+        assert_eq!(json["time"], "2024-03-01T18:00:00Z");
+        assert_eq!(json["data"]["job_id"], "job-1");
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+
+        assert_eq!(
+            "cloudevents".parse::<WebhookFormat>().unwrap(),
+            WebhookFormat::CloudEvents
+        );
+        assert!("xml".parse::<WebhookFormat>().is_err());
+    }
+}
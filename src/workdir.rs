@@ -0,0 +1,147 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Directory under the work directory failed checkouts are moved to
+pub const KEPT_DIR: &str = "kept";
+
+/// What happens to a job's checkout once it has been processed
+///
+/// Successful checkouts are always deleted; this decides whether failed ones
+/// are kept under `{work_dir}/kept/` to be debugged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WorkdirRetention {
+    #[default]
+    AlwaysDelete,
+    /// Keep failed checkouts until they are removed by hand
+    KeepOnFailure,
+    /// Keep failed checkouts, deleting them once they are this old
+    KeepFor(Duration),
+}
+
+impl WorkdirRetention {
+    /// Whether a failed checkout is kept rather than deleted
+    pub fn keeps_failures(&self) -> bool {
+        !matches!(self, WorkdirRetention::AlwaysDelete)
+    }
+}
+
+impl FromStr for WorkdirRetention {
+    type Err = anyhow::Error;
+
+    /// `always-delete`, `keep-on-failure` or `keep-for:<duration>`, where the
+    /// duration is a number of seconds, minutes, hours or days such as `12h`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always-delete" => Ok(WorkdirRetention::AlwaysDelete),
+            "keep-on-failure" => Ok(WorkdirRetention::KeepOnFailure),
+            _ => match s.strip_prefix("keep-for:") {
+                Some(duration) => Ok(WorkdirRetention::KeepFor(parse_duration(duration)?)),
+                None => bail!(
+                    "Unknown retention policy {}; expected always-delete, keep-on-failure \
+                     or keep-for:<duration>",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// A number of seconds, or of minutes, hours or days such as `12h`
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration {}", s))?;
+    let unit_secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Invalid duration {}; expected a number followed by s, m, h or d", s),
+    };
+    Ok(Duration::from_secs(number * unit_secs))
+}
+
+/// Move a failed checkout to `kept_dir/name`, replacing anything there, and
+/// mark it as kept now for `sweep`
+pub fn keep(repo_dir: &Path, kept_dir: &Path, name: &str) -> Result<()> {
+    std::fs::create_dir_all(kept_dir)
+        .with_context(|| format!("Failed to create {}", kept_dir.display()))?;
+    let target = kept_dir.join(name);
+    if target.exists() {
+        std::fs::remove_dir_all(&target)
+            .with_context(|| format!("Failed to remove {}", target.display()))?;
+    }
+    std::fs::rename(repo_dir, &target)
+        .with_context(|| format!("Failed to move checkout to {}", target.display()))?;
+    std::fs::File::open(&target)
+        .and_then(|dir| dir.set_modified(SystemTime::now()))
+        .with_context(|| format!("Failed to mark {} as kept", target.display()))?;
+
+    info!("Kept failed checkout at {}", target.display());
+    Ok(())
+}
+
+/// Delete the checkouts in `kept_dir` that were kept more than `max_age` ago
+pub fn sweep(kept_dir: &Path, max_age: Duration) -> Result<usize> {
+    let entries = match std::fs::read_dir(kept_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", kept_dir.display()))
+        }
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry?.path();
+        let age = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| modified.elapsed().unwrap_or_default());
+        match age {
+            Ok(age) if age > max_age => match std::fs::remove_dir_all(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove kept checkout {}: {}", path.display(), e),
+            },
+            Ok(_) => {}
+            Err(e) => warn!("Failed to read age of kept checkout {}: {}", path.display(), e),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_and_sweep() {
+        assert_eq!(
+            "keep-for:12h".parse::<WorkdirRetention>().unwrap(),
+            WorkdirRetention::KeepFor(Duration::from_secs(12 * 60 * 60))
+        );
+        assert_eq!(
+            "keep-for:90".parse::<WorkdirRetention>().unwrap(),
+            WorkdirRetention::KeepFor(Duration::from_secs(90))
+        );
+        assert!("keep-for:2w".parse::<WorkdirRetention>().is_err());
+        assert!("keep".parse::<WorkdirRetention>().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let repo_dir = dir.path().join("job-1");
+        std::fs::create_dir_all(repo_dir.join("src")).unwrap();
+        let kept_dir = dir.path().join(KEPT_DIR);
+
+        keep(&repo_dir, &kept_dir, "job-1.attempt-1").unwrap();
+        assert!(!repo_dir.exists());
+        assert!(kept_dir.join("job-1.attempt-1/src").exists());
+
+        assert_eq!(sweep(&kept_dir, Duration::from_secs(60)).unwrap(), 0);
+        assert_eq!(sweep(&kept_dir, Duration::ZERO).unwrap(), 1);
+        assert!(!kept_dir.join("job-1.attempt-1").exists());
+        assert_eq!(sweep(&dir.path().join("missing"), Duration::ZERO).unwrap(), 0);
+    }
+}
@@ -1,11 +1,82 @@
-use anyhow::{Context, Result};
-use std::path::PathBuf;
-use tracing::{error, info, warn};
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::agent::{AgentConfig, AgentExecutor};
-use crate::git::GitRepo;
-use crate::instance::{InstanceAllocator, InstanceGuard};
-use crate::queue::{Job, ReliableQueue};
+use crate::agent::{AgentConfig, AgentExecutor, AgentLimits, AgentProgress, GuestFailure};
+use crate::agent_metadata::{self, AgentMetadata, MetadataMode, Provenance};
+use crate::cache;
+use crate::credentials::GitCredentials;
+use crate::crypto::PayloadCipher;
+use crate::describe::{DescriptionInput, DescriptionTemplate};
+use crate::doctor::{self, CheckStatus, HealthGate, PreflightConfig};
+use crate::git::{AsyncGitRepo, CloneBudget, CloneBudgetExceeded, GitRepo};
+use crate::github::{CommitState, CommitStatusReporter};
+use crate::error::ErrorClass;
+use crate::guest_binary;
+use crate::heartbeat::{Heartbeat, WorkerInfo, WorkerRegistry};
+use crate::http::{self, HttpClientConfig};
+use crate::instance::{
+    Instance, InstanceAllocator, InstanceQuarantine, InstanceReturner, JobOutcome,
+    OnlyQuarantinedInstances,
+};
+use crate::ledger::InstanceLedger;
+use crate::llm::{LlmProvider, LlmRouter};
+use crate::maintenance;
+use crate::memo::MemoConfig;
+use crate::metrics::{Stage, StageHistogram};
+use crate::policy::RepoPolicy;
+use crate::prompt_policy::PromptPolicy;
+use crate::queue::{
+    context_key, now_secs, ContextFile, ContextSource, DeadReason, Job, JobState, ParentContext,
+    ReliableQueue, PARENT_CONTEXT_FILE,
+};
+use crate::redis_timeout;
+use crate::result::{JobResult, JobSummary, Salvage, TaskResult, ToolCall};
+use crate::retry::RetryPolicy;
+use crate::scratch::{ScratchDir, SCRATCH_DIR};
+use crate::tenant::{FairScheduler, TenantRegistry};
+use crate::timeline::TimelineEvent;
+use crate::tool_schema::ToolSchemas;
+use crate::trace::{self, TraceContext};
+use crate::webhook::{JobEvent, WebhookConfig, WebhookNotifier};
+use crate::workdir::{self, WorkdirRetention, KEPT_DIR};
+
+/// Directory in the checkout that holds a job's context files
+pub const CONTEXT_DIR: &str = ".agent-context";
+
+/// How often a paused worker checks whether its maintenance window closed
+const MAINTENANCE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long healthy dependencies are trusted before they are checked again
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often a worker holding off checks whether its dependencies recovered
+const HEALTH_RETRY_INTERVAL: Duration = Duration::from_secs(15);
+/// How long a concurrency group stays locked after its holder last extended
+/// the lock; holders extend it every third of this
+const GROUP_LOCK_TTL: Duration = Duration::from_secs(60);
+/// Wait before retrying a failed push, doubled after each failure
+const PUSH_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+/// How long the changes of a job whose push failed are kept for its next
+/// attempt to push
+const PUSH_CHECKPOINT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How long a worker waits after postponing a job whose group is busy, so a
+/// queue of such jobs isn't cycled through in a tight loop
+const GROUP_BUSY_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// Instances borrowed for a job before giving up when the allocator keeps
+/// handing out quarantined ones
+const QUARANTINE_BORROW_ATTEMPTS: usize = 3;
+/// How long a worker waits after postponing a job because the allocator
+/// only had quarantined instances, so it doesn't ask again right away
+const QUARANTINE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// How long downloading one context file may take
+const CONTEXT_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest context file downloaded from a URL
+const MAX_CONTEXT_DOWNLOAD_BYTES: usize = 64 * 1024 * 1024;
 
 pub struct WorkerConfig {
     pub redis_url: String,
@@ -13,194 +84,1834 @@ pub struct WorkerConfig {
     pub queue_timeout: u64,
     pub allocator_api_url: String,
     pub work_dir: String,
+    /// Stable identity of this worker; must survive restarts for leaked
+    /// instances to be reconciled
+    pub worker_id: String,
+    /// Seconds between leaked-instance reconciliation passes
+    pub reconcile_interval: u64,
+    /// Only take jobs carrying all of these labels; any job when empty
+    pub label_selector: BTreeMap<String, String>,
+    /// Key job payloads are encrypted with, if any
+    pub cipher: Option<PayloadCipher>,
+    /// Repositories this worker may clone; any when empty
+    pub repo_policy: RepoPolicy,
+    /// Tenant whose queue `queue_name` is, recorded on every job's log lines
+    pub tenant: Option<String>,
+    /// Take turns dequeuing from every tenant of `queue_name` instead of
+    /// working on one queue
+    pub fair_tenants: bool,
+    /// Git credentials per repository; the ssh-agent for unmapped ones
+    pub git_credentials: GitCredentials,
+    /// Posts the progress of jobs on GitHub repositories to their base commit
+    pub commit_status: Option<CommitStatusReporter>,
+    /// Receives a signed event whenever a job succeeds, fails or is cancelled
+    pub webhook: Option<WebhookConfig>,
+    /// Reuse the result of an identical job started from the same commit
+    /// within this long instead of running the agent again
+    pub result_cache_ttl: Option<Duration>,
+    /// Renders commit messages and pull request descriptions from the
+    /// agent's report
+    pub description_template: DescriptionTemplate,
+    /// Push the changes of an agent that failed or timed out to a quarantine
+    /// branch instead of discarding them with the checkout
+    pub salvage: bool,
+    /// Whether failed checkouts are kept around to be debugged
+    pub workdir_retention: WorkdirRetention,
+    /// Prompts the worker refuses to run, dead-lettering their jobs
+    pub prompt_policy: PromptPolicy,
+    /// Schemas MCP tool responses are checked against
+    pub tool_schemas: ToolSchemas,
+    /// Dependencies that must be up before a job is dequeued
+    pub health_gate: Option<HealthGate>,
+    /// Times a push is tried before the attempt fails; the changes are kept
+    /// for the next attempt to push without running the agent again
+    pub push_attempts: u32,
+    /// How long failed jobs wait before they are retried, by error class
+    pub retry_policy: RetryPolicy,
+    /// How much a clone may download and how long it may take; jobs can
+    /// only tighten it
+    pub clone_budget: CloneBudget,
+    /// Record how each of the agent's commits was generated, in the commit
+    /// or in a git note
+    pub agent_metadata: Option<MetadataMode>,
+    /// LLM endpoints the guest's completion requests go to, in order of
+    /// preference
+    pub llm_providers: Vec<LlmProvider>,
+    /// Tools and LLM requests whose repeated calls within an agent run are
+    /// answered from the first response
+    pub memo: MemoConfig,
+    /// Time the guest's agent may spend before it stops
+    pub agent_limits: AgentLimits,
+    /// Stop once this many jobs have been processed; run until shut down
+    /// when unset
+    pub max_jobs: Option<usize>,
+    /// Stop once the queue has no pending, processing or delayed jobs
+    pub until_empty: bool,
+    /// Stop running jobs on instances that failed several in a row
+    pub instance_quarantine: Option<InstanceQuarantine>,
+    /// Retries a failed job gets before it is dead-lettered, unless its
+    /// options say otherwise; retried indefinitely when unset
+    pub max_retries: Option<u32>,
+    /// Check the allocator, git credentials, work directory and sandbox
+    /// before taking jobs, failing to start if any is unusable
+    pub preflight: Option<PreflightConfig>,
+    /// Connection pooling and timeouts of the client used for the allocator
+    /// and MCP servers
+    pub http: HttpClientConfig,
+    /// How long a Redis command other than a blocking dequeue may take
+    /// before it fails; unlimited when unset
+    pub redis_timeout: Option<Duration>,
+    /// How long job results are kept; forever when unset
+    pub result_ttl: Option<Duration>,
+}
+
+/// Default worker ID derived from the host name
+pub fn default_worker_id() -> String {
+    hostname()
+}
+
+/// Name of the host this process runs on
+pub fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "agent-worker".to_string())
 }
 
 pub struct Worker {
     queue: ReliableQueue,
     allocator: InstanceAllocator,
+    ledger: InstanceLedger,
+    registry: WorkerRegistry,
+    heartbeat: Option<Heartbeat>,
+    worker_id: String,
+    returner: InstanceReturner,
     agent_executor: AgentExecutor,
+    /// Settings of the client shared with the agent and allocator, which
+    /// context downloads build their own clients from
+    http_config: HttpClientConfig,
     work_dir: PathBuf,
+    repo_policy: RepoPolicy,
+    git_credentials: GitCredentials,
+    commit_status: Option<CommitStatusReporter>,
+    webhook: Option<WebhookNotifier>,
+    result_cache_ttl: Option<Duration>,
+    description_template: DescriptionTemplate,
+    salvage: bool,
+    push_attempts: u32,
+    retry_policy: RetryPolicy,
+    clone_budget: CloneBudget,
+    agent_metadata: Option<MetadataMode>,
+    workdir_retention: WorkdirRetention,
+    prompt_policy: PromptPolicy,
+    tenant: Option<String>,
+    /// Picks the tenant of each job when serving every tenant
+    fair: Option<FairScheduler>,
+    reconcile_interval: Duration,
+    last_reconcile: Option<Instant>,
+    /// Name of the maintenance window the worker is paused for
+    maintenance: Option<String>,
+    health_gate: Option<HealthGate>,
+    /// When the health gate last found every dependency up
+    last_healthy: Option<Instant>,
+    /// Whether the worker is holding off because a dependency is down
+    holding_off: bool,
+    /// Time the current job spent in each stage so far
+    job_stages: Mutex<BTreeMap<Stage, Duration>>,
+    quarantine: Option<Mutex<InstanceQuarantine>>,
+    max_jobs: Option<usize>,
+    until_empty: bool,
+    max_retries: Option<u32>,
+    /// Jobs handled since the worker started, postponed ones aside
+    jobs_processed: usize,
+    shutdown: Arc<AtomicBool>,
 }
 
 impl Worker {
     pub async fn new(config: WorkerConfig) -> Result<Self> {
         info!("Initializing worker");
 
+        if config.cipher.is_some() && !config.label_selector.is_empty() {
+            // Selectors are matched inside Redis, which can't read encrypted jobs
+            bail!("Label selectors can't be used with encrypted job payloads");
+        }
+
         let queue = ReliableQueue::new(
             &config.redis_url,
             &config.queue_name,
             config.queue_timeout,
         )
         .await
-        .context("Failed to create queue")?;
+        .context("Failed to create queue")?
+        .with_worker_id(&config.worker_id)
+        .with_op_timeout(config.redis_timeout)
+        .with_label_selector(config.label_selector)
+        .with_cipher(config.cipher)
+        .with_result_ttl(config.result_ttl);
 
-        let allocator = InstanceAllocator::new(config.allocator_api_url);
+        let fair = if config.fair_tenants {
+            let tenants = TenantRegistry::new(&config.redis_url, &config.queue_name)
+                .await
+                .context("Failed to create tenant registry")?;
+            let timeout = Duration::from_secs(config.queue_timeout);
+            Some(FairScheduler::new(tenants, queue.clone(), timeout))
+        } else {
+            None
+        };
+
+        let ledger = InstanceLedger::new(
+            &config.redis_url,
+            &config.queue_name,
+            &config.worker_id,
+        )
+        .await
+        .context("Failed to create instance ledger")?;
+
+        let registry = WorkerRegistry::new(&config.redis_url, &config.queue_name)
+            .await
+            .context("Failed to create worker registry")?;
+
+        let webhook = match config.webhook {
+            Some(webhook) => Some(
+                WebhookNotifier::spawn(&config.redis_url, &config.queue_name, webhook)
+                    .await
+                    .context("Failed to start webhook notifier")?,
+            ),
+            None => None,
+        };
 
         let agent_config = AgentConfig {
             working_directory: config.work_dir.clone(),
         };
-        let agent_executor = AgentExecutor::new(agent_config);
+        let http_client = config.http.build()?;
+        let agent_executor = AgentExecutor::new(agent_config)
+            .with_http_client(http_client.clone())
+            .with_tool_schemas(config.tool_schemas)
+            .with_llm_router(LlmRouter::new(config.llm_providers)?)
+            .with_memo(config.memo)
+            .with_limits(config.agent_limits);
+
+        if let Some(preflight) = &config.preflight {
+            let results = doctor::preflight(
+                preflight,
+                &config.allocator_api_url,
+                &config.work_dir,
+                &config.git_credentials,
+                &agent_executor,
+            )
+            .await;
+            for result in results.iter().filter(|result| result.status == CheckStatus::Warn) {
+                warn!("Preflight check {}: {}", result.name, result.detail);
+            }
+            doctor::ensure_ready(&results).context("Worker failed its preflight checks")?;
+            info!("Worker passed {} preflight checks", results.len());
+        }
+
+        let allocator =
+            InstanceAllocator::new(config.allocator_api_url).with_client(http_client);
+        let returner = InstanceReturner::spawn(allocator.clone(), Some(ledger.clone()));
 
         let work_dir = PathBuf::from(config.work_dir);
         std::fs::create_dir_all(&work_dir)
             .context("Failed to create work directory")?;
 
-        info!("Worker initialized successfully");
+        info!("Worker {} initialized successfully", config.worker_id);
 
         Ok(Self {
             queue,
             allocator,
+            ledger,
+            registry,
+            heartbeat: None,
+            worker_id: config.worker_id,
+            returner,
             agent_executor,
+            http_config: config.http,
             work_dir,
+            repo_policy: config.repo_policy,
+            git_credentials: config.git_credentials,
+            commit_status: config.commit_status,
+            webhook,
+            result_cache_ttl: config.result_cache_ttl,
+            description_template: config.description_template,
+            salvage: config.salvage,
+            push_attempts: config.push_attempts.max(1),
+            retry_policy: config.retry_policy,
+            clone_budget: config.clone_budget,
+            agent_metadata: config.agent_metadata,
+            workdir_retention: config.workdir_retention,
+            prompt_policy: config.prompt_policy,
+            tenant: config.tenant,
+            fair,
+            reconcile_interval: Duration::from_secs(config.reconcile_interval),
+            last_reconcile: None,
+            maintenance: None,
+            health_gate: config.health_gate,
+            last_healthy: None,
+            holding_off: false,
+            job_stages: Mutex::new(BTreeMap::new()),
+            max_jobs: config.max_jobs,
+            until_empty: config.until_empty,
+            max_retries: config.max_retries,
+            quarantine: config.instance_quarantine.map(Mutex::new),
+            jobs_processed: 0,
+            shutdown: Arc::new(AtomicBool::new(false)),
         })
     }
 
     /// Run the worker loop
     pub async fn run(&mut self) -> Result<()> {
         info!("Starting worker loop");
+        info!("Agent profiles: {}", guest_binary::profiles().join(", "));
 
-        // Recover any stalled jobs on startup
+        // Recover jobs left by a previous run of this worker and by dead peers
+        self.queue.recover_own_jobs().await?;
         self.queue.recover_stalled_jobs().await?;
 
-        loop {
+        self.listen_for_shutdown();
+
+        self.heartbeat = Some(Heartbeat::spawn(
+            self.registry.clone(),
+            WorkerInfo {
+                worker_id: self.worker_id.clone(),
+                hostname: hostname(),
+                started_at: now_secs(),
+                last_heartbeat: now_secs(),
+                current_job: None,
+                // Jobs are processed one at a time
+                concurrency: 1,
+            },
+        ));
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            if self.max_jobs.is_some_and(|max| self.jobs_processed >= max) {
+                info!("Processed {} jobs, stopping", self.jobs_processed);
+                break;
+            }
+
+            // Reconcile between jobs, when this worker holds no instance
+            let reconcile_due = self
+                .last_reconcile
+                .is_none_or(|at| at.elapsed() >= self.reconcile_interval);
+            if reconcile_due {
+                if let Err(e) = self.reconcile_instances().await {
+                    warn!("Instance reconciliation failed: {:#}", e);
+                }
+                self.last_reconcile = Some(Instant::now());
+            }
+
+            if self.in_maintenance().await {
+                tokio::time::sleep(MAINTENANCE_POLL_INTERVAL).await;
+                continue;
+            }
+            if self.dependencies_down().await {
+                tokio::time::sleep(HEALTH_RETRY_INTERVAL).await;
+                continue;
+            }
+
             match self.process_next_job().await {
                 Ok(processed) => {
+                    if !processed && self.until_empty && self.queue_drained().await {
+                        info!("Queue is drained, stopping");
+                        break;
+                    }
                     if !processed {
                         info!("No jobs available, waiting...");
                     }
                 }
+                // Don't spin on a Redis that stopped answering
+                Err(e) if redis_timeout::is_timeout(&e) => {
+                    warn!("{:#}, retrying in {:?}", e, HEALTH_RETRY_INTERVAL);
+                    tokio::time::sleep(HEALTH_RETRY_INTERVAL).await;
+                }
                 Err(e) => {
                     error!("Error processing job: {:#}", e);
                     // Continue processing other jobs even if one fails
                 }
             }
         }
+
+        info!("Worker loop finished, flushing pending instance returns");
+        self.returner.flush().await;
+
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop().await;
+        }
+
+        if let Some(webhook) = self.webhook.take() {
+            info!("Delivering pending webhook events");
+            webhook.shutdown().await;
+        }
+
+        info!("Worker stopped");
+        Ok(())
+    }
+
+    /// Return instances the ledger says this worker holds although no job is
+    /// using them, e.g. instances leaked by a crash before they were returned
+    /// Must only be called between jobs
+    pub async fn reconcile_instances(&self) -> Result<usize> {
+        // Make sure returns queued by dropped guards have landed first
+        self.returner.flush().await;
+
+        let leaked = self.ledger.held_by_self().await?;
+        let mut reclaimed = 0;
+
+        for entry in leaked {
+            warn!(
+                "Returning leaked instance {} (borrowed at {})",
+                entry.instance.id, entry.borrowed_at
+            );
+            match self.allocator.return_instance(&entry.instance, None).await {
+                Ok(()) => {
+                    self.ledger.release(&entry.instance.id).await?;
+                    reclaimed += 1;
+                }
+                Err(e) => warn!(
+                    "Failed to return leaked instance {}: {:#}",
+                    entry.instance.id, e
+                ),
+            }
+        }
+
+        if reclaimed > 0 {
+            info!("Reclaimed {} leaked instances", reclaimed);
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Whether the queue has no jobs left for any worker; an unreadable queue
+    /// isn't drained
+    async fn queue_drained(&mut self) -> bool {
+        let drained = match &mut self.fair {
+            Some(fair) => fair.is_drained().await,
+            None => self.queue.is_drained().await,
+        };
+        match drained {
+            Ok(drained) => drained,
+            Err(e) => {
+                warn!("Failed to check whether the queue is drained: {:#}", e);
+                false
+            }
+        }
+    }
+
+    /// Request that the worker loop stop after the job currently in progress
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Flip the shutdown flag on SIGINT/SIGTERM
+    fn listen_for_shutdown(&self) {
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::terminate()) {
+                    Ok(mut sigterm) => {
+                        tokio::select! {
+                            _ = tokio::signal::ctrl_c() => {}
+                            _ = sigterm.recv() => {}
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to install SIGTERM handler: {}", e);
+                        let _ = tokio::signal::ctrl_c().await;
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+
+            info!("Shutdown signal received, finishing current job");
+            shutdown.store(true, Ordering::SeqCst);
+        });
     }
 
     /// Process the next job from the queue
     async fn process_next_job(&mut self) -> Result<bool> {
-        // Dequeue a job
-        let job = match self.queue.dequeue().await? {
-            Some(job) => job,
-            None => return Ok(false),
+        // Dequeue a job, from the next tenant's queue if serving every tenant
+        let (job, tenant_queue) = match &mut self.fair {
+            Some(fair) => match fair.dequeue().await? {
+                Some((tenant, queue, job)) => {
+                    self.tenant = Some(tenant);
+                    (job, Some(queue))
+                }
+                None => return Ok(false),
+            },
+            None => match self.queue.dequeue().await? {
+                Some(job) => (job, None),
+                None => return Ok(false),
+            },
+        };
+
+        // The job is acked, locked and recorded on its tenant's queue, but
+        // everything between jobs, such as maintenance windows and
+        // recovery, stays on the shared one
+        let shared = tenant_queue.map(|queue| std::mem::replace(&mut self.queue, queue));
+        let processed = self.process_job_locked(job).await;
+        if let Some(shared) = shared {
+            self.queue = shared;
+        }
+        processed
+    }
+
+    /// Process a dequeued job under the lock of its concurrency group, if
+    /// it has one
+    async fn process_job_locked(&mut self, job: Job) -> Result<bool> {
+        let group_lock = match &job.concurrency_group {
+            Some(group) => match self.lock_group(&job, group).await? {
+                Some(refresh) => Some((group, refresh)),
+                None => return Ok(true),
+            },
+            None => None,
+        };
+
+        // Everything logged while handling the job also goes to its log stream,
+        // and every request made for it carries its trace context
+        let trace = TraceContext::new();
+        let span = info_span!(
+            "job",
+            job_id = %job.id,
+            tenant = self.tenant.as_deref(),
+            trace_id = trace.trace_id()
+        );
+        self.set_current_job(Some(&job.id));
+        let handled = trace::scope(trace, self.handle_job(&job)).instrument(span).await;
+        self.set_current_job(None);
+        if !matches!(handled, Ok(false)) {
+            self.jobs_processed += 1;
+        }
+
+        if let Some((group, refresh)) = group_lock {
+            refresh.abort();
+            if let Err(e) = self.queue.unlock_group(group, &job.id).await {
+                warn!("Failed to unlock concurrency group {}: {:#}", group, e);
+            }
+        }
+        if !handled? {
+            // Only quarantined instances were free; don't ask again right away
+            tokio::time::sleep(QUARANTINE_RETRY_INTERVAL).await;
+        }
+
+        Ok(true)
+    }
+
+    /// Take the lock of a job's concurrency group and keep extending it in
+    /// the background, or postpone the job if another job of the group runs
+    /// Returns `None` if the job was postponed
+    async fn lock_group(&mut self, job: &Job, group: &str) -> Result<Option<JoinHandle<()>>> {
+        let locked = match self.queue.lock_group(group, &job.id, GROUP_LOCK_TTL).await {
+            Ok(locked) => locked,
+            Err(e) => {
+                warn!("Failed to lock concurrency group {}: {:#}", group, e);
+                false
+            }
         };
+        if !locked {
+            info!("Concurrency group {} is busy, postponing job {}", group, job.id);
+            self.queue.postpone(job).await?;
+            tokio::time::sleep(GROUP_BUSY_RETRY_INTERVAL).await;
+            return Ok(None);
+        }
+
+        let mut queue = self.queue.clone();
+        let group = group.to_string();
+        let job_id = job.id.clone();
+        Ok(Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(GROUP_LOCK_TTL / 3);
+            // The first tick completes immediately, right after locking
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match queue.lock_group(&group, &job_id, GROUP_LOCK_TTL).await {
+                    Ok(true) => {}
+                    Ok(false) => warn!(
+                        "Job {} lost the lock of concurrency group {} while running",
+                        job_id, group
+                    ),
+                    Err(e) => warn!("Failed to extend concurrency group lock: {:#}", e),
+                }
+            }
+        })))
+    }
+
+    fn set_current_job(&self, job_id: Option<&str>) {
+        if let Some(heartbeat) = &self.heartbeat {
+            heartbeat.set_current_job(job_id);
+        }
+    }
+
+    /// Process a dequeued job and ACK, NACK or cancel it accordingly
+    /// Returns `false` if the job was postponed instead
+    async fn handle_job(&mut self, job: &Job) -> Result<bool> {
+        if job.is_expired(now_secs()) {
+            warn!("Job {} missed its deadline, not starting it", job.id);
+            return self.dead_letter(job, DeadReason::Expired, None).await.map(|()| true);
+        }
+        if !self.repo_policy.allows(&job.repo_url) {
+            warn!(
+                "Job {} targets a repository outside the allowlist: {}",
+                job.id, job.repo_url
+            );
+            return self
+                .dead_letter(job, DeadReason::RepoNotAllowed, None)
+                .await
+                .map(|()| true);
+        }
+        if let Some(profile) = &job.options.agent_profile {
+            if guest_binary::guest_binary(Some(profile)).is_none() {
+                let detail = format!(
+                    "Agent profile {} is not embedded; this worker has {}",
+                    profile,
+                    guest_binary::profiles().join(", ")
+                );
+                warn!("Job {}: {}", job.id, detail);
+                return self
+                    .dead_letter(job, DeadReason::UnknownAgentProfile, Some(&detail))
+                    .await
+                    .map(|()| true);
+            }
+        }
 
         info!("Processing job: {}", job.id);
+        let started = Instant::now();
+        self.job_stages.lock().unwrap().clear();
 
-        // Process the job and handle result
-        match self.process_job(&job).await {
-            Ok(_) => {
+        // Process the job and handle result, if its prompts are allowed
+        let result = match self.prompt_policy.check(job).await {
+            Ok(Some(violation)) => {
+                warn!("Job {} violates the prompt policy: {}", job.id, violation);
+                return self
+                    .dead_letter(job, DeadReason::PromptPolicy, Some(&violation))
+                    .await
+                    .map(|()| true);
+            }
+            Ok(None) => self.process_job(job).await,
+            Err(e) => Err(e.context(ErrorClass::Policy)),
+        };
+        self.retain_workdir(job, &result).await;
+        match result {
+            Ok(result) if result.awaiting_approval => {
+                info!("Job awaits approval: {}", job.id);
+                // Approving pushes the stored changes, so the result must be kept
+                self.queue.store_result(&result).await?;
+                self.queue.await_approval(job).await?;
+                self.notify(JobEvent::new(&job.id, JobState::AwaitingApproval));
+            }
+            Ok(result) => {
                 info!("Job completed successfully: {}", job.id);
-                self.queue.ack(&job).await?;
+                if let Some(sha) = &result.commit_sha {
+                    self.queue.record_commit(job, sha).await;
+                }
+                if let Err(e) = self.queue.store_result(&result).await {
+                    warn!("Failed to store result for job {}: {:#}", job.id, e);
+                }
+                self.queue.ack(job).await?;
+                let summary = self.summary(&result, started.elapsed());
+                self.queue.publish_summary(&summary).await;
+                self.notify(JobEvent {
+                    commit_sha: result.commit_sha.clone(),
+                    summary: Some(summary),
+                    ..JobEvent::new(&job.id, JobState::Succeeded)
+                });
+                self.enqueue_follow_ups(job, &result).await;
+            }
+            Err(e) if ErrorClass::of(&e) == ErrorClass::Cancelled => {
+                info!("Job cancelled: {}", job.id);
+                self.queue.finish_cancelled(job).await?;
+                self.notify(JobEvent::new(&job.id, JobState::Cancelled));
+            }
+            // Only the instances were at fault, so the attempt doesn't count
+            Err(e) if e.downcast_ref::<OnlyQuarantinedInstances>().is_some() => {
+                warn!("Job {}: {:#}; postponing it", job.id, e);
+                self.queue.postpone(job).await?;
+                return Ok(false);
+            }
+            // Retrying wouldn't make the repository any smaller
+            Err(e) if e.downcast_ref::<CloneBudgetExceeded>().is_some() => {
+                let detail = format!("{:#}", e);
+                warn!("Job {}: {}", job.id, detail);
+                self.queue.record_failure(job, ErrorClass::Clone, &detail).await;
+                self.dead_letter(job, DeadReason::CloneBudget, Some(&detail))
+                    .await?;
+            }
+            // The guest knows when another attempt would fail the same way
+            Err(e)
+                if e.downcast_ref::<GuestFailure>()
+                    .is_some_and(|failure| !failure.code.is_retryable()) =>
+            {
+                let detail = format!("{:#}", e);
+                warn!("Job {}: {}", job.id, detail);
+                self.queue.record_failure(job, ErrorClass::of(&e), &detail).await;
+                self.dead_letter(job, DeadReason::GuestError, Some(&detail))
+                    .await?;
             }
             Err(e) => {
                 error!("Job failed: {} - {:#}", job.id, e);
-                // Move job back to queue for retry
-                self.queue.nack(&job).await?;
+                let class = ErrorClass::of(&e);
+                let error = format!("{:#}", e);
+                let attempts = self.attempts(job).await;
+                if self.retries_exhausted(job, attempts) {
+                    // Park it where it can be inspected instead of retrying a
+                    // poison job forever
+                    self.queue.record_failure(job, class, &error).await;
+                    self.dead_letter(job, DeadReason::RetriesExhausted, Some(&error))
+                        .await?;
+                } else {
+                    // Move job back to queue for retry, once its delay has passed
+                    let delay = self.retry_policy.delay_after(class, attempts);
+                    self.queue.nack_after(job, class, &error, delay).await?;
+                }
             }
         }
 
         Ok(true)
     }
 
+    /// Enqueue the jobs a succeeded job declares in `on_success`, each told
+    /// the parent's commit through its `parent.json` context file
+    ///
+    /// Best-effort: the parent already succeeded, so a follow-up that can't
+    /// be enqueued is only logged. Dry runs push nothing for a follow-up to
+    /// build on, so they enqueue none.
+    async fn enqueue_follow_ups(&self, job: &Job, result: &JobResult) {
+        if job.on_success.is_empty() || job.options.dry_run {
+            return;
+        }
+
+        let parent = ParentContext {
+            job_id: job.id.clone(),
+            commit_sha: result.commit_sha.clone(),
+            branch: job.push_branch(),
+            summary: result.summary.clone(),
+        };
+        let contents = match serde_json::to_vec_pretty(&parent) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to serialize parent context of job {}: {}", job.id, e);
+                return;
+            }
+        };
+
+        let mut queue = self.queue.clone();
+        for index in 0..job.on_success.len() {
+            let mut follow_up = job.follow_up(index);
+            let enqueued = async {
+                let key = queue
+                    .store_context(&follow_up.id, PARENT_CONTEXT_FILE, &contents)
+                    .await?;
+                follow_up.context.push(ContextFile {
+                    name: PARENT_CONTEXT_FILE.to_string(),
+                    source: ContextSource::RedisKey(key),
+                });
+                queue.enqueue_unique(&follow_up).await
+            };
+            match enqueued.await {
+                Ok(true) => info!("Enqueued follow-up job {} of {}", follow_up.id, job.id),
+                Ok(false) => debug!("Follow-up job {} was already enqueued", follow_up.id),
+                Err(e) => warn!(
+                    "Failed to enqueue follow-up job {} of {}: {:#}",
+                    follow_up.id, job.id, e
+                ),
+            }
+        }
+    }
+
+    /// Move a job to the dead-letter queue without running it
+    async fn dead_letter(
+        &mut self,
+        job: &Job,
+        reason: DeadReason,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        self.queue.dead_letter_with_detail(job, reason, detail).await?;
+        self.notify(JobEvent {
+            error: detail.map(str::to_string),
+            dead_reason: Some(reason),
+            ..JobEvent::new(&job.id, JobState::Failed)
+        });
+        Ok(())
+    }
+
+    /// Send a job event to the webhook, if one is configured
+    fn notify(&self, event: JobEvent) {
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(JobEvent {
+                trace_id: trace::current().map(|trace| trace.trace_id().to_string()),
+                ..event
+            });
+        }
+    }
+
     /// Process a single job
-    async fn process_job(&self, job: &Job) -> Result<()> {
+    async fn process_job(&self, job: &Job) -> Result<JobResult> {
         info!("Starting job processing: {}", job.id);
+        let started = Instant::now();
 
-        // Step 1: Borrow an instance
-        info!("Borrowing instance for job: {}", job.id);
-        let instance = self.allocator.borrow_instance().await?;
-        let instance_guard = InstanceGuard::new(instance, self.allocator.clone());
+        if job.options.approved {
+            let stored = self
+                .queue
+                .clone()
+                .get_result(&job.id)
+                .await?
+                .with_context(|| format!("No stored changes to push for approved job {}", job.id))?;
+            return self.push_stored(job, stored).await;
+        }
+        match self.queue.clone().checkpoint(&job.id).await {
+            Ok(Some(checkpoint)) => {
+                info!("Pushing the changes job {} made before its push failed", job.id);
+                let result = self.push_stored(job, checkpoint).await?;
+                if let Err(e) = self.queue.clone().clear_checkpoint(&job.id).await {
+                    warn!("Failed to remove push checkpoint of job {}: {:#}", job.id, e);
+                }
+                return Ok(result);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read push checkpoint of job {}: {:#}", job.id, e),
+        }
 
-        // Step 2: Clone repository
+        // Steps 1 and 2: Borrow an instance while cloning the repository.
+        // The job deadline bounds the clone, so a stalled clone neither
+        // outlives the timeout nor holds the borrowed instance past it, and
+        // dropping it cancels the clone thread before a retry reuses its
+        // directory. The borrow isn't cut short, since an instance the
+        // allocator has already handed out would never be returned
+        info!("Borrowing instance for job: {}", job.id);
         let repo_dir = self.work_dir.join(&job.id);
+        let deadline = job.options.timeout_secs.map(|secs| {
+            (secs, tokio::time::Instant::from_std(started + Duration::from_secs(secs)))
+        });
+        let timed_out = |secs| {
+            anyhow::anyhow!("Job {} timed out after {}s", job.id, secs)
+                .context(ErrorClass::Timeout)
+        };
+        let clone = async {
+            match deadline {
+                Some((secs, deadline)) => {
+                    tokio::time::timeout_at(deadline, self.clone_repo(job, &repo_dir))
+                        .await
+                        .unwrap_or_else(|_| Err(timed_out(secs)))
+                }
+                None => self.clone_repo(job, &repo_dir).await,
+            }
+        };
+        let (instance, git_repo) = tokio::join!(
+            self.timed(Stage::Borrow, self.borrow_instance(job)),
+            clone,
+        );
+        let instance = match instance {
+            Ok(instance) => instance,
+            Err(e) => {
+                if git_repo.is_ok() {
+                    if let Err(e) = tokio::fs::remove_dir_all(&repo_dir).await {
+                        warn!("Failed to remove repo directory of job {}: {}", job.id, e);
+                    }
+                }
+                return Err(e.context(ErrorClass::Allocator));
+            }
+        };
+        if let Err(e) = self.ledger.record(&instance).await {
+            warn!("Failed to record instance {} in ledger: {:#}", instance.id, e);
+        }
+        self.record_event(job, TimelineEvent::InstanceBorrowed).await;
+        let instance_guard = self.returner.guard(instance);
+
+        // Set once the base branch is checked out, even if the job times out
+        let mut base_commit = None;
+        let run = self.run_job(job, instance_guard.instance(), git_repo, &mut base_commit);
+        let result = match deadline {
+            // The rest of the job shares the deadline with the clone. The
+            // sandbox call blocks, so a timeout fires at the next await after it
+            Some((secs, deadline)) => tokio::time::timeout_at(deadline, run)
+                .await
+                .unwrap_or_else(|_| Err(timed_out(secs))),
+            None => run.await,
+        };
+
+        // Step 7: Return instance with the job outcome (on early exit the
+        // guard hands it to the returner task instead)
+        let outcome = JobOutcome {
+            job_id: job.id.clone(),
+            success: result.is_ok(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            error_class: result.as_ref().err().map(ErrorClass::of),
+        };
+        let instance_id = instance_guard.instance().id.clone();
+        if let Err(e) = instance_guard.return_instance(Some(&outcome)).await {
+            // The ledger still lists the instance, so reconciliation retries it
+            warn!("Failed to return instance for job {}: {:#}", job.id, e);
+        }
+        if let Some(quarantine) = &self.quarantine {
+            let failed = result.as_ref().is_err_and(blames_instance);
+            if quarantine.lock().unwrap().record(&instance_id, failed, Instant::now()) {
+                warn!("Quarantining instance {} after repeated failures", instance_id);
+            }
+        }
+
+        if let Some(sha) = &base_commit {
+            let (state, description) = match &result {
+                Ok(_) => (CommitState::Success, "agent-worker: succeeded".to_string()),
+                Err(e) if ErrorClass::of(e) == ErrorClass::Cancelled => {
+                    (CommitState::Error, "agent-worker: cancelled".to_string())
+                }
+                Err(e) => (CommitState::Failure, format!("agent-worker: failed: {:#}", e)),
+            };
+            self.report_status(job, sha, state, &description).await;
+        }
+
+        if let (Err(e), Some(sha), true) = (&result, &base_commit, self.salvage) {
+            if matches!(ErrorClass::of(e), ErrorClass::Agent | ErrorClass::Timeout)
+                && !job.options.dry_run
+                && !job.options.read_only
+            {
+                self.salvage(job, sha, e).await;
+            }
+        }
+
+        if result.is_ok() {
+            info!("Job processing completed: {}", job.id);
+        }
+        result
+    }
+
+    /// Borrow an instance for the job, handing quarantined ones straight
+    /// back and asking for another
+    async fn borrow_instance(&self, job: &Job) -> Result<Instance> {
+        match &self.quarantine {
+            Some(quarantine) => {
+                self.allocator
+                    .borrow_outside(
+                        &job.required_capabilities,
+                        quarantine,
+                        QUARANTINE_BORROW_ATTEMPTS,
+                    )
+                    .await
+            }
+            None => self.allocator.borrow_instance(&job.required_capabilities).await,
+        }
+    }
+
+    /// Whether one of the queue's maintenance windows is open, logging when
+    /// the worker pauses and resumes
+    async fn in_maintenance(&mut self) -> bool {
+        let windows = match self.queue.maintenance_windows().await {
+            Ok(windows) => windows,
+            Err(e) => {
+                warn!("Failed to read maintenance windows: {:#}", e);
+                return false;
+            }
+        };
+
+        match maintenance::open_window(&windows, now_secs()) {
+            Some((window, until)) => {
+                if self.maintenance.as_deref() != Some(window.name.as_str()) {
+                    info!(
+                        "Maintenance window {} is open, pausing until {}",
+                        window.name, until
+                    );
+                    self.maintenance = Some(window.name.clone());
+                }
+                true
+            }
+            None => {
+                if let Some(name) = self.maintenance.take() {
+                    info!("Maintenance window {} closed, resuming", name);
+                }
+                false
+            }
+        }
+    }
+
+    /// Whether a dependency checked by the health gate is down, logging when
+    /// the worker holds off and resumes
+    async fn dependencies_down(&mut self) -> bool {
+        let Some(gate) = &self.health_gate else {
+            return false;
+        };
+        if self
+            .last_healthy
+            .is_some_and(|at| at.elapsed() < HEALTH_CHECK_INTERVAL)
+        {
+            return false;
+        }
+
+        let failures = gate.failures().await;
+        if failures.is_empty() {
+            if self.holding_off {
+                info!("Dependencies are healthy again, resuming");
+            }
+            self.holding_off = false;
+            self.last_healthy = Some(Instant::now());
+            return false;
+        }
+
+        let down: Vec<String> = failures
+            .iter()
+            .map(|check| format!("{} ({})", check.name, check.detail))
+            .collect();
+        warn!("Holding off dequeuing while dependencies are down: {}", down.join(", "));
+        self.holding_off = true;
+        self.last_healthy = None;
+        true
+    }
+
+    async fn record_event(&self, job: &Job, event: TimelineEvent) {
+        self.queue.clone().record_event(&job.id, event).await;
+    }
+
+    /// Record each progress report of a job's agent in its status hash and
+    /// log, until the agent returns
+    fn relay_progress(
+        &self,
+        job: &Job,
+        mut reports: mpsc::UnboundedReceiver<AgentProgress>,
+    ) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut queue = self.queue.clone();
+        let job_id = job.id.clone();
+        async move {
+            while let Some(progress) = reports.recv().await {
+                info!(
+                    "Agent progress: {} ({}%): {}",
+                    progress.step, progress.percent, progress.message
+                );
+                queue.record_progress(&job_id, &progress).await;
+            }
+        }
+    }
+
+    /// Run a stage of a job, recording how long it took whether or not it succeeded
+    async fn timed<T>(&self, stage: Stage, work: impl std::future::Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = work.await;
+        let elapsed = started.elapsed();
+        *self.job_stages.lock().unwrap().entry(stage).or_default() += elapsed;
+        self.queue.clone().record_stage_duration(stage, elapsed).await;
+        output
+    }
+
+    /// Summary of a succeeded job that took `duration`, with the stage
+    /// durations recorded while it ran
+    fn summary(&self, result: &JobResult, duration: Duration) -> JobSummary {
+        let stages_ms = self
+            .job_stages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(stage, elapsed)| (stage.as_str().to_string(), elapsed.as_millis() as u64))
+            .collect();
+        JobSummary::new(result, duration.as_millis() as u64, stages_ms)
+    }
+
+    /// Attempts made at a job so far, counting the current one
+    async fn attempts(&mut self, job: &Job) -> u32 {
+        match self.queue.get_status(&job.id).await {
+            Ok(status) => status.map_or(0, |status| status.attempts),
+            Err(e) => {
+                warn!("Failed to read attempts of job {}: {:#}", job.id, e);
+                0
+            }
+        }
+    }
+
+    /// Whether a job that failed after `attempts` attempts has used up the
+    /// retries its options, or else the worker, allow
+    fn retries_exhausted(&self, job: &Job, attempts: u32) -> bool {
+        // Every dequeue counts as an attempt; the first isn't a retry
+        job.options
+            .max_retries
+            .or(self.max_retries)
+            .is_some_and(|max_retries| attempts > max_retries)
+    }
+
+    /// Fail with `ErrorClass::Cancelled` if cancellation was requested
+    async fn check_cancelled(&self, job: &Job) -> Result<()> {
+        let mut queue = self.queue.clone();
+        match queue.is_cancel_requested(&job.id).await {
+            Ok(true) => Err(anyhow::anyhow!("Job {} was cancelled", job.id)
+                .context(ErrorClass::Cancelled)),
+            Ok(false) => Ok(()),
+            Err(e) => {
+                warn!("Failed to check cancellation of job {}: {:#}", job.id, e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the status of the job's base commit, if it is on GitHub
+    /// Best-effort: a failure is logged and doesn't affect the job
+    async fn report_status(&self, job: &Job, sha: &str, state: CommitState, description: &str) {
+        let Some(reporter) = &self.commit_status else {
+            return;
+        };
+        if let Err(e) = reporter
+            .report(&job.repo_url, sha, &job.id, state, description)
+            .await
+        {
+            warn!("Failed to set commit status for job {}: {:#}", job.id, e);
+        }
+    }
+
+    /// Clone the job's repository into a fresh `repo_dir` on a blocking
+    /// thread, so the instance can be borrowed in the meantime
+    async fn clone_repo(&self, job: &Job, repo_dir: &Path) -> Result<AsyncGitRepo> {
         if repo_dir.exists() {
             info!("Cleaning up existing repository directory");
-            std::fs::remove_dir_all(&repo_dir)
-                .context("Failed to remove existing repo directory")?;
+            tokio::fs::remove_dir_all(repo_dir)
+                .await
+                .context("Failed to remove existing repo directory")
+                .context(ErrorClass::Cleanup)?;
         }
 
         info!("Cloning repository: {}", job.repo_url);
-        let git_repo = GitRepo::clone(&job.repo_url, &repo_dir)
-            .context("Failed to clone repository")?;
+        let budget = self.clone_budget.min(CloneBudget {
+            max_bytes: job.options.max_repo_mb.map(|mb| mb * 1024 * 1024),
+            max_duration: job.options.max_clone_secs.map(Duration::from_secs),
+            max_bytes_per_sec: None,
+        });
+        let clone = async {
+            let credential = self.git_credentials.resolve(&job.repo_url).await?;
+            AsyncGitRepo::clone_within(&job.repo_url, repo_dir, &credential, &budget).await
+        };
+        let git_repo = self
+            .timed(Stage::Clone, clone)
+            .await
+            .context("Failed to clone repository")
+            .context(ErrorClass::Clone)?;
+        self.record_event(job, TimelineEvent::CloneDone).await;
 
-        // Step 3: Checkout branch
-        info!("Checking out branch: {}", job.branch);
-        git_repo.fetch().context("Failed to fetch from remote")?;
-        git_repo
-            .checkout_branch(&job.branch)
-            .context("Failed to checkout branch")?;
+        Ok(git_repo)
+    }
+
+    /// Commit whatever a failed agent left in the checkout to a quarantine
+    /// branch, push it and attach the diff to the job's failure record
+    /// Best-effort: a failure is logged and the job fails as it would have
+    async fn salvage(&self, job: &Job, base_commit: &str, error: &anyhow::Error) {
+        let mut queue = self.queue.clone();
+        let attempt = self.attempt(job).await;
+        let branch = format!("agent-salvage/{}/attempt-{}", job.id, attempt);
+        let error = format!("{:#}", error);
+
+        let salvage = async {
+            let repo_dir = self.work_dir.join(&job.id);
+            if !repo_dir.exists() {
+                return Ok(None);
+            }
+            let credential = self.git_credentials.resolve(&job.repo_url).await?;
+            let (job, base_commit, branch, error) =
+                (job.clone(), base_commit.to_string(), branch.clone(), error.clone());
+            tokio::task::spawn_blocking(move || -> Result<Option<Salvage>> {
+                let git_repo = GitRepo::open(&repo_dir)?;
+                // Earlier tasks of the job may have committed already
+                if git_repo.has_changes()? {
+                    git_repo.stage_all()?;
+                    let message = format!("Salvaged work of failed job: {}\n\n{}", job.id, error);
+                    match &job.options.commit_author {
+                        Some(author) => {
+                            git_repo.commit_as(&message, &author.name, &author.email)
+                        }
+                        None => git_repo.commit(&message),
+                    }?;
+                }
+                let commit_sha = git_repo.head_commit()?;
+                if commit_sha == base_commit {
+                    return Ok(None);
+                }
+                let diff = git_repo.diff_since(&base_commit)?;
+                git_repo.create_branch(&branch)?;
+                git_repo.push_with(&branch, &credential)?;
+
+                Ok(Some(Salvage {
+                    job_id: job.id.clone(),
+                    attempt,
+                    error,
+                    branch,
+                    commit_sha,
+                    diff,
+                }))
+            })
+            .await?
+        };
+
+        match salvage.await {
+            Ok(Some(salvage)) => {
+                info!("Salvaged the changes of job {} to branch {}", job.id, salvage.branch);
+                if let Err(e) = queue.store_salvage(&salvage).await {
+                    warn!("Failed to store salvaged work of job {}: {:#}", job.id, e);
+                }
+            }
+            Ok(None) => debug!("Job {} left no changes to salvage", job.id),
+            Err(e) => warn!("Failed to salvage the changes of job {}: {:#}", job.id, e),
+        }
+    }
+
+    /// Which attempt at the job this is, counting from 1
+    async fn attempt(&self, job: &Job) -> u32 {
+        match self.queue.clone().get_status(&job.id).await {
+            Ok(status) => status.map_or(1, |status| status.attempts),
+            Err(e) => {
+                warn!("Failed to read attempts of job {}: {:#}", job.id, e);
+                1
+            }
+        }
+    }
+
+    /// Delete or keep whatever checkout a processed job left behind,
+    /// following the retention policy, and delete kept checkouts that have
+    /// expired
+    /// Best-effort: a failure is logged and doesn't affect the job
+    async fn retain_workdir(&self, job: &Job, result: &Result<JobResult>) {
+        let repo_dir = self.work_dir.join(&job.id);
+        let kept_dir = self.work_dir.join(KEPT_DIR);
+        let failed = matches!(result, Err(e) if ErrorClass::of(e) != ErrorClass::Cancelled);
+
+        if repo_dir.exists() {
+            let cleanup = if failed && self.workdir_retention.keeps_failures() {
+                let name = format!("{}.attempt-{}", job.id, self.attempt(job).await);
+                let kept_dir = kept_dir.clone();
+                tokio::task::spawn_blocking(move || workdir::keep(&repo_dir, &kept_dir, &name))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|kept| kept)
+            } else {
+                self.timed(Stage::Cleanup, tokio::fs::remove_dir_all(&repo_dir))
+                    .await
+                    .context("Failed to remove repo directory")
+            };
+            if let Err(e) = cleanup {
+                warn!("Failed to clean up checkout of job {}: {:#}", job.id, e);
+            }
+        }
+
+        // Scratch files are never worth keeping, even of failed jobs
+        let scratch_dir = self.work_dir.join(SCRATCH_DIR).join(&job.id);
+        if scratch_dir.exists() {
+            if let Err(e) = tokio::fs::remove_dir_all(&scratch_dir).await {
+                warn!("Failed to remove scratch directory of job {}: {}", job.id, e);
+            }
+        }
+
+        if let WorkdirRetention::KeepFor(max_age) = self.workdir_retention {
+            match tokio::task::spawn_blocking(move || workdir::sweep(&kept_dir, max_age)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => info!("Removed {} expired kept checkouts", removed),
+                Ok(Err(e)) => warn!("Failed to remove expired kept checkouts: {:#}", e),
+                Err(e) => warn!("Failed to remove expired kept checkouts: {}", e),
+            }
+        }
+    }
+
+    /// Push `branch`, with a freshly resolved credential since a GitHub App
+    /// token from the clone may have expired while the agent ran
+    ///
+    /// A failed push is retried with exponential backoff, up to
+    /// `push_attempts` tries in all.
+    async fn push(&self, job: &Job, git_repo: &AsyncGitRepo, branch: &str) -> Result<()> {
+        let mut backoff = PUSH_RETRY_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let pushed = self
+                .timed(Stage::Push, async {
+                    let credential = self.git_credentials.resolve(&job.repo_url).await?;
+                    let branch = branch.to_string();
+                    git_repo
+                        .run(move |repo| repo.push_with(&branch, &credential))
+                        .await
+                })
+                .await;
+
+            match pushed {
+                Ok(()) => break,
+                Err(e) if attempt >= self.push_attempts => {
+                    return Err(e.context("Failed to push changes").context(ErrorClass::Push));
+                }
+                Err(e) => {
+                    warn!(
+                        "Push of job {} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                        job.id, attempt, self.push_attempts, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+        self.record_event(job, TimelineEvent::Pushed).await;
+
+        if self.agent_metadata == Some(MetadataMode::Note) {
+            let notes = async {
+                let credential = self.git_credentials.resolve(&job.repo_url).await?;
+                git_repo
+                    .run(move |repo| repo.push_notes_with(agent_metadata::NOTES_REF, &credential))
+                    .await
+            };
+            // The commits are out already; their notes only add to them
+            if let Err(e) = notes.await {
+                warn!("Failed to push agent metadata notes of job {}: {:#}", job.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the changes of a job whose push failed, so its next attempt
+    /// pushes them without running the agent again
+    ///
+    /// Best-effort: without a checkpoint the next attempt starts over.
+    async fn checkpoint_push(&self, job: &Job, result: &JobResult) {
+        // The commits are discarded with the checkout; the next attempt
+        // commits the stored diff again
+        let checkpoint = JobResult {
+            commit_sha: None,
+            tasks: result
+                .tasks
+                .iter()
+                .map(|task| TaskResult {
+                    commit_sha: None,
+                    ..task.clone()
+                })
+                .collect(),
+            ..result.clone()
+        };
+        match self
+            .queue
+            .clone()
+            .store_checkpoint(&checkpoint, PUSH_CHECKPOINT_TTL)
+            .await
+        {
+            Ok(()) => info!("Kept the changes of job {} for its next attempt to push", job.id),
+            Err(e) => warn!("Failed to store push checkpoint of job {}: {:#}", job.id, e),
+        }
+    }
+
+    /// Run the git and agent stages of a job on a borrowed instance, once its
+    /// repository is cloned, recording the commit the job starts from in
+    /// `base_commit`
+    async fn run_job(
+        &self,
+        job: &Job,
+        instance: &Instance,
+        git_repo: Result<AsyncGitRepo>,
+        base_commit: &mut Option<String>,
+    ) -> Result<JobResult> {
+        let repo_dir = self.work_dir.join(&job.id);
+        let git_repo = git_repo?;
+
+        self.check_cancelled(job).await?;
+
+        // Step 3: Checkout branch and fetch context files
+        info!("Checking out branch: {}", job.base_branch);
+        self.timed(Stage::Checkout, async {
+            let base_branch = job.base_branch.clone();
+            git_repo
+                .run(move |repo| {
+                    repo.fetch().context("Failed to fetch from remote")?;
+                    repo.checkout_branch(&base_branch)
+                        .context("Failed to checkout branch")
+                })
+                .await
+                .context(ErrorClass::Checkout)?;
+            self.fetch_context(job, &git_repo)
+                .await
+                .context("Failed to fetch context files")
+                .context(ErrorClass::Context)
+        })
+        .await?;
+
+        match git_repo.run(|repo| repo.head_commit()).await {
+            Ok(sha) => {
+                self.report_status(job, &sha, CommitState::Pending, "agent-worker: running")
+                    .await;
+                *base_commit = Some(sha);
+            }
+            Err(e) => warn!("Failed to resolve base commit of job {}: {:#}", job.id, e),
+        }
+
+        self.check_cancelled(job).await?;
 
-        // Step 4: Execute agent with MCP permissions
-        info!("Executing agent for job: {}", job.id);
+        // Step 4: Execute agent with MCP permissions, committing after each prompt
         let mcp_url = job
             .mcp_connection_url
             .as_deref()
-            .or(Some(&instance_guard.instance().mcp_connection_url));
+            .or(Some(&instance.mcp_connection_url));
+        let target_branch = self.target_branch(job, &git_repo).await?;
+        let prompts = job.prompts();
 
-        let result = self
-            .agent_executor
-            .execute(git_repo.path(), &job.prompt, mcp_url)
-            .await
-            .context("Failed to execute agent")?;
+        let cache_key = match (self.result_cache_ttl, base_commit.as_deref()) {
+            (Some(_), Some(sha)) if !job.options.no_cache => {
+                Some(cache::cache_key(job, sha, &target_branch))
+            }
+            _ => None,
+        };
+        if let Some(cached) = self.cached_result(cache_key.as_deref()).await {
+            info!("Reusing the cached result of job {} for job {}", cached.job_id, job.id);
+            tokio::fs::remove_dir_all(&repo_dir)
+                .await
+                .context("Failed to remove repo directory")
+                .context(ErrorClass::Cleanup)?;
+            return Ok(JobResult {
+                job_id: job.id.clone(),
+                cached_from: Some(cached.job_id.clone()),
+                ..cached
+            });
+        }
 
-        if !result.is_success() {
-            anyhow::bail!(
-                "Agent execution failed with exit code {}: {}",
-                result.exit_code,
-                result.stderr
+        let mut job_result = JobResult {
+            job_id: job.id.clone(),
+            guest_sha256: guest_binary::sha256(job.options.agent_profile.as_deref())
+                .map(str::to_string),
+            worker_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            ..Default::default()
+        };
+        // Shared by the job's tasks; removed with its checkout
+        let work_dir = self.work_dir.clone();
+        let job_id = job.id.clone();
+        let scratch = tokio::task::spawn_blocking(move || ScratchDir::create(&work_dir, &job_id))
+            .await?
+            .context("Failed to create scratch directory")?;
+        for (index, prompt) in prompts.iter().enumerate() {
+            info!(
+                "Executing agent for job {} (task {}/{})",
+                job.id,
+                index + 1,
+                prompts.len()
             );
-        }
+            self.record_event(job, TimelineEvent::AgentStarted).await;
+            let (progress, reports) = mpsc::unbounded_channel();
+            let relay = tokio::spawn(self.relay_progress(job, reports).in_current_span());
+            let execution = self.agent_executor.execute(
+                git_repo.path(),
+                prompt,
+                mcp_url,
+                job.options.allowed_tools.as_deref(),
+                job.options.agent_profile.as_deref(),
+                Some(progress),
+                Some(&scratch),
+                job.options.read_only,
+            );
+            let result = self.timed(Stage::Agent, execution).await;
+            // The executor closes the channel when the agent returns
+            let _ = relay.await;
+            self.record_event(job, TimelineEvent::AgentFinished).await;
+            let result = result
+                .context("Failed to execute agent")
+                .context(ErrorClass::Agent)?;
+            self.queue.clone().record_sandbox_metrics(&result.sandbox).await;
+            job_result.sandbox = Some(match job_result.sandbox {
+                Some(sandbox) => sandbox.merge(result.sandbox),
+                None => result.sandbox,
+            });
+            if result.tokens_used > 0 {
+                job_result.tokens_used =
+                    Some(job_result.tokens_used.unwrap_or(0) + result.tokens_used);
+            }
 
-        // Step 5: Check for changes and commit/push if needed
-        if git_repo.has_changes()? {
-            info!("Changes detected, committing and pushing");
+            if !result.is_success() {
+                return Err(anyhow::anyhow!(
+                    "Agent execution failed with exit code {}: {}",
+                    result.exit_code,
+                    result.stderr
+                )
+                .context(ErrorClass::Agent));
+            }
 
-            git_repo.stage_all().context("Failed to stage changes")?;
+            self.check_cancelled(job).await?;
 
-            let commit_message = format!(
-                "Agent changes for job: {}\n\nPrompt: {}",
-                job.id, job.prompt
-            );
-            git_repo
-                .commit(&commit_message)
-                .context("Failed to commit changes")?;
+            let mut task = TaskResult {
+                prompt: prompt.to_string(),
+                summary: result.stdout,
+                ..Default::default()
+            };
+            if job.options.read_only {
+                // Anything the agent changed is deleted with the checkout
+                debug!("Job {} is read-only, leaving task {} uncommitted", job.id, index + 1);
+            } else {
+                let commit_message = self.commit_message(job, &task, index, &result.tool_calls);
+                let metadata = self.agent_metadata.map(|mode| {
+                    let mut metadata =
+                        AgentMetadata::new(job, prompt, &result.tool_calls, &self.worker_id);
+                    if mode == MetadataMode::Note {
+                        metadata = metadata.with_provenance(Provenance::new(
+                            job,
+                            prompt,
+                            &result.tool_calls,
+                            base_commit.as_deref(),
+                        ));
+                    }
+                    (mode, metadata.to_json())
+                });
+                self.timed(
+                    Stage::Commit,
+                    self.commit_task(job, &git_repo, &mut task, commit_message, metadata),
+                )
+                .await?;
+            }
+            job_result.tool_transcript.extend(result.tool_calls);
+            job_result.tasks.push(task);
+        }
 
-            git_repo
-                .push(&job.branch)
-                .context("Failed to push changes")?;
+        // Last chance to stop before anything leaves this machine
+        self.check_cancelled(job).await?;
 
-            info!("Changes successfully pushed to branch: {}", job.branch);
+        let mut last_commit = job_result
+            .tasks
+            .iter()
+            .rev()
+            .find_map(|task| task.commit_sha.clone());
+        if job.options.require_approval && last_commit.is_some() {
+            info!("Job {} awaits approval before pushing", job.id);
+            // The commits are discarded with the checkout; approving the job
+            // commits the stored diff again
+            for task in &mut job_result.tasks {
+                task.commit_sha = None;
+            }
+            last_commit = None;
+            job_result.awaiting_approval = true;
+        }
+
+        job_result.commit_sha = last_commit;
+        job_result.diff = if job.options.dry_run {
+            // Nothing was committed, so each task's diff includes the ones before it
+            job_result
+                .tasks
+                .iter()
+                .rev()
+                .find(|task| !task.diff.is_empty())
+                .map(|task| task.diff.clone())
+                .unwrap_or_default()
         } else {
-            warn!("No changes detected after agent execution");
+            job_result
+                .tasks
+                .iter()
+                .map(|task| task.diff.as_str())
+                .collect()
+        };
+        if let Some(last) = job_result.tasks.last() {
+            job_result.summary = last.summary.clone();
+        }
+        if !job_result.diff.is_empty() {
+            job_result.description = self.description_template.render(&DescriptionInput {
+                job_id: &job.id,
+                task: None,
+                prompt: &job.prompt,
+                report: &job_result.summary,
+                tool_calls: &job_result.tool_transcript,
+            });
+        }
+        // Per-task results only add something for jobs with several prompts
+        if job_result.tasks.len() == 1 {
+            job_result.tasks.clear();
+        }
+
+        // Step 5: Push the commits, if the agent made any
+        if job_result.commit_sha.is_some() {
+            if let Err(e) = self.push(job, &git_repo, &target_branch).await {
+                self.checkpoint_push(job, &job_result).await;
+                return Err(e);
+            }
+            info!("Changes successfully pushed to branch: {}", target_branch);
         }
 
         // Step 6: Clean up repository
         info!("Cleaning up repository directory");
-        std::fs::remove_dir_all(&repo_dir)
-            .context("Failed to remove repo directory")?;
+        self.timed(Stage::Cleanup, tokio::fs::remove_dir_all(&repo_dir))
+            .await
+            .context("Failed to remove repo directory")
+            .context(ErrorClass::Cleanup)?;
+
+        // Unpushed changes aren't worth reusing
+        if let (Some(key), Some(ttl), false) =
+            (&cache_key, self.result_cache_ttl, job_result.awaiting_approval)
+        {
+            if let Err(e) = self.queue.clone().cache_result(key, &job_result, ttl).await {
+                warn!("Failed to cache result of job {}: {:#}", job.id, e);
+            }
+        }
+
+        Ok(job_result)
+    }
+
+    /// Commit and push changes stored by an earlier attempt, when the job
+    /// stopped to await approval or its push failed, without running the
+    /// agent again
+    async fn push_stored(&self, job: &Job, stored: JobResult) -> Result<JobResult> {
+        let repo_dir = self.work_dir.join(&job.id);
+        let git_repo = self.clone_repo(job, &repo_dir).await?;
+        let base_branch = job.base_branch.clone();
+        self.timed(
+            Stage::Checkout,
+            git_repo.run(move |repo| {
+                repo.fetch().context("Failed to fetch from remote")?;
+                repo.checkout_branch(&base_branch)
+                    .context("Failed to checkout branch")
+            }),
+        )
+        .await
+        .context(ErrorClass::Checkout)?;
+        let target_branch = self.target_branch(job, &git_repo).await?;
+
+        // Each task's patch applies on top of the ones before it
+        let patches: Vec<String> = if stored.tasks.is_empty() {
+            vec![stored.diff.clone()]
+        } else {
+            stored.tasks.iter().map(|task| task.diff.clone()).collect()
+        };
+        let message = if stored.description.is_empty() {
+            format!("Agent changes for job: {}", job.id)
+        } else {
+            stored.description.clone()
+        };
+        let author = job.options.commit_author.clone();
+        // A file written by the agent's run is part of the stored patches
+        let note = (self.agent_metadata == Some(MetadataMode::Note)).then(|| {
+            let prompt = job.prompts().join("\n\n");
+            let tool_calls = &stored.tool_transcript;
+            AgentMetadata::new(job, &prompt, tool_calls, &self.worker_id)
+                .with_provenance(Provenance::new(job, &prompt, tool_calls, None))
+                .to_json()
+        });
+        let commit_sha = self
+            .timed(
+                Stage::Commit,
+                git_repo.run(move |repo| {
+                    for patch in patches.iter().filter(|patch| !patch.is_empty()) {
+                        repo.apply(patch)?;
+                    }
+                    let commit_sha = match &author {
+                        Some(author) => repo.commit_as(&message, &author.name, &author.email),
+                        None => repo.commit(&message),
+                    }?;
+                    if let Some(note) = &note {
+                        repo.add_note(agent_metadata::NOTES_REF, &commit_sha, note)?;
+                    }
+                    Ok(commit_sha)
+                }),
+            )
+            .await
+            .context("Failed to commit the stored changes")
+            .context(ErrorClass::Commit)?;
 
-        // Step 7: Return instance (automatically handled by InstanceGuard drop)
-        instance_guard.return_instance().await?;
+        self.push(job, &git_repo, &target_branch).await?;
+        info!("Stored changes pushed to branch: {}", target_branch);
+
+        self.timed(Stage::Cleanup, tokio::fs::remove_dir_all(&repo_dir))
+            .await
+            .context("Failed to remove repo directory")
+            .context(ErrorClass::Cleanup)?;
+
+        Ok(JobResult {
+            commit_sha: Some(commit_sha),
+            awaiting_approval: false,
+            ..stored
+        })
+    }
+
+    /// Best-effort cache lookup; an unreadable cache just means running the agent
+    async fn cached_result(&self, cache_key: Option<&str>) -> Option<JobResult> {
+        match self.queue.clone().cached_result(cache_key?).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!("Failed to read the result cache: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Download the job's context files into `CONTEXT_DIR`, which is kept
+    /// out of the job's commits
+    async fn fetch_context(&self, job: &Job, git_repo: &AsyncGitRepo) -> Result<()> {
+        if job.context.is_empty() {
+            return Ok(());
+        }
+        // Jobs pushed straight into Redis were never validated
+        job.validate_context()?;
+
+        let context_dir = git_repo.path().join(CONTEXT_DIR);
+        std::fs::create_dir_all(&context_dir).context("Failed to create context directory")?;
+        git_repo
+            .run(|repo| repo.exclude(&format!("/{}/", CONTEXT_DIR)))
+            .await?;
+
+        let mut queue = self.queue.clone();
+        for file in &job.context {
+            // URLs may carry credentials (presigned S3 URLs), so only log the name
+            info!("Fetching context file: {}", file.name);
+            let contents = match &file.source {
+                ContextSource::RedisKey(key) => {
+                    // Any other key could be another job's or tenant's data
+                    if *key != context_key(queue.queue_name(), &job.id, &file.name) {
+                        bail!("Context key {} doesn't belong to job {}", key, job.id);
+                    }
+                    queue
+                        .get_context(key)
+                        .await?
+                        .with_context(|| format!("Context key {} does not exist", key))?
+                }
+                ContextSource::Url(url) => self
+                    .download_context(url)
+                    .await
+                    .with_context(|| format!("Failed to download {}", file.name))?,
+            };
+            std::fs::write(context_dir.join(&file.name), contents)
+                .with_context(|| format!("Failed to write {}", file.name))?;
+        }
 
-        info!("Job processing completed: {}", job.id);
+        Ok(())
+    }
+
+    /// Download a context file from a URL a job gave
+    ///
+    /// Whoever enqueued the job chose the URL, so it and every redirect must
+    /// be HTTPS to a public address (see `http::download_public`).
+    async fn download_context(&self, url: &str) -> Result<Vec<u8>> {
+        http::download_public(
+            &self.http_config,
+            url,
+            CONTEXT_DOWNLOAD_TIMEOUT,
+            MAX_CONTEXT_DOWNLOAD_BYTES,
+        )
+        .await
+    }
+
+    /// Branch the job's commits are pushed to, created from the base branch
+    /// unless it is the base branch itself
+    async fn target_branch(&self, job: &Job, git_repo: &AsyncGitRepo) -> Result<String> {
+        let branch = job.push_branch();
+        if branch != job.base_branch {
+            let new_branch = branch.clone();
+            git_repo
+                .run(move |repo| repo.create_branch(&new_branch))
+                .await
+                .context("Failed to create branch")
+                .context(ErrorClass::Commit)?;
+        }
+        Ok(branch)
+    }
+
+    /// Message of a task's commit, rendered from the agent's report
+    fn commit_message(
+        &self,
+        job: &Job,
+        task: &TaskResult,
+        index: usize,
+        tool_calls: &[ToolCall],
+    ) -> String {
+        let total = job.prompts().len();
+        let mut commit_message = self.description_template.render(&DescriptionInput {
+            job_id: &job.id,
+            task: (total > 1).then_some((index + 1, total)),
+            prompt: &task.prompt,
+            report: &task.summary,
+            tool_calls,
+        });
+        // A provenance note carries the trace instead
+        if let (Some(trace), false) =
+            (trace::current(), self.agent_metadata == Some(MetadataMode::Note))
+        {
+            commit_message.push_str(&format!("\n\nTrace-Id: {}", trace.trace_id()));
+        }
+        commit_message
+    }
+
+    /// Stage and, unless this is a dry run, commit what the agent changed
+    /// for one task, recording the patch and commit on the task's result
+    async fn commit_task(
+        &self,
+        job: &Job,
+        git_repo: &AsyncGitRepo,
+        task: &mut TaskResult,
+        commit_message: String,
+        metadata: Option<(MetadataMode, String)>,
+    ) -> Result<()> {
+        let job = job.clone();
+        let (diff, commit_sha) = git_repo
+            .run(move |repo| commit_changes(&job, repo, &commit_message, metadata.as_ref()))
+            .await?;
+        task.diff = diff;
+        task.commit_sha = commit_sha;
         Ok(())
     }
 
     /// Get queue statistics
     pub async fn get_stats(&mut self) -> Result<WorkerStats> {
-        let queue_len = self.queue.len().await?;
-        let processing_len = self.queue.processing_len().await?;
+        let (queue_len, processing_len) = self.queue.depths().await?;
+        let stages = self.queue.stage_histograms().await?;
 
         Ok(WorkerStats {
             queue_length: queue_len,
             processing_length: processing_len,
+            stages,
         })
     }
 }
 
+/// Whether a job's failure may be down to the instance it ran on, such as
+/// its MCP server failing, rather than to the job's repository or prompt
+fn blames_instance(e: &anyhow::Error) -> bool {
+    ErrorClass::of(e) == ErrorClass::Agent
+        && e.downcast_ref::<GuestFailure>()
+            .is_none_or(|failure| failure.code.is_retryable())
+}
+
+/// Stage the agent's changes and commit them with `commit_message` unless
+/// the job is a dry run, returning the staged patch and the commit
+///
+/// `metadata` is written to `.agent-metadata` or attached as a note, as its
+/// mode says.
+fn commit_changes(
+    job: &Job,
+    git_repo: &GitRepo,
+    commit_message: &str,
+    metadata: Option<&(MetadataMode, String)>,
+) -> Result<(String, Option<String>)> {
+    if !git_repo.has_changes().context(ErrorClass::Commit)? {
+        warn!("No changes detected after agent execution");
+        return Ok((String::new(), None));
+    }
+
+    info!("Changes detected, committing");
+    if let (Some((MetadataMode::File, metadata)), false) = (metadata, job.options.dry_run) {
+        std::fs::write(git_repo.path().join(agent_metadata::METADATA_FILE), metadata)
+            .context("Failed to write agent metadata")
+            .context(ErrorClass::Commit)?;
+    }
+    git_repo
+        .stage_all()
+        .context("Failed to stage changes")
+        .context(ErrorClass::Commit)?;
+
+    let diff = git_repo.staged_diff().unwrap_or_else(|e| {
+        warn!("Failed to render diff for job {}: {:#}", job.id, e);
+        String::new()
+    });
+
+    if job.options.dry_run {
+        info!("Dry run, leaving changes uncommitted");
+        return Ok((diff, None));
+    }
+
+    let commit = match &job.options.commit_author {
+        Some(author) => git_repo.commit_as(commit_message, &author.name, &author.email),
+        None => git_repo.commit(commit_message),
+    };
+    let commit_sha = commit
+        .context("Failed to commit changes")
+        .context(ErrorClass::Commit)?;
+    if let Some((MetadataMode::Note, metadata)) = metadata {
+        git_repo
+            .add_note(agent_metadata::NOTES_REF, &commit_sha, metadata)
+            .context(ErrorClass::Commit)?;
+    }
+    Ok((diff, Some(commit_sha)))
+}
+
 #[derive(Debug)]
 pub struct WorkerStats {
     pub queue_length: usize,
     pub processing_length: usize,
+    /// How long each stage of processing jobs took, across the queue's workers
+    pub stages: BTreeMap<Stage, StageHistogram>,
 }
@@ -1,7 +1,13 @@
 mod common;
 
 use anyhow::Result;
+use redis_agent_worker::agent::AgentLimits;
+use redis_agent_worker::error::ErrorClass;
+use redis_agent_worker::git::CloneBudget;
+use redis_agent_worker::http::HttpClientConfig;
+use redis_agent_worker::memo::MemoConfig;
 use redis_agent_worker::queue::{Job, ReliableQueue};
+use redis_agent_worker::retry::RetryPolicy;
 use redis_agent_worker::worker::{Worker, WorkerConfig};
 use std::time::Duration;
 use tempfile::TempDir;
@@ -36,8 +42,39 @@ async fn test_e2e_worker_stats() -> Result<()> {
         queue_name: "e2e_stats_queue".to_string(),
         queue_timeout: 2,
         allocator_api_url: allocator_url,
-        hyperlight_path: "/usr/local/bin/hyperlight".to_string(),
         work_dir: work_dir.to_str().unwrap().to_string(),
+        worker_id: "e2e-worker".to_string(),
+        reconcile_interval: 300,
+        label_selector: Default::default(),
+        cipher: None,
+        repo_policy: Default::default(),
+        tenant: None,
+        git_credentials: Default::default(),
+        commit_status: None,
+        webhook: None,
+        result_cache_ttl: None,
+        description_template: Default::default(),
+        salvage: false,
+        workdir_retention: Default::default(),
+        prompt_policy: Default::default(),
+        tool_schemas: Default::default(),
+        health_gate: None,
+        push_attempts: 3,
+        retry_policy: RetryPolicy::default(),
+        clone_budget: CloneBudget::default(),
+        agent_metadata: None,
+        llm_providers: Vec::new(),
+        memo: MemoConfig::default(),
+        agent_limits: AgentLimits::default(),
+        max_jobs: None,
+        until_empty: false,
+        fair_tenants: false,
+        redis_timeout: None,
+        http: HttpClientConfig::default(),
+        instance_quarantine: None,
+        max_retries: None,
+        preflight: None,
+        result_ttl: None,
     };
 
     // Create worker
@@ -50,9 +87,10 @@ async fn test_e2e_worker_stats() -> Result<()> {
         let job = Job {
             id: format!("stats-job-{}", i),
             repo_url: "git@github.com:test/repo.git".to_string(),
-            branch: "main".to_string(),
+            base_branch: "main".to_string(),
             prompt: format!("Task {}", i),
             mcp_connection_url: None,
+            ..Default::default()
         };
         queue.enqueue(&job).await?;
     }
@@ -102,9 +140,10 @@ async fn test_e2e_worker_recovery_on_startup() -> Result<()> {
         let job = Job {
             id: format!("recovery-job-{}", i),
             repo_url: "git@github.com:test/repo.git".to_string(),
-            branch: "main".to_string(),
+            base_branch: "main".to_string(),
             prompt: format!("Task {}", i),
             mcp_connection_url: None,
+            ..Default::default()
         };
         queue.enqueue(&job).await?;
         queue.dequeue().await?; // Move to processing queue
@@ -120,8 +159,39 @@ async fn test_e2e_worker_recovery_on_startup() -> Result<()> {
         queue_name: "e2e_recovery_queue".to_string(),
         queue_timeout: 2,
         allocator_api_url: allocator_url,
-        hyperlight_path: "/usr/local/bin/hyperlight".to_string(),
         work_dir: work_dir.to_str().unwrap().to_string(),
+        worker_id: "e2e-worker".to_string(),
+        reconcile_interval: 300,
+        label_selector: Default::default(),
+        cipher: None,
+        repo_policy: Default::default(),
+        tenant: None,
+        git_credentials: Default::default(),
+        commit_status: None,
+        webhook: None,
+        result_cache_ttl: None,
+        description_template: Default::default(),
+        salvage: false,
+        workdir_retention: Default::default(),
+        prompt_policy: Default::default(),
+        tool_schemas: Default::default(),
+        health_gate: None,
+        push_attempts: 3,
+        retry_policy: RetryPolicy::default(),
+        clone_budget: CloneBudget::default(),
+        agent_metadata: None,
+        llm_providers: Vec::new(),
+        memo: MemoConfig::default(),
+        agent_limits: AgentLimits::default(),
+        max_jobs: None,
+        until_empty: false,
+        fair_tenants: false,
+        redis_timeout: None,
+        http: HttpClientConfig::default(),
+        instance_quarantine: None,
+        max_retries: None,
+        preflight: None,
+        result_ttl: None,
     };
 
     // Note: Worker::new doesn't trigger recovery automatically
@@ -164,9 +234,10 @@ async fn test_e2e_job_failure_and_retry() -> Result<()> {
     let job = Job {
         id: Uuid::new_v4().to_string(),
         repo_url: "git@github.com:invalid/nonexistent-repo-12345.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "This should fail".to_string(),
         mcp_connection_url: None,
+        ..Default::default()
     };
 
     // Enqueue and test retry logic
@@ -178,7 +249,7 @@ async fn test_e2e_job_failure_and_retry() -> Result<()> {
     assert!(dequeued.is_some());
 
     // Simulate failure - NACK the job
-    queue.nack(&job).await?;
+    queue.nack(&job, ErrorClass::Agent, "agent crashed").await?;
 
     // Job should be back in main queue
     let stats_queue_len = queue.len().await?;
@@ -226,9 +297,10 @@ async fn test_e2e_multiple_jobs_sequential_processing() -> Result<()> {
         let job = Job {
             id: format!("seq-job-{}", i),
             repo_url: remote_url.clone(),
-            branch: branch_name.to_string(),
+            base_branch: branch_name.to_string(),
             prompt: format!("Task {}", i),
             mcp_connection_url: None,
+            ..Default::default()
         };
         queue.enqueue(&job).await?;
     }
@@ -247,13 +319,13 @@ async fn test_e2e_multiple_jobs_sequential_processing() -> Result<()> {
         let job = queue.dequeue().await?.expect("Should have job");
 
         // Borrow instance
-        let instance = allocator.borrow_instance().await?;
+        let instance = allocator.borrow_instance(&Default::default()).await?;
 
         // Simulate processing
         let job_work_dir = work_dir.join(&job.id);
         let git_repo = GitRepo::clone(&job.repo_url, &job_work_dir)?;
         git_repo.fetch()?;
-        git_repo.checkout_branch(&job.branch)?;
+        git_repo.checkout_branch(&job.base_branch)?;
 
         // Make a change
         let test_file = job_work_dir.join(format!("task-{}.txt", i));
@@ -261,10 +333,10 @@ async fn test_e2e_multiple_jobs_sequential_processing() -> Result<()> {
 
         git_repo.stage_all()?;
         git_repo.commit(&format!("Complete task {}", i))?;
-        git_repo.push(&job.branch)?;
+        git_repo.push(&job.base_branch)?;
 
         // Return instance
-        allocator.return_instance(&instance).await?;
+        allocator.return_instance(&instance, None).await?;
 
         // ACK job
         queue.ack(&job).await?;
@@ -286,29 +358,31 @@ async fn test_e2e_instance_guard_cleanup_on_panic() -> Result<()> {
     // Setup mock allocator
     let (allocator_url, allocator_state) = common::start_mock_allocator().await;
 
-    use redis_agent_worker::instance::{InstanceAllocator, InstanceGuard};
+    use redis_agent_worker::instance::{InstanceAllocator, InstanceReturner};
 
     let allocator = InstanceAllocator::new(allocator_url);
+    let returner = InstanceReturner::spawn(allocator.clone(), None);
 
     // Borrow instance
-    let instance = allocator.borrow_instance().await?;
+    let instance = allocator.borrow_instance(&Default::default()).await?;
     assert_eq!(allocator_state.borrow_count().await, 1);
     assert_eq!(allocator_state.return_count().await, 0);
 
     // Create guard in a scope that we'll drop
     {
-        let guard = InstanceGuard::new(instance.clone(), allocator.clone());
+        let _guard = returner.guard(instance.clone());
         // Guard should not have returned yet
         assert_eq!(allocator_state.return_count().await, 0);
     } // Guard drops here
 
-    // Give the drop a moment to execute
-    tokio::time::sleep(Duration::from_millis(100)).await;
+    // Shutting down the returner flushes the return queued by the drop
+    returner.shutdown().await;
 
-    // Instance should be returned (note: this tests the Drop behavior,
-    // but in the current implementation, InstanceGuard::drop doesn't
-    // actually return the instance - we need to call return_instance())
-    // This test verifies the current behavior
+    assert_eq!(
+        allocator_state.return_count().await,
+        1,
+        "Dropped guard should return the instance"
+    );
 
     Ok(())
 }
@@ -398,9 +472,10 @@ async fn test_e2e_job_with_mcp_connection() -> Result<()> {
     let job = Job {
         id: Uuid::new_v4().to_string(),
         repo_url: "git@github.com:test/repo.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "Test with MCP".to_string(),
         mcp_connection_url: Some("http://custom-mcp.example.com".to_string()),
+        ..Default::default()
     };
 
     // Enqueue and verify
@@ -442,9 +517,10 @@ async fn test_e2e_concurrent_queue_operations() -> Result<()> {
                 let job = Job {
                     id: format!("worker-{}-job-{}", worker_id, i),
                     repo_url: "git@github.com:test/repo.git".to_string(),
-                    branch: "main".to_string(),
+                    base_branch: "main".to_string(),
                     prompt: format!("Task from worker {}", worker_id),
                     mcp_connection_url: None,
+                    ..Default::default()
                 };
                 queue.enqueue(&job).await.unwrap();
             }
@@ -464,3 +540,141 @@ async fn test_e2e_concurrent_queue_operations() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_e2e_fair_worker_stays_on_shared_queue() -> Result<()> {
+    use redis_agent_worker::heartbeat::{WorkerInfo, WorkerRegistry};
+    use redis_agent_worker::maintenance::MaintenanceWindow;
+    use redis_agent_worker::queue::now_secs;
+    use redis_agent_worker::tenant::{tenant_queue_name, TenantRegistry};
+    common::init_test_logging();
+
+    // Setup Redis
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let (allocator_url, _) = common::start_mock_allocator().await;
+    let temp_dir = TempDir::new()?;
+    let work_dir = temp_dir.path().join("work");
+    std::fs::create_dir_all(&work_dir)?;
+
+    let queue_name = "e2e_fair_queue";
+    TenantRegistry::new(&redis_url, queue_name).await?.register("acme").await?;
+    let acme_queue_name = tenant_queue_name(queue_name, "acme");
+    let mut acme = ReliableQueue::new(&redis_url, &acme_queue_name, 1).await?;
+
+    // Jobs past their deadline are dead-lettered without being run
+    let job = |id: &str| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Tidy up".to_string(),
+        deadline: Some(1),
+        ..Default::default()
+    };
+
+    // A fair worker that crashed left a job in acme's queue, and one that is
+    // still running, registered with the shared queue, holds another
+    for (worker_id, job_id) in [("crashed-worker", "acme-stalled"), ("live-worker", "acme-busy")] {
+        acme.enqueue(&job(job_id)).await?;
+        ReliableQueue::new(&redis_url, &acme_queue_name, 1)
+            .await?
+            .with_worker_id(worker_id)
+            .dequeue()
+            .await?
+            .expect("Should dequeue job");
+    }
+    WorkerRegistry::new(&redis_url, queue_name)
+        .await?
+        .heartbeat(&WorkerInfo {
+            worker_id: "live-worker".to_string(),
+            hostname: "test-host".to_string(),
+            started_at: now_secs(),
+            last_heartbeat: now_secs(),
+            current_job: Some("acme-busy".to_string()),
+            concurrency: 1,
+        })
+        .await?;
+    acme.enqueue_batch(&[job("acme-1"), job("acme-2")]).await?;
+    // Fair workers only take jobs from the tenants' queues
+    let mut shared = ReliableQueue::new(&redis_url, queue_name, 1).await?;
+    shared.enqueue(&job("shared-1")).await?;
+
+    // A window on the tenant's queue doesn't pause workers serving every tenant
+    let always = MaintenanceWindow::new("always", "* * * * *", 3600)?;
+    acme.set_maintenance_window(&always).await?;
+
+    let config = |max_jobs| WorkerConfig {
+        redis_url: redis_url.clone(),
+        queue_name: queue_name.to_string(),
+        queue_timeout: 1,
+        allocator_api_url: allocator_url.clone(),
+        work_dir: work_dir.to_str().unwrap().to_string(),
+        worker_id: "e2e-fair-worker".to_string(),
+        reconcile_interval: 300,
+        label_selector: Default::default(),
+        cipher: None,
+        repo_policy: Default::default(),
+        tenant: None,
+        git_credentials: Default::default(),
+        commit_status: None,
+        webhook: None,
+        result_cache_ttl: None,
+        description_template: Default::default(),
+        salvage: false,
+        workdir_retention: Default::default(),
+        prompt_policy: Default::default(),
+        tool_schemas: Default::default(),
+        health_gate: None,
+        push_attempts: 3,
+        retry_policy: RetryPolicy::default(),
+        clone_budget: CloneBudget::default(),
+        agent_metadata: None,
+        llm_providers: Vec::new(),
+        memo: MemoConfig::default(),
+        agent_limits: AgentLimits::default(),
+        max_jobs: Some(max_jobs),
+        until_empty: false,
+        fair_tenants: true,
+        redis_timeout: None,
+        http: HttpClientConfig::default(),
+        instance_quarantine: None,
+        max_retries: None,
+        preflight: None,
+        result_ttl: None,
+    };
+
+    // The crashed worker's job is recovered and handled with the others,
+    // and the live worker keeps its job
+    let mut worker = Worker::new(config(3)).await?;
+    tokio::time::timeout(Duration::from_secs(30), worker.run())
+        .await
+        .expect("Fair worker should handle all three jobs")?;
+    assert_eq!(acme.dead_len().await?, 3);
+    assert_eq!(acme.len().await?, 0);
+    let processing = acme.list_processing().await?;
+    assert_eq!(processing.len(), 1);
+    assert_eq!(processing[0].id, "acme-busy");
+    let stats = worker.get_stats().await?;
+    assert_eq!(stats.queue_length, 1, "Stats should come from the shared queue");
+
+    // A window on the shared queue pauses them
+    acme.enqueue(&job("acme-late")).await?;
+    shared.set_maintenance_window(&always).await?;
+    let mut worker = Worker::new(config(1)).await?;
+    assert!(
+        tokio::time::timeout(Duration::from_secs(5), worker.run())
+            .await
+            .is_err(),
+        "Fair worker should pause for the shared queue's maintenance window"
+    );
+    assert_eq!(acme.len().await?, 1);
+
+    Ok(())
+}
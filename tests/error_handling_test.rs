@@ -1,6 +1,7 @@
 mod common;
 
 use anyhow::Result;
+use redis_agent_worker::error::ErrorClass;
 use redis_agent_worker::git::GitRepo;
 use redis_agent_worker::instance::InstanceAllocator;
 use redis_agent_worker::queue::{Job, ReliableQueue};
@@ -40,12 +41,33 @@ async fn test_error_invalid_job_json() -> Result<()> {
 
     use redis::AsyncCommands;
     let _: () = conn.lpush("invalid_json_queue", "not a valid json").await?;
+    let _: () = conn.lpush("invalid_json_queue", &[0xff_u8, 0xfe][..]).await?;
 
-    // Try to dequeue - should fail to deserialize
     let mut queue = ReliableQueue::new(&redis_url, "invalid_json_queue", 2).await?;
-    let result = queue.dequeue().await;
+    let job = Job {
+        id: Uuid::new_v4().to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Behind the bad entries".to_string(),
+        ..Default::default()
+    };
+    queue.enqueue(&job).await?;
+
+    // The malformed entries are quarantined instead of wedging the queue
+    let dequeued = queue.dequeue().await?.expect("Should dequeue the valid job");
+    assert_eq!(dequeued.id, job.id);
+
+    let invalid = queue.list_invalid().await?;
+    assert_eq!(invalid.len(), 2);
+    assert!(invalid[0].base64);
+    assert_eq!(invalid[0].bytes()?, vec![0xff, 0xfe]);
+    assert_eq!(invalid[1].payload, "not a valid json");
+    assert!(!invalid[1].base64);
+    assert!(!invalid[1].error.is_empty());
 
-    assert!(result.is_err(), "Should fail to deserialize invalid JSON");
+    let processing: Vec<String> =
+        conn.lrange("invalid_json_queue_processing", 0, -1).await?;
+    assert_eq!(processing.len(), 1, "Only the valid job should be processing");
 
     Ok(())
 }
@@ -126,7 +148,7 @@ async fn test_error_allocator_unreachable() -> Result<()> {
     let allocator = InstanceAllocator::new("http://localhost:99999".to_string());
 
     // Try to borrow instance
-    let result = allocator.borrow_instance().await;
+    let result = allocator.borrow_instance(&Default::default()).await;
 
     assert!(result.is_err(), "Should fail to connect to unreachable allocator");
 
@@ -153,9 +175,10 @@ async fn test_error_ack_nonexistent_job() -> Result<()> {
     let fake_job = Job {
         id: "fake-job-id".to_string(),
         repo_url: "git@github.com:test/repo.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "Fake job".to_string(),
         mcp_connection_url: None,
+        ..Default::default()
     };
 
     // This should succeed but log a warning (job not found)
@@ -186,13 +209,14 @@ async fn test_error_nack_nonexistent_job() -> Result<()> {
     let fake_job = Job {
         id: "fake-job-id".to_string(),
         repo_url: "git@github.com:test/repo.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "Fake job".to_string(),
         mcp_connection_url: None,
+        ..Default::default()
     };
 
     // This should succeed but log an error (job not found)
-    let result = queue.nack(&fake_job).await;
+    let result = queue.nack(&fake_job, ErrorClass::Internal, "boom").await;
 
     assert!(result.is_ok(), "NACK should succeed even if job not found");
 
@@ -265,9 +289,10 @@ async fn test_error_redis_connection_loss() -> Result<()> {
     let job = Job {
         id: Uuid::new_v4().to_string(),
         repo_url: "git@github.com:test/repo.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "Test".to_string(),
         mcp_connection_url: None,
+        ..Default::default()
     };
 
     queue.enqueue(&job).await?;
@@ -341,9 +366,10 @@ async fn test_error_double_ack_same_job() -> Result<()> {
     let job = Job {
         id: Uuid::new_v4().to_string(),
         repo_url: "git@github.com:test/repo.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "Test".to_string(),
         mcp_connection_url: None,
+        ..Default::default()
     };
 
     queue.enqueue(&job).await?;
@@ -383,9 +409,10 @@ async fn test_error_queue_serialization_edge_cases() -> Result<()> {
     let job = Job {
         id: "job-with-special-chars-!@#$%^&*()".to_string(),
         repo_url: "git@github.com:user/repo-with-dashes_and_underscores.git".to_string(),
-        branch: "feature/test-branch-123".to_string(),
+        base_branch: "feature/test-branch-123".to_string(),
         prompt: "Test with \"quotes\" and 'apostrophes' and\nnewlines".to_string(),
         mcp_connection_url: Some("http://example.com:8080/path?query=value&key=123".to_string()),
+        ..Default::default()
     };
 
     // Enqueue and dequeue - should handle special characters correctly
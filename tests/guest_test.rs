@@ -0,0 +1,34 @@
+mod common;
+
+use anyhow::Result;
+use redis_agent_worker::agent::{AgentConfig, AgentExecutor};
+use redis_agent_worker::guest_binary;
+
+/// Boots every embedded guest and runs its self-test, so a regression in a
+/// guest function shows up without running a job end to end
+#[tokio::test]
+async fn test_guest_self_test() -> Result<()> {
+    common::init_test_logging();
+
+    if !hyperlight_host::is_hypervisor_present() {
+        eprintln!("Skipping guest self-test: no hypervisor available");
+        return Ok(());
+    }
+
+    let executor = AgentExecutor::new(AgentConfig {
+        working_directory: std::env::temp_dir().to_string_lossy().to_string(),
+    });
+    for profile in guest_binary::profiles() {
+        let report = executor.self_test(Some(profile)).await?;
+        assert!(!report.checks.is_empty(), "Guest {} ran no checks", profile);
+        let failures = report.failures();
+        assert!(
+            failures.is_empty(),
+            "Guest {} failed its self-test: {:#?}",
+            profile,
+            failures
+        );
+    }
+
+    Ok(())
+}
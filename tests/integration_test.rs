@@ -1,7 +1,11 @@
 mod common;
 
 use anyhow::Result;
-use redis_agent_worker::queue::{Job, ReliableQueue};
+use redis_agent_worker::agent::AgentProgress;
+use redis_agent_worker::error::ErrorClass;
+use redis_agent_worker::metrics::Stage;
+use redis_agent_worker::queue::{CancelOutcome, Job, JobState, ReliableQueue};
+use redis_agent_worker::timeline::TimelineEvent;
 use std::time::Duration;
 use tempfile::TempDir;
 use testcontainers::{runners::AsyncRunner, GenericImage};
@@ -28,9 +32,10 @@ async fn test_queue_enqueue_dequeue() -> Result<()> {
     let job = Job {
         id: Uuid::new_v4().to_string(),
         repo_url: "git@github.com:test/repo.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "Test prompt".to_string(),
         mcp_connection_url: Some("http://mcp.example.com".to_string()),
+        ..Default::default()
     };
 
     // Enqueue the job
@@ -87,9 +92,10 @@ async fn test_queue_nack_retry() -> Result<()> {
     let job = Job {
         id: Uuid::new_v4().to_string(),
         repo_url: "git@github.com:test/repo.git".to_string(),
-        branch: "main".to_string(),
+        base_branch: "main".to_string(),
         prompt: "Test prompt".to_string(),
         mcp_connection_url: None,
+        ..Default::default()
     };
 
     // Enqueue the job
@@ -100,7 +106,7 @@ async fn test_queue_nack_retry() -> Result<()> {
     assert!(dequeued.is_some(), "Should dequeue job");
 
     // NACK the job (simulating failure)
-    queue.nack(&job).await?;
+    queue.nack(&job, ErrorClass::Agent, "agent crashed").await?;
 
     // Job should be back in the main queue
     let len = queue.len().await?;
@@ -117,6 +123,61 @@ async fn test_queue_nack_retry() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_delayed_nack() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_delayed_queue", 1).await?;
+    let job = Job {
+        id: "rate-limited-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        ..Default::default()
+    };
+    queue.enqueue(&job).await?;
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+
+    let delay = Duration::from_secs(2);
+    queue.nack_after(&dequeued, ErrorClass::Push, "rate limited", delay).await?;
+    let stats = queue.stats().await?;
+    assert_eq!((stats.pending, stats.processing, stats.delayed), (0, 0, 1));
+    // A job waiting out its delay keeps the queue from counting as drained
+    assert!(!queue.is_drained().await?);
+    let status = queue.get_status(&job.id).await?.expect("Status after nack");
+    assert_eq!(status.state, Some(JobState::Pending));
+    assert!(status.retry_at.is_some());
+
+    // Not due yet
+    assert!(queue.dequeue().await?.is_none());
+    tokio::time::sleep(delay).await;
+    let retried = queue.dequeue().await?.expect("Should dequeue the job once it is due");
+    assert_eq!(retried.id, job.id);
+    let status = queue.get_status(&job.id).await?.expect("Status after retry");
+    assert_eq!(status.retry_at, None);
+    assert_eq!(status.attempts, 2);
+
+    // Delayed jobs can be cancelled like pending ones
+    let delay = Duration::from_secs(600);
+    queue.nack_after(&retried, ErrorClass::Push, "rate limited", delay).await?;
+    assert_eq!(queue.list_delayed().await?.len(), 1);
+    assert_eq!(queue.cancel(&job.id).await?, CancelOutcome::Removed);
+    assert_eq!(queue.stats().await?.delayed, 0);
+    assert!(queue.is_drained().await?);
+
+    queue.clear().await?;
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_queue_recovery() -> Result<()> {
     common::init_test_logging();
@@ -139,9 +200,10 @@ async fn test_queue_recovery() -> Result<()> {
         .map(|i| Job {
             id: format!("job-{}", i),
             repo_url: "git@github.com:test/repo.git".to_string(),
-            branch: "main".to_string(),
+            base_branch: "main".to_string(),
             prompt: format!("Test prompt {}", i),
             mcp_connection_url: None,
+            ..Default::default()
         })
         .collect();
 
@@ -177,75 +239,2380 @@ async fn test_queue_recovery() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_instance_allocator() -> Result<()> {
+async fn test_recovery_spares_live_workers() -> Result<()> {
+    use redis_agent_worker::heartbeat::{WorkerInfo, WorkerRegistry};
+    use redis_agent_worker::queue::now_secs;
+
     common::init_test_logging();
 
-    // Start mock allocator
-    let (allocator_url, state) = common::start_mock_allocator().await;
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
 
-    // Test borrowing an instance
-    use redis_agent_worker::instance::InstanceAllocator;
-    let allocator = InstanceAllocator::new(allocator_url.clone());
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
 
-    let instance1 = allocator.borrow_instance().await?;
-    assert!(instance1.id.starts_with("mock-instance-"));
-    assert!(instance1.mcp_connection_url.starts_with("http://mock-mcp-"));
+    let queue_name = "test_sharded_queue";
+    let mut admin = ReliableQueue::new(&redis_url, queue_name, 1).await?;
+    let mut live = ReliableQueue::new(&redis_url, queue_name, 1)
+        .await?
+        .with_worker_id("worker-live");
+    let mut dead = ReliableQueue::new(&redis_url, queue_name, 1)
+        .await?
+        .with_worker_id("worker-dead");
+
+    for i in 0..2 {
+        admin
+            .enqueue(&Job {
+                id: format!("job-{}", i),
+                repo_url: "git@github.com:test/repo.git".to_string(),
+                base_branch: "main".to_string(),
+                prompt: "Test prompt".to_string(),
+                ..Default::default()
+            })
+            .await?;
+    }
+    let live_job = live.dequeue().await?.expect("job for the live worker");
+    dead.dequeue().await?.expect("job for the dead worker");
+    assert_eq!(admin.depths().await?, (0, 2));
+    assert_eq!(admin.list_processing().await?.len(), 2);
+
+    // Only the live worker keeps heartbeating
+    let registry = WorkerRegistry::new(&redis_url, queue_name).await?;
+    let registration = |worker_id: &str, last_heartbeat| WorkerInfo {
+        worker_id: worker_id.to_string(),
+        hostname: "test-host".to_string(),
+        started_at: 0,
+        last_heartbeat,
+        current_job: None,
+        concurrency: 1,
+    };
+    registry.heartbeat(&registration("worker-live", now_secs())).await?;
+    registry.heartbeat(&registration("worker-dead", 0)).await?;
 
-    // Check state
-    let borrow_count = state.borrow_count().await;
-    assert_eq!(borrow_count, 1, "Should have 1 borrowed instance");
+    assert_eq!(admin.recover_stalled_jobs().await?, 1);
+    assert_eq!(admin.depths().await?, (1, 1));
+    assert_eq!(admin.list_processing().await?[0].id, live_job.id);
 
-    // Return the instance
-    allocator.return_instance(&instance1).await?;
+    // A live worker's jobs are still visible to admin commands
+    assert!(admin.requeue(&live_job.id).await?);
+    assert_eq!(admin.depths().await?, (2, 0));
 
-    let return_count = state.return_count().await;
-    assert_eq!(return_count, 1, "Should have 1 returned instance");
+    // A worker restarting under its ID takes its own jobs back
+    live.dequeue().await?.expect("job for the live worker");
+    assert_eq!(admin.recover_stalled_jobs().await?, 0);
+    assert_eq!(live.recover_own_jobs().await?, 1);
+    assert_eq!(admin.depths().await?, (2, 0));
 
-    // Borrow multiple instances
-    let instance2 = allocator.borrow_instance().await?;
-    let instance3 = allocator.borrow_instance().await?;
+    Ok(())
+}
 
-    assert_ne!(instance2.id, instance3.id, "Instances should have unique IDs");
+#[tokio::test]
+async fn test_dequeue_leases_job() -> Result<()> {
+    use redis_agent_worker::queue::{now_secs, JobOptions, DEFAULT_LEASE_SECS};
+    common::init_test_logging();
 
-    let borrow_count = state.borrow_count().await;
-    assert_eq!(borrow_count, 3, "Should have 3 total borrowed instances");
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_queue", 1)
+        .await?
+        .with_worker_id("worker-a");
+    let job = |id: &str, timeout_secs: Option<u64>| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        options: JobOptions {
+            timeout_secs,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    queue.enqueue(&job("timed", Some(600))).await?;
+    queue.enqueue(&job("untimed", None)).await?;
+
+    let before = now_secs();
+    let dequeued = queue.dequeue().await?.expect("job to dequeue");
+    assert_eq!(dequeued.id, "timed");
+    let status = queue.get_status("timed").await?.unwrap();
+    assert_eq!(status.state, Some(JobState::Running));
+    assert_eq!(status.worker.as_deref(), Some("worker-a"));
+    assert_eq!(status.attempts, 1);
+    let deadline = status.lease_deadline.expect("lease deadline");
+    assert!((before + 600..=now_secs() + 600).contains(&deadline));
+
+    queue.dequeue().await?.expect("job to dequeue");
+    let deadline = queue.get_status("untimed").await?.unwrap().lease_deadline.unwrap();
+    assert!(deadline >= before + DEFAULT_LEASE_SECS);
+
+    // Another attempt takes a new lease
+    queue.nack(&dequeued, ErrorClass::Clone, "clone failed").await?;
+    queue.dequeue().await?.expect("retried job");
+    assert_eq!(queue.get_status("timed").await?.unwrap().attempts, 2);
+
+    // Waiting on an empty queue times out without a job
+    assert!(queue.dequeue().await?.is_none());
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_git_operations() -> Result<()> {
+async fn test_concurrency_group_lock_and_postpone() -> Result<()> {
     common::init_test_logging();
 
-    let temp_dir = TempDir::new()?;
-    let branch_name = "test-branch";
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
 
-    // Setup git environment with remote
-    let (local_path, remote_url) = common::setup_test_git_env(temp_dir.path(), branch_name)?;
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
 
-    // Clone the repository to a new location
-    let clone_dir = temp_dir.path().join("cloned");
-    use redis_agent_worker::git::GitRepo;
-    let git_repo = GitRepo::clone(&remote_url, &clone_dir)?;
+    let mut queue = ReliableQueue::new(&redis_url, "test_group_queue", 1).await?;
+    let ttl = Duration::from_secs(60);
 
-    // Checkout the test branch
-    git_repo.fetch()?;
-    git_repo.checkout_branch(branch_name)?;
+    assert!(queue.lock_group("staging", "job-1", ttl).await?);
+    // Re-locking extends the holder's lock but excludes everyone else
+    assert!(queue.lock_group("staging", "job-1", ttl).await?);
+    assert!(!queue.lock_group("staging", "job-2", ttl).await?);
+    assert!(queue.lock_group("production", "job-2", ttl).await?);
 
-    // Make a change
-    let test_file = clone_dir.join("test.txt");
-    std::fs::write(&test_file, "Test content\n")?;
+    // Only the holder can unlock
+    queue.unlock_group("staging", "job-2").await?;
+    assert_eq!(queue.group_holder("staging").await?.as_deref(), Some("job-1"));
+    queue.unlock_group("staging", "job-1").await?;
+    assert_eq!(queue.group_holder("staging").await?, None);
 
-    // Check that there are changes
-    assert!(git_repo.has_changes()?, "Should detect changes");
+    let job = Job {
+        id: "job-3".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        concurrency_group: Some("production".to_string()),
+        ..Default::default()
+    };
+    queue.enqueue(&job).await?;
+    let dequeued = queue.dequeue().await?.expect("job should be dequeued");
+    assert_eq!(dequeued.concurrency_group.as_deref(), Some("production"));
+
+    queue.postpone(&dequeued).await?;
+    assert_eq!(queue.depths().await?, (1, 0));
+    let status = queue.get_status(&job.id).await?.expect("status should exist");
+    assert_eq!(status.state, Some(JobState::Pending));
+    assert_eq!(status.attempts, 0, "A postponed attempt doesn't count");
+    let timeline = queue.timeline(&job.id).await?.expect("timeline should exist");
+    assert_eq!(
+        timeline.steps.last().map(|step| step.event),
+        Some(TimelineEvent::Postponed)
+    );
 
-    // Commit and push
-    git_repo.stage_all()?;
-    git_repo.commit("Add test file")?;
-    git_repo.push(branch_name)?;
+    Ok(())
+}
 
-    // Verify no more changes
-    assert!(!git_repo.has_changes()?, "Should have no changes after commit");
+#[tokio::test]
+async fn test_enqueue_batch_preserves_order() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_batch_queue", 5).await?;
+
+    let jobs: Vec<Job> = (0..3)
+        .map(|i| Job {
+            id: format!("batch-job-{}", i),
+            repo_url: "git@github.com:test/repo.git".to_string(),
+            base_branch: "main".to_string(),
+            prompt: format!("Prompt {}", i),
+            ..Default::default()
+        })
+        .collect();
+
+    queue.enqueue_batch(&jobs).await?;
+    assert_eq!(queue.len().await?, 3);
+
+    for job in &jobs {
+        let status = queue.get_status(&job.id).await?.expect("Status after batch enqueue");
+        assert_eq!(status.state, Some(JobState::Pending));
+    }
+
+    for job in &jobs {
+        let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+        assert_eq!(dequeued.id, job.id);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_enqueue_unique_skips_duplicates() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_unique_queue", 5).await?;
+
+    let mut labels = std::collections::BTreeMap::new();
+    labels.insert("team".to_string(), "payments".to_string());
+    let job = Job {
+        id: "unique-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Fix the bug".to_string(),
+        labels,
+        ..Default::default()
+    };
+
+    assert!(queue.enqueue_unique(&job).await?);
+    assert!(!queue.enqueue_unique(&job).await?, "Redelivered job should be skipped");
+    assert_eq!(queue.len().await?, 1);
+
+    let status = queue.get_status(&job.id).await?.expect("Status after enqueue");
+    assert_eq!(status.state, Some(JobState::Pending));
+    assert_eq!(
+        queue.jobs_with_labels(&job.labels).await?.len(),
+        1,
+        "Labels should be indexed"
+    );
+
+    // Still a duplicate once the job has been processed
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    queue.ack(&dequeued).await?;
+    assert!(!queue.enqueue_unique(&job).await?);
+    assert_eq!(queue.len().await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_requeue_single_job() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_requeue_queue", 5).await?;
+
+    for id in ["stuck-job", "healthy-job"] {
+        queue
+            .enqueue(&Job {
+                id: id.to_string(),
+                repo_url: "git@github.com:test/repo.git".to_string(),
+                base_branch: "main".to_string(),
+                prompt: "Test prompt".to_string(),
+                ..Default::default()
+            })
+            .await?;
+    }
+    queue.dequeue().await?.expect("Should dequeue first job");
+    queue.dequeue().await?.expect("Should dequeue second job");
+    assert_eq!(queue.processing_len().await?, 2);
+
+    assert!(queue.requeue("stuck-job").await?);
+    assert_eq!(queue.processing_len().await?, 1);
+    assert_eq!(queue.len().await?, 1);
+    assert_eq!(queue.peek().await?.map(|job| job.id), Some("stuck-job".to_string()));
+
+    let status = queue.get_status("stuck-job").await?.expect("Status after requeue");
+    assert_eq!(status.state, Some(JobState::Pending));
+
+    // Jobs that aren't in flight can't be requeued
+    assert!(!queue.requeue("stuck-job").await?);
+    assert!(!queue.requeue("missing-job").await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_definition_is_recorded_for_replay() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_replay_queue", 5).await?;
+
+    let job = Job {
+        id: "original-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        ..Default::default()
+    };
+    queue.enqueue(&job).await?;
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    queue.ack(&dequeued).await?;
+
+    // The definition outlives the job itself
+    let recorded = queue.get_job("original-job").await?.expect("Job definition");
+    assert_eq!(recorded.prompt, job.prompt);
+    assert!(queue.get_job("missing-job").await?.is_none());
+
+    let replay = Job {
+        id: "replay-job".to_string(),
+        replay_of: Some(job.id.clone()),
+        ..job
+    };
+    queue.enqueue(&replay).await?;
+    let dequeued = queue.dequeue().await?.expect("Should dequeue replay");
+    assert_eq!(dequeued.replay_of.as_deref(), Some("original-job"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_import_round_trip() -> Result<()> {
+    use redis_agent_worker::backup::{self, Section};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut source = ReliableQueue::new(&redis_url, "test_export_queue", 5).await?;
+    for id in ["in-flight", "first", "second"] {
+        source
+            .enqueue(&Job {
+                id: id.to_string(),
+                repo_url: "git@github.com:test/repo.git".to_string(),
+                base_branch: "main".to_string(),
+                prompt: "Test prompt".to_string(),
+                ..Default::default()
+            })
+            .await?;
+    }
+    source.dequeue().await?.expect("Should dequeue first job");
+
+    let mut backup_file = Vec::new();
+    let exported = backup::export(&mut source, &mut backup_file).await?;
+    assert_eq!((exported.pending, exported.processing), (2, 1));
+
+    let records = backup::read_backup(backup_file.as_slice())?;
+    assert_eq!(records[0].section, Section::Pending);
+    assert_eq!(records[2].section, Section::Processing);
+
+    let mut restored = ReliableQueue::new(&redis_url, "test_import_queue", 5).await?;
+    let imported = backup::import(&mut restored, &records).await?;
+    assert_eq!((imported.pending, imported.processing), (2, 1));
+
+    let pending: Vec<String> =
+        restored.list_pending().await?.into_iter().map(|j| j.id).collect();
+    assert_eq!(pending, vec!["first", "second"]);
+    let processing = restored.list_processing().await?;
+    assert_eq!(processing[0].id, "in-flight");
+    let status = restored.get_status("in-flight").await?.expect("Restored status");
+    assert_eq!(status.state, Some(JobState::Running));
+
+    // Restoring twice would duplicate every job
+    assert!(backup::import(&mut restored, &records).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_worker_registry() -> Result<()> {
+    use redis_agent_worker::heartbeat::{WorkerInfo, WorkerRegistry};
+    use redis_agent_worker::queue::now_secs;
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let registry = WorkerRegistry::new(&redis_url, "test_workers_queue").await?;
+    assert!(registry.list().await?.is_empty());
+
+    let now = now_secs();
+    for (worker_id, last_heartbeat) in [("worker-b", now), ("worker-a", now - 600)] {
+        registry
+            .heartbeat(&WorkerInfo {
+                worker_id: worker_id.to_string(),
+                hostname: "test-host".to_string(),
+                started_at: now - 1200,
+                last_heartbeat,
+                current_job: Some("job-1".to_string()),
+                concurrency: 1,
+            })
+            .await?;
+    }
+
+    let workers = registry.list().await?;
+    assert_eq!(workers.len(), 2);
+    assert_eq!(workers[0].worker_id, "worker-a");
+    assert!(workers[0].is_stale(now));
+    assert!(!workers[1].is_stale(now));
+    assert_eq!(workers[1].current_job.as_deref(), Some("job-1"));
+
+    registry.deregister("worker-a").await?;
+    assert_eq!(registry.list().await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_options_and_giving_up() -> Result<()> {
+    use redis_agent_worker::queue::{BranchMode, JobOptions};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_options_queue", 5).await?;
+
+    let plain = Job {
+        id: "plain-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        ..Default::default()
+    };
+    // Jobs without options serialize exactly as before options existed
+    assert!(!serde_json::to_string(&plain)?.contains("options"));
+
+    let job = Job {
+        id: "options-job".to_string(),
+        options: JobOptions {
+            max_retries: Some(0),
+            dry_run: true,
+            branch_mode: BranchMode::NewBranch,
+            allowed_tools: Some(vec!["read_file".to_string()]),
+            ..Default::default()
+        },
+        ..plain
+    };
+    queue.enqueue(&job).await?;
+
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    assert_eq!(dequeued.options, job.options);
+
+    queue.record_failure(&dequeued, ErrorClass::Agent, "agent crashed").await;
+    queue.fail(&dequeued).await?;
+    assert_eq!(queue.processing_len().await?, 0);
+    assert_eq!(queue.len().await?, 0);
+
+    let status = queue.get_status("options-job").await?.expect("Status after failure");
+    assert_eq!(status.state, Some(JobState::Failed));
+    assert!(status.finished_at.is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_label_filtering_and_routing() -> Result<()> {
+    use std::collections::BTreeMap;
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_labels_queue", 1).await?;
+    let jobs = [
+        ("unlabelled", None),
+        ("payments-1", Some("payments")),
+        ("search-1", Some("search")),
+    ];
+    for (id, team) in jobs {
+        queue
+            .enqueue(&Job {
+                id: id.to_string(),
+                repo_url: "git@github.com:test/repo.git".to_string(),
+                base_branch: "main".to_string(),
+                prompt: "Test prompt".to_string(),
+                labels: team
+                    .map(|team| BTreeMap::from([("team".to_string(), team.to_string())]))
+                    .unwrap_or_default(),
+                ..Default::default()
+            })
+            .await?;
+    }
+
+    let selector = BTreeMap::from([("team".to_string(), "search".to_string())]);
+    let mut search_worker = ReliableQueue::new(&redis_url, "test_labels_queue", 1)
+        .await?
+        .with_label_selector(selector.clone());
+
+    // Older jobs that don't match are skipped, not taken
+    let job = search_worker.dequeue().await?.expect("Should dequeue matching job");
+    assert_eq!(job.id, "search-1");
+    assert!(search_worker.dequeue().await?.is_none());
+    assert_eq!(queue.len().await?, 2);
+    assert_eq!(queue.peek().await?.map(|job| job.id), Some("unlabelled".to_string()));
+
+    assert_eq!(
+        queue.jobs_with_labels(&selector).await?.into_iter().collect::<Vec<_>>(),
+        vec!["search-1".to_string()]
+    );
+    let stats = queue.stats_with_labels(&selector).await?;
+    assert_eq!((stats.pending, stats.processing), (0, 1));
+
+    search_worker.ack(&job).await?;
+    let stats = queue.stats_with_labels(&selector).await?;
+    assert_eq!((stats.processing, stats.succeeded), (0, 1));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multi_task_job() -> Result<()> {
+    use redis_agent_worker::result::{JobResult, TaskResult};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_tasks_queue", 5).await?;
+
+    let mut job = Job {
+        id: "refactor-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Extract the parser module".to_string(),
+        tasks: vec!["Add tests for the parser".to_string(), " ".to_string()],
+        ..Default::default()
+    };
+    assert!(job.validate().is_err(), "Empty tasks must be rejected");
+    job.tasks.pop();
+    job.validate()?;
+    assert_eq!(
+        job.prompts(),
+        vec!["Extract the parser module", "Add tests for the parser"]
+    );
+
+    queue.enqueue(&job).await?;
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    assert_eq!(dequeued.prompts(), job.prompts());
+
+    let tasks: Vec<TaskResult> = job
+        .prompts()
+        .into_iter()
+        .enumerate()
+        .map(|(index, prompt)| TaskResult {
+            prompt: prompt.to_string(),
+            summary: format!("Done with task {}", index + 1),
+            commit_sha: Some(format!("sha{}", index + 1)),
+            diff: String::new(),
+        })
+        .collect();
+    queue
+        .store_result(&JobResult {
+            job_id: job.id.clone(),
+            summary: "Done with task 2".to_string(),
+            commit_sha: Some("sha2".to_string()),
+            tasks,
+            ..Default::default()
+        })
+        .await?;
+
+    let result = queue.get_result("refactor-job").await?.expect("Stored result");
+    assert_eq!(result.tasks.len(), 2);
+    assert_eq!(result.tasks[0].commit_sha.as_deref(), Some("sha1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_branch_fields_wire_format() -> Result<()> {
+    // Jobs written before the base/target split keep deserializing, and
+    // serialize back byte-for-byte so LREM still finds them
+    let legacy = r#"{"id":"old-job","repo_url":"git@github.com:test/repo.git","branch":"main","prompt":"Test prompt","mcp_connection_url":null}"#;
+    let job: Job = serde_json::from_str(legacy)?;
+    assert_eq!(job.base_branch, "main");
+    assert_eq!(job.target_branch, None);
+    assert_eq!(serde_json::to_string(&job)?, legacy);
+
+    let job: Job = serde_json::from_str(
+        r#"{"id":"new-job","repo_url":"r","base_branch":"main","target_branch":"feature","prompt":"p"}"#,
+    )?;
+    assert_eq!(job.base_branch, "main");
+    assert_eq!(job.target_branch.as_deref(), Some("feature"));
+
+    Ok(())
+}
+
+#[test]
+fn test_follow_up_jobs() -> Result<()> {
+    let job: Job = serde_json::from_str(
+        r#"{
+            "id": "feature-job",
+            "repo_url": "git@github.com:test/repo.git",
+            "branch": "main",
+            "prompt": "Add a search endpoint",
+            "labels": {"team": "search"},
+            "options": {"branch_mode": "new_branch"},
+            "on_success": [{
+                "prompt": "Fix any failing tests",
+                "labels": {"stage": "tests"},
+                "on_success": [{"prompt": "Update the changelog"}]
+            }]
+        }"#,
+    )?;
+    job.validate()?;
+
+    let tests = job.follow_up(0);
+    assert_eq!(tests.id, "feature-job.1");
+    assert_eq!(tests.parent.as_deref(), Some("feature-job"));
+    // Starts from the branch the parent pushed to
+    assert_eq!(tests.base_branch, "agent/feature-job");
+    assert_eq!(tests.push_branch(), "agent/feature-job");
+    assert_eq!(tests.labels.len(), 2);
+
+    let changelog = tests.follow_up(0);
+    assert_eq!(changelog.id, "feature-job.1.1");
+    assert_eq!(changelog.prompt, "Update the changelog");
+    assert!(changelog.on_success.is_empty());
+
+    let mut invalid = job.clone();
+    invalid.on_success[0].on_success[0].prompt.clear();
+    assert!(invalid.validate().is_err(), "Empty follow-up prompts must be rejected");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_context_files() -> Result<()> {
+    use redis_agent_worker::git::GitRepo;
+    use redis_agent_worker::queue::{ContextFile, ContextSource};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_context_queue", 5).await?;
+
+    let key = queue
+        .store_context("context-job", "design.md", b"# Design\n")
+        .await?;
+    assert_eq!(
+        queue.get_context(&key).await?.as_deref(),
+        Some(&b"# Design\n"[..])
+    );
+
+    let mut job = Job {
+        id: "context-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Implement the design".to_string(),
+        context: vec![ContextFile {
+            name: "../design.md".to_string(),
+            source: ContextSource::RedisKey(key.clone()),
+        }],
+        ..Default::default()
+    };
+    assert!(
+        job.validate().is_err(),
+        "Names must not escape the context directory"
+    );
+    job.context[0].name = "design.md".to_string();
+    job.validate()?;
+
+    // Only the job's own context keys may be read, not any key in Redis
+    let mut foreign = job.clone();
+    foreign.context[0].source =
+        ContextSource::RedisKey("test_context_queue_result:other-job".to_string());
+    assert!(foreign.validate().is_err(), "Other keys must be rejected");
+    foreign.context[0].source =
+        ContextSource::RedisKey("test_context_queue_context:other-job:design.md".to_string());
+    assert!(foreign.validate().is_err(), "Other jobs' context must be rejected");
+
+    queue.enqueue(&job).await?;
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    assert_eq!(dequeued.context.len(), 1);
+    assert_eq!(dequeued.context[0].source, ContextSource::RedisKey(key));
+
+    // Downloaded context never ends up in a commit
+    let temp_dir = TempDir::new()?;
+    common::create_mock_git_repo(temp_dir.path(), "main")?;
+    let git_repo = GitRepo::open(temp_dir.path())?;
+    git_repo.exclude("/.agent-context/")?;
+    std::fs::create_dir(temp_dir.path().join(".agent-context"))?;
+    std::fs::write(temp_dir.path().join(".agent-context/design.md"), "# Design\n")?;
+    assert!(!git_repo.has_changes()?, "Context files should be ignored");
+
+    queue.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_expired_job_is_dead_lettered() -> Result<()> {
+    use redis_agent_worker::queue::{now_secs, DeadReason};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_deadline_queue", 5).await?;
+
+    let job = Job {
+        id: "ci-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Fix the failing build".to_string(),
+        deadline: Some(now_secs() - 60),
+        ..Default::default()
+    };
+    assert!(job.is_expired(now_secs()));
+    assert!(!Job { deadline: None, ..job.clone() }.is_expired(now_secs()));
+
+    queue.enqueue(&job).await?;
+    // A first attempt failed before the deadline passed
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    queue.nack(&dequeued, ErrorClass::Clone, "clone timed out").await?;
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job again");
+    assert_eq!(dequeued.deadline, job.deadline);
+    queue.dead_letter(&dequeued, DeadReason::Expired).await?;
+
+    assert_eq!(queue.processing_len().await?, 0);
+    let dead = queue.list_dead().await?;
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].job.id, "ci-job");
+    assert_eq!(dead[0].reason, DeadReason::Expired);
+    assert_eq!(dead[0].failures.len(), 1);
+    assert_eq!(dead[0].failures[0].class, ErrorClass::Clone);
+
+    let status = queue.get_status("ci-job").await?.expect("Should have status");
+    assert_eq!(status.state, Some(JobState::Failed));
+    assert_eq!(status.dead_reason, Some(DeadReason::Expired));
+    assert_eq!(queue.stats().await?.dead, 1);
+
+    // A redriven job is pending again with its retries starting over
+    assert!(queue.redrive_dead("ci-job").await?);
+    assert!(!queue.redrive_dead("ci-job").await?);
+    assert_eq!(queue.dead_len().await?, 0);
+    let status = queue.get_status("ci-job").await?.expect("Should have status");
+    assert_eq!(status.state, Some(JobState::Pending));
+    assert_eq!(status.attempts, 0);
+    assert_eq!(status.dead_reason, None);
+
+    let dequeued = queue.dequeue().await?.expect("Should dequeue redriven job");
+    queue.dead_letter(&dequeued, DeadReason::RetriesExhausted).await?;
+    let drained = queue.drain_dead().await?;
+    assert_eq!(drained.len(), 1);
+    assert_eq!(drained[0].reason, DeadReason::RetriesExhausted);
+    assert_eq!(queue.dead_len().await?, 0);
+
+    queue.clear().await?;
+    assert_eq!(queue.dead_len().await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_encrypted_job_payloads() -> Result<()> {
+    use redis_agent_worker::crypto::PayloadCipher;
+    use redis_agent_worker::queue::DeadReason;
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let cipher = PayloadCipher::from_base64("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=")?;
+    let mut queue = ReliableQueue::new(&redis_url, "test_encrypted_queue", 5)
+        .await?
+        .with_cipher(Some(cipher));
+    let mut plain_queue = ReliableQueue::new(&redis_url, "test_encrypted_queue", 5).await?;
+
+    let job = Job {
+        id: "secret-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Port the proprietary pricing engine".to_string(),
+        ..Default::default()
+    };
+    queue.enqueue(&job).await?;
+
+    // Without the key the payload can't be read
+    assert!(plain_queue.peek().await.is_err());
+    assert!(plain_queue.list_pending().await?.is_empty());
+    assert_eq!(plain_queue.len().await?, 1);
+
+    let peeked = queue.peek().await?.expect("Should peek job");
+    assert_eq!(peeked.prompt, job.prompt);
+    let recorded = queue.get_job("secret-job").await?.expect("Should record job");
+    assert_eq!(recorded.prompt, job.prompt);
+
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    assert_eq!(dequeued.prompt, job.prompt);
+    queue.ack(&dequeued).await?;
+    assert_eq!(queue.processing_len().await?, 0);
+
+    // Context files are encrypted at rest like the jobs that reference them
+    let key = queue
+        .store_context("secret-job", "design.md", b"# Pricing rules\n")
+        .await?;
+    assert!(plain_queue.get_context(&key).await.is_err());
+    assert_eq!(
+        queue.get_context(&key).await?.as_deref(),
+        Some(&b"# Pricing rules\n"[..])
+    );
+
+    // Draining without the key leaves encrypted dead letters in place
+    queue.enqueue(&job).await?;
+    let dequeued = queue.dequeue().await?.expect("Should dequeue job");
+    queue.dead_letter(&dequeued, DeadReason::RetriesExhausted).await?;
+    assert!(plain_queue.drain_dead().await?.is_empty());
+    assert_eq!(plain_queue.dead_len().await?, 1);
+    let drained = queue.drain_dead().await?;
+    assert_eq!(drained.len(), 1);
+    assert_eq!(drained[0].job.prompt, job.prompt);
+    assert_eq!(queue.dead_len().await?, 0);
+
+    queue.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replication() -> Result<()> {
+    use redis_agent_worker::replication::{self, ReplicaTarget, Replicator};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut primary = ReliableQueue::new(&redis_url, "test_primary_queue", 1).await?;
+    let job = |id: &str| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        ..Default::default()
+    };
+
+    // Nothing is recorded until the oplog is turned on
+    primary.enqueue(&job("before")).await?;
+    assert!(primary.read_oplog("0", 100, None).await?.is_empty());
+    primary.clear().await?;
+
+    primary.set_oplog_enabled(true).await?;
+    primary.enqueue(&job("done")).await?;
+    primary.enqueue_batch(&[job("retried"), job("cancelled")]).await?;
+    let dequeued = primary.dequeue().await?.expect("Should dequeue job");
+    assert_eq!(dequeued.id, "done");
+    primary.ack(&dequeued).await?;
+    let dequeued = primary.dequeue().await?.expect("Should dequeue job");
+    primary.nack(&dequeued, ErrorClass::Agent, "flaky").await?;
+    primary.cancel("cancelled").await?;
+
+    let replica = ReliableQueue::new(&redis_url, "test_replica_queue", 1).await?;
+    let mut replicator =
+        Replicator::new(primary.clone(), ReplicaTarget::Redis(Box::new(replica))).await?;
+    assert_eq!(replicator.sync(None).await?, 6);
+    assert_eq!(replicator.sync(None).await?, 0);
+
+    let mut replica = ReliableQueue::new(&redis_url, "test_replica_queue", 1).await?;
+    let pending: Vec<String> =
+        replica.list_pending().await?.into_iter().map(|job| job.id).collect();
+    assert_eq!(pending, vec!["retried"]);
+    let status = replica.get_status("done").await?.expect("Replicated status");
+    assert_eq!(status.state, Some(JobState::Succeeded));
+    assert_eq!(replica.get_status("retried").await?.unwrap().attempts, 1);
+
+    // A file replica replays to the same state, skipping what was applied
+    let dir = TempDir::new()?;
+    let path = dir.path().join("oplog.jsonl");
+    let mut replicator = Replicator::new(primary.clone(), ReplicaTarget::file(&path)?).await?;
+    assert_eq!(replicator.sync(None).await?, 6);
+    let entries = replication::read_oplog(std::io::BufReader::new(std::fs::File::open(&path)?))?;
+    let mut restored = ReliableQueue::new(&redis_url, "test_restored_queue", 1).await?;
+    assert_eq!(replication::replay_oplog(&mut restored, &entries).await?, 6);
+    assert_eq!(replication::replay_oplog(&mut restored, &entries).await?, 0);
+    let pending: Vec<String> =
+        restored.list_pending().await?.into_iter().map(|job| job.id).collect();
+    assert_eq!(pending, vec!["retried"]);
+
+    primary.clear().await?;
+    replica.clear().await?;
+    restored.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_payload_limits() -> Result<()> {
+    use redis_agent_worker::queue::{PayloadLimits, PayloadTooLarge};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_payload_queue", 5).await?;
+    let job = |id: &str, prompt_bytes: usize| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "x".repeat(prompt_bytes),
+        ..Default::default()
+    };
+
+    let limits = PayloadLimits {
+        warn_bytes: Some(1024),
+        max_bytes: Some(4096),
+    };
+    queue.set_payload_limits(limits).await?;
+    assert_eq!(queue.payload_limits().await?, limits);
+
+    // Over the warning threshold is still accepted
+    queue.enqueue(&job("large", 2048)).await?;
+    let error = queue.enqueue(&job("too-large", 8192)).await.unwrap_err();
+    let too_large = error.downcast_ref::<PayloadTooLarge>().expect("PayloadTooLarge");
+    assert_eq!((too_large.job_id.as_str(), too_large.max_bytes), ("too-large", 4096));
+    // A batch with one oversized job is rejected as a whole
+    assert!(queue
+        .enqueue_batch(&[job("small", 10), job("too-large", 8192)])
+        .await
+        .is_err());
+    assert_eq!(queue.len().await?, 1);
+
+    let stats = queue.stats().await?;
+    assert!(stats.memory_bytes.is_some_and(|bytes| bytes > 0));
+
+    queue.set_payload_limits(PayloadLimits::default()).await?;
+    queue.enqueue(&job("too-large", 8192)).await?;
+
+    queue.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tenant_isolation_and_rate_limits() -> Result<()> {
+    use redis_agent_worker::queue::RateLimited;
+    use redis_agent_worker::tenant::{tenant_queue_name, TenantRegistry};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let registry = TenantRegistry::new(&redis_url, "test_tenant_queue").await?;
+    registry.register("acme").await?;
+    registry.register("globex").await?;
+    registry.register("acme").await?;
+    assert_eq!(
+        registry.list().await?.into_iter().collect::<Vec<_>>(),
+        vec!["acme", "globex"]
+    );
+
+    let acme_queue_name = tenant_queue_name("test_tenant_queue", "acme");
+    let globex_queue_name = tenant_queue_name("test_tenant_queue", "globex");
+    let mut acme = ReliableQueue::new(&redis_url, &acme_queue_name, 5).await?;
+    let mut globex = ReliableQueue::new(&redis_url, &globex_queue_name, 5).await?;
+
+    let job = |id: &str| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Tidy up".to_string(),
+        ..Default::default()
+    };
+
+    acme.set_rate_limit(Some(2)).await?;
+    assert_eq!(acme.rate_limit().await?, Some(2));
+    acme.enqueue(&job("acme-1")).await?;
+    acme.enqueue(&job("acme-2")).await?;
+    let error = acme.enqueue(&job("acme-3")).await.unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<RateLimited>(),
+        Some(&RateLimited { per_minute: 2 })
+    );
+    assert!(acme.enqueue_batch(&[job("acme-3")]).await.is_err());
+
+    // Other tenants neither see the jobs nor share the limit
+    globex.enqueue(&job("globex-1")).await?;
+    assert_eq!(acme.len().await?, 2);
+    assert_eq!(globex.len().await?, 1);
+    assert!(globex.get_status("acme-1").await?.is_none());
+
+    acme.set_rate_limit(None).await?;
+    acme.enqueue(&job("acme-3")).await?;
+    assert_eq!(acme.stats().await?.pending, 3);
+
+    acme.clear().await?;
+    globex.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fair_scheduler_takes_turns_between_tenants() -> Result<()> {
+    use redis_agent_worker::tenant::{tenant_queue_name, FairScheduler, TenantRegistry};
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let registry = TenantRegistry::new(&redis_url, "test_fair_queue").await?;
+    registry.register("acme").await?;
+    registry.register("globex").await?;
+
+    let job = |id: &str| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Tidy up".to_string(),
+        ..Default::default()
+    };
+    // acme's burst arrives before globex's single job
+    let mut acme =
+        ReliableQueue::new(&redis_url, &tenant_queue_name("test_fair_queue", "acme"), 1).await?;
+    let mut globex =
+        ReliableQueue::new(&redis_url, &tenant_queue_name("test_fair_queue", "globex"), 1).await?;
+    acme.enqueue_batch(&[job("acme-1"), job("acme-2"), job("acme-3")]).await?;
+    globex.enqueue(&job("globex-1")).await?;
+
+    let base = ReliableQueue::new(&redis_url, "test_fair_queue", 1)
+        .await?
+        .with_worker_id("fair-worker");
+    let mut scheduler = FairScheduler::new(registry, base, Duration::from_secs(1));
+    let mut served = Vec::new();
+    while let Some((tenant, mut queue, job)) = scheduler.dequeue().await? {
+        assert_eq!(queue.queue_name(), tenant_queue_name("test_fair_queue", &tenant));
+        queue.ack(&job).await?;
+        served.push(job.id);
+    }
+    assert_eq!(served, ["acme-1", "globex-1", "acme-2", "acme-3"]);
+    assert!(scheduler.is_drained().await?);
+    assert_eq!(
+        acme.get_status("acme-2").await?.and_then(|status| status.state),
+        Some(JobState::Succeeded)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_discover_queues_under_key_prefix() -> Result<()> {
+    use redis_agent_worker::namespace::namespaced_queue_name;
+    use redis_agent_worker::queue::{discover_queues, now_secs};
+    use redis_agent_worker::tenant::tenant_queue_name;
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let prod_queue_name = namespaced_queue_name("agentworker:prod:", "agent_jobs");
+    let tenant_name = tenant_queue_name(&prod_queue_name, "acme");
+    let staging_queue_name = namespaced_queue_name("agentworker:staging:", "agent_jobs");
+    let mut prod = ReliableQueue::new(&redis_url, &prod_queue_name, 5).await?;
+    let mut tenant = ReliableQueue::new(&redis_url, &tenant_name, 5).await?;
+    let mut staging = ReliableQueue::new(&redis_url, &staging_queue_name, 5).await?;
+
+    let job = |id: &str| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Tidy up".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(prod.oldest_pending_age(now_secs()).await?, None);
+    prod.enqueue(&job("prod-1")).await?;
+    prod.enqueue(&job("prod-2")).await?;
+    tenant.enqueue(&job("acme-1")).await?;
+    staging.enqueue(&job("staging-1")).await?;
+
+    let queues = discover_queues(&redis_url, "agentworker:prod:").await?;
+    assert_eq!(
+        queues.into_iter().collect::<Vec<_>>(),
+        vec![prod_queue_name.clone(), tenant_name.clone()]
+    );
+    assert_eq!(discover_queues(&redis_url, "").await?.len(), 3);
+
+    let age = prod.oldest_pending_age(now_secs() + 60).await?;
+    assert!(age.is_some_and(|age| (60..=65).contains(&age)), "{:?}", age);
+    assert!(prod.stats().await?.oldest_pending_secs.is_some_and(|age| age <= 5));
+
+    prod.clear().await?;
+    tenant.clear().await?;
+    staging.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_webhook_delivery_retries_and_failures() -> Result<()> {
+    use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use redis_agent_worker::webhook::{
+        signature, JobEvent, WebhookConfig, WebhookFailures, WebhookFormat, WebhookNotifier,
+    };
+    use std::sync::{Arc, Mutex};
+
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    // Receiver that rejects the first delivery attempt
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+    async fn receive(
+        State(received): State<Received>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        let mut received = received.lock().unwrap();
+        received.push((headers, body));
+        if received.len() == 1 {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        }
+    }
+    let received: Received = Default::default();
+    let app = Router::new()
+        .route("/hook", post(receive))
+        .with_state(received.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let hook_url = format!("http://{}/hook", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let notifier = WebhookNotifier::spawn(
+        &redis_url,
+        "test_webhook_queue",
+        WebhookConfig {
+            url: hook_url.clone(),
+            secret: "hook-secret".to_string(),
+            max_attempts: 3,
+            format: WebhookFormat::Native,
+            source: None,
+        },
+    )
+    .await?;
+    notifier.notify(JobEvent::new("webhook-job", JobState::Succeeded));
+    notifier.shutdown().await;
+
+    {
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2, "Rejected delivery should be retried");
+        let (headers, body) = &received[1];
+        assert_eq!(headers["x-agent-worker-event"], "job.succeeded");
+        assert_eq!(
+            headers["x-agent-worker-signature-256"],
+            signature("hook-secret", body.as_bytes()).as_str()
+        );
+        assert_eq!(
+            headers["x-agent-worker-delivery"], received[0].0["x-agent-worker-delivery"],
+            "Retries should keep the delivery ID"
+        );
+        assert!(body.contains(r#""job_id":"webhook-job""#));
+    }
+
+    // CloudEvents deliveries wrap the event
+    let notifier = WebhookNotifier::spawn(
+        &redis_url,
+        "test_webhook_queue",
+        WebhookConfig {
+            url: hook_url.clone(),
+            secret: "hook-secret".to_string(),
+            max_attempts: 3,
+            format: WebhookFormat::CloudEvents,
+            source: None,
+        },
+    )
+    .await?;
+    notifier.notify(JobEvent::new("webhook-job", JobState::Failed));
+    notifier.shutdown().await;
+
+    {
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        let (headers, body) = &received[2];
+        assert_eq!(
+            headers["content-type"],
+            "application/cloudevents+json; charset=utf-8"
+        );
+        let event: serde_json::Value = serde_json::from_str(body)?;
+        assert_eq!(
+            event["type"],
+            "io.github.r33drichards.redis-agent-worker.job.failed"
+        );
+        assert_eq!(event["source"], "/redis-agent-worker/test_webhook_queue");
+        assert_eq!(event["subject"], "webhook-job");
+        assert_eq!(event["data"]["job_id"], "webhook-job");
+    }
+
+    // Deliveries to an unreachable receiver are recorded once retries run out
+    let notifier = WebhookNotifier::spawn(
+        &redis_url,
+        "test_webhook_queue",
+        WebhookConfig {
+            url: "http://127.0.0.1:1/hook".to_string(),
+            secret: "hook-secret".to_string(),
+            max_attempts: 2,
+            format: WebhookFormat::Native,
+            source: None,
+        },
+    )
+    .await?;
+    notifier.notify(JobEvent::new("unreachable-job", JobState::Failed));
+    notifier.shutdown().await;
+
+    let failures = WebhookFailures::new(&redis_url, "test_webhook_queue")
+        .await?
+        .list()
+        .await?;
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].payload.job_id, "unreachable-job");
+    assert_eq!(failures[0].attempts, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failure_digest() -> Result<()> {
+    use redis_agent_worker::email::Digest;
+    use redis_agent_worker::queue::DeadReason;
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_digest_queue", 5).await?;
+
+    let jobs: Vec<Job> = ["given-up-job", "expired-job", "succeeded-job"]
+        .iter()
+        .map(|id| Job {
+            id: id.to_string(),
+            repo_url: "git@github.com:test/repo.git".to_string(),
+            base_branch: "main".to_string(),
+            prompt: "Fix the bug".to_string(),
+            ..Default::default()
+        })
+        .collect();
+    queue.enqueue_batch(&jobs).await?;
+
+    let given_up = queue.dequeue().await?.expect("Should dequeue job");
+    queue.record_failure(&given_up, ErrorClass::Push, "Failed to push changes").await;
+    queue.fail(&given_up).await?;
+    let expired = queue.dequeue().await?.expect("Should dequeue job");
+    queue.dead_letter(&expired, DeadReason::Expired).await?;
+    let succeeded = queue.dequeue().await?.expect("Should dequeue job");
+    queue.ack(&succeeded).await?;
+
+    let digest = Digest::collect(&mut queue, Duration::from_secs(3600)).await?;
+    let failed: Vec<&str> = digest.failed.iter().map(|s| s.job_id.as_str()).collect();
+    assert_eq!(failed, vec!["given-up-job"], "Dead letters are listed separately");
+    assert_eq!(
+        digest.failed[0].last_error.as_deref(),
+        Some("Failed to push changes")
+    );
+    assert_eq!(digest.dead.len(), 1);
+    assert_eq!(digest.dead[0].job.id, "expired-job");
+    assert_eq!(digest.dead_total, 1);
+
+    // Failures before the window are left out
+    assert!(queue.failed_since(u64::MAX).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migrate_between_queues() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    use redis_agent_worker::migrate::{migrate, MigrateOptions};
+    let mut source = ReliableQueue::new(&redis_url, "test_migrate_source", 5).await?;
+    let mut destination = ReliableQueue::new(&redis_url, "test_migrate_destination", 5).await?;
+
+    let job = |id: &str, prompt: &str| Job {
+        id: id.to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: prompt.to_string(),
+        ..Default::default()
+    };
+    for id in ["job-a", "job-b", "job-c"] {
+        source.enqueue(&job(id, "Original")).await?;
+    }
+    // A different job already owns this ID in the destination
+    destination.enqueue(&job("job-b", "Unrelated")).await?;
+
+    let dry_run = MigrateOptions {
+        move_jobs: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    let report = migrate(&mut source, &mut destination, dry_run).await?;
+    assert_eq!(report.pending, 2);
+    assert_eq!(source.len().await?, 3);
+    assert_eq!(destination.len().await?, 1);
+
+    let move_jobs = MigrateOptions {
+        move_jobs: true,
+        ..Default::default()
+    };
+    let report = migrate(&mut source, &mut destination, move_jobs).await?;
+    assert_eq!(report.pending, 2);
+    assert_eq!(report.skipped_duplicates, vec!["job-b".to_string()]);
+
+    let left: Vec<String> = source.list_pending().await?.into_iter().map(|j| j.id).collect();
+    assert_eq!(left, vec!["job-b".to_string()]);
+    let moved: Vec<String> = destination
+        .list_pending()
+        .await?
+        .into_iter()
+        .map(|j| j.id)
+        .collect();
+    assert_eq!(moved, vec!["job-b", "job-a", "job-c"]);
+
+    // Status history moves with the job
+    let status = destination.get_status("job-a").await?.expect("Migrated status");
+    assert_eq!(status.state, Some(JobState::Pending));
+    assert!(status.enqueued_at.is_some());
+    assert!(source.get_status("job-a").await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_status_tracking() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_status_queue", 5)
+        .await?
+        .with_worker_id("status-worker");
+
+    let job = Job {
+        id: Uuid::new_v4().to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        base_branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        ..Default::default()
+    };
+
+    assert!(queue.get_status(&job.id).await?.is_none());
+
+    queue.enqueue(&job).await?;
+    let status = queue.get_status(&job.id).await?.expect("Status after enqueue");
+    assert_eq!(status.state, Some(JobState::Pending));
+    assert_eq!(status.attempts, 0);
+    assert!(status.enqueued_at.is_some());
+
+    queue.dequeue().await?.expect("Should dequeue job");
+    let status = queue.get_status(&job.id).await?.expect("Status after dequeue");
+    assert_eq!(status.state, Some(JobState::Running));
+    assert_eq!(status.attempts, 1);
+    assert_eq!(status.worker.as_deref(), Some("status-worker"));
+    assert_eq!(queue.depths().await?, (0, 1));
+    assert_eq!(
+        queue.busy_workers().await?.into_iter().collect::<Vec<_>>(),
+        ["status-worker"]
+    );
+    let statuses = queue
+        .get_statuses(&[job.id.clone(), "unknown-job".to_string()])
+        .await?;
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].job_id, job.id);
+
+    let progress = AgentProgress {
+        step: "discover".to_string(),
+        percent: 30,
+        message: "Listing the available tools".to_string(),
+    };
+    queue.record_progress(&job.id, &progress).await;
+    let status = queue.get_status(&job.id).await?.expect("Status after progress");
+    assert_eq!(status.progress, Some(progress));
+
+    queue.nack(&job, ErrorClass::Clone, "clone: boom").await?;
+    let status = queue.get_status(&job.id).await?.expect("Status after nack");
+    assert_eq!(status.state, Some(JobState::Pending));
+    assert_eq!(status.last_error.as_deref(), Some("clone: boom"));
+    assert_eq!(status.failures.len(), 1);
+    let failure = &status.failures[0];
+    assert_eq!((failure.attempt, failure.class), (1, ErrorClass::Clone));
+    assert_eq!(failure.error, "clone: boom");
+
+    queue.dequeue().await?.expect("Should dequeue job again");
+    let status = queue.get_status(&job.id).await?.expect("Status after retry");
+    assert_eq!(status.progress, None, "Progress of the last attempt should be cleared");
+    queue.record_commit(&job, "abc123").await;
+    queue.ack(&job).await?;
+    let status = queue.get_status(&job.id).await?.expect("Status after ack");
+    assert_eq!(status.state, Some(JobState::Succeeded));
+    assert_eq!(status.attempts, 2);
+    assert_eq!(status.commit_sha.as_deref(), Some("abc123"));
+    assert!(status.finished_at.is_some());
+
+    let stats = queue.stats().await?;
+    assert_eq!(stats.succeeded, 1);
+    assert_eq!(stats.failed, 1);
+
+    let timeline = queue.timeline(&job.id).await?.expect("Timeline after ack");
+    let events: Vec<TimelineEvent> = timeline.steps.iter().map(|step| step.event).collect();
+    assert_eq!(
+        events,
+        [
+            TimelineEvent::Enqueued,
+            TimelineEvent::Dequeued,
+            TimelineEvent::Nacked,
+            TimelineEvent::Dequeued,
+            TimelineEvent::Acked,
+        ]
+    );
+    assert!(queue.timeline("unknown-job").await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancel_pending_and_in_flight_jobs() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_cancel_queue", 5).await?;
+
+    let jobs: Vec<Job> = (0..2)
+        .map(|i| Job {
+            id: format!("cancel-job-{}", i),
+            repo_url: "git@github.com:test/repo.git".to_string(),
+            base_branch: "main".to_string(),
+            prompt: format!("Test prompt {}", i),
+            ..Default::default()
+        })
+        .collect();
+    for job in &jobs {
+        queue.enqueue(job).await?;
+    }
+
+    // The first job is dequeued (in flight), the second stays pending
+    let in_flight = queue.dequeue().await?.expect("Should dequeue job");
+    assert_eq!(in_flight.id, jobs[0].id);
+
+    assert_eq!(queue.cancel(&jobs[1].id).await?, CancelOutcome::Removed);
+    assert_eq!(queue.len().await?, 0, "Pending job should be removed");
+    let status = queue.get_status(&jobs[1].id).await?.unwrap();
+    assert_eq!(status.state, Some(JobState::Cancelled));
+
+    assert!(!queue.is_cancel_requested(&in_flight.id).await?);
+    assert_eq!(queue.cancel(&in_flight.id).await?, CancelOutcome::Flagged);
+    assert!(queue.is_cancel_requested(&in_flight.id).await?);
+
+    queue.finish_cancelled(&in_flight).await?;
+    assert_eq!(queue.processing_len().await?, 0);
+    let status = queue.get_status(&in_flight.id).await?.unwrap();
+    assert_eq!(status.state, Some(JobState::Cancelled));
+
+    assert_eq!(queue.cancel("missing-job").await?, CancelOutcome::NotFound);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_approval_gate() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let mut queue = ReliableQueue::new(&redis_url, "test_approval_queue", 5).await?;
+
+    let jobs: Vec<Job> = (0..2)
+        .map(|i| {
+            let mut job = Job {
+                id: format!("approval-job-{}", i),
+                repo_url: "git@github.com:test/repo.git".to_string(),
+                base_branch: "main".to_string(),
+                prompt: format!("Test prompt {}", i),
+                ..Default::default()
+            };
+            job.options.require_approval = true;
+            job
+        })
+        .collect();
+    for job in &jobs {
+        queue.enqueue(job).await?;
+        let job = queue.dequeue().await?.expect("Should dequeue job");
+        queue.await_approval(&job).await?;
+        let status = queue.get_status(&job.id).await?.unwrap();
+        assert_eq!(status.state, Some(JobState::AwaitingApproval));
+    }
+    assert_eq!(queue.processing_len().await?, 0);
+
+    // Approving enqueues the job again to push the stored changes, once
+    assert!(queue.approve(&jobs[0].id).await?);
+    assert!(!queue.approve(&jobs[0].id).await?);
+    let approved = queue.dequeue().await?.expect("Approved job should be enqueued");
+    assert_eq!(approved.id, jobs[0].id);
+    assert!(approved.options.approved);
+    assert!(!approved.options.require_approval);
+
+    assert!(queue.reject(&jobs[1].id).await?);
+    assert!(!queue.approve(&jobs[1].id).await?, "Rejected jobs can't be approved");
+    let status = queue.get_status(&jobs[1].id).await?.unwrap();
+    assert_eq!(status.state, Some(JobState::Cancelled));
+    assert_eq!(queue.len().await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_log_stream() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    use redis_agent_worker::joblog::JobLogStream;
+    let stream = JobLogStream::new(&redis_url, "test_log_queue").await?;
+
+    stream.append("log-job", "INFO", "Cloning repository").await?;
+    stream.append("log-job", "WARN", "No changes detected").await?;
+
+    let entries = stream.read_after("log-job", "0", None).await?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].level, "INFO");
+    assert_eq!(entries[1].message, "No changes detected");
+
+    // Reading after the last ID only returns newer lines
+    let newer = stream.read_after("log-job", &entries[1].id, None).await?;
+    assert!(newer.is_empty());
+
+    assert!(stream.read_after("other-job", "0", None).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_result_storage() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    use redis_agent_worker::result::{JobResult, ToolCall};
+    let mut queue = ReliableQueue::new(&redis_url, "test_result_queue", 5).await?;
+
+    assert!(queue.get_result("result-job").await?.is_none());
+
+    let result = JobResult {
+        job_id: "result-job".to_string(),
+        summary: "Fixed the typo".to_string(),
+        commit_sha: Some("abc123".to_string()),
+        diff: "--- a/README.md\n+++ b/README.md\n".to_string(),
+        tool_transcript: vec![ToolCall {
+            tool: "write_file".to_string(),
+            arguments: "{\"path\":\"README.md\"}".to_string(),
+            response: "ok".to_string(),
+            cached: false,
+        }],
+        ..Default::default()
+    };
+    queue.store_result(&result).await?;
+
+    let stored = queue.get_result("result-job").await?.expect("result should be stored");
+    assert_eq!(stored.summary, "Fixed the typo");
+    assert_eq!(stored.commit_sha.as_deref(), Some("abc123"));
+    assert_eq!(stored.diff, result.diff);
+    assert_eq!(stored.tool_transcript.len(), 1);
+    assert_eq!(stored.tool_transcript[0].tool, "write_file");
+
+    assert!(queue.cached_result("content-key").await?.is_none());
+    queue
+        .cache_result("content-key", &result, Duration::from_secs(1))
+        .await?;
+    let cached = queue
+        .cached_result("content-key")
+        .await?
+        .expect("result should be cached");
+    assert_eq!(cached.job_id, "result-job");
+    assert_eq!(cached.diff, result.diff);
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(
+        queue.cached_result("content-key").await?.is_none(),
+        "Cached result should expire"
+    );
+
+    // Results expire once the queue has a result TTL, unless their changes
+    // still await approval
+    let mut expiring = queue.clone().with_result_ttl(Some(Duration::from_secs(1)));
+    expiring.store_result(&result).await?;
+    expiring
+        .store_result(&JobResult {
+            job_id: "approval-job".to_string(),
+            awaiting_approval: true,
+            ..result.clone()
+        })
+        .await?;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(
+        queue.get_result("result-job").await?.is_none(),
+        "Stored result should expire"
+    );
+    assert!(queue.get_result("approval-job").await?.is_some());
+    queue.store_result(&result).await?;
+
+    // Changes whose push failed are kept apart from the job's result
+    assert!(queue.checkpoint("result-job").await?.is_none());
+    queue
+        .store_checkpoint(&result, Duration::from_secs(60))
+        .await?;
+    let checkpoint = queue
+        .checkpoint("result-job")
+        .await?
+        .expect("checkpoint should be stored");
+    assert_eq!(checkpoint.diff, result.diff);
+    queue.clear_checkpoint("result-job").await?;
+    assert!(queue.checkpoint("result-job").await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_server() -> Result<()> {
+    use redis_agent_worker::github_events::GitHubWebhookConfig;
+    use redis_agent_worker::metrics::MetricLabels;
+    use redis_agent_worker::webhook;
+
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    let queue = ReliableQueue::new(&redis_url, "test_api_queue", 5).await?;
+    let github_webhook = GitHubWebhookConfig {
+        secret: "webhook-secret".to_string(),
+        label: "agent".to_string(),
+        clone_https: false,
+    };
+    let metric_labels = MetricLabels::default().render("test_api_queue", None);
+    let app = redis_agent_worker::server::router(
+        queue,
+        "secret-token",
+        Some(github_webhook),
+        &metric_labels,
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+
+    let response = client.get(format!("{}/health", base_url)).send().await?;
+    assert_eq!(response.status(), 200);
+
+    // Everything else requires the token
+    let response = client.get(format!("{}/stats", base_url)).send().await?;
+    assert_eq!(response.status(), 401);
+
+    let response = client
+        .post(format!("{}/jobs", base_url))
+        .bearer_auth("secret-token")
+        .json(&serde_json::json!({
+            "id": "api-job",
+            "repo_url": "git@github.com:test/repo.git",
+            "branch": "main",
+            "prompt": "Test prompt",
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+
+    let response = client
+        .post(format!("{}/jobs", base_url))
+        .bearer_auth("secret-token")
+        .json(&serde_json::json!({
+            "id": "bad-job",
+            "repo_url": "",
+            "branch": "main",
+            "prompt": "Test prompt",
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+
+    let stats: serde_json::Value = client
+        .get(format!("{}/stats", base_url))
+        .bearer_auth("secret-token")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(stats["pending"], 1);
+    assert_eq!(stats["processing"], 0);
+
+    let mut metrics_queue = ReliableQueue::new(&redis_url, "test_api_queue", 5).await?;
+    metrics_queue
+        .record_stage_duration(Stage::Clone, Duration::from_millis(1500))
+        .await;
+    metrics_queue
+        .record_stage_duration(Stage::Clone, Duration::from_secs(40))
+        .await;
+    let stages = metrics_queue.stage_histograms().await?;
+    assert_eq!(stages[&Stage::Clone].count, 2);
+    assert_eq!(stages[&Stage::Clone].sum_secs, 41.5);
+    assert_eq!(stages[&Stage::Push].count, 0);
+
+    let response = client
+        .get(format!("{}/metrics", base_url))
+        .bearer_auth("secret-token")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let metrics = response.text().await?;
+    assert!(metrics.contains("agent_worker_jobs_pending{queue=\"test_api_queue\"} 1\n"));
+    assert!(metrics.contains(
+        "agent_worker_stage_duration_seconds_bucket{queue=\"test_api_queue\",\
+         stage=\"clone\",le=\"2.5\"} 1\n"
+    ));
+    assert!(metrics.contains(
+        "agent_worker_stage_duration_seconds_count{queue=\"test_api_queue\",\
+         stage=\"clone\"} 2\n"
+    ));
+
+    let status: serde_json::Value = client
+        .get(format!("{}/jobs/api-job", base_url))
+        .bearer_auth("secret-token")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(status["state"], "pending");
+
+    let cancel: serde_json::Value = client
+        .post(format!("{}/jobs/api-job/cancel", base_url))
+        .bearer_auth("secret-token")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(cancel["outcome"], "removed");
+
+    let response = client
+        .get(format!("{}/jobs/api-job/result", base_url))
+        .bearer_auth("secret-token")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    // GitHub deliveries are authenticated by their signature, not the token
+    let delivery = serde_json::json!({
+        "action": "created",
+        "issue": { "number": 3, "title": "Flaky test", "body": "See CI" },
+        "comment": { "body": "/agent do Fix the flaky test", "author_association": "OWNER" },
+        "repository": {
+            "full_name": "test/repo",
+            "clone_url": "https://github.com/test/repo.git",
+            "ssh_url": "git@github.com:test/repo.git",
+            "default_branch": "main",
+        },
+    })
+    .to_string();
+    let send_delivery = |signature: String| {
+        client
+            .post(format!("{}/github/webhook", base_url))
+            .header("X-GitHub-Event", "issue_comment")
+            .header("X-GitHub-Delivery", "delivery-1")
+            .header("X-Hub-Signature-256", signature)
+            .body(delivery.clone())
+            .send()
+    };
+    let response = send_delivery("sha256=0".to_string()).await?;
+    assert_eq!(response.status(), 401);
+
+    let signature = webhook::signature("webhook-secret", delivery.as_bytes());
+    let response = send_delivery(signature.clone()).await?;
+    assert_eq!(response.status(), 202);
+    let redelivery: serde_json::Value = send_delivery(signature).await?.json().await?;
+    assert_eq!(redelivery["duplicate"], true);
+
+    let status: serde_json::Value = client
+        .get(format!("{}/jobs/github-delivery-1", base_url))
+        .bearer_auth("secret-token")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(status["state"], "pending");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_grpc_service() -> Result<()> {
+    common::init_test_logging();
+
+    let redis_container = GenericImage::new("redis", "7-alpine")
+        .with_exposed_port(6379.into())
+        .start()
+        .await
+        .expect("Failed to start Redis container");
+
+    let redis_port = redis_container.get_host_port_ipv4(6379).await.unwrap();
+    let redis_url = format!("redis://127.0.0.1:{}", redis_port);
+
+    use redis_agent_worker::grpc::{self, proto, JobServiceImpl};
+    use redis_agent_worker::joblog::JobLogStream;
+    use proto::job_service_client::JobServiceClient;
+
+    let job_service = JobServiceImpl::new(&redis_url, "test_grpc_queue").await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move {
+        tonic::transport::Server::builder()
+            .add_service(grpc::service(job_service, "secret-token"))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+            .await
+            .unwrap();
+    });
+
+    let mut client = JobServiceClient::connect(endpoint).await?;
+    fn authorized<T>(message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret-token".parse().unwrap());
+        request
+    }
+
+    let job = proto::Job {
+        id: "grpc-job".to_string(),
+        repo_url: "git@github.com:test/repo.git".to_string(),
+        branch: "main".to_string(),
+        prompt: "Test prompt".to_string(),
+        ..Default::default()
+    };
+
+    // Calls without the token are rejected
+    let error = client
+        .enqueue(proto::EnqueueRequest { job: Some(job.clone()) })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), tonic::Code::Unauthenticated);
+
+    let response = client
+        .enqueue(authorized(proto::EnqueueRequest { job: Some(job) }))
+        .await?;
+    assert_eq!(response.into_inner().job_id, "grpc-job");
+
+    let status = client
+        .get_status(authorized(proto::GetStatusRequest {
+            job_id: "grpc-job".to_string(),
+        }))
+        .await?
+        .into_inner();
+    assert_eq!(status.state(), proto::JobState::Pending);
+
+    let logs = JobLogStream::new(&redis_url, "test_grpc_queue").await?;
+    logs.append("grpc-job", "INFO", "Cloning repository").await?;
+    let mut stream = client
+        .stream_logs(authorized(proto::StreamLogsRequest {
+            job_id: "grpc-job".to_string(),
+            follow: false,
+        }))
+        .await?
+        .into_inner();
+    let entry = stream.message().await?.expect("Should stream a log line");
+    assert_eq!(entry.message, "Cloning repository");
+    assert!(stream.message().await?.is_none());
+
+    let cancel = client
+        .cancel(authorized(proto::CancelRequest {
+            job_id: "grpc-job".to_string(),
+        }))
+        .await?
+        .into_inner();
+    assert_eq!(cancel.outcome(), proto::CancelOutcome::Removed);
+
+    let error = client
+        .cancel(authorized(proto::CancelRequest {
+            job_id: "grpc-job".to_string(),
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), tonic::Code::NotFound);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_instance_allocator() -> Result<()> {
+    common::init_test_logging();
+
+    // Start mock allocator
+    let (allocator_url, state) = common::start_mock_allocator().await;
+
+    // Test borrowing an instance
+    use redis_agent_worker::instance::InstanceAllocator;
+    let allocator = InstanceAllocator::new(allocator_url.clone());
+
+    let instance1 = allocator.borrow_instance(&Default::default()).await?;
+    assert!(instance1.id.starts_with("mock-instance-"));
+    assert!(instance1.mcp_connection_url.starts_with("http://mock-mcp-"));
+
+    // Check state
+    let borrow_count = state.borrow_count().await;
+    assert_eq!(borrow_count, 1, "Should have 1 borrowed instance");
+
+    // Return the instance
+    allocator.return_instance(&instance1, None).await?;
+
+    let return_count = state.return_count().await;
+    assert_eq!(return_count, 1, "Should have 1 returned instance");
+
+    // Borrow multiple instances
+    let instance2 = allocator.borrow_instance(&Default::default()).await?;
+    let instance3 = allocator.borrow_instance(&Default::default()).await?;
+
+    assert_ne!(instance2.id, instance3.id, "Instances should have unique IDs");
+
+    let borrow_count = state.borrow_count().await;
+    assert_eq!(borrow_count, 3, "Should have 3 total borrowed instances");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_instance_allocator_rejects_missing_capabilities() -> Result<()> {
+    common::init_test_logging();
+
+    let (allocator_url, state) = common::start_mock_allocator().await;
+
+    use redis_agent_worker::instance::InstanceAllocator;
+    let allocator = InstanceAllocator::new(allocator_url);
+
+    // The mock allocator never advertises capabilities, so any requirement fails
+    let mut required = std::collections::BTreeMap::new();
+    required.insert("gpu".to_string(), "true".to_string());
+
+    let result = allocator.borrow_instance(&required).await;
+    assert!(result.is_err(), "Borrow should fail when capabilities are missing");
+
+    // The unsuitable instance must be handed back rather than leaked
+    assert_eq!(state.borrow_count().await, 1);
+    assert_eq!(state.return_count().await, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_instance_allocator_avoids_quarantined_instances() -> Result<()> {
+    use axum::{extract::State, routing::post, Json, Router};
+    use redis_agent_worker::instance::{
+        InstanceAllocator, InstanceQuarantine, OnlyQuarantinedInstances,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    common::init_test_logging();
+
+    // Allocator with a single bad instance, which leaves out the instances
+    // it's asked to exclude only once `honor_exclude` is set
+    #[derive(Clone, Default)]
+    struct Allocator {
+        honor_exclude: Arc<Mutex<bool>>,
+        requests: Arc<Mutex<Vec<serde_json::Value>>>,
+        returned: Arc<Mutex<usize>>,
+    }
+    async fn borrow(
+        State(allocator): State<Allocator>,
+        Json(request): Json<serde_json::Value>,
+    ) -> Json<serde_json::Value> {
+        let excluded = request["exclude"]
+            .as_array()
+            .is_some_and(|ids| ids.iter().any(|id| id == "bad-instance"));
+        allocator.requests.lock().unwrap().push(request);
+        let id = if excluded && *allocator.honor_exclude.lock().unwrap() {
+            "good-instance"
+        } else {
+            "bad-instance"
+        };
+        Json(serde_json::json!({
+            "id": id,
+            "mcp_connection_url": "http://mcp.example.com",
+            "api_url": "http://api.example.com",
+        }))
+    }
+    async fn give_back(State(allocator): State<Allocator>) {
+        *allocator.returned.lock().unwrap() += 1;
+    }
+    let state = Allocator::default();
+    let app = Router::new()
+        .route("/borrow", post(borrow))
+        .route("/return", post(give_back))
+        .with_state(state.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let allocator = InstanceAllocator::new(format!("http://{}", listener.local_addr()?));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let quarantine = Mutex::new(InstanceQuarantine::new(1, Duration::from_secs(60)));
+    quarantine.lock().unwrap().record("bad-instance", true, Instant::now());
+
+    // An allocator that ignores the exclusions only ever hands out the bad
+    // instance, which fails the borrow in a way the worker doesn't count
+    let error = allocator
+        .borrow_outside(&Default::default(), &quarantine, 3)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<OnlyQuarantinedInstances>(),
+        Some(&OnlyQuarantinedInstances { attempts: 3 })
+    );
+    assert_eq!(*state.returned.lock().unwrap(), 3, "Bad instances should be returned");
+    assert_eq!(
+        state.requests.lock().unwrap()[0]["exclude"],
+        serde_json::json!(["bad-instance"])
+    );
+
+    *state.honor_exclude.lock().unwrap() = true;
+    let instance = allocator
+        .borrow_outside(&Default::default(), &quarantine, 3)
+        .await?;
+    assert_eq!(instance.id, "good-instance");
+    assert_eq!(*state.returned.lock().unwrap(), 3);
+
+    // Without a quarantine nothing is excluded
+    allocator.borrow_instance(&Default::default()).await?;
+    let requests = state.requests.lock().unwrap();
+    assert!(requests.last().unwrap().get("exclude").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trace_context_propagation() -> Result<()> {
+    use axum::{extract::State, http::HeaderMap, routing::post, Json, Router};
+    use redis_agent_worker::instance::InstanceAllocator;
+    use redis_agent_worker::trace::{self, TraceContext};
+    use std::sync::{Arc, Mutex};
+
+    common::init_test_logging();
+
+    // Allocator that records the traceparent of every request
+    type Seen = Arc<Mutex<Vec<Option<String>>>>;
+    async fn borrow(State(seen): State<Seen>, headers: HeaderMap) -> Json<serde_json::Value> {
+        let traceparent = headers
+            .get("traceparent")
+            .map(|value| value.to_str().unwrap().to_string());
+        seen.lock().unwrap().push(traceparent);
+        Json(serde_json::json!({
+            "id": "traced-instance",
+            "mcp_connection_url": "http://mcp.example.com",
+            "api_url": "http://api.example.com"
+        }))
+    }
+    let seen: Seen = Default::default();
+    let app = Router::new()
+        .route("/borrow", post(borrow))
+        .with_state(seen.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let allocator = InstanceAllocator::new(format!("http://{}", listener.local_addr()?));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let job_trace = TraceContext::new();
+    trace::scope(job_trace.clone(), allocator.borrow_instance(&Default::default())).await?;
+    allocator.borrow_instance(&Default::default()).await?;
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen[0].as_deref(), Some(job_trace.traceparent().as_str()));
+    assert_eq!(seen[1], None, "Requests outside a job carry no trace");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_git_operations() -> Result<()> {
+    common::init_test_logging();
+
+    let temp_dir = TempDir::new()?;
+    let branch_name = "test-branch";
+
+    // Setup git environment with remote
+    let (local_path, remote_url) = common::setup_test_git_env(temp_dir.path(), branch_name)?;
+
+    // Clone the repository to a new location
+    let clone_dir = temp_dir.path().join("cloned");
+    use redis_agent_worker::git::GitRepo;
+    let git_repo = GitRepo::clone(&remote_url, &clone_dir)?;
+
+    // Checkout the test branch
+    git_repo.fetch()?;
+    git_repo.checkout_branch(branch_name)?;
+
+    // Make a change
+    let test_file = clone_dir.join("test.txt");
+    std::fs::write(&test_file, "Test content\n")?;
+
+    // Check that there are changes
+    assert!(git_repo.has_changes()?, "Should detect changes");
+
+    // Commit and push
+    git_repo.stage_all()?;
+    git_repo.commit("Add test file")?;
+    git_repo.push(branch_name)?;
+
+    // Verify no more changes
+    assert!(!git_repo.has_changes()?, "Should have no changes after commit");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_git_repo() -> Result<()> {
+    common::init_test_logging();
+
+    let temp_dir = TempDir::new()?;
+    let branch_name = "test-branch";
+    let (_local_path, remote_url) = common::setup_test_git_env(temp_dir.path(), branch_name)?;
+
+    use redis_agent_worker::credentials::GitCredential;
+    use redis_agent_worker::git::AsyncGitRepo;
+    let clone_dir = temp_dir.path().join("cloned");
+    let git_repo =
+        AsyncGitRepo::clone_with(&remote_url, &clone_dir, &GitCredential::default()).await?;
+    assert_eq!(git_repo.path(), clone_dir);
+
+    git_repo
+        .run(move |repo| {
+            repo.fetch()?;
+            repo.checkout_branch(branch_name)
+        })
+        .await?;
+    std::fs::write(clone_dir.join("test.txt"), "Test content\n")?;
+
+    let commit_sha = git_repo
+        .run(|repo| {
+            repo.stage_all()?;
+            repo.commit("Add test file")
+        })
+        .await?;
+    assert_eq!(git_repo.run(|repo| repo.head_commit()).await?, commit_sha);
+
+    // Errors from the blocking thread come back to the caller
+    let missing = git_repo.run(|repo| repo.checkout_branch("no-such-branch")).await;
+    assert!(missing.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_apply_staged_diff() -> Result<()> {
+    common::init_test_logging();
+
+    let temp_dir = TempDir::new()?;
+    let branch_name = "test-branch";
+    let (_local_path, remote_url) = common::setup_test_git_env(temp_dir.path(), branch_name)?;
+
+    use redis_agent_worker::git::GitRepo;
+    let first_dir = temp_dir.path().join("first");
+    let first = GitRepo::clone(&remote_url, &first_dir)?;
+    first.fetch()?;
+    first.checkout_branch(branch_name)?;
+    std::fs::write(first_dir.join("test.txt"), "Test content\n")?;
+    first.stage_all()?;
+    let patch = first.staged_diff()?;
+
+    // The patch recreates the change in a fresh checkout, as when an
+    // approved job is pushed
+    let second_dir = temp_dir.path().join("second");
+    let second = GitRepo::clone(&remote_url, &second_dir)?;
+    second.fetch()?;
+    second.checkout_branch(branch_name)?;
+    second.apply(&patch)?;
+    assert_eq!(
+        std::fs::read_to_string(second_dir.join("test.txt"))?,
+        "Test content\n"
+    );
+    second.commit("Add test file")?;
+    assert!(!second.has_changes()?, "Applied changes should be committed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_diff_since_base_commit() -> Result<()> {
+    common::init_test_logging();
+
+    let temp_dir = TempDir::new()?;
+    let branch_name = "test-branch";
+    let (_local_path, remote_url) = common::setup_test_git_env(temp_dir.path(), branch_name)?;
+
+    use redis_agent_worker::git::GitRepo;
+    let repo_dir = temp_dir.path().join("salvage");
+    let git_repo = GitRepo::clone(&remote_url, &repo_dir)?;
+    git_repo.fetch()?;
+    git_repo.checkout_branch(branch_name)?;
+    let base_commit = git_repo.head_commit()?;
+
+    // A task committed before the agent failed, then left more changes behind
+    std::fs::write(repo_dir.join("first.txt"), "First task\n")?;
+    git_repo.stage_all()?;
+    git_repo.commit("First task")?;
+    std::fs::write(repo_dir.join("second.txt"), "Unfinished\n")?;
+    git_repo.stage_all()?;
+    git_repo.commit("Salvaged work")?;
+    git_repo.create_branch("agent-salvage/job-1/attempt-1")?;
+
+    let diff = git_repo.diff_since(&base_commit)?;
+    assert!(diff.contains("+First task"), "Diff should include earlier commits: {}", diff);
+    assert!(diff.contains("+Unfinished"), "Diff should include the salvaged changes: {}", diff);
+    assert_eq!(git_repo.diff_since(&git_repo.head_commit()?)?, "");
 
     Ok(())
 }
@@ -280,9 +2647,10 @@ async fn test_full_workflow_with_mock_agent() -> Result<()> {
     let job = Job {
         id: Uuid::new_v4().to_string(),
         repo_url: remote_url.clone(),
-        branch: branch_name.to_string(),
+        base_branch: branch_name.to_string(),
         prompt: "Add a new feature".to_string(),
         mcp_connection_url: None,
+        ..Default::default()
     };
 
     // Enqueue the job
@@ -304,7 +2672,7 @@ async fn test_full_workflow_with_mock_agent() -> Result<()> {
     // Simulate worker borrowing an instance
     use redis_agent_worker::instance::InstanceAllocator;
     let allocator = InstanceAllocator::new(allocator_url);
-    let instance = allocator.borrow_instance().await?;
+    let instance = allocator.borrow_instance(&Default::default()).await?;
 
     assert_eq!(allocator_state.borrow_count().await, 1);
 
@@ -313,7 +2681,7 @@ async fn test_full_workflow_with_mock_agent() -> Result<()> {
     use redis_agent_worker::git::GitRepo;
     let git_repo = GitRepo::clone(&dequeued_job.repo_url, &job_work_dir)?;
     git_repo.fetch()?;
-    git_repo.checkout_branch(&dequeued_job.branch)?;
+    git_repo.checkout_branch(&dequeued_job.base_branch)?;
 
     // Simulate agent making changes
     let feature_file = job_work_dir.join("feature.txt");
@@ -322,10 +2690,10 @@ async fn test_full_workflow_with_mock_agent() -> Result<()> {
     // Commit and push changes
     git_repo.stage_all()?;
     git_repo.commit(&format!("Implement feature for job {}", dequeued_job.id))?;
-    git_repo.push(&dequeued_job.branch)?;
+    git_repo.push(&dequeued_job.base_branch)?;
 
     // Return the instance
-    allocator.return_instance(&instance).await?;
+    allocator.return_instance(&instance, None).await?;
     assert_eq!(allocator_state.return_count().await, 1);
 
     // Acknowledge the job
@@ -404,9 +2772,10 @@ async fn test_concurrent_workers() -> Result<()> {
         let job = Job {
             id: format!("concurrent-job-{}", i),
             repo_url: "git@github.com:test/repo.git".to_string(),
-            branch: "main".to_string(),
+            base_branch: "main".to_string(),
             prompt: format!("Task {}", i),
             mcp_connection_url: None,
+            ..Default::default()
         };
         queue.enqueue(&job).await?;
     }